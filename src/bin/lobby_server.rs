@@ -0,0 +1,27 @@
+// 방 코드로 모이는 로비 서버.
+//
+//   lobby_server [listen addr]     기본값 0.0.0.0:7600
+//
+// 클라이언트는 project setting application/netcode/lobby_server 에 이 주소를 넣는다.
+
+use p2pactiongame::lobby_server::{LobbyConfig, LobbyServer};
+use p2pactiongame::server_log::ServerLog;
+
+const DEFAULT_LISTEN: &str = "0.0.0.0:7600";
+
+fn main() {
+    let listen = std::env::args().nth(1).unwrap_or_else(|| DEFAULT_LISTEN.to_string());
+    let config = LobbyConfig {
+        log: ServerLog::stdout(),
+        ..LobbyConfig::new()
+    };
+    let mut server = match LobbyServer::bind(&listen, config) {
+        Ok(server) => server,
+        Err(err) => {
+            eprintln!("failed to listen on {}: {}", listen, err);
+            std::process::exit(1);
+        }
+    };
+    println!("lobby listening on {}", server.local_addr().map_or(listen, |addr| addr.to_string()));
+    server.run();
+}
@@ -0,0 +1,27 @@
+// 직접 연결되지 않는 피어들을 위한 중계 서버.
+//
+//   relay_server [listen addr]     기본값 0.0.0.0:7400
+//
+// 클라이언트는 project setting application/netcode/relay_server 에 이 주소를 넣고 같은 방 코드를 쓴다.
+
+use p2pactiongame::relay_server::{RelayConfig, RelayServer};
+use p2pactiongame::server_log::ServerLog;
+
+const DEFAULT_LISTEN: &str = "0.0.0.0:7400";
+
+fn main() {
+    let listen = std::env::args().nth(1).unwrap_or_else(|| DEFAULT_LISTEN.to_string());
+    let config = RelayConfig {
+        log: ServerLog::stdout(),
+        ..RelayConfig::new()
+    };
+    let mut server = match RelayServer::bind(&listen, config) {
+        Ok(server) => server,
+        Err(err) => {
+            eprintln!("failed to listen on {}: {}", listen, err);
+            std::process::exit(1);
+        }
+    };
+    println!("relay listening on {}", server.local_addr().map_or(listen, |addr| addr.to_string()));
+    server.run();
+}
@@ -0,0 +1,29 @@
+// 구멍 뚫기를 위한 중개 서버. 게임과 같은 UDP 로 받는다.
+//
+//   rendezvous_server [listen addr]     기본값 0.0.0.0:7500
+//
+// 클라이언트는 project setting application/netcode/rendezvous_server 에 이 주소를 넣고 같은 방 코드를 쓴다.
+
+use std::net::UdpSocket;
+
+use p2pactiongame::rendezvous_server::{RendezvousConfig, RendezvousServer};
+use p2pactiongame::server_log::ServerLog;
+
+const DEFAULT_LISTEN: &str = "0.0.0.0:7500";
+
+fn main() {
+    let listen = std::env::args().nth(1).unwrap_or_else(|| DEFAULT_LISTEN.to_string());
+    let socket = match UdpSocket::bind(&listen) {
+        Ok(socket) => socket,
+        Err(err) => {
+            eprintln!("failed to listen on {}: {}", listen, err);
+            std::process::exit(1);
+        }
+    };
+    println!("rendezvous listening on {}", socket.local_addr().map_or(listen, |addr| addr.to_string()));
+    let config = RendezvousConfig {
+        log: ServerLog::stdout(),
+        ..RendezvousConfig::new()
+    };
+    RendezvousServer::new(config).run(socket);
+}
@@ -0,0 +1,96 @@
+// 엔진 없이 리플레이를 돌려서 틱마다 상태 해시를 출력하거나 골든 파일과 비교한다.
+//
+//   replay_check <replay.p2pr>                        해시 출력
+//   replay_check <replay.p2pr> --golden <file>        비교, 다르면 종료 코드 1
+//   replay_check <replay.p2pr> --golden <file> --bless 골든 파일을 새로 쓴다
+
+use std::process::ExitCode;
+
+use p2pactiongame::replay::{first_mismatch, format_hashes, parse_hashes, Replay};
+use p2pactiongame::udp_net::PROTOCOL_VERSION;
+
+fn usage() -> ExitCode {
+    eprintln!("usage: replay_check <replay.p2pr> [--golden <file> [--bless]]");
+    ExitCode::from(2)
+}
+
+fn main() -> ExitCode {
+    let mut replay_path = None;
+    let mut golden_path = None;
+    let mut bless = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--golden" => match args.next() {
+                Some(path) => golden_path = Some(path),
+                None => return usage(),
+            },
+            "--bless" => bless = true,
+            _ if replay_path.is_none() => replay_path = Some(arg),
+            _ => return usage(),
+        }
+    }
+    let Some(replay_path) = replay_path else {
+        return usage();
+    };
+
+    let replay = match std::fs::read(&replay_path).map(|data| Replay::from_bytes(&data)) {
+        Ok(Ok(replay)) => replay,
+        Ok(Err(err)) => {
+            eprintln!("invalid replay {}: {}", replay_path, err);
+            return ExitCode::FAILURE;
+        }
+        Err(err) => {
+            eprintln!("failed to read {}: {}", replay_path, err);
+            return ExitCode::FAILURE;
+        }
+    };
+    if replay.protocol_version != PROTOCOL_VERSION {
+        eprintln!(
+            "note: recorded with protocol {} (current {})",
+            replay.protocol_version, PROTOCOL_VERSION
+        );
+    }
+    let hashes = replay.state_hashes();
+
+    let Some(golden_path) = golden_path else {
+        print!("{}", format_hashes(&hashes));
+        return ExitCode::SUCCESS;
+    };
+    if bless {
+        if let Err(err) = std::fs::write(&golden_path, format_hashes(&hashes)) {
+            eprintln!("failed to write {}: {}", golden_path, err);
+            return ExitCode::FAILURE;
+        }
+        println!("wrote {} hashes to {}", hashes.len(), golden_path);
+        return ExitCode::SUCCESS;
+    }
+
+    let golden = match std::fs::read_to_string(&golden_path).map(|text| parse_hashes(&text)) {
+        Ok(Ok(golden)) => golden,
+        Ok(Err(err)) => {
+            eprintln!("invalid golden file {}: {}", golden_path, err);
+            return ExitCode::FAILURE;
+        }
+        Err(err) => {
+            eprintln!("failed to read {}: {}", golden_path, err);
+            return ExitCode::FAILURE;
+        }
+    };
+    match first_mismatch(&golden, &hashes) {
+        None => {
+            println!("{}: {} ticks match", replay_path, hashes.len());
+            ExitCode::SUCCESS
+        }
+        Some(mismatch) => {
+            eprintln!(
+                "{}: diverged at tick {} (expected {:?}, got {:?})",
+                replay_path,
+                mismatch.tick,
+                mismatch.expected.map(|hash| format!("{:016x}", hash)),
+                mismatch.actual.map(|hash| format!("{:016x}", hash)),
+            );
+            ExitCode::FAILURE
+        }
+    }
+}
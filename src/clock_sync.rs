@@ -0,0 +1,148 @@
+use std::collections::VecDeque;
+
+// 최근 이만큼의 샘플로 추정한다.
+const WINDOW: usize = 16;
+// 이만큼 모여야 시계를 맞췄다고 본다.
+pub const MIN_SAMPLES: usize = 5;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ClockSample {
+    // 상대 시계 - 내 시계 (ms)
+    pub offset: i64,
+    pub rtt: u64,
+}
+
+// Ping/Pong 의 네 타임스탬프로 NTP 처럼 상대 시계와의 차이를 추정한다.
+pub struct ClockSync {
+    samples: VecDeque<ClockSample>,
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        Self {
+            samples: VecDeque::new(),
+        }
+    }
+
+    // ping_sent, pong_received 는 내 시계, ping_received, pong_sent 는 상대 시계
+    pub fn add_sample(&mut self, ping_sent: u64, ping_received: u64, pong_sent: u64, pong_received: u64) -> ClockSample {
+        let t0 = ping_sent as i64;
+        let t1 = ping_received as i64;
+        let t2 = pong_sent as i64;
+        let t3 = pong_received as i64;
+
+        let sample = ClockSample {
+            offset: ((t1 - t0) + (t2 - t3)) / 2,
+            rtt: ((t3 - t0) - (t2 - t1)).max(0) as u64,
+        };
+        self.samples.push_back(sample);
+        while self.samples.len() > WINDOW {
+            self.samples.pop_front();
+        }
+        sample
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.samples.len() >= MIN_SAMPLES
+    }
+
+    // RTT 가 큰 절반은 경로가 비대칭이었을 가능성이 커서 버리고, 남은 것의 중앙값을 쓴다.
+    pub fn offset(&self) -> Option<i64> {
+        if !self.is_synced() {
+            return None;
+        }
+        let mut by_rtt: Vec<ClockSample> = self.samples.iter().copied().collect();
+        by_rtt.sort_by_key(|s| s.rtt);
+        by_rtt.truncate(by_rtt.len().div_ceil(2));
+
+        let mut offsets: Vec<i64> = by_rtt.iter().map(|s| s.offset).collect();
+        offsets.sort();
+        Some(offsets[offsets.len() / 2])
+    }
+}
+
+impl Default for ClockSync {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 상대 시계가 offset 만큼 앞서 있고 가는 데 there, 오는 데 back ms 걸린 Ping/Pong
+    fn exchange(sync: &mut ClockSync, at: u64, offset: u64, there: u64, back: u64) -> ClockSample {
+        let ping_received = at + there + offset;
+        let pong_sent = ping_received + 5;
+        sync.add_sample(at, ping_received, pong_sent, pong_sent - offset + back)
+    }
+
+    #[test]
+    fn measures_offset_and_rtt_of_a_symmetric_path() {
+        let mut sync = ClockSync::new();
+        let sample = exchange(&mut sync, 100, 1000, 10, 10);
+        assert_eq!(sample.offset, 1000);
+        assert_eq!(sample.rtt, 20);
+    }
+
+    #[test]
+    fn needs_enough_samples() {
+        let mut sync = ClockSync::new();
+        for i in 0..MIN_SAMPLES as u64 - 1 {
+            exchange(&mut sync, i * 100, 1000, 10, 10);
+            assert!(!sync.is_synced());
+            assert_eq!(sync.offset(), None);
+        }
+        exchange(&mut sync, 1000, 1000, 10, 10);
+        assert!(sync.is_synced());
+        assert_eq!(sync.offset(), Some(1000));
+    }
+
+    #[test]
+    fn rejects_slow_asymmetric_samples() {
+        let mut sync = ClockSync::new();
+        // 돌아오는 길이 막힌 샘플은 offset 이 크게 틀리지만 RTT 도 크다.
+        for i in 0..6 {
+            let outlier = exchange(&mut sync, i * 100, 1000, 10, 300);
+            assert!(outlier.offset < 900);
+        }
+        for i in 6..12 {
+            exchange(&mut sync, i * 100, 1000, 10 + i % 3, 10);
+        }
+        assert_eq!(sync.offset(), Some(1000));
+    }
+
+    #[test]
+    fn estimates_a_negative_offset() {
+        let mut sync = ClockSync::new();
+        for i in 0..MIN_SAMPLES as u64 {
+            // 상대 시계가 500ms 늦다.
+            let at = 10_000 + i * 100;
+            sync.add_sample(at, at + 10 - 500, at + 15 - 500, at + 25);
+        }
+        assert_eq!(sync.offset(), Some(-500));
+    }
+
+    #[test]
+    fn forgets_old_samples() {
+        let mut sync = ClockSync::new();
+        for i in 0..WINDOW as u64 {
+            exchange(&mut sync, i * 100, 1000, 10, 10);
+        }
+        // 상대 시계가 바뀌면 창이 새 샘플로 채워진 뒤 따라간다.
+        for i in 0..WINDOW as u64 {
+            exchange(&mut sync, 10_000 + i * 100, 2000, 10, 10);
+        }
+        assert_eq!(sync.samples.len(), WINDOW);
+        assert_eq!(sync.offset(), Some(2000));
+    }
+
+    #[test]
+    fn clamps_negative_rtt() {
+        let mut sync = ClockSync::new();
+        // 상대가 처리에 쓴 시간이 왕복보다 길다고 보고해도 RTT 는 0 이다.
+        let sample = sync.add_sample(100, 1000, 1100, 150);
+        assert_eq!(sample.rtt, 0);
+    }
+}
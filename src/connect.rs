@@ -6,10 +6,6 @@ use godot::prelude::*;
 
 use crate::game_manager::GAME_TICK;
 use crate::network_controller::NetworkController;
-use crate::time;
-use crate::udp_net;
-use crate::udp_net::Connect;
-use crate::udp_net::PacketType;
 
 #[derive(GodotClass)]
 #[class(base=Node2D)]
@@ -20,6 +16,8 @@ pub struct GUIConnect {
     tick_text: Option<Gd<Label>>,
     nc: Option<Gd<NetworkController>>,
     init_port: bool,
+    // 중개 서버가 알려 준 공인 주소를 보여 줬다.
+    shown_public: bool,
 }
 
 #[godot_api]
@@ -32,6 +30,7 @@ impl INode2D for GUIConnect {
             tick_text: None,
             nc: None,
             init_port: false,
+            shown_public: false,
         }
     }
 
@@ -64,15 +63,14 @@ impl INode2D for GUIConnect {
             label.set_text(format!("Tick: {}", GAME_TICK.lock().unwrap().tick).into());
         }
 
-        if let Some(nc) = self.nc.clone() {
+        if let Some(mut nc) = self.nc.clone() {
             if let Some(text_edit) = self.text_edit.clone().as_mut() {
                 if text_edit.is_editable() {
-                    if let Some(endpoint) =
-                        nc.bind().net.as_ref().unwrap().other_peer_endpoint.as_ref()
-                    {
+                    let room_status = nc.bind().room_status();
+                    if let Some(status) = room_status.as_ref() {
                         text_edit.set_editable(false);
                         text_edit.release_focus();
-                        text_edit.set_text(endpoint.as_str().into());
+                        text_edit.set_text(status.as_str().into());
                     } else {
                       if text_edit.has_focus() {
                         let input = Input::singleton();
@@ -80,12 +78,13 @@ impl INode2D for GUIConnect {
                           let text = text_edit.get_text().to_string().trim().to_string();
                           let player = self.base().get_tree().unwrap().get_root().unwrap().get_node_as::<Node2D>("Root/Player");
                           let pos = player.get_position();
-                          
-                          let game_start_time = time::get_ms_timestamp() + 1000;
                         
-                          let mut packet = udp_net::pack::<Connect>(&Connect { x: pos.x, y: pos.y, game_start_time }, PacketType::Connect);
-                          packet.insert(0, (packet.len() + 1) as u8);
-                          nc.bind().send_to(packet.as_slice(), text.as_str());
+                          // 비워 두면 중개 서버가 알려 주는 호스트로 들어간다.
+                          if text.is_empty() {
+                            nc.bind_mut().send_connect_via_rendezvous(pos);
+                          } else {
+                            nc.bind_mut().send_connect(text.as_str(), pos);
+                          }
                         
                           godot_print!("Sent connect packet to {}", text.as_str());
                           text_edit.set_text(text.into());
@@ -94,14 +93,39 @@ impl INode2D for GUIConnect {
                       } else if !self.init_port {
                         self.init_port = true;
                         let mut text = text_edit.get_text().to_string();
-                        text.push_str(self.nc.as_ref().unwrap().bind().net.as_ref().unwrap().my_port.to_string().as_str());
+                        text.push_str(self.nc.as_ref().unwrap().bind().my_port.to_string().as_str());
                         text_edit.set_text(text.into());
-                      } 
+                      } else if !self.shown_public {
+                        let endpoint = nc.bind().get_public_endpoint();
+                        if !endpoint.is_empty() {
+                          self.shown_public = true;
+                          text_edit.set_text(endpoint);
+                        }
+                      }
                     }
                 }
                 else {
+                    // 연결이 끝나면 다시 주소를 입력할 수 있게 한다.
+                    if nc.bind().room_status().is_none() {
+                        text_edit.set_editable(true);
+                        return;
+                    }
                     if let Some(label) = self.ping_text.clone().as_mut() {
-                        label.set_text(format!("Ping: {}ms", nc.bind().net.as_ref().unwrap().network_latency).into());
+                        let nc = nc.bind();
+                        let Some(stats) = nc.worst_stats() else {
+                            return;
+                        };
+                        label.set_text(
+                            format!(
+                                "Ping: {}ms (±{}ms, {}~{}ms) Loss: {:.0}%",
+                                stats.srtt(),
+                                stats.jitter(),
+                                stats.min_rtt(),
+                                stats.max_rtt(),
+                                stats.loss_rate() * 100.0
+                            )
+                            .into(),
+                        );
                     }
                 }
             } else {
@@ -0,0 +1,254 @@
+// Connect 를 보내고 답을 기다리는 최대 시간 (ms). 재전송은 reliable 채널이 한다.
+pub const CONNECT_TIMEOUT_MS: u64 = 10000;
+// 이 시간 동안 상대에게서 아무것도 못 받으면 끊긴 것으로 본다.
+pub const INACTIVITY_TIMEOUT_MS: u64 = 5000;
+// Disconnect 의 ack 를 기다리는 최대 시간
+pub const DISCONNECT_LINGER_MS: u64 = 1000;
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Idle,
+    // Connect 를 보내고 상대의 Connect 를 기다린다.
+    Connecting,
+    // 상대는 정해졌고 시계를 맞추며 시작 시각을 기다린다.
+    Synchronizing,
+    Running,
+    // Disconnect 를 보내고 ack 를 기다린다.
+    Disconnecting,
+    Disconnected,
+}
+
+// 상대 하나와의 연결 상태. 시각은 모두 로컬 monotonic ms
+pub struct Connection {
+    state: ConnectionState,
+    entered_at: u64,
+    last_received: u64,
+    // 아직 알리지 않은 상태 변화
+    transitions: Vec<ConnectionState>,
+}
+
+impl Connection {
+    pub fn new() -> Self {
+        Self {
+            state: ConnectionState::Idle,
+            entered_at: 0,
+            last_received: 0,
+            transitions: Vec::new(),
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    // 상대가 정해져 있는 상태
+    pub fn is_connected(&self) -> bool {
+        matches!(self.state, ConnectionState::Synchronizing | ConnectionState::Running)
+    }
+
+    fn enter(&mut self, state: ConnectionState, now: u64) {
+        if self.state == state {
+            return;
+        }
+        self.state = state;
+        self.entered_at = now;
+        self.transitions.push(state);
+    }
+
+    // Connect 를 보내고 답을 기다린 시간
+    pub fn connecting_for(&self, now: u64) -> Option<u64> {
+        (self.state == ConnectionState::Connecting).then(|| now.saturating_sub(self.entered_at))
+    }
+
+    // 우리가 Connect 를 보냈다.
+    pub fn connect(&mut self, now: u64) {
+        if matches!(self.state, ConnectionState::Idle | ConnectionState::Disconnected) {
+            self.enter(ConnectionState::Connecting, now);
+        }
+    }
+
+    // 상대의 Connect 를 받았다. 이미 연결되어 있으면 false
+    pub fn on_connect(&mut self, now: u64) -> bool {
+        match self.state {
+            ConnectionState::Idle | ConnectionState::Connecting | ConnectionState::Disconnected => {
+                self.last_received = now;
+                self.enter(ConnectionState::Synchronizing, now);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // 상대에게서 무엇이든 받았다.
+    pub fn on_receive(&mut self, now: u64) {
+        self.last_received = now;
+    }
+
+    // 우리가 끊는다.
+    pub fn disconnect(&mut self, now: u64) {
+        match self.state {
+            ConnectionState::Connecting => self.enter(ConnectionState::Disconnected, now),
+            ConnectionState::Synchronizing | ConnectionState::Running => {
+                self.enter(ConnectionState::Disconnecting, now)
+            }
+            _ => {}
+        }
+    }
+
+    // 상대가 Disconnect 를 보냈다.
+    pub fn on_disconnect(&mut self, now: u64) {
+        if self.state != ConnectionState::Idle {
+            self.enter(ConnectionState::Disconnected, now);
+        }
+    }
+
+    // 매 프레임 부른다. in_flight 는 ack 를 기다리는 reliable 메시지 수
+    pub fn update(&mut self, now: u64, game_started: bool, in_flight: usize) {
+        let elapsed = now.saturating_sub(self.entered_at);
+        match self.state {
+            ConnectionState::Connecting if elapsed > CONNECT_TIMEOUT_MS => {
+                self.enter(ConnectionState::Disconnected, now);
+            }
+            ConnectionState::Synchronizing | ConnectionState::Running
+                if now.saturating_sub(self.last_received) > INACTIVITY_TIMEOUT_MS =>
+            {
+                self.enter(ConnectionState::Disconnected, now);
+            }
+            ConnectionState::Synchronizing if game_started => {
+                self.enter(ConnectionState::Running, now);
+            }
+            ConnectionState::Disconnecting if in_flight == 0 || elapsed > DISCONNECT_LINGER_MS => {
+                self.enter(ConnectionState::Disconnected, now);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn take_transitions(&mut self) -> Vec<ConnectionState> {
+        std::mem::take(&mut self.transitions)
+    }
+}
+
+impl Default for Connection {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ConnectionState::*;
+
+    fn running(now: u64) -> Connection {
+        let mut connection = Connection::new();
+        connection.connect(now);
+        connection.on_connect(now);
+        connection.update(now, true, 0);
+        connection.take_transitions();
+        connection
+    }
+
+    #[test]
+    fn connects_then_runs_when_the_game_starts() {
+        let mut connection = Connection::new();
+        assert_eq!(connection.state(), Idle);
+        connection.connect(100);
+        assert_eq!(connection.connecting_for(350), Some(250));
+        assert!(connection.on_connect(400));
+        assert!(connection.is_connected());
+        assert_eq!(connection.connecting_for(400), None);
+        // 이미 연결되어 있으면 다시 받아도 그대로다.
+        assert!(!connection.on_connect(450));
+        connection.update(500, false, 0);
+        assert_eq!(connection.state(), Synchronizing);
+        connection.update(600, true, 0);
+        assert_eq!(connection.state(), Running);
+        assert_eq!(connection.take_transitions(), vec![Connecting, Synchronizing, Running]);
+        assert!(connection.take_transitions().is_empty());
+    }
+
+    #[test]
+    fn accepts_a_connect_without_sending_one() {
+        let mut connection = Connection::new();
+        assert!(connection.on_connect(10));
+        assert_eq!(connection.take_transitions(), vec![Synchronizing]);
+    }
+
+    #[test]
+    fn gives_up_connecting() {
+        let mut connection = Connection::new();
+        connection.connect(0);
+        connection.update(CONNECT_TIMEOUT_MS, false, 0);
+        assert_eq!(connection.state(), Connecting);
+        connection.update(CONNECT_TIMEOUT_MS + 1, false, 0);
+        assert_eq!(connection.state(), Disconnected);
+        // 끊긴 뒤에는 다시 시도할 수 있다.
+        connection.connect(20_000);
+        assert_eq!(connection.state(), Connecting);
+    }
+
+    #[test]
+    fn times_out_without_traffic() {
+        let mut connection = running(0);
+        connection.on_receive(3000);
+        connection.update(3000 + INACTIVITY_TIMEOUT_MS, true, 0);
+        assert_eq!(connection.state(), Running);
+        connection.update(3001 + INACTIVITY_TIMEOUT_MS, true, 0);
+        assert_eq!(connection.state(), Disconnected);
+        assert_eq!(connection.take_transitions(), vec![Disconnected]);
+    }
+
+    #[test]
+    fn times_out_while_synchronizing() {
+        let mut connection = Connection::new();
+        connection.on_connect(0);
+        connection.update(INACTIVITY_TIMEOUT_MS + 1, false, 0);
+        assert_eq!(connection.state(), Disconnected);
+    }
+
+    #[test]
+    fn lingers_until_the_disconnect_is_acked() {
+        let mut connection = running(0);
+        connection.disconnect(100);
+        assert_eq!(connection.state(), Disconnecting);
+        assert!(!connection.is_connected());
+        connection.update(200, true, 1);
+        assert_eq!(connection.state(), Disconnecting);
+        connection.update(300, true, 0);
+        assert_eq!(connection.state(), Disconnected);
+    }
+
+    #[test]
+    fn stops_lingering_after_a_while() {
+        let mut connection = running(0);
+        connection.disconnect(100);
+        connection.update(100 + DISCONNECT_LINGER_MS, true, 1);
+        assert_eq!(connection.state(), Disconnecting);
+        connection.update(101 + DISCONNECT_LINGER_MS, true, 1);
+        assert_eq!(connection.state(), Disconnected);
+    }
+
+    #[test]
+    fn disconnect_while_connecting_is_immediate() {
+        let mut connection = Connection::new();
+        connection.connect(0);
+        connection.disconnect(10);
+        assert_eq!(connection.state(), Disconnected);
+        // 아무 일도 없었으면 끊을 것도 없다.
+        let mut idle = Connection::new();
+        idle.disconnect(0);
+        idle.on_disconnect(0);
+        assert_eq!(idle.state(), Idle);
+        assert!(idle.take_transitions().is_empty());
+    }
+
+    #[test]
+    fn remote_disconnect_ends_the_connection() {
+        let mut connection = running(0);
+        connection.on_disconnect(50);
+        assert_eq!(connection.state(), Disconnected);
+        assert_eq!(connection.take_transitions(), vec![Disconnected]);
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+// 비교가 끝난 뒤에도 덤프용으로 남겨 두는 체크섬 수
+const HISTORY: usize = 32;
+
+// 어긋난 틱마다 디렉터리 하나에 피어마다 자기 덤프를 남긴다. me 는 쓰는 쪽, peer 는 어긋난 상대의 id.
+// 상대는 같은 디렉터리에 me 와 peer 가 바뀐 이름으로 쓰므로 두 파일을 그대로 diff 하면 된다.
+pub fn dump_file_name(tick: u64, me: i64, peer: i64) -> String {
+    format!("tick_{}/player_{}_vs_{}.txt", tick, me, peer)
+}
+
+// 양쪽 피어가 같은 틱에 만든 체크섬을 비교한다.
+pub struct DesyncDetector {
+    local: BTreeMap<u64, u64>,
+    remote: BTreeMap<u64, u64>,
+    pub first_divergent_tick: Option<u64>,
+    // first_divergent_tick 의 (우리, 상대) 체크섬. 기록에서 밀려나도 덤프에 남긴다.
+    divergent_hashes: Option<(u64, u64)>,
+}
+
+impl DesyncDetector {
+    pub fn new() -> Self {
+        Self {
+            local: BTreeMap::new(),
+            remote: BTreeMap::new(),
+            first_divergent_tick: None,
+            divergent_hashes: None,
+        }
+    }
+
+    // 처음 보는 불일치면 그 틱을 돌려준다.
+    pub fn add_local(&mut self, tick: u64, hash: u64) -> Option<u64> {
+        self.local.insert(tick, hash);
+        self.compare(tick)
+    }
+
+    pub fn add_remote(&mut self, tick: u64, hash: u64) -> Option<u64> {
+        self.remote.insert(tick, hash);
+        self.compare(tick)
+    }
+
+    fn compare(&mut self, tick: u64) -> Option<u64> {
+        let mut found = None;
+        if let (Some(local), Some(remote)) = (self.local.get(&tick), self.remote.get(&tick)) {
+            if local != remote && self.first_divergent_tick.is_none_or(|t| tick < t) {
+                self.first_divergent_tick = Some(tick);
+                self.divergent_hashes = Some((*local, *remote));
+                found = Some(tick);
+            }
+        }
+        while self.local.len() > HISTORY {
+            self.local.pop_first();
+        }
+        while self.remote.len() > HISTORY {
+            self.remote.pop_first();
+        }
+        found
+    }
+
+    pub fn dump(&self) -> String {
+        let mut out = String::new();
+        out.push_str(&format!("first divergent tick: {:?}\n", self.first_divergent_tick));
+        if let (Some(tick), Some((local, remote))) = (self.first_divergent_tick, self.divergent_hashes) {
+            out.push_str(&format!(
+                "received remote checksum for tick {}: {:016x} (local {:016x})\n",
+                tick, remote, local
+            ));
+        }
+        // 상대가 앞서 있으면 우리에게 아직 없는 틱의 체크섬도 있다.
+        let ticks: BTreeSet<u64> = self.local.keys().chain(self.remote.keys()).copied().collect();
+        for tick in ticks {
+            let (local, remote) = (self.local.get(&tick), self.remote.get(&tick));
+            out.push_str(&format!(
+                "tick {}: local {} remote {}{}\n",
+                tick,
+                format_hash(local),
+                format_hash(remote),
+                if local.is_some() && remote.is_some() && local != remote { " MISMATCH" } else { "" }
+            ));
+        }
+        out
+    }
+}
+
+fn format_hash(hash: Option<&u64>) -> String {
+    hash.map_or("-".to_string(), |hash| format!("{:016x}", hash))
+}
+
+impl Default for DesyncDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_the_first_divergent_tick_once() {
+        let mut detector = DesyncDetector::new();
+        assert_eq!(detector.add_local(30, 1), None);
+        assert_eq!(detector.add_remote(30, 1), None);
+        assert_eq!(detector.add_remote(60, 2), None);
+        assert_eq!(detector.add_local(60, 3), Some(60));
+        assert_eq!(detector.first_divergent_tick, Some(60));
+        // 그 뒤의 불일치는 이미 알고 있다.
+        assert_eq!(detector.add_local(90, 4), None);
+        assert_eq!(detector.add_remote(90, 5), None);
+        assert_eq!(detector.first_divergent_tick, Some(60));
+    }
+
+    #[test]
+    fn earlier_mismatch_arriving_late_moves_the_first_tick() {
+        let mut detector = DesyncDetector::new();
+        detector.add_local(30, 1);
+        detector.add_local(60, 1);
+        assert_eq!(detector.add_remote(60, 2), Some(60));
+        assert_eq!(detector.add_remote(30, 2), Some(30));
+        assert_eq!(detector.first_divergent_tick, Some(30));
+    }
+
+    #[test]
+    fn keeps_a_bounded_history() {
+        let mut detector = DesyncDetector::new();
+        for tick in 0..100 {
+            detector.add_local(tick, tick);
+            detector.add_remote(tick, tick);
+        }
+        assert_eq!(detector.first_divergent_tick, None);
+        assert_eq!(detector.local.len(), HISTORY);
+        assert_eq!(detector.remote.len(), HISTORY);
+        assert_eq!(detector.local.keys().next(), Some(&(100 - HISTORY as u64)));
+    }
+
+    #[test]
+    fn dump_marks_mismatches() {
+        let mut detector = DesyncDetector::new();
+        detector.add_local(30, 0xa);
+        detector.add_remote(30, 0xa);
+        detector.add_local(60, 0xb);
+        detector.add_remote(60, 0xc);
+        detector.add_local(90, 0xd);
+        detector.add_remote(120, 0xe);
+        let dump = detector.dump();
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines[0], "first divergent tick: Some(60)");
+        assert_eq!(lines[1], "received remote checksum for tick 60: 000000000000000c (local 000000000000000b)");
+        assert_eq!(lines[2], "tick 30: local 000000000000000a remote 000000000000000a");
+        assert_eq!(lines[3], "tick 60: local 000000000000000b remote 000000000000000c MISMATCH");
+        assert_eq!(lines[4], "tick 90: local 000000000000000d remote -");
+        assert_eq!(lines[5], "tick 120: local - remote 000000000000000e");
+    }
+
+    #[test]
+    fn dump_keeps_the_divergent_checksums_after_they_leave_the_history() {
+        let mut detector = DesyncDetector::new();
+        detector.add_local(0, 1);
+        detector.add_remote(0, 2);
+        for tick in 1..=HISTORY as u64 {
+            detector.add_local(tick, tick);
+            detector.add_remote(tick, tick);
+        }
+        assert!(!detector.local.contains_key(&0));
+        let dump = detector.dump();
+        assert_eq!(
+            dump.lines().nth(1),
+            Some("received remote checksum for tick 0: 0000000000000002 (local 0000000000000001)")
+        );
+    }
+
+    #[test]
+    fn both_sides_dump_next_to_each_other() {
+        let mine = dump_file_name(60, 0, 1);
+        let theirs = dump_file_name(60, 1, 0);
+        assert_ne!(mine, theirs);
+        assert_eq!(mine.split('/').next(), theirs.split('/').next());
+    }
+}
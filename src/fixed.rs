@@ -0,0 +1,162 @@
+use std::ops::{Add, Mul, Neg, Sub};
+
+// 48.16 고정소수점 (i64 에 소수부 16비트). 시뮬레이션 상태는 모두 이 타입으로 계산해서
+// 어느 기계에서든 같은 입력이면 비트 단위로 같은 결과가 나오게 한다.
+// 넘치면 디버그/릴리스 빌드 모두 같은 값으로 감싸진다.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Fixed(i64);
+
+impl Fixed {
+    pub const FRAC_BITS: u32 = 16;
+    pub const ZERO: Fixed = Fixed(0);
+    pub const ONE: Fixed = Fixed(1 << Self::FRAC_BITS);
+
+    pub const fn raw(self) -> i64 {
+        self.0
+    }
+
+    pub const fn from_int(v: i64) -> Self {
+        Fixed(v << Self::FRAC_BITS)
+    }
+
+    // num / den 를 0 쪽으로 버림해서 만든다. 상수 정의용.
+    pub const fn from_ratio(num: i64, den: i64) -> Self {
+        Fixed((num << Self::FRAC_BITS) / den)
+    }
+
+    // 엔진에서 넘어온 좌표를 들일 때만 쓴다. 같은 f32 비트는 항상 같은 값이 된다.
+    pub fn from_f32(v: f32) -> Self {
+        Fixed((v as f64 * (1i64 << Self::FRAC_BITS) as f64).round() as i64)
+    }
+
+    pub fn to_f32(self) -> f32 {
+        (self.0 as f64 / (1i64 << Self::FRAC_BITS) as f64) as f32
+    }
+}
+
+impl Add for Fixed {
+    type Output = Fixed;
+
+    fn add(self, rhs: Fixed) -> Fixed {
+        Fixed(self.0.wrapping_add(rhs.0))
+    }
+}
+
+impl Sub for Fixed {
+    type Output = Fixed;
+
+    fn sub(self, rhs: Fixed) -> Fixed {
+        Fixed(self.0.wrapping_sub(rhs.0))
+    }
+}
+
+impl Neg for Fixed {
+    type Output = Fixed;
+
+    fn neg(self) -> Fixed {
+        Fixed(self.0.wrapping_neg())
+    }
+}
+
+impl Mul for Fixed {
+    type Output = Fixed;
+
+    // 음수 쪽으로 버림한다.
+    fn mul(self, rhs: Fixed) -> Fixed {
+        Fixed(((self.0 as i128 * rhs.0 as i128) >> Self::FRAC_BITS) as i64)
+    }
+}
+
+impl Mul<i64> for Fixed {
+    type Output = Fixed;
+
+    fn mul(self, rhs: i64) -> Fixed {
+        Fixed(self.0.wrapping_mul(rhs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::{PlayerState, Simulation, INPUT_JUMP, INPUT_LEFT, INPUT_RIGHT};
+
+    const EPSILON: Fixed = Fixed(1);
+
+    #[test]
+    fn converts_integers_and_ratios() {
+        assert_eq!(Fixed::from_int(3).raw(), 3 << 16);
+        assert_eq!(Fixed::from_int(-3).raw(), -3 << 16);
+        assert_eq!(Fixed::from_ratio(1, 2).raw(), 1 << 15);
+        // 나눗셈은 0 쪽으로 버림한다.
+        assert_eq!(Fixed::from_ratio(400, 60).raw(), 436906);
+        assert_eq!(Fixed::from_ratio(-400, 60).raw(), -436906);
+        assert_eq!(Fixed::from_ratio(1, 3) * 3, Fixed::ONE - EPSILON);
+    }
+
+    #[test]
+    fn converts_f32_by_rounding() {
+        assert_eq!(Fixed::from_f32(1.5).raw(), 3 << 15);
+        assert_eq!(Fixed::from_f32(-5.0), Fixed::from_int(-5));
+        assert_eq!(Fixed::from_f32(0.1).raw(), 6554);
+        assert_eq!(Fixed::from_f32(-0.1).raw(), -6554);
+        assert_eq!(Fixed::from_int(-12).to_f32(), -12.0);
+        assert_eq!(Fixed::from_f32(123.25).to_f32(), 123.25);
+    }
+
+    #[test]
+    fn multiplies_rounding_toward_negative_infinity() {
+        assert_eq!(Fixed::from_int(3) * Fixed::from_ratio(1, 2), Fixed::from_ratio(3, 2));
+        assert_eq!(Fixed::from_int(-2) * Fixed::from_int(3), Fixed::from_int(-6));
+        assert_eq!(EPSILON * Fixed::from_ratio(1, 2), Fixed::ZERO);
+        assert_eq!(-EPSILON * Fixed::from_ratio(1, 2), -EPSILON);
+        assert_eq!(Fixed::from_int(7) * -2, Fixed::from_int(-14));
+    }
+
+    #[test]
+    fn multiplies_large_values_without_intermediate_overflow() {
+        let big = Fixed::from_int(1 << 40);
+        assert_eq!(big * Fixed::from_ratio(1, 4), Fixed::from_int(1 << 38));
+        assert_eq!(Fixed::from_int(1 << 20) * Fixed::from_int(1 << 20), Fixed::from_int(1 << 40));
+    }
+
+    #[test]
+    fn wraps_on_overflow() {
+        let max = Fixed(i64::MAX);
+        let min = Fixed(i64::MIN);
+        assert_eq!(max + EPSILON, min);
+        assert_eq!(min - EPSILON, max);
+        assert_eq!(-min, min);
+        assert_eq!(max * 2, Fixed(-2));
+        assert_eq!(Fixed::from_int(1 << 40) * Fixed::from_int(1 << 10), Fixed::ZERO);
+    }
+
+    fn script(tick: u64, player: usize) -> u8 {
+        match (tick / 17 + player as u64) % 5 {
+            0 => INPUT_RIGHT,
+            1 => INPUT_RIGHT | INPUT_JUMP,
+            2 => 0,
+            3 => INPUT_LEFT,
+            _ => INPUT_LEFT | INPUT_JUMP,
+        }
+    }
+
+    fn run() -> Vec<u64> {
+        let players = vec![
+            PlayerState::at(Fixed::from_f32(100.0), Fixed::from_f32(-5.0)),
+            PlayerState::at(Fixed::from_f32(-37.5), Fixed::from_f32(-300.25)),
+        ];
+        let mut sim = Simulation::new(players);
+        (1..=600)
+            .map(|tick| sim.step(&[script(tick, 0), script(tick, 1)]).checksum())
+            .collect()
+    }
+
+    // 같은 입력이면 실행마다, 기계마다 비트 단위로 같은 상태가 나와야 한다.
+    // 마지막 체크섬은 고정해 두었으니 계산 규칙이 바뀌면 여기서 잡힌다.
+    #[test]
+    fn simulation_is_bit_identical() {
+        let first = run();
+        assert_eq!(first, run());
+        assert_eq!(*first.last().unwrap(), 16614215655162026382);
+    }
+}
@@ -1,46 +1,49 @@
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 use godot::engine::Node2D;
 use godot::prelude::*;
 
-use crate::time;
+use crate::mesh::{NetworkStat, SharedSession, SharedStat};
+use crate::network_controller::NetworkController;
 
 use lazy_static::lazy_static;
 
-pub struct NetworkStat {
-    pub tick: u64,
-    pub latency: u64,
-}
-
 lazy_static! {
-    pub static ref GAME_TICK: Mutex<NetworkStat> = {
-        Mutex::new(NetworkStat {
-            tick: 0,
-            latency: 0,
-        })
-    };
+    pub static ref GAME_TICK: SharedStat = Arc::new(Mutex::new(NetworkStat::new()));
+
+    // 상대와 연결되면 만들어진다. 플레이어 노드들은 여기 상태를 그린다.
+    pub static ref SESSION: SharedSession = Arc::new(Mutex::new(None));
 }
 
+// 공유 시계에 맞춰 틱을 진행시킨다. 계산은 NetworkController 의 Mesh 가 한다.
 #[derive(GodotClass)]
 #[class(base=Node2D)]
 pub struct GameTick {
     base: Base<Node2D>,
-    pub game_start_time: u64,
+    nc: Option<Gd<NetworkController>>,
 }
 
 #[godot_api]
 impl INode2D for GameTick {
     fn init(base: Base<Node2D>) -> Self {
-        Self {
-            base,
-            game_start_time: 0,
+        Self { base, nc: None }
+    }
+
+    fn ready(&mut self) {
+        if self.nc.is_none() {
+            self.nc = self
+                .base()
+                .get_tree()
+                .unwrap()
+                .get_root()
+                .unwrap()
+                .try_get_node_as::<NetworkController>("Root/NetworkController");
         }
     }
 
     fn physics_process(&mut self, _delta: f64) {
-      if (self.game_start_time == 0) || self.game_start_time > time::get_ms_timestamp() {
-          return;
+      if let Some(nc) = self.nc.as_mut() {
+          nc.bind_mut().advance_ticks();
       }
-      (*GAME_TICK.lock().unwrap()).tick += 1;
   }
-}
\ No newline at end of file
+}
@@ -34,6 +34,11 @@ impl INode2D for GUIPlayerState {
     
     fn process(&mut self, _: f64) {
         if let Some(target) = self.target.clone() {
+            // 따라가던 플레이어가 나가면 같이 사라진다.
+            if !target.is_instance_valid() {
+                self.base_mut().queue_free();
+                return;
+            }
             if let Some(position_text) = self.position_text.clone().as_mut() {
                 position_text.set_text(format!("Pos: {}, {}", target.get_position().x, target.get_position().y).into());
                 //follow the target
@@ -1,14 +1,14 @@
 use godot::engine::INode2D;
 use godot::engine::Label;
 use godot::engine::Node2D;
+use godot::engine::ProjectSettings;
 use godot::prelude::*;
 
 use crate::game_manager::GAME_TICK;
+use crate::input_delay::InputDelay;
 use crate::network_controller::NetworkController;
-use crate::player::Player;
-use crate::udp_net::pack;
-use crate::udp_net::InputPacket;
-use crate::udp_net::PacketType;
+use crate::simulation::{INPUT_JUMP, INPUT_LEFT, INPUT_RIGHT};
+use crate::time;
 
 #[derive(GodotClass)]
 #[class(base=Node2D)]
@@ -17,6 +17,25 @@ pub struct InputController {
     nc: Option<Gd<NetworkController>>,
     gui_text_keypress: Option<Gd<Label>>,
     pub local_input: u8,
+    input_delay: InputDelay,
+}
+
+// 0 이상이면 적응형 대신 이 지연(틱)을 쓴다.
+const FIXED_INPUT_DELAY_SETTING: &str = "application/netcode/fixed_input_delay";
+
+#[godot_api]
+impl InputController {
+    // 음수면 네트워크 상태에 맞춰 자동으로 정한다.
+    #[func]
+    pub fn set_fixed_input_delay(&mut self, delay: i64) {
+        self.input_delay
+            .set_pinned(if delay >= 0 { Some(delay as u64) } else { None });
+    }
+
+    #[func]
+    pub fn get_input_delay(&self) -> i64 {
+        self.input_delay.current() as i64
+    }
 }
 
 #[godot_api]
@@ -27,6 +46,7 @@ impl INode2D for InputController {
             nc: None,
             gui_text_keypress: None,
             local_input: 0,
+            input_delay: InputDelay::new(),
         }
     }
 
@@ -43,31 +63,29 @@ impl INode2D for InputController {
         if self.gui_text_keypress.is_none() {
             self.gui_text_keypress = self.base().try_get_node_as::<Label>("UI_Text_Keypress");
         }
+
+        let settings = ProjectSettings::singleton();
+        if settings.has_setting(FIXED_INPUT_DELAY_SETTING.into()) {
+            let delay = settings.get_setting(FIXED_INPUT_DELAY_SETTING.into()).to::<i64>();
+            self.set_fixed_input_delay(delay);
+        }
     }
 
     fn physics_process(&mut self, _delta: f64) {
         let input = Input::singleton();
 
-        let mut local_player = self
-            .base()
-            .get_tree()
-            .unwrap()
-            .get_root()
-            .unwrap()
-            .get_node_as::<Player>("Root/Player");
-
         let mut input2send: u8 = 0;
         let mut key_str = "".to_string();
         if input.is_action_pressed("d".into()) {
-            input2send |= 0b0001;
+            input2send |= INPUT_RIGHT;
             key_str.push_str("d");
         }
         if input.is_action_pressed("a".into()) {
-            input2send |= 0b0010;
+            input2send |= INPUT_LEFT;
             key_str.push_str("a");
         }
         if input.is_action_pressed("w".into()) {
-            input2send |= 0b0100;
+            input2send |= INPUT_JUMP;
             key_str.push_str("w");
         }
 
@@ -80,39 +98,31 @@ impl INode2D for InputController {
                 format!("Keypress: [{}]", key_str).into()
             });
 
+        let tick = GAME_TICK.lock().unwrap().tick;
+        // 게임이 시작되기 전 입력은 로컬에서만 쓴다.
+        if tick == 0 {
+            self.local_input = input2send;
+            return;
+        }
+
         let mut nc = self.nc.as_mut().unwrap().bind_mut();
-        let nd = nc.net.as_mut().unwrap();
-        let dt = 1000.0 / 60.0;
-        let delay = ((nd.network_latency) as f64) / dt;
-        if input2send == 0 {
+        // 관전자는 입력을 보내지 않는다.
+        if nc.local_player_id().is_none() {
             self.local_input = input2send;
             return;
         }
+        // 가장 느린 피어에 맞춘다.
+        if let Some(stats) = nc.worst_stats() {
+            self.input_delay.update(stats.srtt(), stats.jitter());
+        }
 
         //실제 계산될 틱
-        let real_tick: u64 = GAME_TICK.lock().unwrap().tick + 3 + delay as u64;
-
-        local_player.bind_mut().push_input(input2send, real_tick);
-        let input2pkt = local_player.bind_mut().get_input_5(real_tick);
-
-        let input_packet = InputPacket {
-            input: input2pkt
-                .iter()
-                .map(|x| x.1)
-                .collect::<Vec<u8>>()
-                .try_into()
-                .unwrap(),
-            tick: input2pkt
-                .iter()
-                .map(|x| x.0)
-                .collect::<Vec<u64>>()
-                .try_into()
-                .unwrap(),
-        };
-
-        let mut packet = pack::<InputPacket>(&input_packet, PacketType::Input);
-        packet.insert(0, (packet.len() + 1) as u8);
-        nc.send_buffer.push(packet);
+        // 아무 키도 누르지 않은 틱도 보내야 상대가 예측한 입력을 확정할 수 있다.
+        let mesh = nc.mesh_mut();
+        for (real_tick, input) in self.input_delay.schedule(tick, input2send) {
+            mesh.add_local_input(real_tick, input);
+        }
+        mesh.send_inputs(time::monotonic_ms());
 
         self.local_input = input2send;
     }
@@ -0,0 +1,182 @@
+use crate::simulation::TICKS_PER_SECOND;
+
+pub const MIN_INPUT_DELAY: u64 = 2;
+pub const MAX_INPUT_DELAY: u64 = 12;
+// 목표 지연이 이 프레임 수 동안 계속 달라야 바꾼다.
+const STABLE_FRAMES: u32 = 60;
+
+// 로컬 입력을 몇 틱 뒤에 적용할지 정한다. 측정값이 흔들려도 지연은 자주 바뀌지 않게 한다.
+pub struct InputDelay {
+    current: u64,
+    // 설정에서 고정한 지연
+    pinned: Option<u64>,
+    candidate: u64,
+    candidate_frames: u32,
+    // 마지막으로 입력을 넣은 틱
+    last_scheduled: Option<u64>,
+}
+
+impl InputDelay {
+    pub fn new() -> Self {
+        Self {
+            current: 3,
+            pinned: None,
+            candidate: 3,
+            candidate_frames: 0,
+            last_scheduled: None,
+        }
+    }
+
+    pub fn current(&self) -> u64 {
+        self.current
+    }
+
+    pub fn set_pinned(&mut self, delay: Option<u64>) {
+        self.pinned = delay.map(|d| d.clamp(MIN_INPUT_DELAY, MAX_INPUT_DELAY));
+        if let Some(delay) = self.pinned {
+            self.current = delay;
+        }
+    }
+
+    // 편도 지연에 지터 여유를 더한 만큼을 틱으로 바꾼다.
+    pub fn target(srtt: u64, jitter: u64) -> u64 {
+        let one_way_ms = srtt / 2 + jitter * 2;
+        let tick_ms = 1000 / TICKS_PER_SECOND as u64;
+        let ticks = one_way_ms.div_ceil(tick_ms) + 1;
+        ticks.clamp(MIN_INPUT_DELAY, MAX_INPUT_DELAY)
+    }
+
+    // 프레임마다 부른다. 목표가 1 틱 넘게 벌어진 채로 STABLE_FRAMES 동안 유지되면 바꾼다.
+    pub fn update(&mut self, srtt: u64, jitter: u64) -> u64 {
+        if self.pinned.is_some() {
+            return self.current;
+        }
+
+        let target = Self::target(srtt, jitter);
+        if target.abs_diff(self.current) <= 1 {
+            self.candidate_frames = 0;
+            return self.current;
+        }
+
+        if target == self.candidate {
+            self.candidate_frames += 1;
+        } else {
+            self.candidate = target;
+            self.candidate_frames = 1;
+        }
+        if self.candidate_frames >= STABLE_FRAMES {
+            self.current = target;
+            self.candidate_frames = 0;
+        }
+        self.current
+    }
+
+    // tick 에 누른 입력을 넣을 (틱, 입력) 목록.
+    // 지연이 늘어 생긴 틈은 이번 입력으로 채우고, 줄어서 이미 입력이 있는 틱과 겹치면 이번 입력은 버린다.
+    pub fn schedule(&mut self, tick: u64, input: u8) -> Vec<(u64, u8)> {
+        let target = tick + self.current;
+        let from = match self.last_scheduled {
+            Some(last) if target <= last => return Vec::new(),
+            Some(last) => last + 1,
+            None => target,
+        };
+        self.last_scheduled = Some(target);
+        (from..=target).map(|t| (t, input)).collect()
+    }
+}
+
+impl Default for InputDelay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn targets_one_way_latency_plus_jitter() {
+        assert_eq!(InputDelay::target(0, 0), MIN_INPUT_DELAY);
+        // 100 + 20ms 는 16ms 틱으로 8 틱, 거기에 한 틱 여유
+        assert_eq!(InputDelay::target(200, 10), 9);
+        assert_eq!(InputDelay::target(2000, 100), MAX_INPUT_DELAY);
+    }
+
+    #[test]
+    fn changes_only_after_a_stable_target() {
+        let mut delay = InputDelay::new();
+        // 9 틱을 원하는 네트워크
+        for _ in 0..STABLE_FRAMES - 1 {
+            assert_eq!(delay.update(200, 10), 3);
+        }
+        assert_eq!(delay.update(200, 10), 9);
+        assert_eq!(delay.current(), 9);
+    }
+
+    #[test]
+    fn ignores_one_tick_wobble() {
+        let mut delay = InputDelay::new();
+        for frame in 0..STABLE_FRAMES * 3 {
+            // 목표가 2 와 4 사이에서 흔들린다.
+            let srtt = if frame % 2 == 0 { 0 } else { 80 };
+            assert_eq!(delay.update(srtt, 0), 3);
+        }
+    }
+
+    #[test]
+    fn restarts_when_the_target_moves() {
+        let mut delay = InputDelay::new();
+        for _ in 0..STABLE_FRAMES - 1 {
+            delay.update(200, 10);
+        }
+        // 다른 목표가 끼어들면 처음부터 센다.
+        delay.update(400, 10);
+        for _ in 0..STABLE_FRAMES - 2 {
+            assert_eq!(delay.update(200, 10), 3);
+        }
+        // 1 틱 안쪽으로 돌아오면 세던 것도 버린다.
+        delay.update(60, 0);
+        assert_eq!(delay.update(200, 10), 3);
+    }
+
+    #[test]
+    fn pinned_delay_wins() {
+        let mut delay = InputDelay::new();
+        delay.set_pinned(Some(100));
+        assert_eq!(delay.current(), MAX_INPUT_DELAY);
+        for _ in 0..STABLE_FRAMES * 2 {
+            assert_eq!(delay.update(0, 0), MAX_INPUT_DELAY);
+        }
+        delay.set_pinned(Some(0));
+        assert_eq!(delay.current(), MIN_INPUT_DELAY);
+    }
+
+    #[test]
+    fn schedules_each_tick_once() {
+        let mut delay = InputDelay::new();
+        assert_eq!(delay.schedule(1, 1), vec![(4, 1)]);
+        assert_eq!(delay.schedule(2, 2), vec![(5, 2)]);
+    }
+
+    #[test]
+    fn fills_the_gap_when_delay_grows() {
+        let mut delay = InputDelay::new();
+        delay.schedule(1, 1);
+        delay.set_pinned(Some(6));
+        assert_eq!(delay.schedule(2, 2), vec![(5, 2), (6, 2), (7, 2), (8, 2)]);
+        assert_eq!(delay.schedule(3, 3), vec![(9, 3)]);
+    }
+
+    #[test]
+    fn drops_overlapping_inputs_when_delay_shrinks() {
+        let mut delay = InputDelay::new();
+        delay.set_pinned(Some(6));
+        assert_eq!(delay.schedule(1, 1), vec![(7, 1)]);
+        delay.set_pinned(Some(3));
+        // 5, 6, 7 틱에는 이미 입력이 들어가 있다.
+        assert_eq!(delay.schedule(2, 2), Vec::new());
+        assert_eq!(delay.schedule(4, 4), Vec::new());
+        assert_eq!(delay.schedule(5, 5), vec![(8, 5)]);
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+// "through 까지 전부 받았고, 그 뒤로는 bits 에 표시된 틱을 받았다"
+// bits 의 i 번째 비트는 through + 1 + i 틱이다.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputAck {
+    pub through: u64,
+    pub bits: u32,
+}
+
+impl InputAck {
+    pub fn covers(&self, tick: u64) -> bool {
+        if tick <= self.through {
+            return true;
+        }
+        let offset = tick - self.through - 1;
+        offset < 32 && self.bits & (1 << offset) != 0
+    }
+}
+
+// 받는 쪽 윈도. 보내는 쪽은 ack 를 받기 전까지 입력을 지우지 않으므로
+// 패킷의 첫 틱보다 앞의 틱은 이미 다 받은 것이다.
+pub struct InputReceiveWindow {
+    through: u64,
+    // through 뒤로 띄엄띄엄 받은 틱
+    received: BTreeSet<u64>,
+}
+
+impl InputReceiveWindow {
+    pub fn new() -> Self {
+        Self {
+            through: 0,
+            received: BTreeSet::new(),
+        }
+    }
+
+    // ticks 는 오름차순
+    pub fn on_packet(&mut self, ticks: &[u64]) -> InputAck {
+        if let Some(first) = ticks.first() {
+            self.through = self.through.max(first.saturating_sub(1));
+        }
+        for tick in ticks {
+            if *tick > self.through {
+                self.received.insert(*tick);
+            }
+        }
+        self.received = self.received.split_off(&(self.through + 1));
+        while self.received.remove(&(self.through + 1)) {
+            self.through += 1;
+        }
+        self.ack()
+    }
+
+    pub fn ack(&self) -> InputAck {
+        let mut bits = 0u32;
+        for tick in self.received.iter() {
+            let offset = tick - self.through - 1;
+            if offset < 32 {
+                bits |= 1 << offset;
+            }
+        }
+        InputAck {
+            through: self.through,
+            bits,
+        }
+    }
+}
+
+impl Default for InputReceiveWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 보내는 쪽. 우리 입력을 모든 피어가 ack 할 때까지 들고 있다가 피어마다 빠진 것을 다시 보낸다.
+pub struct InputSendBuffer {
+    inputs: BTreeMap<u64, u8>,
+    // 피어별로 받은 마지막 ack
+    acks: HashMap<u8, InputAck>,
+}
+
+impl InputSendBuffer {
+    pub fn new() -> Self {
+        Self {
+            inputs: BTreeMap::new(),
+            acks: HashMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, tick: u64, input: u8) {
+        self.inputs.insert(tick, input);
+    }
+
+    // peer 가 보낸 ack. peers 는 지금 연결된 피어들
+    pub fn on_ack(&mut self, peer: u8, ack: InputAck, peers: &[u8]) {
+        // 늦게 온 옛 ack 는 무시한다.
+        if self.acks.get(&peer).is_some_and(|last| last.through > ack.through) {
+            return;
+        }
+        self.acks.insert(peer, ack);
+        self.prune(peers);
+    }
+
+    // 모든 피어가 받았다고 한 입력은 더 보낼 필요가 없으니 지운다.
+    pub fn prune(&mut self, peers: &[u8]) {
+        self.acks.retain(|peer, _| peers.contains(peer));
+        let acks: Vec<InputAck> = peers.iter().filter_map(|peer| self.acks.get(peer).copied()).collect();
+        if acks.len() < peers.len() {
+            return;
+        }
+        self.inputs.retain(|tick, _| !acks.iter().all(|ack| ack.covers(*tick)));
+    }
+
+    // peer 가 받았다고 하지 않은 입력들, 오래된 것부터 최대 limit 개
+    pub fn unacked(&self, peer: u8, limit: usize) -> Vec<(u64, u8)> {
+        let ack = self.acks.get(&peer);
+        self.inputs
+            .iter()
+            .filter(|(tick, _)| !ack.is_some_and(|ack| ack.covers(**tick)))
+            .map(|(tick, input)| (*tick, *input))
+            .take(limit)
+            .collect()
+    }
+}
+
+impl Default for InputSendBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acks_contiguous_ticks_cumulatively() {
+        let mut window = InputReceiveWindow::new();
+        assert_eq!(window.ack(), InputAck { through: 0, bits: 0 });
+        assert_eq!(window.on_packet(&[1, 2, 3]), InputAck { through: 3, bits: 0 });
+        // 이미 받은 틱이 다시 와도 그대로다.
+        assert_eq!(window.on_packet(&[2, 3]), InputAck { through: 3, bits: 0 });
+        assert_eq!(window.on_packet(&[4]), InputAck { through: 4, bits: 0 });
+    }
+
+    #[test]
+    fn first_tick_of_a_packet_confirms_everything_before_it() {
+        let mut window = InputReceiveWindow::new();
+        assert_eq!(window.on_packet(&[10, 11]), InputAck { through: 11, bits: 0 });
+    }
+
+    #[test]
+    fn acks_gaps_selectively() {
+        let mut window = InputReceiveWindow::new();
+        window.on_packet(&[1, 2]);
+        // 빠진 틱은 bits 로 따로 알려 준다.
+        let ack = window.on_packet(&[3, 5, 7]);
+        assert_eq!(ack, InputAck { through: 3, bits: 0b1010 });
+        assert!(ack.covers(3));
+        assert!(!ack.covers(4));
+        assert!(ack.covers(5));
+        assert!(!ack.covers(6));
+        assert!(ack.covers(7));
+        assert!(!ack.covers(8));
+        // 빈 틱이 채워지면 누적 ack 가 따라온다.
+        assert_eq!(window.on_packet(&[4]), InputAck { through: 5, bits: 0b10 });
+        assert_eq!(window.on_packet(&[6]), InputAck { through: 7, bits: 0 });
+    }
+
+    #[test]
+    fn prunes_ticks_covered_by_the_cumulative_ack() {
+        let mut window = InputReceiveWindow::new();
+        window.on_packet(&[1]);
+        window.on_packet(&[3, 8, 9]);
+        window.on_packet(&[20]);
+        assert!(window.received.is_empty());
+        assert_eq!(window.ack(), InputAck { through: 20, bits: 0 });
+        // 늦게 온 오래된 패킷은 누적 ack 를 되돌리지 않는다.
+        assert_eq!(window.on_packet(&[5, 6]), InputAck { through: 20, bits: 0 });
+    }
+
+    #[test]
+    fn bits_cover_only_the_next_32_ticks() {
+        let mut window = InputReceiveWindow::new();
+        window.on_packet(&[1]);
+        let ack = window.on_packet(&[2, 34, 35, 36]);
+        assert_eq!(ack.through, 2);
+        assert_eq!(ack.bits, 1 << 31);
+        assert!(!ack.covers(36));
+        // 비트 밖의 틱도 기억하고 있다가 빈 곳이 채워지면 ack 한다.
+        let ticks: Vec<u64> = (3..34).collect();
+        assert_eq!(window.on_packet(&ticks), InputAck { through: 36, bits: 0 });
+    }
+
+    #[test]
+    fn resends_until_every_peer_acks() {
+        let mut buffer = InputSendBuffer::new();
+        for tick in 1..=5 {
+            buffer.push(tick, tick as u8);
+        }
+        let peers = [1, 2];
+        assert_eq!(buffer.unacked(1, 3), vec![(1, 1), (2, 2), (3, 3)]);
+        buffer.on_ack(1, InputAck { through: 3, bits: 0b10 }, &peers);
+        assert_eq!(buffer.unacked(1, 10), vec![(4, 4)]);
+        // 2 번은 아직 아무것도 받지 않았으니 지우지 않는다.
+        assert_eq!(buffer.inputs.len(), 5);
+        assert_eq!(buffer.unacked(2, 10).len(), 5);
+        buffer.on_ack(2, InputAck { through: 4, bits: 0 }, &peers);
+        assert_eq!(buffer.unacked(2, 10), vec![(5, 5)]);
+        // 둘 다 받은 틱만 지운다.
+        assert_eq!(buffer.inputs.len(), 2);
+    }
+
+    #[test]
+    fn ignores_stale_acks_and_departed_peers() {
+        let mut buffer = InputSendBuffer::new();
+        for tick in 1..=4 {
+            buffer.push(tick, 0);
+        }
+        buffer.on_ack(1, InputAck { through: 3, bits: 0 }, &[1, 2]);
+        buffer.on_ack(1, InputAck { through: 1, bits: 0 }, &[1, 2]);
+        assert_eq!(buffer.unacked(1, 10), vec![(4, 0)]);
+        // 2 번이 나가면 1 번이 받은 것은 모두 지운다.
+        buffer.prune(&[1]);
+        assert_eq!(buffer.inputs.len(), 1);
+    }
+}
@@ -6,10 +6,37 @@ struct MyExtension;
 unsafe impl ExtensionLibrary for MyExtension {}
 
 mod player;
-mod udp_net;
+pub mod udp_net;
 mod gui_player_state;
 mod network_controller;
 mod time;
 mod connect;
 mod input_controller;
-mod game_manager;
\ No newline at end of file
+mod game_manager;
+pub mod rollback;
+pub mod simulation;
+pub mod fixed;
+mod desync;
+mod clock_sync;
+mod tick_scheduler;
+mod net_stats;
+mod input_delay;
+mod input_window;
+pub mod reliable;
+mod connection;
+pub mod session;
+pub mod secure;
+mod peer;
+pub mod mesh;
+mod spectator;
+pub mod replay;
+mod replay_player;
+pub mod transport;
+pub mod netsim;
+pub mod relay;
+pub mod server_log;
+pub mod relay_server;
+pub mod rendezvous;
+pub mod rendezvous_server;
+pub mod lobby;
+pub mod lobby_server;
\ No newline at end of file
@@ -0,0 +1,417 @@
+// 로비. 호스트가 방을 만들면 서버가 짧은 코드를 주고, 손님은 그 코드로 들어온다.
+// 모두 준비되고 호스트가 시작하면 서버가 호스트 주소를 알려 주고, 그 뒤로는 기존 P2P 연결을 쓴다.
+// 서버와는 TCP 로 연결한다.
+//
+// 스트림은 [길이 u16][종류 u8][필드들] 의 연속이다. 길이는 종류부터 센다.
+
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+
+use crate::time;
+use crate::udp_net::{Reader, UnpackError, Writer};
+
+// 서버와 클라이언트가 같아야 한다.
+pub const LOBBY_VERSION: u8 = 1;
+// 헷갈리는 글자 (0/O, 1/I) 는 뺐다.
+pub const LOBBY_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+pub const LOBBY_CODE_LEN: usize = 6;
+pub const MAX_NAME_LEN: usize = 32;
+pub const MAX_ENDPOINT_LEN: usize = 64;
+pub const MAX_SETTINGS: usize = 16;
+pub const MAX_SETTING_LEN: usize = 64;
+pub const MAX_LOBBY_MESSAGE: usize = 4096;
+// 보낼 것이 없으면 이 간격으로 KeepAlive 를 보낸다. 서버의 idle 시간보다 짧아야 한다.
+pub const LOBBY_KEEPALIVE_MS: u64 = 5000;
+// 방을 만든 사람의 번호. P2P 에서도 호스트가 0 번이다.
+pub const LOBBY_HOST_ID: u8 = 0;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct LobbyMember {
+    pub id: u8,
+    pub name: String,
+    // P2P 로 연결할 주소
+    pub endpoint: String,
+    pub ready: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum LobbyMessage {
+    // 클라이언트 -> 서버: 방을 만든다. endpoint 가 ":포트" 면 서버가 본 IP 를 붙인다.
+    Create { version: u8, name: String, endpoint: String, settings: Vec<(String, String)> },
+    // 클라이언트 -> 서버: code 의 방에 들어간다.
+    Join { version: u8, code: String, name: String, endpoint: String },
+    // 서버 -> 클라이언트: 들어왔다. 방 안에서 우리 번호
+    Joined { code: String, member: u8 },
+    // 서버 -> 클라이언트: 방이 바뀔 때마다 전부 보낸다.
+    RoomState { members: Vec<LobbyMember>, settings: Vec<(String, String)> },
+    // 클라이언트 -> 서버
+    SetReady { ready: bool },
+    // 호스트 -> 서버
+    SetSetting { key: String, value: String },
+    Start,
+    // 서버 -> 클라이언트: 이제 host 로 P2P 연결을 한다.
+    Started { host: String },
+    // 서버 -> 클라이언트: 이 뒤로 연결을 끊는다.
+    Error { reason: String },
+    // 클라이언트 -> 서버: 보낼 것이 없어도 방에 남아 있는다.
+    KeepAlive,
+}
+
+fn put_settings(w: &mut Writer, settings: &[(String, String)]) {
+    w.put_u8(settings.len() as u8);
+    for (key, value) in settings {
+        w.put_str(key);
+        w.put_str(value);
+    }
+}
+
+fn get_settings(r: &mut Reader) -> Result<Vec<(String, String)>, UnpackError> {
+    let count = r.get_u8()? as usize;
+    if count > MAX_SETTINGS {
+        return Err(UnpackError::BadLength(count));
+    }
+    let mut settings = Vec::with_capacity(count);
+    for _ in 0..count {
+        let key = get_limited_str(r, MAX_SETTING_LEN)?;
+        let value = get_limited_str(r, MAX_SETTING_LEN)?;
+        settings.push((key, value));
+    }
+    Ok(settings)
+}
+
+fn get_limited_str(r: &mut Reader, max: usize) -> Result<String, UnpackError> {
+    let text = r.get_str()?;
+    if text.len() > max {
+        return Err(UnpackError::BadLength(text.len()));
+    }
+    Ok(text)
+}
+
+fn get_bool(r: &mut Reader) -> Result<bool, UnpackError> {
+    match r.get_u8()? {
+        0 => Ok(false),
+        1 => Ok(true),
+        value => Err(UnpackError::BadValue(value)),
+    }
+}
+
+impl LobbyMessage {
+    // 길이 prefix 를 붙인 바이트
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        match self {
+            LobbyMessage::Create { version, name, endpoint, settings } => {
+                w.put_u8(0);
+                w.put_u8(*version);
+                w.put_str(name);
+                w.put_str(endpoint);
+                put_settings(&mut w, settings);
+            }
+            LobbyMessage::Join { version, code, name, endpoint } => {
+                w.put_u8(1);
+                w.put_u8(*version);
+                w.put_str(code);
+                w.put_str(name);
+                w.put_str(endpoint);
+            }
+            LobbyMessage::Joined { code, member } => {
+                w.put_u8(2);
+                w.put_str(code);
+                w.put_u8(*member);
+            }
+            LobbyMessage::RoomState { members, settings } => {
+                w.put_u8(3);
+                w.put_u8(members.len() as u8);
+                for member in members {
+                    w.put_u8(member.id);
+                    w.put_str(&member.name);
+                    w.put_str(&member.endpoint);
+                    w.put_u8(member.ready as u8);
+                }
+                put_settings(&mut w, settings);
+            }
+            LobbyMessage::SetReady { ready } => {
+                w.put_u8(4);
+                w.put_u8(*ready as u8);
+            }
+            LobbyMessage::SetSetting { key, value } => {
+                w.put_u8(5);
+                w.put_str(key);
+                w.put_str(value);
+            }
+            LobbyMessage::Start => w.put_u8(6),
+            LobbyMessage::Started { host } => {
+                w.put_u8(7);
+                w.put_str(host);
+            }
+            LobbyMessage::Error { reason } => {
+                w.put_u8(8);
+                w.put_str(reason);
+            }
+            LobbyMessage::KeepAlive => w.put_u8(9),
+        }
+        let body = w.into_bytes();
+        let mut bytes = Vec::with_capacity(body.len() + 2);
+        bytes.extend_from_slice(&(body.len() as u16).to_le_bytes());
+        bytes.extend(body);
+        bytes
+    }
+
+    // 길이 prefix 를 뺀 바이트
+    pub fn decode(body: &[u8]) -> Result<Self, UnpackError> {
+        let mut r = Reader::new(body);
+        let message = match r.get_u8()? {
+            0 => LobbyMessage::Create {
+                version: r.get_u8()?,
+                name: get_limited_str(&mut r, MAX_NAME_LEN)?,
+                endpoint: get_limited_str(&mut r, MAX_ENDPOINT_LEN)?,
+                settings: get_settings(&mut r)?,
+            },
+            1 => LobbyMessage::Join {
+                version: r.get_u8()?,
+                code: get_limited_str(&mut r, LOBBY_CODE_LEN * 4)?,
+                name: get_limited_str(&mut r, MAX_NAME_LEN)?,
+                endpoint: get_limited_str(&mut r, MAX_ENDPOINT_LEN)?,
+            },
+            2 => LobbyMessage::Joined {
+                code: r.get_str()?,
+                member: r.get_u8()?,
+            },
+            3 => {
+                let count = r.get_u8()?;
+                let mut members = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    members.push(LobbyMember {
+                        id: r.get_u8()?,
+                        name: r.get_str()?,
+                        endpoint: r.get_str()?,
+                        ready: get_bool(&mut r)?,
+                    });
+                }
+                LobbyMessage::RoomState {
+                    members,
+                    settings: get_settings(&mut r)?,
+                }
+            }
+            4 => LobbyMessage::SetReady { ready: get_bool(&mut r)? },
+            5 => LobbyMessage::SetSetting {
+                key: get_limited_str(&mut r, MAX_SETTING_LEN)?,
+                value: get_limited_str(&mut r, MAX_SETTING_LEN)?,
+            },
+            6 => LobbyMessage::Start,
+            7 => LobbyMessage::Started { host: r.get_str()? },
+            8 => LobbyMessage::Error { reason: r.get_str()? },
+            9 => LobbyMessage::KeepAlive,
+            kind => return Err(UnpackError::UnknownType(kind)),
+        };
+        if r.remaining() > 0 {
+            return Err(UnpackError::TrailingBytes(r.remaining()));
+        }
+        Ok(message)
+    }
+}
+
+// 논블로킹 스트림에서 모은 buffer 앞의 메시지 하나를 꺼낸다. 아직 덜 왔으면 None
+pub fn take_message(buffer: &mut Vec<u8>) -> Result<Option<LobbyMessage>, UnpackError> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+    let len = u16::from_le_bytes([buffer[0], buffer[1]]) as usize;
+    if len == 0 || len > MAX_LOBBY_MESSAGE {
+        return Err(UnpackError::BadLength(len));
+    }
+    if buffer.len() < 2 + len {
+        return Ok(None);
+    }
+    let message = LobbyMessage::decode(&buffer[2..2 + len]);
+    buffer.drain(..2 + len);
+    message.map(Some)
+}
+
+// 블로킹 스트림에서 메시지 하나를 읽는다.
+pub fn read_message(stream: &mut impl Read) -> io::Result<LobbyMessage> {
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len)?;
+    let len = u16::from_le_bytes(len) as usize;
+    if len == 0 || len > MAX_LOBBY_MESSAGE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("bad lobby message length {}", len)));
+    }
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body)?;
+    LobbyMessage::decode(&body).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
+}
+
+// 사람이 입력한 코드를 서버가 준 형태로. 소문자와 앞뒤 공백을 허용한다.
+pub fn normalize_code(code: &str) -> String {
+    code.trim().to_uppercase()
+}
+
+#[derive(Default)]
+struct LobbyState {
+    code: Option<String>,
+    member: Option<u8>,
+    members: Vec<LobbyMember>,
+    settings: Vec<(String, String)>,
+    // RoomState 를 받을 때마다 늘어난다.
+    revision: u64,
+    started: Option<String>,
+    closed: Option<String>,
+}
+
+// 로비 서버의 방 하나에 들어가 있는 연결
+pub struct LobbyClient {
+    stream: TcpStream,
+    state: Arc<Mutex<LobbyState>>,
+    last_sent: u64,
+}
+
+impl LobbyClient {
+    // 방을 만든다. 코드는 서버가 답하면 code() 로 알 수 있다.
+    pub fn create(server: &str, name: &str, endpoint: &str, settings: Vec<(String, String)>) -> io::Result<Self> {
+        Self::connect(
+            server,
+            LobbyMessage::Create {
+                version: LOBBY_VERSION,
+                name: name.to_string(),
+                endpoint: endpoint.to_string(),
+                settings,
+            },
+        )
+    }
+
+    pub fn join(server: &str, code: &str, name: &str, endpoint: &str) -> io::Result<Self> {
+        Self::connect(
+            server,
+            LobbyMessage::Join {
+                version: LOBBY_VERSION,
+                code: normalize_code(code),
+                name: name.to_string(),
+                endpoint: endpoint.to_string(),
+            },
+        )
+    }
+
+    fn connect(server: &str, first: LobbyMessage) -> io::Result<Self> {
+        let mut stream = TcpStream::connect(server)?;
+        stream.set_nodelay(true)?;
+        stream.write_all(&first.encode())?;
+
+        let state = Arc::new(Mutex::new(LobbyState::default()));
+        let state_for_thread = state.clone();
+        let mut reader = stream.try_clone()?;
+        std::thread::spawn(move || loop {
+            let message = match read_message(&mut reader) {
+                Ok(message) => message,
+                Err(err) => {
+                    let mut state = state_for_thread.lock().unwrap();
+                    if state.closed.is_none() {
+                        state.closed = Some(err.to_string());
+                    }
+                    return;
+                }
+            };
+            let mut state = state_for_thread.lock().unwrap();
+            match message {
+                LobbyMessage::Joined { code, member } => {
+                    state.code = Some(code);
+                    state.member = Some(member);
+                }
+                LobbyMessage::RoomState { members, settings } => {
+                    state.members = members;
+                    state.settings = settings;
+                    state.revision += 1;
+                }
+                LobbyMessage::Started { host } => state.started = Some(host),
+                LobbyMessage::Error { reason } => {
+                    state.closed = Some(reason);
+                    return;
+                }
+                _ => {}
+            }
+        });
+        Ok(Self {
+            stream,
+            state,
+            last_sent: time::monotonic_ms(),
+        })
+    }
+
+    fn write(&mut self, message: &LobbyMessage) -> io::Result<()> {
+        self.last_sent = time::monotonic_ms();
+        self.stream.write_all(&message.encode())
+    }
+
+    pub fn set_ready(&mut self, ready: bool) -> io::Result<()> {
+        self.write(&LobbyMessage::SetReady { ready })
+    }
+
+    // 호스트만 바꿀 수 있다. 바꾸면 모두의 준비가 풀린다.
+    pub fn set_setting(&mut self, key: &str, value: &str) -> io::Result<()> {
+        self.write(&LobbyMessage::SetSetting {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+    }
+
+    // 호스트만 할 수 있다. 모두 준비되어 있어야 한다.
+    pub fn start(&mut self) -> io::Result<()> {
+        self.write(&LobbyMessage::Start)
+    }
+
+    // 매 프레임 부른다. 조용하면 KeepAlive 를 보낸다.
+    pub fn poll(&mut self) -> io::Result<()> {
+        if time::monotonic_ms().saturating_sub(self.last_sent) > LOBBY_KEEPALIVE_MS {
+            self.write(&LobbyMessage::KeepAlive)?;
+        }
+        Ok(())
+    }
+
+    pub fn code(&self) -> Option<String> {
+        self.state.lock().unwrap().code.clone()
+    }
+
+    pub fn member_id(&self) -> Option<u8> {
+        self.state.lock().unwrap().member
+    }
+
+    pub fn is_host(&self) -> bool {
+        self.member_id() == Some(LOBBY_HOST_ID)
+    }
+
+    pub fn members(&self) -> Vec<LobbyMember> {
+        self.state.lock().unwrap().members.clone()
+    }
+
+    pub fn settings(&self) -> Vec<(String, String)> {
+        self.state.lock().unwrap().settings.clone()
+    }
+
+    pub fn setting(&self, key: &str) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        state.settings.iter().find(|(k, _)| k == key).map(|(_, value)| value.clone())
+    }
+
+    // 방이 바뀔 때마다 늘어난다. 화면을 다시 그릴지 정할 때 쓴다.
+    pub fn revision(&self) -> u64 {
+        self.state.lock().unwrap().revision
+    }
+
+    // 호스트가 시작했으면 연결할 호스트 주소
+    pub fn started(&self) -> Option<String> {
+        self.state.lock().unwrap().started.clone()
+    }
+
+    // 서버가 연결을 끊었으면 그 이유
+    pub fn closed(&self) -> Option<String> {
+        self.state.lock().unwrap().closed.clone()
+    }
+}
+
+// 읽는 스레드가 스트림을 복제해 들고 있으므로 직접 닫아야 서버가 떠난 것을 안다.
+impl Drop for LobbyClient {
+    fn drop(&mut self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
@@ -0,0 +1,470 @@
+// 로비 서버. 방과 코드, 준비 상태와 설정만 들고 있고 게임 데이터는 거치지 않는다.
+// 엔진 없이 src/bin/lobby_server.rs 에서 돌리고, 테스트에서는 poll 에 시각을 넣어 직접 돌린다.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+
+use rand_core::{OsRng, RngCore};
+
+use crate::lobby::{
+    normalize_code, take_message, LobbyMember, LobbyMessage, LOBBY_CODE_ALPHABET, LOBBY_CODE_LEN, LOBBY_HOST_ID,
+    LOBBY_VERSION, MAX_SETTINGS,
+};
+use crate::server_log::ServerLog;
+use crate::time;
+use crate::udp_net::MAX_PLAYERS;
+
+// 보내지 못하고 쌓인 바이트가 이보다 많으면 느린 클라이언트로 보고 끊는다.
+const MAX_OUTGOING_BYTES: usize = 64 * 1024;
+
+pub struct LobbyConfig {
+    // 이 시간 동안 아무것도 보내지 않은 클라이언트는 끊는다. KeepAlive 도 센다.
+    pub idle_timeout_ms: u64,
+    pub max_room_members: usize,
+    pub max_rooms: usize,
+    // 방을 만들고, 들어오고, 시작하는 일을 한 줄씩 받는다.
+    pub log: ServerLog,
+}
+
+impl LobbyConfig {
+    pub fn new() -> Self {
+        Self {
+            idle_timeout_ms: 30_000,
+            max_room_members: MAX_PLAYERS,
+            max_rooms: 4096,
+            log: ServerLog::default(),
+        }
+    }
+}
+
+impl Default for LobbyConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Client {
+    stream: TcpStream,
+    addr: SocketAddr,
+    incoming: Vec<u8>,
+    outgoing: Vec<u8>,
+    // 들어가 있는 방의 코드
+    room: Option<String>,
+    last_active: u64,
+    // 남은 outgoing 을 보내고 끊는다.
+    closing: bool,
+}
+
+impl Client {
+    fn queue(&mut self, message: &LobbyMessage) {
+        self.outgoing.extend(message.encode());
+    }
+}
+
+struct Room {
+    // (클라이언트, 방 안의 정보). 첫 번째가 호스트다.
+    members: Vec<(u64, LobbyMember)>,
+    settings: Vec<(String, String)>,
+    // 시작한 방에는 더 들어올 수 없다.
+    started: bool,
+}
+
+pub struct LobbyServer {
+    listener: TcpListener,
+    config: LobbyConfig,
+    clients: BTreeMap<u64, Client>,
+    rooms: HashMap<String, Room>,
+    next_client: u64,
+}
+
+impl LobbyServer {
+    pub fn bind(addr: &str, config: LobbyConfig) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            config,
+            clients: BTreeMap::new(),
+            rooms: HashMap::new(),
+            next_client: 1,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn room_count(&self) -> usize {
+        self.rooms.len()
+    }
+
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.poll(time::monotonic_ms());
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+    }
+
+    // 받을 것을 받고, 방을 바꾸고, 보낼 것을 보낸다.
+    pub fn poll(&mut self, now: u64) {
+        self.accept(now);
+
+        let ids: Vec<u64> = self.clients.keys().copied().collect();
+        for id in ids.iter() {
+            for message in self.read(*id) {
+                self.handle(*id, message, now);
+            }
+        }
+
+        for (id, client) in self.clients.iter_mut() {
+            if !client.closing && now.saturating_sub(client.last_active) > self.config.idle_timeout_ms {
+                self.config.log.write(&format!("client {} ({}) idle", id, client.addr));
+                client.closing = true;
+            }
+        }
+
+        let mut closed = Vec::new();
+        for (id, client) in self.clients.iter_mut() {
+            let broken = !flush(client) || client.outgoing.len() > MAX_OUTGOING_BYTES;
+            if broken || (client.closing && client.outgoing.is_empty()) {
+                closed.push(*id);
+            }
+        }
+        for id in closed {
+            self.remove(id);
+        }
+    }
+
+    fn accept(&mut self, now: u64) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, addr)) => {
+                    if stream.set_nonblocking(true).is_err() {
+                        continue;
+                    }
+                    let _ = stream.set_nodelay(true);
+                    let id = self.next_client;
+                    self.next_client += 1;
+                    self.clients.insert(
+                        id,
+                        Client {
+                            stream,
+                            addr,
+                            incoming: Vec::new(),
+                            outgoing: Vec::new(),
+                            room: None,
+                            last_active: now,
+                            closing: false,
+                        },
+                    );
+                }
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => return,
+                Err(err) => {
+                    self.config.log.write(&format!("accept failed: {}", err));
+                    return;
+                }
+            }
+        }
+    }
+
+    // 다 받은 메시지들. 연결이 끊겼거나 형식이 틀리면 닫는다.
+    fn read(&mut self, id: u64) -> Vec<LobbyMessage> {
+        let client = self.clients.get_mut(&id).unwrap();
+        let mut buffer = [0u8; 4096];
+        loop {
+            match client.stream.read(&mut buffer) {
+                Ok(0) => {
+                    client.closing = true;
+                    break;
+                }
+                Ok(size) => client.incoming.extend_from_slice(&buffer[..size]),
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => {
+                    client.closing = true;
+                    break;
+                }
+            }
+        }
+        let mut messages = Vec::new();
+        loop {
+            match take_message(&mut client.incoming) {
+                Ok(Some(message)) => messages.push(message),
+                Ok(None) => break,
+                Err(err) => {
+                    self.config.log.write(&format!("client {} ({}) sent bad data: {}", id, client.addr, err));
+                    client.incoming.clear();
+                    client.closing = true;
+                    break;
+                }
+            }
+        }
+        messages
+    }
+
+    fn handle(&mut self, id: u64, message: LobbyMessage, now: u64) {
+        let client = self.clients.get_mut(&id).unwrap();
+        if client.closing {
+            return;
+        }
+        client.last_active = now;
+        match message {
+            LobbyMessage::Create { version, name, endpoint, settings } => {
+                if self.check_new_member(id, version) {
+                    self.create(id, name, endpoint, settings);
+                }
+            }
+            LobbyMessage::Join { version, code, name, endpoint } => {
+                if self.check_new_member(id, version) {
+                    self.join(id, &normalize_code(&code), name, endpoint);
+                }
+            }
+            LobbyMessage::SetReady { ready } => self.set_ready(id, ready),
+            LobbyMessage::SetSetting { key, value } => self.set_setting(id, key, value),
+            LobbyMessage::Start => self.start(id),
+            LobbyMessage::KeepAlive => {}
+            _ => self.reject(id, "unexpected message"),
+        }
+    }
+
+    fn check_new_member(&mut self, id: u64, version: u8) -> bool {
+        if version != LOBBY_VERSION {
+            self.reject(id, &format!("lobby version {} (server {})", version, LOBBY_VERSION));
+            return false;
+        }
+        if self.clients[&id].room.is_some() {
+            self.reject(id, "already in a room");
+            return false;
+        }
+        true
+    }
+
+    // ":포트" 면 서버가 본 IP 를 붙인다. NAT 뒤라면 공인 IP 가 된다.
+    fn member_endpoint(&self, id: u64, endpoint: &str) -> String {
+        let port = endpoint.strip_prefix(':').and_then(|port| port.parse::<u16>().ok());
+        match port {
+            Some(port) => SocketAddr::new(self.clients[&id].addr.ip(), port).to_string(),
+            None => endpoint.to_string(),
+        }
+    }
+
+    fn new_code(&self) -> String {
+        loop {
+            let code: String = (0..LOBBY_CODE_LEN)
+                .map(|_| {
+                    let index = OsRng.next_u32() as usize % LOBBY_CODE_ALPHABET.len();
+                    LOBBY_CODE_ALPHABET[index] as char
+                })
+                .collect();
+            if !self.rooms.contains_key(&code) {
+                return code;
+            }
+        }
+    }
+
+    fn create(&mut self, id: u64, name: String, endpoint: String, settings: Vec<(String, String)>) {
+        if self.rooms.len() >= self.config.max_rooms {
+            self.reject(id, "too many rooms");
+            return;
+        }
+        let code = self.new_code();
+        let host = LobbyMember {
+            id: LOBBY_HOST_ID,
+            name,
+            endpoint: self.member_endpoint(id, &endpoint),
+            ready: false,
+        };
+        self.rooms.insert(
+            code.clone(),
+            Room {
+                members: vec![(id, host)],
+                settings,
+                started: false,
+            },
+        );
+        let client = self.clients.get_mut(&id).unwrap();
+        client.room = Some(code.clone());
+        self.config.log.write(&format!("client {} ({}) created room {}", id, client.addr, code));
+        client.queue(&LobbyMessage::Joined {
+            code: code.clone(),
+            member: LOBBY_HOST_ID,
+        });
+        self.broadcast(&code);
+    }
+
+    fn join(&mut self, id: u64, code: &str, name: String, endpoint: String) {
+        let endpoint = self.member_endpoint(id, &endpoint);
+        let Some(room) = self.rooms.get_mut(code) else {
+            self.reject(id, "no such room");
+            return;
+        };
+        if room.started {
+            self.reject(id, "game already started");
+            return;
+        }
+        if room.members.len() >= self.config.max_room_members {
+            self.reject(id, "room is full");
+            return;
+        }
+        // 비어 있는 가장 작은 번호
+        let member = (1..=u8::MAX)
+            .find(|candidate| room.members.iter().all(|(_, member)| member.id != *candidate))
+            .unwrap();
+        room.members.push((
+            id,
+            LobbyMember {
+                id: member,
+                name,
+                endpoint,
+                ready: false,
+            },
+        ));
+        let client = self.clients.get_mut(&id).unwrap();
+        client.room = Some(code.to_string());
+        self.config.log.write(&format!("client {} ({}) joined room {} as {}", id, client.addr, code, member));
+        client.queue(&LobbyMessage::Joined {
+            code: code.to_string(),
+            member,
+        });
+        self.broadcast(code);
+    }
+
+    fn set_ready(&mut self, id: u64, ready: bool) {
+        let Some(code) = self.clients[&id].room.clone() else {
+            self.reject(id, "not in a room");
+            return;
+        };
+        let Some(room) = self.rooms.get_mut(&code) else {
+            return;
+        };
+        if let Some((_, member)) = room.members.iter_mut().find(|(client, _)| *client == id) {
+            member.ready = ready;
+        }
+        self.broadcast(&code);
+    }
+
+    // 설정이 바뀌면 바뀐 설정으로 다시 준비하도록 모두의 준비를 푼다.
+    fn set_setting(&mut self, id: u64, key: String, value: String) {
+        let Some(code) = self.host_room(id) else {
+            self.reject(id, "only the host can change settings");
+            return;
+        };
+        let room = self.rooms.get_mut(&code).unwrap();
+        match room.settings.iter().position(|(k, _)| *k == key) {
+            Some(index) => room.settings[index].1 = value,
+            None if room.settings.len() < MAX_SETTINGS => room.settings.push((key, value)),
+            None => {
+                self.reject(id, "too many settings");
+                return;
+            }
+        }
+        for (_, member) in room.members.iter_mut() {
+            member.ready = false;
+        }
+        self.broadcast(&code);
+    }
+
+    fn start(&mut self, id: u64) {
+        let Some(code) = self.host_room(id) else {
+            self.reject(id, "only the host can start");
+            return;
+        };
+        let room = self.rooms.get_mut(&code).unwrap();
+        // 시작하지 못하는 것은 연결을 끊을 일은 아니다. 방 상태를 다시 보내 준다.
+        if room.members.len() < 2 || room.members.iter().any(|(_, member)| !member.ready) {
+            self.broadcast(&code);
+            return;
+        }
+        room.started = true;
+        let started = LobbyMessage::Started {
+            host: room.members[0].1.endpoint.clone(),
+        };
+        self.config.log.write(&format!("room {} started with {} members", code, room.members.len()));
+        for (client, _) in room.members.iter() {
+            if let Some(client) = self.clients.get_mut(client) {
+                client.queue(&started);
+            }
+        }
+    }
+
+    // id 가 호스트인 방의 코드
+    fn host_room(&self, id: u64) -> Option<String> {
+        let code = self.clients[&id].room.clone()?;
+        let room = self.rooms.get(&code)?;
+        (room.members[0].0 == id).then_some(code)
+    }
+
+    fn broadcast(&mut self, code: &str) {
+        let Some(room) = self.rooms.get(code) else {
+            return;
+        };
+        let state = LobbyMessage::RoomState {
+            members: room.members.iter().map(|(_, member)| member.clone()).collect(),
+            settings: room.settings.clone(),
+        };
+        for (client, _) in room.members.iter() {
+            if let Some(client) = self.clients.get_mut(client) {
+                client.queue(&state);
+            }
+        }
+    }
+
+    fn reject(&mut self, id: u64, reason: &str) {
+        let client = self.clients.get_mut(&id).unwrap();
+        self.config.log.write(&format!("client {} ({}) rejected: {}", id, client.addr, reason));
+        client.queue(&LobbyMessage::Error { reason: reason.to_string() });
+        client.closing = true;
+    }
+
+    // 방에서 뺀다. 호스트가 나가면 방을 닫고, 아니면 남은 사람들에게 알린다.
+    fn remove(&mut self, id: u64) {
+        let Some(client) = self.clients.remove(&id) else {
+            return;
+        };
+        self.config.log.write(&format!("client {} ({}) left", id, client.addr));
+        let Some(code) = client.room else {
+            return;
+        };
+        let Some(room) = self.rooms.get_mut(&code) else {
+            return;
+        };
+        let host_left = room.members[0].0 == id;
+        room.members.retain(|(client, _)| *client != id);
+        if !host_left && !room.members.is_empty() {
+            self.broadcast(&code);
+            return;
+        }
+        let room = self.rooms.remove(&code).unwrap();
+        let error = LobbyMessage::Error { reason: "host left".to_string() };
+        for (client, _) in room.members {
+            if let Some(client) = self.clients.get_mut(&client) {
+                client.room = None;
+                // 시작한 방의 손님들은 이미 P2P 로 넘어갔다.
+                if !room.started {
+                    client.queue(&error);
+                    client.closing = true;
+                }
+            }
+        }
+    }
+}
+
+// 보낼 수 있는 만큼 보낸다. 연결이 끊겼으면 false
+fn flush(client: &mut Client) -> bool {
+    while !client.outgoing.is_empty() {
+        match client.stream.write(&client.outgoing) {
+            Ok(0) => return false,
+            Ok(size) => {
+                client.outgoing.drain(..size);
+            }
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => return true,
+            Err(_) => return false,
+        }
+    }
+    true
+}
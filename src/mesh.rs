@@ -0,0 +1,1656 @@
+// 엔진과 무관한 방 하나의 연결, 입력, 롤백. NetworkController 는 이것을 프레임마다 돌리고
+// 나온 MeshEvent 로 노드를 만들거나 지우고 시그널을 보낸다. 시각은 모두 부르는 쪽이 준다.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::connection::ConnectionState;
+use crate::input_window::{InputAck, InputSendBuffer};
+use crate::net_stats::NetStats;
+use crate::peer::Peer;
+use crate::relay::{RelayFallback, RelayTransport};
+use crate::reliable::ChannelEndpoint;
+use crate::rendezvous::{HolePuncher, PunchState};
+use crate::replay::Replay;
+use crate::rollback::RollbackSession;
+use crate::secure::psk_from_room_code;
+use crate::session::Session;
+use crate::simulation::TICKS_PER_SECOND;
+use crate::spectator::{
+    SpectatorFeed, SpectatorPlayout, MAX_SPECTATORS, SPECTATOR_MAX_IN_FLIGHT, SPECTATOR_TICKS_PER_PACKET,
+};
+use crate::tick_scheduler::TickScheduler;
+use crate::transport::{PeerId, Transport};
+use crate::udp_net::{self, Challenge, ChallengeResponse, ChecksumPacket, Connect, Disconnect, InputOKPacket, InputPacket, Pong};
+use crate::udp_net::{Channel, ChatPacket, MatchAction, MatchControlPacket, Message, Packet, MAX_CHAT_LEN};
+use crate::udp_net::{PeerList, SpectatorInputs, StartMatch, MAX_INPUTS_PER_PACKET, MAX_PLAYERS, SPECTATOR_ID, UNASSIGNED_ID};
+
+pub const DEFAULT_MAX_PLAYERS: usize = 2;
+// StartMatch 를 보내고 실제로 시작하기까지 (ms). 모든 피어에 도착할 시간
+pub const START_DELAY_MS: u64 = 1000;
+// 호스트가 이 시간 동안 직접 답하지 않으면 중계 서버로 다시 Connect 를 보낸다. (ms)
+pub const RELAY_FALLBACK_MS: u64 = 3000;
+
+pub struct NetworkStat {
+    pub tick: u64,
+    pub latency: u64,
+    // 기준 피어(0 번 플레이어) 시계 - 내 시계 (ms)
+    pub clock_offset: i64,
+    pub clock_synced: bool,
+    // 상대가 Ping 으로 알려 준 틱과 비교해 우리가 앞선 틱 수. advance 가 가져간다.
+    pub frame_advantage: Option<i64>,
+    // 관전 중이면 시계 대신 받은 확정 입력을 따라간다.
+    pub spectating: bool,
+}
+
+impl NetworkStat {
+    pub fn new() -> Self {
+        Self {
+            tick: 0,
+            latency: 0,
+            clock_offset: 0,
+            clock_synced: false,
+            frame_advantage: None,
+            spectating: false,
+        }
+    }
+
+    // 양쪽 피어가 함께 쓰는 시계. now 는 내 시계
+    pub fn shared_time(&self, now: u64) -> u64 {
+        (now as i64 + self.clock_offset).max(0) as u64
+    }
+}
+
+impl Default for NetworkStat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 엔진 노드들도 읽으므로 Mesh 와 함께 들고 있는다.
+pub type SharedStat = Arc<Mutex<NetworkStat>>;
+pub type SharedSession = Arc<Mutex<Option<RollbackSession>>>;
+
+pub enum MeshEvent {
+    // 다른 플레이어와 연결됐다. Connect 로 받은 시작 위치
+    PlayerJoined { id: u8, x: f32, y: f32 },
+    PlayerLeft(u8),
+    // 경기가 시작됐다. 관전자면 my_id 가 None
+    MatchStarted { my_id: Option<u8>, players: Vec<(u8, f32, f32)> },
+    // player 는 아직 모르면 None
+    ConnectionChanged { player: Option<u8>, state: ConnectionState },
+    Chat { player: u8, text: String },
+    MatchControl { player: u8, action: MatchAction },
+    // dump 는 세션 덤프 뒤에 체크섬 기록을 붙인 것
+    Desync { me: Option<u8>, player: Option<u8>, tick: u64, dump: String },
+    // 방을 떠났다. 만들었던 플레이어들과 이번 경기의 확정 입력 기록
+    RoomClosed { players: Vec<u8>, replay: Option<Replay> },
+    Log(String),
+}
+
+// 프레임들을 세션에 맞게 (평문/암호문) 데이터그램으로 묶어 보낸다.
+fn send_frames(transport: &mut dyn Transport, session: &mut Session, frames: &[Vec<u8>], addr: PeerId) {
+    for body in udp_net::batch_frames(frames) {
+        transport.send(addr, &session.wrap(&body));
+    }
+}
+
+// 프레임 하나를 풀어서 넘겨줄 메시지들. 채널 패킷이면 채널이 내보낸 것들이다. 버린 이유는 dropped 에
+fn unwrap_channel(channel: &mut ChannelEndpoint, frame: &[u8], dropped: &mut Vec<String>) -> Vec<Message> {
+    let mut decoded = Vec::new();
+    match udp_net::decode(frame) {
+        Ok(Message::Channel(packet)) => {
+            for payload in channel.receive(packet) {
+                match udp_net::decode(payload.as_slice()) {
+                    Ok(Message::Channel(_)) | Ok(Message::ChannelAck(_)) => {
+                        dropped.push("nested channel packet".to_string())
+                    }
+                    Ok(message) => decoded.push(message),
+                    Err(err) => dropped.push(err.to_string()),
+                }
+            }
+        }
+        Ok(Message::ChannelAck(ack)) => channel.on_ack(&ack),
+        Ok(message) => decoded.push(message),
+        Err(err) => dropped.push(err.to_string()),
+    }
+    decoded
+}
+
+// reliable 로 감싼 Connect 인지. 처음 보는 주소는 이것만 피어로 받아 본다.
+fn is_channel_connect(frame: &[u8]) -> bool {
+    match udp_net::decode(frame) {
+        Ok(Message::Channel(packet)) => {
+            matches!(udp_net::decode(packet.payload.as_slice()), Ok(Message::Connect(_)))
+        }
+        _ => false,
+    }
+}
+
+// 호스트 하나와 손님들의 풀 메시. 호스트가 id 를 나눠 주고 손님끼리는 PeerList 로 서로 연결한다.
+pub struct Mesh {
+    transport: Box<dyn Transport>,
+    stat: SharedStat,
+    session: SharedSession,
+    // 주소별 피어. 우리가 Connect 를 보내는 중인 주소와 Connect 를 받아 본 주소도 들어 있다.
+    peers: BTreeMap<PeerId, Peer>,
+    // 호스트는 0 번이고 손님은 호스트의 답장으로 받는다.
+    my_id: Option<u8>,
+    // 호스트가 알려 준 다른 손님들 (id -> 주소). 이 id 로 오는 Connect 만 받는다.
+    expected: BTreeMap<u8, PeerId>,
+    got_peer_list: bool,
+    sent_ready: bool,
+    started: bool,
+    // 호스트가 정한 시작 정보. 나중에 들어온 관전자에게도 보낸다.
+    start: Option<StartMatch>,
+    // 우리가 다른 플레이어를 관전하는 중
+    spectating: bool,
+    // 관전자들에게 보낼 확정 입력
+    spectator_feed: SpectatorFeed,
+    // 이번 경기의 확정 입력 기록
+    recorder: Option<Replay>,
+    max_players: usize,
+    // 엔진이 알려 준 우리 위치
+    position: (f32, f32),
+    // 우리가 먼저 Connect 를 보냈을 때 보낸 위치
+    sent_connect_pos: Option<(f32, f32)>,
+    secure: bool,
+    room_code: Option<String>,
+    // 들어가 있는 중계 서버의 방
+    relay_room: Option<String>,
+    punch: Option<HolePuncher>,
+    // 손님: 중개 서버가 알려 준 호스트로 구멍이 뚫리면 Connect 를 보낸다.
+    join_via_rendezvous: bool,
+    // 우리 입력. 모든 피어가 ack 할 때까지 들고 있는다.
+    local_inputs: InputSendBuffer,
+    // 공유 시계 기준. 0 이면 아직 정해지지 않았다.
+    game_start_time: u64,
+    scheduler: TickScheduler,
+    playout: SpectatorPlayout,
+    // 형식이 잘못되어 버려진 데이터그램/프레임 수
+    dropped_packets: u64,
+    events: Vec<MeshEvent>,
+}
+
+impl Mesh {
+    pub fn new(transport: Box<dyn Transport>) -> Self {
+        Self::with_state(transport, Arc::new(Mutex::new(NetworkStat::new())), Arc::new(Mutex::new(None)))
+    }
+
+    // 엔진 노드들과 상태를 나눠 쓸 때
+    pub fn with_state(transport: Box<dyn Transport>, stat: SharedStat, session: SharedSession) -> Self {
+        Self {
+            transport,
+            stat,
+            session,
+            peers: BTreeMap::new(),
+            my_id: None,
+            expected: BTreeMap::new(),
+            got_peer_list: false,
+            sent_ready: false,
+            started: false,
+            start: None,
+            spectating: false,
+            spectator_feed: SpectatorFeed::new(),
+            recorder: None,
+            max_players: DEFAULT_MAX_PLAYERS,
+            position: (0.0, 0.0),
+            sent_connect_pos: None,
+            secure: false,
+            room_code: None,
+            relay_room: None,
+            punch: None,
+            join_via_rendezvous: false,
+            local_inputs: InputSendBuffer::new(),
+            game_start_time: 0,
+            scheduler: TickScheduler::new(),
+            playout: SpectatorPlayout::new(),
+            dropped_packets: 0,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn transport(&mut self) -> &mut dyn Transport {
+        self.transport.as_mut()
+    }
+
+    // 다른 전송으로 바꾼다. 방에 있는 동안에는 바꿀 수 없다.
+    pub fn set_transport(&mut self, transport: Box<dyn Transport>) -> bool {
+        if !self.peers.is_empty() {
+            self.log("Cannot change transport while connected".to_string());
+            return false;
+        }
+        self.transport = transport;
+        // 중개 서버의 PeerId 는 이전 전송의 것이다.
+        self.punch = None;
+        true
+    }
+
+    // 지금 전송 옆에 중계 서버의 방 하나를 붙인다. 방에 들어가기 전, use_rendezvous 보다 먼저 해야 한다.
+    pub fn with_relay(mut self, relay: RelayTransport, room: String) -> Self {
+        self.transport = Box::new(RelayFallback::new(self.transport, relay));
+        self.relay_room = Some(room);
+        self
+    }
+
+    // 연결 중이거나 연결된 피어가 있다.
+    pub fn in_room(&self) -> bool {
+        !self.peers.is_empty()
+    }
+
+    pub fn has_relay(&self) -> bool {
+        self.relay_room.is_some()
+    }
+
+    // 중개 서버에 등록해서 방의 다른 피어들과 구멍을 뚫는다.
+    pub fn use_rendezvous(&mut self, server: &str, room: &str) -> bool {
+        if self.punch.is_some() {
+            return false;
+        }
+        let Some(server_peer) = self.transport.resolve(server) else {
+            self.log(format!("Invalid rendezvous server : {}", server));
+            return false;
+        };
+        self.punch = Some(HolePuncher::new(server_peer, room));
+        true
+    }
+
+    pub fn has_rendezvous(&self) -> bool {
+        self.punch.is_some()
+    }
+
+    // 중개 서버가 본 우리 주소
+    pub fn public_endpoint(&self) -> Option<&str> {
+        self.punch.as_ref().and_then(|punch| punch.public_endpoint())
+    }
+
+    // 다음 연결부터 적용된다.
+    pub fn set_secure(&mut self, enabled: bool, room_code: Option<String>) {
+        self.secure = enabled;
+        self.room_code = room_code;
+    }
+
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    pub fn room_code(&self) -> Option<&str> {
+        self.room_code.as_deref()
+    }
+
+    pub fn set_max_players(&mut self, max_players: usize) {
+        self.max_players = max_players.clamp(2, MAX_PLAYERS);
+    }
+
+    pub fn max_players(&self) -> usize {
+        self.max_players
+    }
+
+    // 엔진 쪽 우리 위치. Connect 와 StartMatch 에 넣는다.
+    pub fn set_position(&mut self, x: f32, y: f32) {
+        self.position = (x, y);
+    }
+
+    pub fn stat(&self) -> MutexGuard<'_, NetworkStat> {
+        self.stat.lock().unwrap()
+    }
+
+    pub fn session(&self) -> MutexGuard<'_, Option<RollbackSession>> {
+        self.session.lock().unwrap()
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.stat().tick
+    }
+
+    pub fn dropped_packets(&self) -> u64 {
+        self.dropped_packets
+    }
+
+    pub fn take_events(&mut self) -> Vec<MeshEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn log(&mut self, text: String) {
+        self.events.push(MeshEvent::Log(text));
+    }
+
+    fn drop_packet(&mut self, addr: PeerId, reason: &str) {
+        self.dropped_packets += 1;
+        self.log(format!("Dropped packet from {} : {}", addr, reason));
+    }
+
+    fn new_session(&self) -> Session {
+        if self.secure {
+            Session::new_secure(self.room_code.as_deref().map(psk_from_room_code))
+        } else {
+            Session::new()
+        }
+    }
+
+    fn local_position(&self) -> (f32, f32) {
+        self.sent_connect_pos.unwrap_or(self.position)
+    }
+
+    pub fn is_host(&self) -> bool {
+        self.my_id == Some(0)
+    }
+
+    pub fn is_spectating(&self) -> bool {
+        self.spectating
+    }
+
+    pub fn local_player_id(&self) -> Option<u8> {
+        self.my_id
+    }
+
+    // 연결된 피어들의 id
+    pub fn peer_ids(&self) -> Vec<u8> {
+        let mut ids: Vec<u8> = self
+            .peers
+            .values()
+            .filter(|peer| peer.connection.is_connected())
+            .filter_map(|peer| peer.player_id)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    // 가장 느린 피어의 통계. 입력 지연은 이 피어에 맞춘다.
+    pub fn worst_stats(&self) -> Option<&NetStats> {
+        self.peers
+            .values()
+            .filter(|peer| peer.connection.is_connected() && !peer.spectator)
+            .map(|peer| &peer.stats)
+            .max_by_key(|stats| stats.srtt() + stats.jitter())
+    }
+
+    pub fn connection_state(&self, player_id: u8) -> ConnectionState {
+        self.peers
+            .values()
+            .find(|peer| peer.player_id == Some(player_id))
+            .map_or(ConnectionState::Idle, |peer| peer.connection.state())
+    }
+
+    // GUI 에 보여 줄 방 상태. 방에 없으면 None
+    pub fn room_status(&self) -> Option<String> {
+        let ids = self.peer_ids();
+        if ids.is_empty() {
+            return None;
+        }
+        if self.is_host() {
+            return Some(format!("Hosting {} players", ids.len() + 1));
+        }
+        if self.spectating {
+            let peer = self.peers.values().next()?;
+            return Some(format!("Spectating {}", self.transport.endpoint(peer.addr)?));
+        }
+        self.peers
+            .values()
+            .find(|peer| peer.player_id == Some(0))
+            .and_then(|host| self.transport.endpoint(host.addr))
+    }
+
+    // 호스트의 주소로 방에 들어간다. 이미 방에 있으면 무시한다.
+    pub fn send_connect(&mut self, endpoint: &str, pos: (f32, f32), now: u64) {
+        if self.my_id.is_some() || !self.peers.is_empty() {
+            return;
+        }
+        let Some(addr) = self.transport.resolve(endpoint) else {
+            self.log(format!("Invalid endpoint : {}", endpoint));
+            return;
+        };
+        self.sent_connect_pos = Some(pos);
+        self.connect_to(addr, None, now);
+    }
+
+    // 중개 서버가 알려 주는 방의 호스트로 들어간다. use_rendezvous 를 먼저 해야 한다.
+    pub fn send_connect_via_rendezvous(&mut self, pos: (f32, f32)) {
+        if self.my_id.is_some() || !self.peers.is_empty() {
+            return;
+        }
+        if self.punch.is_none() {
+            self.log("No rendezvous server".to_string());
+            return;
+        }
+        self.sent_connect_pos = Some(pos);
+        self.join_via_rendezvous = true;
+    }
+
+    // endpoint 의 플레이어에게 관전자로 붙는다.
+    pub fn send_spectate(&mut self, endpoint: &str, now: u64) {
+        if self.my_id.is_some() || !self.peers.is_empty() {
+            return;
+        }
+        let Some(addr) = self.transport.resolve(endpoint) else {
+            self.log(format!("Invalid endpoint : {}", endpoint));
+            return;
+        };
+        self.spectating = true;
+        self.connect_to(addr, None, now);
+    }
+
+    // id 는 호스트가 알려 준 상대의 id. 호스트에게 처음 보낼 때는 None
+    fn connect_to(&mut self, addr: PeerId, id: Option<u8>, now: u64) {
+        let (x, y) = self.local_position();
+        // 보낼 때마다 새 nonce 와 키를 쓴다.
+        let mut peer = Peer::new(addr, self.new_session());
+        peer.player_id = id;
+        let connect = Connect {
+            x,
+            y,
+            player_id: if self.spectating { SPECTATOR_ID } else { self.my_id.unwrap_or(UNASSIGNED_ID) },
+            assigned_id: UNASSIGNED_ID,
+            nonce: peer.session.local_nonce(),
+            echo: 0,
+            public_key: peer.session.public_key(),
+        };
+        let frame = peer.channel.send(Channel::ReliableOrdered, &connect, now);
+        send_frames(self.transport.as_mut(), &mut peer.session, &[frame], addr);
+        peer.connection.connect(now);
+        self.peers.insert(addr, peer);
+    }
+
+    // 손님: 호스트가 직접 답하지 않으면 중계 서버의 같은 방에 있는 피어에게 다시 Connect 를 보낸다.
+    // 방 코드마다 호스트는 한 명이라고 보고 가장 먼저 들어온 피어에게 보낸다.
+    fn fall_back_to_relay(&mut self, now: u64) {
+        if self.relay_room.is_none() || self.my_id.is_some() {
+            return;
+        }
+        let pending = self.peers.values().find(|peer| {
+            peer.player_id.is_none()
+                && peer.connection.connecting_for(now).is_some_and(|elapsed| elapsed > RELAY_FALLBACK_MS)
+        });
+        let Some(pending) = pending.map(|peer| peer.addr) else {
+            return;
+        };
+        let relay_peers = self.transport.relay_peers();
+        if relay_peers.contains(&pending) {
+            return;
+        }
+        let Some(target) = relay_peers.first().copied() else {
+            return;
+        };
+        self.log(format!("No direct answer from {}, trying relay {}", pending, target));
+        self.peers.remove(&pending);
+        self.connect_to(target, None, now);
+    }
+
+    // 손님: 호스트 쪽으로 구멍이 뚫리면 Connect 를 보낸다. 뚫리지 않으면 중계 서버로 보낸다.
+    fn connect_punched_host(&mut self, now: u64) {
+        if !self.join_via_rendezvous {
+            return;
+        }
+        if self.my_id.is_some() || !self.peers.is_empty() {
+            self.join_via_rendezvous = false;
+            return;
+        }
+        let Some((host, state)) = self.punch.as_ref().and_then(|punch| punch.host()) else {
+            return;
+        };
+        let target = match state {
+            PunchState::Punching => return,
+            PunchState::Open => Some(host),
+            PunchState::Failed => self.transport.relay_peers().first().copied(),
+        };
+        self.join_via_rendezvous = false;
+        match target {
+            Some(target) => {
+                self.log(format!("Connecting to {} ({:?})", target, state));
+                self.connect_to(target, None, now);
+            }
+            None => self.log(format!("Could not reach host {} and no relay", host)),
+        }
+    }
+
+    // id 의 피어에게 channel 로 보낸다. 연결되어 있지 않으면 버린다.
+    pub fn send_packet_to<T: Packet>(&mut self, id: u8, channel: Channel, packet: &T, now: u64) {
+        if let Some(peer) = self
+            .peers
+            .values_mut()
+            .find(|peer| peer.player_id == Some(id) && peer.connection.is_connected())
+        {
+            let frame = peer.channel.send(channel, packet, now);
+            peer.send_buffer.push(frame);
+        }
+    }
+
+    // 연결된 모든 플레이어에게 보낸다.
+    pub fn send_packet<T: Packet>(&mut self, channel: Channel, packet: &T, now: u64) {
+        for peer in self.peers.values_mut().filter(|peer| peer.connection.is_connected() && !peer.spectator) {
+            let frame = peer.channel.send(channel, packet, now);
+            peer.send_buffer.push(frame);
+        }
+    }
+
+    pub fn send_chat(&mut self, text: &str, now: u64) {
+        let mut text = text.to_string();
+        while text.len() > MAX_CHAT_LEN {
+            text.pop();
+        }
+        self.send_packet(Channel::ReliableOrdered, &ChatPacket { text }, now);
+    }
+
+    pub fn send_match_control(&mut self, action: MatchAction, now: u64) {
+        self.send_packet(Channel::ReliableOrdered, &MatchControlPacket { action }, now);
+    }
+
+    // 쌓아 둔 프레임을 보낸다. 프레임마다 한 번 부른다.
+    pub fn flush(&mut self) {
+        for peer in self.peers.values_mut() {
+            if peer.send_buffer.is_empty() {
+                continue;
+            }
+            send_frames(self.transport.as_mut(), &mut peer.session, &peer.send_buffer, peer.addr);
+            peer.send_buffer.clear();
+        }
+    }
+
+    // 우리 입력. tick 은 입력 지연을 더한 실제로 계산될 틱
+    pub fn add_local_input(&mut self, tick: u64, input: u8) -> bool {
+        if let (Some(id), Some(session)) = (self.my_id, self.session.lock().unwrap().as_mut()) {
+            if !session.add_input(id, tick, input) {
+                self.events
+                    .push(MeshEvent::Log(format!("Input for tick {} arrived too late to roll back", tick)));
+                return false;
+            }
+        }
+        self.local_inputs.push(tick, input);
+        true
+    }
+
+    // 피어마다 그 피어가 아직 받지 못한 입력을 보낸다.
+    // 새 패킷이 앞의 것을 모두 담고 있으니 늦게 온 옛 패킷은 버려도 된다.
+    pub fn send_inputs(&mut self, now: u64) {
+        let Some(player) = self.my_id else {
+            return;
+        };
+        for peer in self.peer_ids() {
+            let packet = InputPacket {
+                player,
+                inputs: self.local_inputs.unacked(peer, MAX_INPUTS_PER_PACKET),
+            };
+            if !packet.inputs.is_empty() {
+                self.send_packet_to(peer, Channel::UnreliableSequenced, &packet, now);
+            }
+        }
+    }
+
+    // 호스트: 우리를 포함한 인원과 손님이 모두 준비됐는지
+    fn guest_readiness(&self) -> (usize, bool) {
+        let guests = self.peers.values().filter(|peer| peer.connection.is_connected() && !peer.spectator);
+        let (count, ready) = guests.fold((0, 0), |(count, ready), peer| (count + 1, ready + peer.ready as usize));
+        (count + 1, count == ready)
+    }
+
+    // 호스트: 최대 인원이 차기 전에 시작한다. 손님이 모두 준비되어 있어야 한다.
+    pub fn start_match(&mut self, now: u64) -> bool {
+        let (count, all_ready) = self.guest_readiness();
+        if !self.is_host() || self.started || count < 2 || !all_ready {
+            self.log("Cannot start match yet".to_string());
+            return false;
+        }
+        self.begin_match(now)
+    }
+
+    // 호스트: 모두에게 시작 시각과 시작 위치를 보내고 우리도 시작한다.
+    fn begin_match(&mut self, now: u64) -> bool {
+        let (x, y) = self.local_position();
+        let mut players = vec![(0, x, y)];
+        for peer in self.peers.values().filter(|peer| peer.connection.is_connected() && !peer.spectator) {
+            if let Some(id) = peer.player_id {
+                players.push((id, peer.position.0, peer.position.1));
+            }
+        }
+        players.sort_by_key(|player| player.0);
+        let start = StartMatch {
+            game_start_time: self.stat().shared_time(now) + START_DELAY_MS,
+            players,
+        };
+        self.send_packet(Channel::ReliableOrdered, &start, now);
+        self.apply_start(&start, now)
+    }
+
+    fn apply_start(&mut self, start: &StartMatch, now: u64) -> bool {
+        let Some(my_id) = self.my_id else {
+            return false;
+        };
+        if self.started {
+            return false;
+        }
+        if !start.players.iter().any(|player| player.0 == my_id) {
+            self.log("Match started without us".to_string());
+            self.disconnect(now);
+            return false;
+        }
+
+        *self.session() = Some(RollbackSession::with_players(&start.players));
+        self.game_start_time = start.game_start_time;
+        self.started = true;
+        self.start = Some(start.clone());
+        self.recorder = Some(Replay::new(start.players.clone()));
+        self.log(format!("Match starts at {} with {} players", start.game_start_time, start.players.len()));
+        self.events.push(MeshEvent::MatchStarted {
+            my_id: Some(my_id),
+            players: start.players.clone(),
+        });
+        true
+    }
+
+    // 관전자: 모든 플레이어를 받은 입력으로 움직인다.
+    fn apply_spectator_start(&mut self, start: &StartMatch) -> bool {
+        if !self.spectating || self.started {
+            return false;
+        }
+        *self.session() = Some(RollbackSession::with_players(&start.players));
+        self.stat().spectating = true;
+        self.started = true;
+        self.recorder = Some(Replay::new(start.players.clone()));
+        self.log(format!("Spectating a match with {} players", start.players.len()));
+        self.events.push(MeshEvent::MatchStarted {
+            my_id: None,
+            players: start.players.clone(),
+        });
+        true
+    }
+
+    // 새로 확정된 틱의 입력을 관전자 버퍼와 리플레이에 넣는다.
+    pub fn collect_confirmed_inputs(&mut self) {
+        let guard = self.session.lock().unwrap();
+        let Some(session) = guard.as_ref() else {
+            return;
+        };
+        while let Some(inputs) = session.confirmed_inputs(self.spectator_feed.next_tick()) {
+            if let Some(recorder) = self.recorder.as_mut() {
+                recorder.push(inputs.clone());
+            }
+            self.spectator_feed.push(inputs);
+        }
+    }
+
+    // 이번 경기의 확정 입력 기록. collect_confirmed_inputs 까지 들어간다.
+    pub fn replay(&self) -> Option<&Replay> {
+        self.recorder.as_ref()
+    }
+
+    // 관전자마다 아직 보내지 않은 확정 입력을 보낸다.
+    // ack 를 기다리는 패킷이 많으면 쉬고, 그 사이 버퍼에서 밀려난 틱이 필요해진 관전자는 내보낸다.
+    fn feed_spectators(&mut self, now: u64) {
+        let Some(start) = self.start.as_ref() else {
+            return;
+        };
+        for peer in self.peers.values_mut().filter(|peer| peer.spectator && peer.connection.is_connected()) {
+            let mut next = match peer.spectator_next {
+                Some(next) => next,
+                None => {
+                    let frame = peer.channel.send(Channel::ReliableOrdered, start, now);
+                    peer.send_buffer.push(frame);
+                    1
+                }
+            };
+            while peer.channel.in_flight() < SPECTATOR_MAX_IN_FLIGHT && next < self.spectator_feed.next_tick() {
+                let Some(inputs) = self.spectator_feed.range(next, SPECTATOR_TICKS_PER_PACKET) else {
+                    self.events
+                        .push(MeshEvent::Log(format!("Spectator {} fell too far behind", peer.addr)));
+                    let frame = peer.channel.send(Channel::ReliableOrdered, &Disconnect, now);
+                    peer.send_buffer.push(frame);
+                    peer.connection.disconnect(now);
+                    break;
+                };
+                let packet = SpectatorInputs { start_tick: next, inputs };
+                next += packet.inputs.len() as u64;
+                let frame = peer.channel.send(Channel::ReliableOrdered, &packet, now);
+                peer.send_buffer.push(frame);
+            }
+            peer.spectator_next = Some(next);
+        }
+    }
+
+    // Connect 를 처리한다. 받아들였으면 true
+    fn handle_connect(&mut self, addr: PeerId, received_at: u64, session: u64, connect: Connect) -> bool {
+        let next_id = (1..MAX_PLAYERS as u8).find(|id| !self.peers.values().any(|peer| peer.player_id == Some(*id)));
+        let room_open = !self.started && self.peer_ids().len() + 1 < self.max_players.min(MAX_PLAYERS);
+        // 다른 곳에 들어가는 중이 아니어야 호스트가 될 수 있다.
+        let can_host = self.is_host()
+            || (self.my_id.is_none()
+                && !self.spectating
+                && self
+                    .peers
+                    .iter()
+                    .all(|(other, peer)| *other == addr || peer.connection.state() == ConnectionState::Idle));
+        let spectators = self.peers.values().filter(|peer| peer.spectator && peer.connection.is_connected()).count();
+        let local_pos = self.local_position();
+        let my_id = self.my_id;
+        let spectating = self.spectating;
+        let Some(peer) = self.peers.get_mut(&addr) else {
+            return false;
+        };
+        if peer.connection.is_connected() {
+            return false;
+        }
+
+        // 답장이면 우리 nonce 를 돌려줬어야 하고 헤더도 두 nonce 로 만든 세션이어야 한다.
+        // 보안 모드가 서로 다르면 어느 쪽이든 거절한다.
+        let initiator = connect.echo != 0;
+        let mut assigned = None;
+        if initiator {
+            // 관전자는 어느 플레이어에게든 붙을 수 있다.
+            let expected_id = if spectating {
+                (connect.player_id as usize) < MAX_PLAYERS
+            } else {
+                peer.player_id.map_or(connect.player_id == 0, |id| id == connect.player_id)
+            };
+            let id_ok = match my_id {
+                _ if spectating => connect.assigned_id == SPECTATOR_ID,
+                Some(_) => connect.assigned_id == UNASSIGNED_ID,
+                None => connect.assigned_id != UNASSIGNED_ID && connect.assigned_id != 0,
+            };
+            if peer.connection.state() != ConnectionState::Connecting
+                || !expected_id
+                || !id_ok
+                || !peer.session.complete(connect.echo, connect.nonce, session, connect.public_key)
+            {
+                self.drop_packet(addr, "rejected Connect reply");
+                return false;
+            }
+            if my_id.is_none() && !spectating {
+                assigned = Some(connect.assigned_id);
+            }
+            peer.player_id = Some(connect.player_id);
+        } else {
+            if session != 0 {
+                return false;
+            }
+            // 서로 동시에 보냈으면 nonce 가 작은 쪽이 답한다.
+            if peer.connection.state() == ConnectionState::Connecting && peer.session.local_nonce() > connect.nonce {
+                return false;
+            }
+            // 새 손님은 호스트만 받고, 이미 있는 손님은 호스트가 알려 준 id 만 받는다.
+            // 주소는 NAT 에 따라 호스트가 본 것과 다를 수 있어 id 만 본다. 관전자는 방에 있는 플레이어 누구나 받는다.
+            let remote_id = if connect.player_id == SPECTATOR_ID {
+                if my_id.is_none() || spectating || spectators >= MAX_SPECTATORS {
+                    self.drop_packet(addr, "rejected Connect : not accepting spectators");
+                    return false;
+                }
+                None
+            } else if connect.player_id == UNASSIGNED_ID {
+                match next_id {
+                    Some(id) if can_host && room_open => Some(id),
+                    _ => {
+                        self.drop_packet(addr, "rejected Connect : not hosting or room full");
+                        return false;
+                    }
+                }
+            } else if my_id.is_some_and(|id| id != 0) && self.expected.contains_key(&connect.player_id) {
+                Some(connect.player_id)
+            } else {
+                self.drop_packet(addr, "rejected Connect : unexpected player id");
+                return false;
+            };
+            let Some(nonce) = peer.session.accept(connect.nonce, connect.public_key) else {
+                self.drop_packet(addr, "rejected Connect : secure mode mismatch");
+                return false;
+            };
+            let local_id = my_id.unwrap_or(0);
+            let reply = Connect {
+                x: local_pos.0,
+                y: local_pos.1,
+                player_id: local_id,
+                assigned_id: match connect.player_id {
+                    UNASSIGNED_ID | SPECTATOR_ID => remote_id.unwrap_or(SPECTATOR_ID),
+                    _ => UNASSIGNED_ID,
+                },
+                nonce,
+                echo: connect.nonce,
+                public_key: peer.session.public_key(),
+            };
+            let reply = peer.channel.send(Channel::ReliableOrdered, &reply, received_at);
+            peer.send_buffer.push(reply);
+            peer.player_id = remote_id;
+            peer.spectator = remote_id.is_none();
+            assigned = Some(local_id);
+        }
+        if !peer.connection.on_connect(received_at) {
+            return false;
+        }
+        peer.position = (connect.x, connect.y);
+        let Some(remote_id) = peer.player_id else {
+            self.log(format!("Spectator connected : {}", addr));
+            return true;
+        };
+
+        if let Some(id) = assigned {
+            self.my_id = Some(id);
+            // 0 번 플레이어의 시계가 기준이다.
+            if id == 0 {
+                let mut stat = self.stat();
+                stat.clock_offset = 0;
+                stat.clock_synced = true;
+            }
+        }
+        self.events.push(MeshEvent::PlayerJoined {
+            id: remote_id,
+            x: connect.x,
+            y: connect.y,
+        });
+        self.log(format!("Connected to player {} : {}", remote_id, addr));
+
+        // 호스트: 새 손님에게 다른 손님들을, 다른 손님들에게 새 손님을 알려 준다.
+        if self.is_host() && !initiator {
+            let transport = self.transport.as_ref();
+            let endpoint = transport.endpoint(addr).unwrap_or_default();
+            let others: Vec<(u8, String)> = self
+                .peers
+                .values()
+                .filter(|peer| peer.connection.is_connected() && peer.addr != addr)
+                .filter_map(|peer| Some((peer.player_id?, transport.endpoint(peer.addr)?)))
+                .collect();
+            for (id, _) in others.iter() {
+                let list = PeerList { peers: vec![(remote_id, endpoint.clone())] };
+                self.send_packet_to(*id, Channel::ReliableOrdered, &list, received_at);
+            }
+            self.send_packet_to(remote_id, Channel::ReliableOrdered, &PeerList { peers: others }, received_at);
+        }
+        true
+    }
+
+    // 호스트가 보낸 다른 손님 목록. 우리보다 id 가 작은 손님에게는 우리가 Connect 를 보낸다.
+    fn on_peer_list(&mut self, list: PeerList, now: u64) {
+        let Some(my_id) = self.my_id else {
+            return;
+        };
+        self.got_peer_list = true;
+        for (id, endpoint) in list.peers {
+            let Some(addr) = self.transport.resolve(&endpoint) else {
+                self.log(format!("Invalid peer address : {}", endpoint));
+                continue;
+            };
+            if id == my_id || id == 0 {
+                continue;
+            }
+            self.expected.insert(id, addr);
+            if id < my_id && !self.peers.contains_key(&addr) {
+                self.connect_to(addr, Some(id), now);
+            }
+        }
+    }
+
+    // 손님: 호스트와 알려 준 손님 모두와 연결되고 시계를 맞췄다.
+    fn mesh_ready(&self) -> bool {
+        let ids = self.peer_ids();
+        self.got_peer_list
+            && ids.contains(&0)
+            && self.expected.keys().all(|id| ids.contains(id))
+            && self.stat().clock_synced
+    }
+
+    // 피어 하나가 나갔다. 시작 전에 호스트가 나갔거나 아무도 남지 않았으면 방을 정리한다.
+    fn remove_peer(&mut self, addr: PeerId, now: u64) {
+        let Some(peer) = self.peers.remove(&addr) else {
+            return;
+        };
+        if let Some(id) = peer.player_id {
+            self.expected.remove(&id);
+            self.events.push(MeshEvent::PlayerLeft(id));
+            if let Some(session) = self.session().as_mut() {
+                session.set_inactive(id);
+            }
+            let ids = self.peer_ids();
+            self.local_inputs.prune(&ids);
+        }
+
+        let host_left = peer.player_id == Some(0) && !self.started;
+        // 관전자만 남아도 방은 끝난다.
+        let alone = self
+            .peers
+            .values()
+            .all(|peer| peer.spectator || peer.connection.state() == ConnectionState::Idle);
+        if host_left || alone {
+            self.reset_room(now);
+        }
+    }
+
+    // 방을 떠나서 새 Connect 를 받을 수 있게 한다.
+    fn reset_room(&mut self, now: u64) {
+        for peer in self.peers.values_mut() {
+            if peer.connection.is_connected() {
+                let frame = peer.channel.send(Channel::ReliableOrdered, &Disconnect, now);
+                peer.send_buffer.push(frame);
+            }
+        }
+        self.flush();
+        self.collect_confirmed_inputs();
+        // 관전자는 연결하지 않은 플레이어의 노드도 만들었다.
+        let mut players: Vec<u8> = self.peers.values().filter_map(|peer| peer.player_id).collect();
+        players.extend(self.start.iter().flat_map(|start| start.players.iter().map(|player| player.0)));
+        players.sort();
+        players.dedup();
+        let replay = self.recorder.take().filter(|recorder| !recorder.inputs.is_empty());
+        self.events.push(MeshEvent::RoomClosed { players, replay });
+
+        *self.session() = None;
+        *self.stat() = NetworkStat::new();
+
+        self.peers.clear();
+        self.expected.clear();
+        self.my_id = None;
+        self.got_peer_list = false;
+        self.sent_ready = false;
+        self.started = false;
+        self.start = None;
+        self.spectating = false;
+        self.spectator_feed = SpectatorFeed::new();
+        self.sent_connect_pos = None;
+        self.local_inputs = InputSendBuffer::new();
+        self.game_start_time = 0;
+        self.scheduler = TickScheduler::new();
+        self.playout = SpectatorPlayout::new();
+    }
+
+    pub fn disconnect(&mut self, now: u64) {
+        for peer in self.peers.values_mut() {
+            if peer.connection.is_connected() {
+                let frame = peer.channel.send(Channel::ReliableOrdered, &Disconnect, now);
+                peer.send_buffer.push(frame);
+            }
+            peer.connection.disconnect(now);
+        }
+    }
+
+    // 체크섬이 어긋나면 상태와 최근 입력을 덤프로 남긴다.
+    fn on_desync(&mut self, addr: PeerId, tick: u64) {
+        let Some(peer) = self.peers.get(&addr) else {
+            return;
+        };
+        let player = peer.player_id;
+        let session_dump = self.session().as_ref().map(|session| session.dump(tick)).unwrap_or_default();
+        let dump = format!("{}{}", session_dump, peer.desync.dump());
+        self.log(format!(
+            "Desync with player {} detected at tick {}",
+            player.map_or(-1, |id| id as i64),
+            tick
+        ));
+        self.events.push(MeshEvent::Desync {
+            me: self.my_id,
+            player,
+            tick,
+            dump,
+        });
+    }
+
+    // 아는 피어의 데이터그램
+    fn receive_datagram(&mut self, addr: PeerId, received_at: u64, datagram: &[u8], messages: &mut Vec<(PeerId, u64, u64, Message)>) {
+        let peer = self.peers.get_mut(&addr).unwrap();
+        let unwrapped = peer
+            .session
+            .unwrap(datagram)
+            .and_then(|(session, body)| udp_net::split_frames(&body).map(|frames| (session, frames)));
+        let (session, frames) = match unwrapped {
+            Ok(unwrapped) => unwrapped,
+            Err(err) => {
+                self.drop_packet(addr, &err.to_string());
+                return;
+            }
+        };
+        let connected = peer.connection.is_connected();
+        if connected {
+            if session != peer.session.id() {
+                self.drop_packet(addr, "wrong session");
+                return;
+            }
+            peer.connection.on_receive(received_at);
+        }
+        let mut dropped = Vec::new();
+        for frame in frames {
+            for message in unwrap_channel(&mut peer.channel, frame.as_slice(), &mut dropped) {
+                messages.push((addr, received_at, session, message));
+            }
+        }
+        for reason in dropped {
+            self.drop_packet(addr, &reason);
+        }
+    }
+
+    // 처음 보는 주소의 데이터그램. 주소가 바뀐 피어이거나 새 Connect 다.
+    fn receive_unknown(&mut self, addr: PeerId, received_at: u64, datagram: &[u8], messages: &mut Vec<(PeerId, u64, u64, Message)>) {
+        let (session, kind, body) = match udp_net::split_datagram(datagram) {
+            Ok(split) => split,
+            Err(err) => {
+                self.drop_packet(addr, &err.to_string());
+                return;
+            }
+        };
+        if session != 0 {
+            let old = self
+                .peers
+                .values()
+                .find(|peer| peer.connection.is_connected() && peer.session.id() == session)
+                .map(|peer| peer.addr);
+            match old {
+                Some(old) => self.receive_migration(old, addr, received_at, datagram),
+                None => self.drop_packet(addr, "unknown session"),
+            }
+            return;
+        }
+        if kind != udp_net::DATAGRAM_PLAIN {
+            self.drop_packet(addr, "sealed datagram without session");
+            return;
+        }
+        let frames = match udp_net::split_frames(body) {
+            Ok(frames) => frames,
+            Err(err) => {
+                self.drop_packet(addr, &err.to_string());
+                return;
+            }
+        };
+        // 거절되면 이번 프레임 끝에 지운다.
+        if !frames.iter().any(|frame| is_channel_connect(frame)) {
+            self.drop_packet(addr, "no peer");
+            return;
+        }
+        let mut peer = Peer::new(addr, self.new_session());
+        let mut dropped = Vec::new();
+        for frame in frames {
+            for message in unwrap_channel(&mut peer.channel, frame.as_slice(), &mut dropped) {
+                if matches!(message, Message::Connect(_)) {
+                    messages.push((addr, received_at, 0, message));
+                }
+            }
+        }
+        self.peers.insert(addr, peer);
+        for reason in dropped {
+            self.drop_packet(addr, &reason);
+        }
+    }
+
+    // 세션 ID 는 맞지만 주소가 다르다. 새 주소가 challenge 를 돌려줄 때만 옮긴다.
+    fn receive_migration(&mut self, old: PeerId, addr: PeerId, received_at: u64, datagram: &[u8]) {
+        let peer = self.peers.get_mut(&old).unwrap();
+        // 보안 모드면 복호화가 곧 인증이다.
+        let frames = match peer.session.unwrap(datagram).and_then(|(_, body)| udp_net::split_frames(&body)) {
+            Ok(frames) => frames,
+            Err(err) => {
+                self.drop_packet(addr, &err.to_string());
+                return;
+            }
+        };
+        for frame in frames {
+            if let Ok(Message::ChallengeResponse(response)) = udp_net::decode(frame.as_slice()) {
+                if peer.session.verify_migration(addr, response.nonce) {
+                    let mut peer = self.peers.remove(&old).unwrap();
+                    peer.addr = addr;
+                    peer.connection.on_receive(received_at);
+                    self.peers.insert(addr, peer);
+                    self.log(format!("Peer moved : {} -> {}", old, addr));
+                    return;
+                }
+            }
+        }
+        if let Some(nonce) = peer.session.challenge(addr, received_at) {
+            send_frames(self.transport.as_mut(), &mut peer.session, &[udp_net::pack_frame(&Challenge { nonce })], addr);
+        }
+    }
+
+    // 받은 것을 처리하고 Ping, 체크섬, 재전송을 보낸다. 프레임마다 한 번 부른다.
+    pub fn update(&mut self, now: u64) {
+        let tick = self.tick();
+
+        // 버퍼를 거치면 보내는 시각이 늦어져 시계 추정이 틀어지므로 Ping 은 바로 보낸다.
+        for peer in self.peers.values_mut() {
+            peer.expire_pings(now);
+            if !peer.connection.is_connected() {
+                continue;
+            }
+            if let Some(ping) = peer.poll_ping(now, tick) {
+                send_frames(self.transport.as_mut(), &mut peer.session, &[udp_net::pack_frame(&ping)], peer.addr);
+            }
+        }
+
+        let mut desync_ticks = Vec::new();
+        let mut input_acks = Vec::new();
+        let mut packets = self.transport.poll();
+        if let Some(err) = self.transport.last_error() {
+            self.log(err);
+        }
+        if let Some(punch) = self.punch.as_mut() {
+            packets = punch.process(self.transport.as_mut(), packets, now);
+            self.events.extend(punch.take_log().into_iter().map(MeshEvent::Log));
+        }
+
+        // 세션을 확인하고 채널 패킷을 풀어서 넘겨줄 메시지만 남긴다.
+        let mut messages = Vec::new();
+        for (addr, received_at, datagram) in packets {
+            if self.peers.contains_key(&addr) {
+                self.receive_datagram(addr, received_at, &datagram, &mut messages);
+            } else {
+                self.receive_unknown(addr, received_at, &datagram, &mut messages);
+            }
+        }
+
+        let mut advantage_updated = false;
+        for (addr, received_at, session, message) in messages {
+            // 연결되기 전에는 Connect 만 받는다. 답장과 같이 온 PeerList 는 Connect 를 처리한 뒤라 받는다.
+            let connected = self.peers.get(&addr).is_some_and(|peer| peer.connection.is_connected());
+            if !connected && !matches!(message, Message::Connect(_)) {
+                continue;
+            }
+            let host = self.peers.get(&addr).is_some_and(|peer| peer.player_id == Some(0));
+            let message = match message {
+                Message::Connect(connect) => {
+                    self.handle_connect(addr, received_at, session, connect);
+                    continue;
+                }
+                Message::PeerList(list) => {
+                    if host {
+                        self.on_peer_list(list, now);
+                    }
+                    continue;
+                }
+                Message::StartMatch(start) => {
+                    // 관전자는 붙은 플레이어가 보내 준다.
+                    if self.spectating {
+                        self.apply_spectator_start(&start);
+                    } else if host {
+                        self.apply_start(&start, now);
+                    }
+                    continue;
+                }
+                Message::SpectatorInputs(packet) => {
+                    if self.spectating {
+                        if let Some(session) = self.session().as_mut() {
+                            for (i, inputs) in packet.inputs.iter().enumerate() {
+                                for (player, input) in inputs.iter().enumerate() {
+                                    session.add_input(player as u8, packet.start_tick + i as u64, *input);
+                                }
+                            }
+                        }
+                    }
+                    continue;
+                }
+                message => message,
+            };
+            let is_host = self.is_host();
+            let Some(peer) = self.peers.get_mut(&addr) else {
+                continue;
+            };
+            // 관전자는 id 가 없고 Ping, 연결 관리 메시지만 보낸다.
+            let peer_id = peer.player_id;
+            let player_message = !matches!(
+                message,
+                Message::Ping(_) | Message::Pong(_) | Message::Disconnect(_) | Message::Challenge(_)
+            );
+            if peer_id.is_none() && player_message {
+                continue;
+            }
+            let peer_id = peer_id.unwrap_or(SPECTATOR_ID);
+            match message {
+                Message::Ping(ping) => {
+                    // 상대의 틱은 편도 지연만큼 더 진행했을 것이다.
+                    if tick > 0 && ping.tick > 0 && !peer.spectator {
+                        let one_way_ticks = peer.stats.srtt() / 2 * TICKS_PER_SECOND as u64 / 1000;
+                        let remote_tick = ping.tick + one_way_ticks;
+                        peer.advantage = Some(tick as i64 - remote_tick as i64);
+                        advantage_updated = true;
+                    }
+
+                    let pong = Pong {
+                        id: ping.id,
+                        ping_time: ping.time,
+                        ping_received: received_at,
+                        time: now.max(received_at),
+                    };
+                    send_frames(self.transport.as_mut(), &mut peer.session, &[udp_net::pack_frame(&pong)], addr);
+                }
+                Message::Pong(pong) => {
+                    let Some(sample) = peer.on_pong(&pong, received_at) else {
+                        self.log(format!("Unknown Pong packet received : {}", pong.id));
+                        continue;
+                    };
+                    // 0 번 플레이어의 시계가 기준이다.
+                    if peer_id == 0 {
+                        if let Some(offset) = peer.clock.offset() {
+                            let mut stat = self.stat.lock().unwrap();
+                            stat.clock_offset = offset;
+                            stat.clock_synced = true;
+                        }
+                    }
+                    self.log(format!("Clock sample from {} : offset {}ms rtt {}ms", peer_id, sample.offset, sample.rtt));
+                }
+                Message::Input(input) => {
+                    // 메시에서는 각자 자기 입력만 보낸다.
+                    if input.player != peer_id {
+                        self.drop_packet(addr, "input for another player");
+                        continue;
+                    }
+                    // 세션이 없으면 넣을 곳이 없으니 ack 하지 않는다. 상대가 다시 보낸다.
+                    let mut session = self.session.lock().unwrap();
+                    let Some(session) = session.as_mut() else {
+                        continue;
+                    };
+                    for (tick, input) in input.inputs.iter() {
+                        if !session.add_input(peer_id, *tick, *input) {
+                            self.events.push(MeshEvent::Log(format!(
+                                "Input for tick {} arrived too late to roll back",
+                                tick
+                            )));
+                        }
+                    }
+                    let ticks: Vec<u64> = input.inputs.iter().map(|(tick, _)| *tick).collect();
+                    let ack = peer.input_window.on_packet(&ticks);
+                    peer.send_buffer.push(udp_net::pack_frame(&InputOKPacket {
+                        through: ack.through,
+                        bits: ack.bits,
+                    }));
+                }
+                Message::InputOK(input_ok) => input_acks.push((
+                    peer_id,
+                    InputAck {
+                        through: input_ok.through,
+                        bits: input_ok.bits,
+                    },
+                )),
+                Message::Checksum(checksum) => {
+                    if let Some(tick) = peer.desync.add_remote(checksum.tick, checksum.hash) {
+                        desync_ticks.push((addr, tick));
+                    }
+                }
+                Message::Disconnect(_) => {
+                    peer.connection.on_disconnect(received_at);
+                    self.log(format!("Peer {} disconnected : {}", peer_id, addr));
+                }
+                Message::Challenge(challenge) => {
+                    let response = udp_net::pack_frame(&ChallengeResponse { nonce: challenge.nonce });
+                    send_frames(self.transport.as_mut(), &mut peer.session, &[response], addr);
+                }
+                Message::Chat(chat) => self.events.push(MeshEvent::Chat {
+                    player: peer_id,
+                    text: chat.text,
+                }),
+                Message::MatchControl(control) => {
+                    if is_host && control.action == MatchAction::Ready {
+                        peer.ready = true;
+                    }
+                    self.events.push(MeshEvent::MatchControl {
+                        player: peer_id,
+                        action: control.action,
+                    });
+                }
+                // 위에서 처리했다.
+                Message::Channel(_)
+                | Message::ChannelAck(_)
+                | Message::ChallengeResponse(_)
+                | Message::Connect(_)
+                | Message::PeerList(_)
+                | Message::StartMatch(_)
+                | Message::SpectatorInputs(_) => {}
+            }
+        }
+
+        if !input_acks.is_empty() {
+            let ids = self.peer_ids();
+            for (peer, ack) in input_acks {
+                self.local_inputs.on_ack(peer, ack, &ids);
+            }
+        }
+
+        // 가장 느린 피어에 맞춰 쉰다.
+        if advantage_updated {
+            let advantage = self.peers.values().filter_map(|peer| peer.advantage).max();
+            self.stat().frame_advantage = advantage;
+        }
+
+        let checksums = self.session().as_mut().map(|session| session.take_checksums()).unwrap_or_default();
+        for (tick, hash) in checksums {
+            // 관전자는 비교만 하고, 플레이어는 관전자에게도 보낸다.
+            if !self.spectating {
+                for peer in self.peers.values_mut().filter(|peer| peer.connection.is_connected()) {
+                    let frame = peer.channel.send(Channel::ReliableOrdered, &ChecksumPacket { tick, hash }, now);
+                    peer.send_buffer.push(frame);
+                }
+            }
+            for peer in self.peers.values_mut().filter(|peer| peer.connection.is_connected() && !peer.spectator) {
+                if let Some(tick) = peer.desync.add_local(tick, hash) {
+                    desync_ticks.push((peer.addr, tick));
+                }
+            }
+        }
+
+        self.collect_confirmed_inputs();
+        self.feed_spectators(now);
+
+        // 손님은 메시가 다 이어지면 호스트에게 준비됐다고 알린다.
+        if !self.started && !self.sent_ready && self.my_id.is_some_and(|id| id != 0) && self.mesh_ready() {
+            self.send_packet_to(0, Channel::ReliableOrdered, &MatchControlPacket { action: MatchAction::Ready }, now);
+            self.sent_ready = true;
+        }
+        // 호스트는 최대 인원이 모두 준비되면 시작한다.
+        if self.is_host() && !self.started {
+            let (count, all_ready) = self.guest_readiness();
+            if count >= self.max_players && all_ready {
+                self.begin_match(now);
+            }
+        }
+
+        // ack 와 재전송. 연결되기 전의 Connect 도 있으니 주소로 바로 보낸다.
+        let game_started = tick > 0;
+        let mut transitions = Vec::new();
+        for peer in self.peers.values_mut() {
+            let frames = peer.channel.poll(now, peer.stats.rto());
+            if !frames.is_empty() {
+                send_frames(self.transport.as_mut(), &mut peer.session, &frames, peer.addr);
+            }
+            peer.connection.update(now, game_started, peer.channel.in_flight());
+            for state in peer.connection.take_transitions() {
+                transitions.push((peer.addr, peer.player_id, state));
+            }
+        }
+        self.fall_back_to_relay(now);
+        self.connect_punched_host(now);
+        // Connect 를 받아 봤지만 거절한 주소
+        self.peers.retain(|_, peer| peer.connection.state() != ConnectionState::Idle);
+
+        for (addr, tick) in desync_ticks {
+            self.on_desync(addr, tick);
+        }
+        for (addr, player, state) in transitions {
+            self.log(format!("Connection to {} : {:?}", addr, state));
+            if state == ConnectionState::Disconnected {
+                self.remove_peer(addr, now);
+            }
+            self.events.push(MeshEvent::ConnectionChanged { player, state });
+        }
+    }
+
+    // 공유 시계로 지금 있어야 할 틱까지 돌린다. 입력이 너무 늦는 플레이어가 있으면 멈춘다.
+    pub fn advance(&mut self, now: u64) {
+        let (shared_now, synced, mut tick, advantage, spectating) = {
+            let mut stat = self.stat();
+            (stat.shared_time(now), stat.clock_synced, stat.tick, stat.frame_advantage.take(), stat.spectating)
+        };
+        if spectating {
+            self.spectate(tick);
+            return;
+        }
+        if !synced || (self.game_start_time == 0) || self.game_start_time > shared_now {
+            return;
+        }
+
+        if let Some(advantage) = advantage {
+            self.scheduler.report_advantage(advantage);
+        }
+
+        // 프레임 수를 세지 않고 공유 시계로 지금 있어야 할 틱을 정한다.
+        let count = self.scheduler.ticks_to_run(self.game_start_time, shared_now, tick);
+        for _ in 0..count {
+            let mut session = self.session.lock().unwrap();
+            // 너무 늦는 플레이어가 있으면 입력이 올 때까지 멈춘다.
+            if session.as_ref().is_some_and(|session| !session.can_advance(tick + 1)) {
+                break;
+            }
+            tick += 1;
+            self.stat.lock().unwrap().tick = tick;
+
+            if let Some(session) = session.as_mut() {
+                if let Some(from) = session.advance(tick) {
+                    let text = format!("Rollback {} ticks ({} -> {})", tick - from + 1, from, tick);
+                    self.events.push(MeshEvent::Log(text));
+                }
+            }
+        }
+    }
+
+    // 관전자: 받은 확정 입력을 조금 모아 두었다가 따라간다.
+    fn spectate(&mut self, mut tick: u64) {
+        let mut session = self.session.lock().unwrap();
+        let Some(session) = session.as_mut() else {
+            return;
+        };
+        let count = self.playout.ticks_to_run(tick, session.confirmed_tick());
+        for _ in 0..count {
+            tick += 1;
+            self.stat.lock().unwrap().tick = tick;
+            session.advance(tick);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input_delay::InputDelay;
+    use crate::netsim::{Latency, LinkConfig, SimNetwork};
+    use crate::rollback::MAX_PREDICTION_TICKS;
+    use crate::simulation::{INPUT_JUMP, INPUT_LEFT, INPUT_RIGHT};
+    use crate::spectator::{SPECTATOR_BUFFER_TICKS, SPECTATOR_DELAY_TICKS};
+
+    const FRAME_MS: u64 = 16;
+
+    struct Node {
+        mesh: Mesh,
+        delay: InputDelay,
+        frozen: bool,
+        desyncs: usize,
+        started: bool,
+        // 이 노드에서 확정된 틱별 입력
+        confirmed: Vec<Vec<u8>>,
+    }
+
+    fn script(tick: u64, player: u8) -> u8 {
+        match (tick / 13 + player as u64) % 4 {
+            0 => INPUT_RIGHT,
+            1 => INPUT_RIGHT | INPUT_JUMP,
+            2 => 0,
+            _ => INPUT_LEFT,
+        }
+    }
+
+    fn addr(i: usize) -> String {
+        format!("10.0.0.{}:5000", i + 1)
+    }
+
+    // 0 번이 방을 만들고 나머지가 들어온다.
+    fn room(network: &SimNetwork, players: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = (0..players)
+            .map(|i| {
+                let mut mesh = Mesh::new(Box::new(network.endpoint(addr(i).parse().unwrap())));
+                mesh.set_max_players(players);
+                let mut delay = InputDelay::new();
+                delay.set_pinned(Some(3));
+                Node { mesh, delay, frozen: false, desyncs: 0, started: false, confirmed: Vec::new() }
+            })
+            .collect();
+        for (i, node) in nodes.iter_mut().enumerate().skip(1) {
+            node.mesh.send_connect(&addr(0), (i as f32 * 50.0, 0.0), network.now());
+        }
+        nodes
+    }
+
+    // addr(index) 에서 addr(player) 의 노드를 관전한다.
+    fn spectator(network: &SimNetwork, index: usize, player: usize) -> Node {
+        let mut mesh = Mesh::new(Box::new(network.endpoint(addr(index).parse().unwrap())));
+        mesh.send_spectate(&addr(player), network.now());
+        Node { mesh, delay: InputDelay::new(), frozen: false, desyncs: 0, started: false, confirmed: Vec::new() }
+    }
+
+    // 엔진의 한 프레임. 멈춘 노드는 아무것도 하지 않는다.
+    fn frame(network: &SimNetwork, nodes: &mut [Node]) {
+        let now = network.now();
+        for node in nodes.iter_mut().filter(|node| !node.frozen) {
+            node.mesh.update(now);
+            node.mesh.advance(now);
+            let tick = node.mesh.tick();
+            if let (true, Some(id)) = (tick > 0, node.mesh.local_player_id()) {
+                for (tick, input) in node.delay.schedule(tick, script(tick, id)) {
+                    node.mesh.add_local_input(tick, input);
+                }
+                node.mesh.send_inputs(now);
+            }
+            node.mesh.flush();
+            if let Some(session) = node.mesh.session().as_ref() {
+                while let Some(inputs) = session.confirmed_inputs(node.confirmed.len() as u64 + 1) {
+                    node.confirmed.push(inputs);
+                }
+            }
+            for event in node.mesh.take_events() {
+                match event {
+                    MeshEvent::Desync { .. } => node.desyncs += 1,
+                    MeshEvent::MatchStarted { .. } => node.started = true,
+                    _ => {}
+                }
+            }
+        }
+        network.advance(FRAME_MS);
+    }
+
+    fn run(network: &SimNetwork, nodes: &mut [Node], frames: usize) {
+        for _ in 0..frames {
+            frame(network, nodes);
+        }
+    }
+
+    fn network() -> SimNetwork {
+        SimNetwork::new(7, LinkConfig { latency: Latency::Fixed(20), ..LinkConfig::ideal() })
+    }
+
+    #[test]
+    fn host_assigns_ids_and_guests_mesh() {
+        let network = network();
+        let mut nodes = room(&network, 3);
+        run(&network, &mut nodes, 300);
+
+        let mut ids: Vec<u8> = nodes.iter().map(|node| node.mesh.local_player_id().unwrap()).collect();
+        ids.sort();
+        assert_eq!(ids, vec![0, 1, 2]);
+        assert!(nodes[0].mesh.is_host());
+        for node in nodes.iter() {
+            let me = node.mesh.local_player_id().unwrap();
+            let others: Vec<u8> = (0..3).filter(|id| *id != me).collect();
+            // 손님끼리도 직접 연결되어 있다.
+            assert_eq!(node.mesh.peer_ids(), others);
+            assert!(node.started);
+            assert!(node.mesh.tick() > 0);
+            assert_eq!(node.desyncs, 0);
+        }
+    }
+
+    #[test]
+    fn waits_for_a_stalled_player_then_catches_up() {
+        let network = network();
+        let mut nodes = room(&network, 3);
+        run(&network, &mut nodes, 300);
+        assert!(nodes.iter().all(|node| node.started && node.mesh.tick() > 0));
+
+        // 2 번 플레이어가 2 초 동안 멈춘다. 연결이 끊길 만큼은 아니다.
+        let stalled = nodes.iter().position(|node| node.mesh.local_player_id() == Some(2)).unwrap();
+        nodes[stalled].frozen = true;
+        run(&network, &mut nodes, 125);
+        for node in nodes.iter().filter(|node| !node.frozen) {
+            let confirmed = node.mesh.session().as_ref().unwrap().confirmed_tick();
+            // 예측할 수 있는 만큼만 앞서가고 멈춘다.
+            assert_eq!(node.mesh.tick(), confirmed + MAX_PREDICTION_TICKS);
+            assert_eq!(node.mesh.connection_state(2), ConnectionState::Running);
+        }
+        let waiting: Vec<u64> = nodes.iter().map(|node| node.mesh.tick()).collect();
+        run(&network, &mut nodes, 10);
+        for (node, tick) in nodes.iter().zip(waiting.iter()) {
+            assert_eq!(node.mesh.tick(), *tick);
+        }
+
+        nodes[stalled].frozen = false;
+        run(&network, &mut nodes, 300);
+        let ticks: Vec<u64> = nodes.iter().map(|node| node.mesh.tick()).collect();
+        for (node, tick) in nodes.iter().zip(ticks.iter()) {
+            assert!(*tick > waiting[0] + 200, "{:?}", ticks);
+            assert!(tick.abs_diff(ticks[0]) <= 4, "{:?}", ticks);
+            assert_eq!(node.desyncs, 0);
+        }
+    }
+
+    // 확정된 입력만으로 처음부터 through 틱까지 다시 돌린 상태의 체크섬
+    fn replayed_checksum(players: &[(u8, f32, f32)], confirmed: &[Vec<u8>], through: u64) -> u64 {
+        let mut session = RollbackSession::with_players(players);
+        for tick in 1..=through {
+            for (player, input) in confirmed[tick as usize - 1].iter().enumerate() {
+                session.add_input(player as u8, tick, *input);
+            }
+            session.advance(tick);
+        }
+        session.state().checksum()
+    }
+
+    fn spectator_peer(node: &mut Node) -> &mut Peer {
+        node.mesh.peers.values_mut().find(|peer| peer.spectator).unwrap()
+    }
+
+    #[test]
+    fn spectators_reproduce_the_match_from_confirmed_inputs() {
+        let network = network();
+        let mut nodes = room(&network, 3);
+        run(&network, &mut nodes, 300);
+        assert!(nodes.iter().all(|node| node.started));
+
+        // 경기 중에 들어와도 첫 틱부터 받는다. 호스트와 손님을 하나씩 관전한다.
+        let guest = nodes.iter().position(|node| node.mesh.local_player_id() == Some(1)).unwrap();
+        nodes.push(spectator(&network, 10, 0));
+        nodes.push(spectator(&network, 11, guest));
+        run(&network, &mut nodes, 300);
+
+        let players = nodes[0].mesh.start.as_ref().unwrap().players.clone();
+        let confirmed = nodes[0].confirmed.clone();
+        for node in nodes[3..].iter() {
+            assert!(node.started);
+            assert!(node.mesh.is_spectating());
+            assert_eq!(node.mesh.local_player_id(), None);
+            // 플레이어가 보낸 체크섬과도 맞는다.
+            assert_eq!(node.desyncs, 0);
+            let tick = node.mesh.tick();
+            assert!(tick + SPECTATOR_DELAY_TICKS * 2 >= confirmed.len() as u64, "{} / {}", tick, confirmed.len());
+            assert_eq!(node.confirmed[..], confirmed[..node.confirmed.len()]);
+            let state = node.mesh.session().as_ref().unwrap().state().checksum();
+            assert_eq!(state, replayed_checksum(&players, &confirmed, tick));
+        }
+        // 관전자는 플레이어로 세지 않는다.
+        for node in nodes[..3].iter() {
+            assert_eq!(node.mesh.peer_ids().len(), 2);
+            assert_eq!(node.desyncs, 0);
+        }
+    }
+
+    #[test]
+    fn holds_back_for_a_slow_spectator_and_drops_one_that_fell_behind() {
+        let network = network();
+        let mut nodes = room(&network, 2);
+        nodes.push(spectator(&network, 10, 0));
+        run(&network, &mut nodes, 300);
+        assert!(nodes[2].started);
+
+        // ack 가 오지 않으면 SPECTATOR_MAX_IN_FLIGHT 개까지만 보내고 기다린다.
+        nodes[2].frozen = true;
+        run(&network, &mut nodes, 60);
+        let feed_next = nodes[0].mesh.spectator_feed.next_tick();
+        let peer = spectator_peer(&mut nodes[0]);
+        let held = peer.spectator_next.unwrap();
+        assert!(peer.channel.in_flight() >= SPECTATOR_MAX_IN_FLIGHT);
+        assert!(held + 30 < feed_next, "{} / {}", held, feed_next);
+        run(&network, &mut nodes, 30);
+        assert_eq!(spectator_peer(&mut nodes[0]).spectator_next, Some(held));
+
+        // 다시 ack 가 오면 밀린 것을 보내고 관전자도 따라잡는다.
+        nodes[2].frozen = false;
+        run(&network, &mut nodes, 300);
+        let confirmed = nodes[0].confirmed.len() as u64;
+        assert!(nodes[2].mesh.tick() + SPECTATOR_DELAY_TICKS * 2 >= confirmed);
+        assert_eq!(nodes[2].desyncs, 0);
+
+        // 버퍼에서 밀려난 틱이 필요해진 관전자는 내보내고 경기는 계속한다.
+        run(&network, &mut nodes, 300);
+        assert!(nodes[0].mesh.spectator_feed.next_tick() > SPECTATOR_BUFFER_TICKS as u64 + 1);
+        spectator_peer(&mut nodes[0]).spectator_next = Some(1);
+        run(&network, &mut nodes, 60);
+        assert!(nodes[0].mesh.peers.values().all(|peer| !peer.spectator));
+        assert!(nodes[2].mesh.peers.is_empty());
+        assert!(!nodes[2].mesh.is_spectating());
+        assert_eq!(nodes[0].mesh.peer_ids(), vec![1]);
+        assert_eq!(nodes[1].mesh.peer_ids(), vec![0]);
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::VecDeque;
+
+// RFC 6298 의 RTO 계산과 같은 가중치
+const RTT_ALPHA: f64 = 1.0 / 8.0;
+const RTT_BETA: f64 = 1.0 / 4.0;
+// min/max 를 구하는 샘플 수
+const RTT_WINDOW: usize = 32;
+// 손실률을 구하는 최근 Ping 수
+const LOSS_WINDOW: usize = 32;
+// 이 시간 안에 Pong 이 안 오면 잃어버린 것으로 본다.
+pub const PING_TIMEOUT_MS: u64 = 2000;
+// RTT 를 재기 전의 재전송 타이머 (ms)
+const INITIAL_RTO_MS: u64 = 1000;
+const MIN_RTO_MS: u64 = 50;
+
+// Ping/Pong 으로 잰 RTT 를 평활화한 통계. 게임 로직과 GUI 는 여기 값을 쓴다.
+pub struct NetStats {
+    srtt: Option<f64>,
+    rttvar: f64,
+    rtts: VecDeque<u64>,
+    // 최근 Ping 들이 응답을 받았는지
+    outcomes: VecDeque<bool>,
+    pub last_rtt: u64,
+}
+
+impl NetStats {
+    pub fn new() -> Self {
+        Self {
+            srtt: None,
+            rttvar: 0.0,
+            rtts: VecDeque::new(),
+            outcomes: VecDeque::new(),
+            last_rtt: 0,
+        }
+    }
+
+    pub fn on_pong(&mut self, rtt: u64) {
+        let sample = rtt as f64;
+        match self.srtt {
+            None => {
+                self.srtt = Some(sample);
+                self.rttvar = sample / 2.0;
+            }
+            Some(srtt) => {
+                self.rttvar = (1.0 - RTT_BETA) * self.rttvar + RTT_BETA * (srtt - sample).abs();
+                self.srtt = Some((1.0 - RTT_ALPHA) * srtt + RTT_ALPHA * sample);
+            }
+        }
+
+        self.last_rtt = rtt;
+        self.rtts.push_back(rtt);
+        while self.rtts.len() > RTT_WINDOW {
+            self.rtts.pop_front();
+        }
+        self.push_outcome(true);
+    }
+
+    pub fn on_ping_lost(&mut self) {
+        self.push_outcome(false);
+    }
+
+    fn push_outcome(&mut self, answered: bool) {
+        self.outcomes.push_back(answered);
+        while self.outcomes.len() > LOSS_WINDOW {
+            self.outcomes.pop_front();
+        }
+    }
+
+    // 평활화한 RTT (ms)
+    pub fn srtt(&self) -> u64 {
+        self.srtt.map_or(0, |srtt| srtt.round() as u64)
+    }
+
+    // RTT 변동폭 (ms)
+    pub fn jitter(&self) -> u64 {
+        self.rttvar.round() as u64
+    }
+
+    // 재전송 타이머 (ms). srtt + 4 * rttvar
+    pub fn rto(&self) -> u64 {
+        match self.srtt {
+            None => INITIAL_RTO_MS,
+            Some(srtt) => ((srtt + 4.0 * self.rttvar).round() as u64).max(MIN_RTO_MS),
+        }
+    }
+
+    pub fn min_rtt(&self) -> u64 {
+        self.rtts.iter().copied().min().unwrap_or(0)
+    }
+
+    pub fn max_rtt(&self) -> u64 {
+        self.rtts.iter().copied().max().unwrap_or(0)
+    }
+
+    // 0.0 ~ 1.0
+    pub fn loss_rate(&self) -> f64 {
+        if self.outcomes.is_empty() {
+            return 0.0;
+        }
+        let lost = self.outcomes.iter().filter(|answered| !**answered).count();
+        lost as f64 / self.outcomes.len() as f64
+    }
+}
+
+impl Default for NetStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_from_the_first_sample() {
+        let mut stats = NetStats::new();
+        assert_eq!(stats.rto(), INITIAL_RTO_MS);
+        assert_eq!((stats.srtt(), stats.jitter()), (0, 0));
+        stats.on_pong(100);
+        assert_eq!(stats.srtt(), 100);
+        assert_eq!(stats.jitter(), 50);
+        assert_eq!(stats.rto(), 300);
+        assert_eq!(stats.last_rtt, 100);
+    }
+
+    #[test]
+    fn smooths_with_rfc6298_weights() {
+        let mut stats = NetStats::new();
+        stats.on_pong(100);
+        stats.on_pong(180);
+        // rttvar = 3/4 * 50 + 1/4 * |100 - 180|, srtt = 7/8 * 100 + 1/8 * 180
+        assert_eq!(stats.jitter(), 58);
+        assert_eq!(stats.srtt(), 110);
+        // 반올림은 합친 뒤에 한다. 110 + 4 * 57.5
+        assert_eq!(stats.rto(), 340);
+        assert_eq!(stats.last_rtt, 180);
+    }
+
+    #[test]
+    fn converges_to_a_steady_rtt() {
+        let mut stats = NetStats::new();
+        stats.on_pong(300);
+        for _ in 0..200 {
+            stats.on_pong(40);
+        }
+        assert_eq!(stats.srtt(), 40);
+        assert_eq!(stats.jitter(), 0);
+        assert_eq!(stats.rto(), MIN_RTO_MS);
+    }
+
+    #[test]
+    fn tracks_min_and_max_over_a_window() {
+        let mut stats = NetStats::new();
+        stats.on_pong(500);
+        stats.on_pong(5);
+        for rtt in 0..RTT_WINDOW as u64 - 2 {
+            stats.on_pong(50 + rtt % 10);
+        }
+        assert_eq!((stats.min_rtt(), stats.max_rtt()), (5, 500));
+        stats.on_pong(60);
+        assert_eq!(stats.max_rtt(), 60);
+        stats.on_pong(60);
+        assert_eq!(stats.min_rtt(), 50);
+    }
+
+    #[test]
+    fn measures_loss_over_recent_pings() {
+        let mut stats = NetStats::new();
+        assert_eq!(stats.loss_rate(), 0.0);
+        stats.on_pong(50);
+        stats.on_ping_lost();
+        stats.on_ping_lost();
+        stats.on_pong(50);
+        assert_eq!(stats.loss_rate(), 0.5);
+        // 오래된 결과는 창 밖으로 밀려난다.
+        for _ in 0..LOSS_WINDOW {
+            stats.on_ping_lost();
+        }
+        assert_eq!(stats.loss_rate(), 1.0);
+        for _ in 0..LOSS_WINDOW / 4 {
+            stats.on_pong(50);
+        }
+        assert_eq!(stats.loss_rate(), 0.75);
+    }
+}
@@ -0,0 +1,392 @@
+// 나쁜 네트워크를 재현하기 위한 시뮬레이션 링크.
+// 지연, 손실, 연속 손실, 순서 바뀜, 중복을 시드로 정해지는 난수로 만든다.
+// 같은 시드로 같은 순서로 보내면 매번 똑같이 도착한다.
+// 주소 앞에 NAT 를 두어 구멍 뚫기도 시험할 수 있다.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::time;
+use crate::transport::{Datagram, PeerId, PeerTable, Transport};
+
+// SplitMix64. 플랫폼과 상관없이 같은 시드면 같은 값이 나온다.
+pub struct SimRng(u64);
+
+impl SimRng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && self.next_f64() < p
+    }
+
+    // [min, max]
+    pub fn range(&mut self, min: u64, max: u64) -> u64 {
+        if max <= min {
+            return min;
+        }
+        min + self.next_u64() % (max - min + 1)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Latency {
+    Fixed(u64),
+    Uniform { min: u64, max: u64 },
+    // 균등분포 12개의 합으로 근사한다. libm 에 기대지 않아서 어디서나 같은 값이 나온다.
+    Normal { mean: u64, std_dev: u64 },
+}
+
+// Gilbert-Elliott 모델. 좋은 상태에서 enter 확률로 나쁜 상태가 되고, 나쁜 상태에서는 loss 로 잃어버린다.
+#[derive(Clone, Debug)]
+pub struct BurstLoss {
+    pub enter: f64,
+    pub exit: f64,
+    pub loss: f64,
+}
+
+// 한 방향 링크의 설정
+#[derive(Clone, Debug)]
+pub struct LinkConfig {
+    pub latency: Latency,
+    pub loss: f64,
+    pub burst: Option<BurstLoss>,
+    // 이 확률로 reorder_delay_ms 만큼 더 늦게 도착해서 뒤에 보낸 것에 추월당한다.
+    pub reorder: f64,
+    pub reorder_delay_ms: u64,
+    pub duplicate: f64,
+}
+
+impl LinkConfig {
+    // 지연도 손실도 없는 링크
+    pub fn ideal() -> Self {
+        Self {
+            latency: Latency::Fixed(0),
+            loss: 0.0,
+            burst: None,
+            reorder: 0.0,
+            reorder_delay_ms: 0,
+            duplicate: 0.0,
+        }
+    }
+}
+
+// 한 방향 링크의 상태. 보낼 때마다 도착까지 걸릴 시간을 정한다.
+pub struct Link {
+    config: LinkConfig,
+    rng: SimRng,
+    in_burst: bool,
+}
+
+impl Link {
+    pub fn new(config: LinkConfig, seed: u64) -> Self {
+        Self {
+            config,
+            rng: SimRng::new(seed),
+            in_burst: false,
+        }
+    }
+
+    // 비어 있으면 잃어버렸고, 두 개면 복제되었다.
+    pub fn delays(&mut self) -> Vec<u64> {
+        let mut loss = self.config.loss;
+        if let Some(burst) = self.config.burst.as_ref() {
+            let switch = if self.in_burst { burst.exit } else { burst.enter };
+            if self.rng.chance(switch) {
+                self.in_burst = !self.in_burst;
+            }
+            if self.in_burst {
+                loss = burst.loss;
+            }
+        }
+        if self.rng.chance(loss) {
+            return Vec::new();
+        }
+        let mut delays = vec![self.delay()];
+        if self.rng.chance(self.config.duplicate) {
+            delays.push(self.delay());
+        }
+        delays
+    }
+
+    fn delay(&mut self) -> u64 {
+        let latency = match self.config.latency {
+            Latency::Fixed(ms) => ms,
+            Latency::Uniform { min, max } => self.rng.range(min, max),
+            Latency::Normal { mean, std_dev } => {
+                let sum: f64 = (0..12).map(|_| self.rng.next_f64()).sum();
+                (mean as f64 + (sum - 6.0) * std_dev as f64).max(0.0) as u64
+            }
+        };
+        if self.rng.chance(self.config.reorder) {
+            latency + self.config.reorder_delay_ms
+        } else {
+            latency
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NatKind {
+    // 목적지와 상관없이 같은 공인 주소를 쓰고, 그 주소로 먼저 보낸 적 있는 곳에서 온 것만 들여보낸다.
+    PortRestricted,
+    // 목적지마다 새 공인 포트를 쓴다. 중개 서버가 본 주소로는 구멍을 뚫을 수 없다.
+    Symmetric,
+}
+
+struct Nat {
+    kind: NatKind,
+    public: SocketAddr,
+    // 목적지 -> 그쪽으로 보낼 때 쓰는 공인 주소
+    mappings: HashMap<SocketAddr, SocketAddr>,
+    // (공인 주소, 상대) 로 나간 적이 있는 쌍. 이 쌍으로 들어오는 것만 통과한다.
+    opened: HashSet<(SocketAddr, SocketAddr)>,
+}
+
+impl Nat {
+    fn outbound(&mut self, to: SocketAddr) -> SocketAddr {
+        let public = match self.kind {
+            NatKind::PortRestricted => self.public,
+            NatKind::Symmetric => {
+                let next = SocketAddr::new(self.public.ip(), self.public.port() + self.mappings.len() as u16);
+                *self.mappings.entry(to).or_insert(next)
+            }
+        };
+        self.opened.insert((public, to));
+        public
+    }
+}
+
+struct SimState {
+    now: u64,
+    rng: SimRng,
+    default_link: LinkConfig,
+    // (보낸 쪽, 받는 쪽) 별 링크. 처음 보낼 때 만든다.
+    links: HashMap<(SocketAddr, SocketAddr), Link>,
+    // (도착 시각, 보낸 순서) -> (보낸 쪽, 받는 쪽, 데이터)
+    in_flight: BTreeMap<(u64, u64), (SocketAddr, SocketAddr, Vec<u8>)>,
+    sent: u64,
+    // 사설 주소 -> 그 앞의 NAT
+    nats: HashMap<SocketAddr, Nat>,
+    // NAT 가 쓰는 공인 주소 -> 사설 주소
+    nat_hosts: HashMap<SocketAddr, SocketAddr>,
+    // NAT 가 막은 데이터그램 수
+    nat_dropped: u64,
+}
+
+// 한 프로세스 안의 가짜 네트워크. 시각은 advance 로만 흐른다.
+#[derive(Clone)]
+pub struct SimNetwork(Arc<Mutex<SimState>>);
+
+impl SimNetwork {
+    pub fn new(seed: u64, default_link: LinkConfig) -> Self {
+        Self(Arc::new(Mutex::new(SimState {
+            now: 0,
+            rng: SimRng::new(seed),
+            default_link,
+            links: HashMap::new(),
+            in_flight: BTreeMap::new(),
+            sent: 0,
+            nats: HashMap::new(),
+            nat_hosts: HashMap::new(),
+            nat_dropped: 0,
+        })))
+    }
+
+    // private 에서 나가는 것은 public 에서 보낸 것으로 바뀐다. 밖에서는 public 으로 보내야 닿는다.
+    pub fn add_nat(&self, private: SocketAddr, public: SocketAddr, kind: NatKind) {
+        let mut state = self.0.lock().unwrap();
+        state.nats.insert(
+            private,
+            Nat {
+                kind,
+                public,
+                mappings: HashMap::new(),
+                opened: HashSet::new(),
+            },
+        );
+        state.nat_hosts.insert(public, private);
+    }
+
+    pub fn nat_dropped(&self) -> u64 {
+        self.0.lock().unwrap().nat_dropped
+    }
+
+    // from -> to 한 방향에만 적용한다.
+    pub fn set_link(&self, from: SocketAddr, to: SocketAddr, config: LinkConfig) {
+        let mut state = self.0.lock().unwrap();
+        let seed = state.rng.next_u64();
+        state.links.insert((from, to), Link::new(config, seed));
+    }
+
+    pub fn endpoint(&self, addr: SocketAddr) -> SimTransport {
+        SimTransport {
+            network: self.clone(),
+            addr,
+            peers: PeerTable::new(),
+        }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.0.lock().unwrap().now
+    }
+
+    pub fn advance(&self, ms: u64) {
+        self.0.lock().unwrap().now += ms;
+    }
+
+    fn send(&self, from: SocketAddr, to: SocketAddr, bytes: &[u8]) {
+        let mut state = self.0.lock().unwrap();
+        if !state.links.contains_key(&(from, to)) {
+            let seed = state.rng.next_u64();
+            let config = state.default_link.clone();
+            state.links.insert((from, to), Link::new(config, seed));
+        }
+        let delays = state.links.get_mut(&(from, to)).unwrap().delays();
+        let source = match state.nats.get_mut(&from) {
+            Some(nat) => {
+                let public = nat.outbound(to);
+                state.nat_hosts.insert(public, from);
+                public
+            }
+            None => from,
+        };
+        for delay in delays {
+            let key = (state.now + delay, state.sent);
+            state.sent += 1;
+            state.in_flight.insert(key, (source, to, bytes.to_vec()));
+        }
+    }
+
+    // NAT 뒤의 주소면 도착하는 시각에 NAT 가 열려 있는 것만 받는다.
+    fn receive(&self, addr: SocketAddr) -> Vec<(SocketAddr, u64, Vec<u8>)> {
+        let mut state = self.0.lock().unwrap();
+        let due: Vec<(u64, u64)> = state
+            .in_flight
+            .range(..(state.now + 1, 0))
+            .filter(|(_, datagram)| datagram.1 == addr || state.nat_hosts.get(&datagram.1) == Some(&addr))
+            .map(|(key, _)| *key)
+            .collect();
+        let mut received = Vec::new();
+        for key in due {
+            let (from, to, bytes) = state.in_flight.remove(&key).unwrap();
+            let allowed = match state.nats.get(&addr) {
+                Some(nat) => nat.opened.contains(&(to, from)),
+                None => true,
+            };
+            if allowed {
+                received.push((from, key.0, bytes));
+            } else {
+                state.nat_dropped += 1;
+            }
+        }
+        received
+    }
+}
+
+// 가짜 네트워크의 한 주소. 주소는 UDP 처럼 "ip:port" 로 쓴다.
+pub struct SimTransport {
+    network: SimNetwork,
+    addr: SocketAddr,
+    peers: PeerTable<SocketAddr>,
+}
+
+impl Transport for SimTransport {
+    fn resolve(&mut self, endpoint: &str) -> Option<PeerId> {
+        endpoint.parse().ok().map(|addr| self.peers.id(&addr))
+    }
+
+    fn endpoint(&self, peer: PeerId) -> Option<String> {
+        self.peers.addr(peer).map(|addr| addr.to_string())
+    }
+
+    fn send(&mut self, peer: PeerId, bytes: &[u8]) {
+        if let Some(addr) = self.peers.addr(peer) {
+            self.network.send(self.addr, *addr, bytes);
+        }
+    }
+
+    fn poll(&mut self) -> Vec<Datagram> {
+        let received = self.network.receive(self.addr);
+        received
+            .into_iter()
+            .map(|(from, received_at, bytes)| (self.peers.id(&from), received_at, bytes))
+            .collect()
+    }
+}
+
+// 실제 전송 앞에 링크를 끼워서 게임 안에서 나쁜 네트워크를 흉내 낸다. 보내는 쪽에만 적용된다.
+pub struct ConditionedTransport<T: Transport> {
+    inner: T,
+    link: Link,
+    queue: BTreeMap<(u64, u64), (PeerId, Vec<u8>)>,
+    sent: u64,
+}
+
+impl<T: Transport> ConditionedTransport<T> {
+    pub fn new(inner: T, config: LinkConfig, seed: u64) -> Self {
+        Self {
+            inner,
+            link: Link::new(config, seed),
+            queue: BTreeMap::new(),
+            sent: 0,
+        }
+    }
+
+    fn flush(&mut self, now: u64) {
+        while let Some(entry) = self.queue.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let (peer, bytes) = entry.remove();
+            self.inner.send(peer, &bytes);
+        }
+    }
+}
+
+impl<T: Transport> Transport for ConditionedTransport<T> {
+    fn resolve(&mut self, endpoint: &str) -> Option<PeerId> {
+        self.inner.resolve(endpoint)
+    }
+
+    fn endpoint(&self, peer: PeerId) -> Option<String> {
+        self.inner.endpoint(peer)
+    }
+
+    fn send(&mut self, peer: PeerId, bytes: &[u8]) {
+        let now = time::monotonic_ms();
+        for delay in self.link.delays() {
+            self.queue.insert((now + delay, self.sent), (peer, bytes.to_vec()));
+            self.sent += 1;
+        }
+        self.flush(now);
+    }
+
+    fn poll(&mut self) -> Vec<Datagram> {
+        self.flush(time::monotonic_ms());
+        self.inner.poll()
+    }
+
+    fn relay_peers(&mut self) -> Vec<PeerId> {
+        self.inner.relay_peers()
+    }
+
+    fn last_error(&mut self) -> Option<String> {
+        self.inner.last_error()
+    }
+}
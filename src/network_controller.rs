@@ -1,277 +1,690 @@
-use std::clone;
-use std::collections::HashMap;
-use std::io::ErrorKind;
-use std::net::SocketAddr;
-use std::ops::Deref;
-use std::sync::Mutex;
-use std::sync::MutexGuard;
+use std::io;
 
 use godot::engine::INode2D;
+use godot::engine::ProjectSettings;
 use godot::engine::Node2D;
 use godot::engine::RandomNumberGenerator;
 use godot::prelude::*;
 
-use crate::game_manager::GameTick;
+use crate::connection::ConnectionState;
+use crate::desync::dump_file_name;
+use crate::net_stats::NetStats;
+use crate::game_manager::{GAME_TICK, SESSION};
 use crate::gui_player_state::GUIPlayerState;
+use crate::mesh::{Mesh, MeshEvent};
 use crate::player::Player;
+use crate::replay::Replay;
+use crate::secure::{relay_room_from_code, rendezvous_room_from_code};
 use crate::time;
-use crate::udp_net;
-use crate::udp_net::Connect;
-use crate::udp_net::InputOKPacket;
-use crate::udp_net::{send_bytes, unpack, PacketType, Ping, Pong};
-
-use lazy_static::lazy_static;
-
-lazy_static! {
-    pub static ref RAW_PACKETS: Mutex<HashMap<SocketAddr, Vec<Vec<u8>>>> =
-        Mutex::new(HashMap::new());
+use crate::transport::{Transport, UdpTransport};
+use crate::relay::RelayTransport;
+use crate::lobby::{LobbyClient, LOBBY_HOST_ID};
+use crate::netsim::{ConditionedTransport, Latency, LinkConfig};
+use crate::udp_net::{MatchAction, MAX_PLAYERS};
+
+// 켜면 Connect 로 키를 교환하고 이후 트래픽을 암호화한다. 양쪽이 같아야 연결된다.
+const SECURE_SETTING: &str = "application/netcode/secure";
+// 비어 있지 않으면 키 교환에 섞는 방 코드
+const ROOM_CODE_SETTING: &str = "application/netcode/room_code";
+// 호스트는 이 인원이 모두 준비되면 바로 시작한다. 그 전에는 start_match 로 시작할 수 있다.
+const MAX_PLAYERS_SETTING: &str = "application/netcode/max_players";
+// 켜면 경기가 끝날 때 user://replays 에 리플레이를 남긴다. 기본은 꺼져 있다.
+const SAVE_REPLAYS_SETTING: &str = "application/netcode/save_replays";
+
+// 비어 있지 않고 방 코드가 있으면 이 중계 서버 (host:port) 의 방에 들어가 둔다.
+const RELAY_SERVER_SETTING: &str = "application/netcode/relay_server";
+// 비어 있지 않고 방 코드가 있으면 이 중개 서버 (host:port) 에 등록해서 NAT 에 구멍을 뚫는다.
+const RENDEZVOUS_SERVER_SETTING: &str = "application/netcode/rendezvous_server";
+// 켜면 보내는 데이터그램을 아래 설정대로 늦추고 잃어버린다. 나쁜 네트워크를 재현할 때만 쓴다.
+const SIMULATE_LINK_SETTING: &str = "application/netcode/simulate_link";
+const SIM_LATENCY_SETTING: &str = "application/netcode/sim_latency_ms";
+const SIM_JITTER_SETTING: &str = "application/netcode/sim_jitter_ms";
+const SIM_LOSS_SETTING: &str = "application/netcode/sim_loss";
+const SIM_DUPLICATE_SETTING: &str = "application/netcode/sim_duplicate";
+const SIM_REORDER_SETTING: &str = "application/netcode/sim_reorder";
+const SIM_SEED_SETTING: &str = "application/netcode/sim_seed";
+
+fn simulated_link(settings: &Gd<ProjectSettings>) -> Option<(LinkConfig, u64)> {
+    let enabled = settings.has_setting(SIMULATE_LINK_SETTING.into())
+        && settings.get_setting(SIMULATE_LINK_SETTING.into()).to::<bool>();
+    if !enabled {
+        return None;
+    }
+    let get = |name: &str| {
+        if settings.has_setting(name.into()) {
+            settings.get_setting(name.into()).to::<f64>()
+        } else {
+            0.0
+        }
+    };
+    let latency = get(SIM_LATENCY_SETTING).max(0.0) as u64;
+    let jitter = get(SIM_JITTER_SETTING).max(0.0) as u64;
+    let config = LinkConfig {
+        latency: Latency::Uniform {
+            min: latency.saturating_sub(jitter),
+            max: latency + jitter,
+        },
+        loss: get(SIM_LOSS_SETTING),
+        burst: None,
+        reorder: get(SIM_REORDER_SETTING),
+        reorder_delay_ms: latency.max(50),
+        duplicate: get(SIM_DUPLICATE_SETTING),
+    };
+    Some((config, get(SIM_SEED_SETTING) as u64))
 }
 
-pub struct NetData {
-    pub socket: Option<std::net::UdpSocket>,
-    pub network_latency: u64,
-    pub other_peer_endpoint: Option<String>,
-    pub my_port: i32,
+// 경기 기록을 path 에 쓴다.
+fn write_replay(replay: &Replay, path: &str) -> bool {
+    if replay.inputs.is_empty() {
+        return false;
+    }
+    let path = ProjectSettings::singleton().globalize_path(path.into()).to_string();
+    if let Some(dir) = std::path::Path::new(&path).parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    match std::fs::write(&path, replay.to_bytes()) {
+        Ok(()) => {
+            godot_print!("Replay saved to {} ({} ticks)", path, replay.inputs.len());
+            true
+        }
+        Err(err) => {
+            godot_print!("Failed to save replay {} : {}", path, err);
+            false
+        }
+    }
 }
 
+// 연결, 입력, 롤백은 Mesh 가 하고 여기서는 그 결과로 노드를 만들고 시그널을 보낸다.
 #[derive(GodotClass)]
 #[class(base=Node2D)]
 pub struct NetworkController {
-    pub net: Option<NetData>,
-    ids: HashMap<u8, u64>,
-    time2ping: Option<u64>,
-    ping_counter: u8,
-    thread: Option<std::thread::JoinHandle<()>>,
-    pub send_buffer: Vec<Vec<u8>>,
+    // ready 에서 전송을 열면 만들어진다.
+    mesh: Option<Mesh>,
+    pub my_port: i32,
+    save_replays: bool,
+    // 시작하기 전까지 들어가 있는 로비의 방. 시작하면 P2P 로 넘어가고 닫는다.
+    lobby: Option<LobbyClient>,
+    lobby_revision: u64,
     base: Base<Node2D>,
 }
 
 impl NetworkController {
-    pub fn get_socket(&self) -> Option<&std::net::UdpSocket> {
-        self.net.as_ref().unwrap().socket.as_ref()
+    pub fn mesh(&self) -> &Mesh {
+        self.mesh.as_ref().unwrap()
+    }
+
+    pub fn mesh_mut(&mut self) -> &mut Mesh {
+        self.mesh.as_mut().unwrap()
+    }
+
+    pub fn transport(&mut self) -> &mut dyn Transport {
+        self.mesh_mut().transport()
+    }
+
+    // 다른 전송으로 바꾼다. 방에 있는 동안에는 바꿀 수 없다.
+    pub fn set_transport(&mut self, transport: Box<dyn Transport>) -> bool {
+        let changed = self.mesh.as_mut().map_or(false, |mesh| mesh.set_transport(transport));
+        self.handle_events();
+        changed
+    }
+
+    // GameTick 이 프레임마다 부른다.
+    pub fn advance_ticks(&mut self) {
+        if let Some(mesh) = self.mesh.as_mut() {
+            mesh.advance(time::monotonic_ms());
+        }
+        self.handle_events();
+    }
+
+    // 로비에 알려 줄 P2P 주소. 중개 서버가 알려 준 주소가 없으면 포트만 주고 IP 는 로비 서버가 채운다.
+    fn lobby_endpoint(&self) -> String {
+        match self.mesh.as_ref().and_then(|mesh| mesh.public_endpoint()) {
+            Some(endpoint) => endpoint.to_string(),
+            None => format!(":{}", self.my_port),
+        }
     }
 
-    pub fn start_send_process(&mut self) {
-        let net_data = self.net.as_ref().unwrap();
-        net_data.other_peer_endpoint.is_some().then(|| {
-            if self.send_buffer.is_empty() {
-                return;
+    // 로비에 있을 때만 보낸다. 실패는 로그만 남긴다.
+    fn send_to_lobby(&mut self, send: impl FnOnce(&mut LobbyClient) -> io::Result<()>) {
+        if let Some(lobby) = self.lobby.as_mut() {
+            if let Err(err) = send(lobby) {
+                godot_print!("Failed to send to lobby : {}", err);
             }
-            let pks: Vec<_> = self
-                .send_buffer
-                .iter()
-                .flat_map(|v| v.iter())
-                .cloned()
-                .collect();
-            send_bytes(
-                net_data.socket.as_ref(),
-                pks.as_slice(),
-                net_data.other_peer_endpoint.as_ref().unwrap().as_str(),
-            );
-            self.send_buffer.clear();
-        });
-    }
-
-    pub fn send_to(&self, packet: &[u8], endpoint: &str) {
-        let net_data = self.net.as_ref().unwrap();
-        send_bytes(net_data.socket.as_ref(), packet, endpoint);
+        }
     }
-}
 
-#[godot_api]
-impl INode2D for NetworkController {
-    fn init(base: Base<Node2D>) -> Self {
-        Self {
-            net: None,
-            ids: HashMap::new(),
-            time2ping: None,
-            ping_counter: 0,
-            thread: None,
-            send_buffer: Vec::new(),
-            base,
+    // 방이 바뀌었으면 알리고, 호스트가 시작했으면 로비를 닫고 P2P 로 넘어간다.
+    fn poll_lobby(&mut self) {
+        self.send_to_lobby(LobbyClient::poll);
+        let Some(lobby) = self.lobby.as_mut() else {
+            return;
+        };
+        let revision = lobby.revision();
+        let started = lobby.started();
+        let closed = lobby.closed();
+        let is_host = lobby.is_host();
+        let players = lobby.members().len();
+        if revision != self.lobby_revision {
+            self.lobby_revision = revision;
+            self.base_mut().emit_signal("lobby_changed".into(), &[]);
+        }
+        if let Some(host) = started {
+            self.lobby = None;
+            if is_host {
+                // 로비에 있던 사람이 모두 들어와 준비되면 시작한다.
+                self.mesh_mut().set_max_players(players);
+                godot_print!("Lobby started, waiting for {} players", self.mesh().max_players());
+            } else {
+                godot_print!("Lobby started, connecting to host {}", host);
+                let pos = self.local_position();
+                self.send_connect(&host, pos);
+            }
+            return;
+        }
+        if let Some(reason) = closed {
+            godot_print!("Lobby closed : {}", reason);
+            self.lobby = None;
+            self.base_mut()
+                .emit_signal("lobby_closed".into(), &[GString::from(reason).to_variant()]);
         }
     }
 
-    fn ready(&mut self) {
-        let rand = Gd::<RandomNumberGenerator>::default();
-        let port = 5000 + (rand.clone().randi_range(50000, 60000));
-        let socket = udp_net::start_udp(port as u16).expect("Failed to start UDP");
-        let socket_for_thread = socket.try_clone();
-        self.net = Some(NetData {
-            socket: Some(socket),
-            network_latency: 0,
-            other_peer_endpoint: None,
-            my_port: port,
-        });
-
-        self.thread = Some(std::thread::spawn(move || {
-            let socket = socket_for_thread.unwrap();
-            let mut buffer = [0; 1024];
-            loop {
-                let result = socket.recv_from(&mut buffer);
-                match result {
-                    Ok((size, addr)) => {
-                        let mut raw_packets = RAW_PACKETS.lock().unwrap();
-                        if !(*raw_packets).contains_key(&addr) {
-                            (*raw_packets).insert(addr, Vec::new());
-                        }
-                        let mut i = 0;
-                        while i < size {
-                            let pkt_size = buffer[i] as usize;
-                            if let Some(vec_ref) = (*raw_packets).get_mut(&addr) {
-                                vec_ref.push(buffer[(i + 1)..(i + pkt_size) as usize].to_vec());
-                                i += pkt_size as usize;
+    // 호스트의 주소로 방에 들어간다. 이미 방에 있으면 무시한다.
+    pub fn send_connect(&mut self, endpoint: &str, pos: Vector2) {
+        self.mesh_mut().send_connect(endpoint, (pos.x, pos.y), time::monotonic_ms());
+        self.handle_events();
+    }
+
+    // 중개 서버가 알려 주는 방의 호스트로 들어간다. use_rendezvous 를 먼저 해야 한다.
+    pub fn send_connect_via_rendezvous(&mut self, pos: Vector2) {
+        self.mesh_mut().send_connect_via_rendezvous((pos.x, pos.y));
+        self.handle_events();
+    }
+
+    fn local_position(&self) -> Vector2 {
+        self.base()
+            .get_tree()
+            .unwrap()
+            .get_root()
+            .unwrap()
+            .get_node_as::<Node2D>("Root/Player")
+            .get_position()
+    }
+
+    // 연결된 피어들의 id
+    pub fn peer_ids(&self) -> Vec<u8> {
+        self.mesh.as_ref().map_or(Vec::new(), |mesh| mesh.peer_ids())
+    }
+
+    pub fn local_player_id(&self) -> Option<u8> {
+        self.mesh.as_ref().and_then(|mesh| mesh.local_player_id())
+    }
+
+    // 가장 느린 피어의 통계. 입력 지연은 이 피어에 맞춘다.
+    pub fn worst_stats(&self) -> Option<&NetStats> {
+        self.mesh.as_ref().and_then(|mesh| mesh.worst_stats())
+    }
+
+    // GUI 에 보여 줄 방 상태. 방에 없으면 None
+    pub fn room_status(&self) -> Option<String> {
+        self.mesh.as_ref().and_then(|mesh| mesh.room_status())
+    }
+
+    fn spawn_remote_player(&mut self, id: u8, x: f32, y: f32) {
+        let mut root = self.base().get_node_as::<Node2D>("../");
+        let name = format!("RemotePlayer{}", id);
+        if root.has_node(name.as_str().into()) {
+            return;
+        }
+        if let Ok(scene) = try_load::<PackedScene>("res://Player/player.tscn") {
+            let remote_player = scene.instantiate_as::<Player>();
+            let mut remote = remote_player.clone();
+            remote.bind_mut().id = Some(id);
+
+            root.add_child(remote_player.upcast::<Node>());
+            remote.set_position(Vector2::new(x, y));
+            remote.set_name(name.as_str().into());
+
+            if let Ok(scene) = try_load::<PackedScene>("res://PlayerState.tscn") {
+                let mut player_state = scene.instantiate_as::<GUIPlayerState>();
+                player_state
+                    .bind_mut()
+                    .set_target(remote.clone().upcast::<Node2D>());
+                root.add_child(player_state.upcast::<Node>());
+            }
+        }
+    }
+
+    fn free_remote_player(&mut self, id: u8) {
+        let root = self.base().get_node_as::<Node2D>("../");
+        if let Some(mut remote) = root.try_get_node_as::<Player>(format!("RemotePlayer{}", id).as_str()) {
+            remote.queue_free();
+        }
+    }
+
+    // 체크섬이 어긋나면 상태와 최근 입력을 user://desync 에 남긴다.
+    // 상대도 같은 틱에 어긋남을 보므로 같은 디렉터리에 자기 덤프를 남긴다.
+    fn write_desync_dump(&self, me: i64, player: i64, tick: u64, dump: &str) {
+        let dir = ProjectSettings::singleton()
+            .globalize_path("user://desync".into())
+            .to_string();
+        let path = format!("{}/{}", dir, dump_file_name(tick, me, player));
+        let result = std::path::Path::new(&path)
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&path, dump));
+        match result {
+            Ok(_) => godot_print!("Desync dump written to {}", path),
+            Err(err) => godot_print!("Failed to write desync dump : {}", err),
+        }
+    }
+
+    // Mesh 에서 나온 일들을 노드와 시그널로 옮긴다.
+    fn handle_events(&mut self) {
+        let Some(events) = self.mesh.as_mut().map(|mesh| mesh.take_events()) else {
+            return;
+        };
+        let root_node = self.base().get_tree().unwrap().get_root().unwrap();
+        for event in events {
+            match event {
+                MeshEvent::PlayerJoined { id, x, y } => self.spawn_remote_player(id, x, y),
+                MeshEvent::PlayerLeft(id) => self.free_remote_player(id),
+                MeshEvent::MatchStarted { my_id, players } => {
+                    let mut player = root_node.get_node_as::<Player>("Root/Player");
+                    match my_id {
+                        Some(id) => player.bind_mut().id = Some(id),
+                        // 관전자: 우리 플레이어는 숨기고 모든 플레이어를 받은 입력으로 움직인다.
+                        None => {
+                            for (id, x, y) in players.iter() {
+                                self.spawn_remote_player(*id, *x, *y);
                             }
+                            player.hide();
                         }
-                        drop(raw_packets);
-                    },
-                    Err(ref err) if err.kind() != ErrorKind::WouldBlock => {
-                        godot_print!("Something went wrong: {}", err)
                     }
-                    _ => {}
+                    self.base_mut()
+                        .emit_signal("match_started".into(), &[(players.len() as i64).to_variant()]);
+                }
+                MeshEvent::ConnectionChanged { player, state } => {
+                    let id = player.map_or(-1, |id| id as i64);
+                    self.base_mut().emit_signal(
+                        "connection_state_changed".into(),
+                        &[id.to_variant(), (state as i64).to_variant()],
+                    );
+                }
+                MeshEvent::Chat { player, text } => {
+                    self.base_mut().emit_signal(
+                        "chat_received".into(),
+                        &[(player as i64).to_variant(), GString::from(text).to_variant()],
+                    );
+                }
+                MeshEvent::MatchControl { player, action } => {
+                    self.base_mut().emit_signal(
+                        "match_control".into(),
+                        &[(player as i64).to_variant(), (action as i64).to_variant()],
+                    );
+                }
+                MeshEvent::Desync { me, player, tick, dump } => {
+                    let me = me.map_or(-1, |id| id as i64);
+                    let id = player.map_or(-1, |id| id as i64);
+                    self.write_desync_dump(me, id, tick, &dump);
+                    self.base_mut()
+                        .emit_signal("desync".into(), &[id.to_variant(), (tick as i64).to_variant()]);
                 }
+                MeshEvent::RoomClosed { players, replay } => {
+                    if let (true, Some(replay)) = (self.save_replays, replay.as_ref()) {
+                        let secs = std::time::SystemTime::now()
+                            .duration_since(std::time::UNIX_EPOCH)
+                            .map_or(0, |duration| duration.as_secs());
+                        write_replay(replay, &format!("user://replays/replay_{}_{}.p2pr", secs, self.my_port));
+                    }
+                    for id in players {
+                        self.free_remote_player(id);
+                    }
+                    if let Some(mut player) = root_node.try_get_node_as::<Player>("Root/Player") {
+                        player.bind_mut().reset();
+                        player.show();
+                    }
+                }
+                MeshEvent::Log(text) => godot_print!("{}", text),
             }
-        }));
-
-        godot_print!("Network Controller Ready");
+        }
     }
+}
 
-    fn physics_process(&mut self, _: f64) {
-        let root_node = self.base().get_tree().unwrap().get_root().unwrap();
-        let mut game_tick = root_node.get_node_as::<GameTick>("Root/GameTick");
-        let mut root = self.base().get_node_as::<Node2D>("../");
-        let mut player = root_node.get_node_as::<Player>("Root/Player");
-        let mut other_player = root_node.try_get_node_as::<Player>("Root/OtherPlayer");
+#[godot_api]
+impl NetworkController {
+    #[signal]
+    fn desync(player_id: i64, tick: i64);
+
+    // state 는 ConnectionState 값. player_id 는 아직 모르면 -1
+    #[signal]
+    fn connection_state_changed(player_id: i64, state: i64);
 
-        let net_data = self.net.as_mut().unwrap();
-        let timestamp = time::get_ms_timestamp();
+    #[signal]
+    fn chat_received(player_id: i64, text: GString);
 
-        if net_data.other_peer_endpoint.is_some() {
-            if self.time2ping.is_none() || timestamp - self.time2ping.unwrap() > 1000 {
-                let ping = Ping {
-                    id: self.ping_counter as u8,
-                };
-                self.ids.insert(ping.id, timestamp);
-                let mut packet = udp_net::pack::<Ping>(&ping, PacketType::Ping);
+    // action 은 MatchAction 값
+    #[signal]
+    fn match_control(player_id: i64, action: i64);
 
-                packet.insert(0, (packet.len() + 1) as u8);
-                self.send_buffer.push(packet);
+    #[signal]
+    fn match_started(player_count: i64);
 
-                self.time2ping = Some(timestamp);
-                self.ping_counter = self.ping_counter.wrapping_add(1);
+    // 로비의 사람, 준비 상태, 설정이 바뀌었다.
+    #[signal]
+    fn lobby_changed();
+
+    // 시작하기 전에 로비에서 나가게 되었다.
+    #[signal]
+    fn lobby_closed(reason: GString);
+
+    // 다음 연결부터 적용된다.
+    #[func]
+    pub fn set_secure(&mut self, enabled: bool, room_code: GString) {
+        let room_code = room_code.to_string();
+        let room_code = if room_code.trim().is_empty() { None } else { Some(room_code) };
+        self.mesh_mut().set_secure(enabled, room_code);
+    }
 
-                godot_print!("Sent ping packet : {}", ping.id);
+    // 중계 서버의 방에 들어가서 직접 연결이 안 될 때 쓴다. 방에 있는 동안에는 바꿀 수 없다.
+    // 전송이 바뀌므로 use_rendezvous 보다 먼저 해야 한다.
+    #[func]
+    pub fn use_relay(&mut self, server: GString, room_code: GString) -> bool {
+        let Some(mesh) = self.mesh.as_ref() else {
+            return false;
+        };
+        if mesh.has_relay() || mesh.has_rendezvous() || mesh.in_room() || room_code.to_string().trim().is_empty() {
+            return false;
+        }
+        let room = relay_room_from_code(&room_code.to_string());
+        let relay = match RelayTransport::connect(&server.to_string(), &room) {
+            Ok(relay) => relay,
+            Err(err) => {
+                godot_print!("Failed to connect to relay {} : {}", server, err);
+                return false;
             }
+        };
+        self.mesh = self.mesh.take().map(|mesh| mesh.with_relay(relay, room));
+        godot_print!("Joined relay {}", server);
+        true
+    }
+
+    // 중개 서버에 등록해서 방의 다른 피어들과 구멍을 뚫는다.
+    #[func]
+    pub fn use_rendezvous(&mut self, server: GString, room_code: GString) -> bool {
+        if self.mesh.is_none() || room_code.to_string().trim().is_empty() {
+            return false;
         }
-        
-        let mut raw = RAW_PACKETS.lock().unwrap();
-        
-        let packets = (*raw).clone();
-        (*raw).clear();
-        for (addr, q) in packets {
-            for pkt in q {
-                let buffer = pkt.as_slice();
-                match PacketType::from(buffer[0]) {
-                    PacketType::Ping => {
-                        let (ping, _) = unpack::<Ping>(&buffer[1..]).expect("Failed to unpack");
-                        godot_print!("Ping packet received : {}", ping.id);
-
-                        let pong = Pong { id: ping.id };
-                        let mut packet = udp_net::pack::<Pong>(&pong, PacketType::Pong);
-
-                        packet.insert(0, (packet.len() + 1) as u8);
-                        self.send_buffer.push(packet);
-                    }
-                    PacketType::Pong => {
-                        let (pong, _) = unpack::<Pong>(&buffer[1..]).expect("Failed to unpack");
-                        if self.ids.get(&pong.id).is_none() {
-                            godot_print!("Unknown Pong packet received : {}", pong.id);
-                            godot_print!("IDs : {:?}", self.ids);
-                            return;
-                        }
-                        let latency = time::get_ms_timestamp() - self.ids.get(&pong.id).unwrap();
-                        self.ids.remove(&pong.id);
+        let room = rendezvous_room_from_code(&room_code.to_string());
+        let registered = self.mesh_mut().use_rendezvous(&server.to_string(), &room);
+        self.handle_events();
+        if registered {
+            godot_print!("Registering with rendezvous {}", server);
+        }
+        registered
+    }
 
-                        net_data.network_latency = latency;
+    // 중개 서버가 본 우리 주소. 아직 모르면 빈 문자열
+    #[func]
+    pub fn get_public_endpoint(&self) -> GString {
+        self.mesh
+            .as_ref()
+            .and_then(|mesh| mesh.public_endpoint())
+            .map_or(GString::new(), GString::from)
+    }
 
-                        godot_print!("Pong packet received : {}", pong.id);
-                    }
-                    PacketType::Connect => {
-                        let (connect, _) =
-                            unpack::<Connect>(&buffer[1..]).expect("Failed to unpack");
+    // 로비 서버 (host:port) 에 방을 만든다. 코드는 get_lobby_code 로 알 수 있다.
+    #[func]
+    pub fn create_lobby(&mut self, server: GString, name: GString) -> bool {
+        if self.lobby.is_some() || self.mesh.as_ref().map_or(true, |mesh| mesh.in_room()) {
+            return false;
+        }
+        let endpoint = self.lobby_endpoint();
+        match LobbyClient::create(&server.to_string(), &name.to_string(), &endpoint, Vec::new()) {
+            Ok(lobby) => {
+                self.lobby = Some(lobby);
+                self.lobby_revision = 0;
+                true
+            }
+            Err(err) => {
+                godot_print!("Failed to connect to lobby {} : {}", server, err);
+                false
+            }
+        }
+    }
 
-                        game_tick.bind_mut().game_start_time = connect.game_start_time;
+    #[func]
+    pub fn join_lobby(&mut self, server: GString, code: GString, name: GString) -> bool {
+        if self.lobby.is_some() || self.mesh.as_ref().map_or(true, |mesh| mesh.in_room()) {
+            return false;
+        }
+        let endpoint = self.lobby_endpoint();
+        match LobbyClient::join(&server.to_string(), &code.to_string(), &name.to_string(), &endpoint) {
+            Ok(lobby) => {
+                self.lobby = Some(lobby);
+                self.lobby_revision = 0;
+                true
+            }
+            Err(err) => {
+                godot_print!("Failed to connect to lobby {} : {}", server, err);
+                false
+            }
+        }
+    }
 
-                        if net_data.other_peer_endpoint.is_some() {
-                            return;
-                        }
-                        if let Ok(scene) = try_load::<PackedScene>("res://Player/player.tscn") {
-                            let other_player = scene.instantiate_as::<Player>();
-                            let mut other = other_player.clone();
-
-                            root.add_child(other_player.upcast::<Node>());
-                            other.set_position(Vector2::new(connect.x, connect.y));
-                            other.set_name("OtherPlayer".into());
-
-                            if let Ok(scene) = try_load::<PackedScene>("res://PlayerState.tscn") {
-                                let mut player_state = scene.instantiate_as::<GUIPlayerState>();
-                                player_state
-                                    .bind_mut()
-                                    .set_target(other.clone().upcast::<Node2D>());
-                                root.add_child(player_state.upcast::<Node>());
-                            }
-                        }
+    #[func]
+    pub fn leave_lobby(&mut self) {
+        self.lobby = None;
+    }
 
-                        net_data.other_peer_endpoint = Some(addr.to_string());
-                        godot_print!("Connected to : {}", addr);
-
-                        let pos = player.get_position();
-
-                        let mut packet = udp_net::pack::<Connect>(
-                            &Connect {
-                                x: pos.x,
-                                y: pos.y,
-                                game_start_time: connect.game_start_time,
-                            },
-                            PacketType::Connect,
-                        );
-                        packet.insert(0, (packet.len() + 1) as u8);
-                        self.send_buffer.push(packet);
-                    }
-                    PacketType::Input => {
-                        let (input, _) =
-                            unpack::<udp_net::InputPacket>(&buffer[1..]).expect("Failed to unpack");
-
-                        if let Some(other_player) = other_player.as_mut() {
-                            let mut other_player = other_player.bind_mut();
-                            for i in 0..5 {
-                                other_player.push_input(input.input[i], input.tick[i]);
-                                other_player.push_input_ok(input.tick[i]);
-                            }
-                        }
+    #[func]
+    pub fn set_lobby_ready(&mut self, ready: bool) {
+        self.send_to_lobby(|lobby| lobby.set_ready(ready));
+    }
 
-                        let mut packet = udp_net::pack::<InputOKPacket>(
-                            &InputOKPacket { tick: input.tick },
-                            PacketType::InputOK,
-                        );
-                        packet.insert(0, (packet.len() + 1) as u8);
-                        self.send_buffer.push(packet);
-                    }
-                    PacketType::InputOK => {
-                        let (input_ok, _) =
-                            unpack::<InputOKPacket>(&buffer[1..]).expect("Failed to unpack");
+    // 호스트만 바꿀 수 있다.
+    #[func]
+    pub fn set_lobby_setting(&mut self, key: GString, value: GString) {
+        self.send_to_lobby(|lobby| lobby.set_setting(&key.to_string(), &value.to_string()));
+    }
 
-                        for i in 0..5 {
-                            let mut local_player = player.bind_mut();
-                            local_player.push_input_ok(input_ok.tick[i]);
-                        }
-                    }
-                }
+    // 호스트만 할 수 있다. 모두 준비되어 있어야 시작된다.
+    #[func]
+    pub fn start_lobby(&mut self) {
+        self.send_to_lobby(LobbyClient::start);
+    }
+
+    // 방 코드. 로비에 없거나 아직 모르면 빈 문자열
+    #[func]
+    pub fn get_lobby_code(&self) -> GString {
+        self.lobby
+            .as_ref()
+            .and_then(|lobby| lobby.code())
+            .map_or(GString::new(), GString::from)
+    }
+
+    // 로비 화면에 보여 줄 방 상태. 사람마다 한 줄, 그 뒤로 설정마다 한 줄
+    #[func]
+    pub fn get_lobby_status(&self) -> GString {
+        let Some(lobby) = self.lobby.as_ref() else {
+            return GString::new();
+        };
+        let mut lines = vec![format!("Room {}", lobby.code().unwrap_or_default())];
+        for member in lobby.members() {
+            let host = if member.id == LOBBY_HOST_ID { " (host)" } else { "" };
+            let ready = if member.ready { "ready" } else { "not ready" };
+            lines.push(format!("{}{} : {}", member.name, host, ready));
+        }
+        for (key, value) in lobby.settings() {
+            lines.push(format!("{} = {}", key, value));
+        }
+        GString::from(lines.join("\n"))
+    }
+
+    #[func]
+    pub fn is_secure(&self) -> bool {
+        self.mesh.as_ref().map_or(false, |mesh| mesh.is_secure())
+    }
+
+    #[func]
+    pub fn get_connection_state(&self, player_id: i64) -> i64 {
+        match (self.mesh.as_ref(), u8::try_from(player_id)) {
+            (Some(mesh), Ok(id)) => mesh.connection_state(id) as i64,
+            _ => ConnectionState::Idle as i64,
+        }
+    }
+
+    #[func]
+    pub fn get_local_player_id(&self) -> i64 {
+        self.local_player_id().map_or(-1, |id| id as i64)
+    }
+
+    #[func]
+    pub fn get_player_ids(&self) -> PackedInt64Array {
+        let ids: Vec<i64> = self.peer_ids().iter().map(|id| *id as i64).collect();
+        PackedInt64Array::from(ids.as_slice())
+    }
+
+    // 호스트: 최대 인원이 차기 전에 시작한다. 손님이 모두 준비되어 있어야 한다.
+    #[func]
+    pub fn start_match(&mut self) {
+        self.mesh_mut().start_match(time::monotonic_ms());
+        self.handle_events();
+    }
+
+    // endpoint 의 플레이어가 확정한 입력을 받아 몇 틱 뒤에서 따라간다.
+    #[func]
+    pub fn spectate(&mut self, endpoint: GString) {
+        self.mesh_mut().send_spectate(endpoint.to_string().trim(), time::monotonic_ms());
+        self.handle_events();
+    }
+
+    #[func]
+    pub fn is_spectating(&self) -> bool {
+        self.mesh.as_ref().map_or(false, |mesh| mesh.is_spectating())
+    }
+
+    #[func]
+    pub fn disconnect(&mut self) {
+        self.mesh_mut().disconnect(time::monotonic_ms());
+    }
+
+    #[func]
+    pub fn send_chat(&mut self, text: GString) {
+        self.mesh_mut().send_chat(&text.to_string(), time::monotonic_ms());
+    }
+
+    #[func]
+    pub fn send_match_control(&mut self, action: i64) {
+        match MatchAction::try_from(action as u8) {
+            Ok(action) => self.mesh_mut().send_match_control(action, time::monotonic_ms()),
+            Err(err) => godot_print!("Invalid match action {} : {}", action, err),
+        }
+    }
+
+    #[func]
+    pub fn get_rtt(&self) -> i64 {
+        self.worst_stats().map_or(0, |stats| stats.srtt() as i64)
+    }
+
+    // 지금까지의 경기 기록을 저장한다. save_replays 설정을 켜면 경기가 끝날 때 자동으로도 저장된다.
+    #[func]
+    pub fn save_replay(&mut self, path: GString) -> bool {
+        let Some(mesh) = self.mesh.as_mut() else {
+            return false;
+        };
+        mesh.collect_confirmed_inputs();
+        mesh.replay().map_or(false, |replay| write_replay(replay, &path.to_string()))
+    }
+
+    #[func]
+    pub fn get_jitter(&self) -> i64 {
+        self.worst_stats().map_or(0, |stats| stats.jitter() as i64)
+    }
+
+    #[func]
+    pub fn get_packet_loss(&self) -> f64 {
+        self.worst_stats().map_or(0.0, |stats| stats.loss_rate())
+    }
+}
+
+#[godot_api]
+impl INode2D for NetworkController {
+    fn init(base: Base<Node2D>) -> Self {
+        Self {
+            mesh: None,
+            my_port: 0,
+            save_replays: false,
+            lobby: None,
+            lobby_revision: 0,
+            base,
+        }
+    }
+
+    fn ready(&mut self) {
+        let rand = Gd::<RandomNumberGenerator>::default();
+        let port = 5000 + (rand.clone().randi_range(50000, 60000));
+        let settings = ProjectSettings::singleton();
+        let udp = UdpTransport::bind(port as u16).expect("Failed to start UDP");
+        godot_print!("UDP socket started on port {}", port);
+        let transport: Box<dyn Transport> = match simulated_link(&settings) {
+            Some((config, seed)) => {
+                godot_print!("Simulating link {:?} (seed {})", config, seed);
+                Box::new(ConditionedTransport::new(udp, config, seed))
+            }
+            None => Box::new(udp),
+        };
+        self.mesh = Some(Mesh::with_state(transport, GAME_TICK.clone(), SESSION.clone()));
+        self.my_port = port;
+
+        if settings.has_setting(SECURE_SETTING.into()) {
+            let enabled = settings.get_setting(SECURE_SETTING.into()).to::<bool>();
+            let room_code = if settings.has_setting(ROOM_CODE_SETTING.into()) {
+                settings.get_setting(ROOM_CODE_SETTING.into()).to::<GString>()
+            } else {
+                GString::new()
+            };
+            self.set_secure(enabled, room_code);
+        }
+        if settings.has_setting(MAX_PLAYERS_SETTING.into()) {
+            let max_players = settings.get_setting(MAX_PLAYERS_SETTING.into()).to::<i64>();
+            self.mesh_mut().set_max_players((max_players.max(2) as usize).min(MAX_PLAYERS));
+        }
+        if settings.has_setting(SAVE_REPLAYS_SETTING.into()) {
+            self.save_replays = settings.get_setting(SAVE_REPLAYS_SETTING.into()).to::<bool>();
+        }
+        let room_code = self.mesh().room_code().map(GString::from);
+        if settings.has_setting(RELAY_SERVER_SETTING.into()) {
+            let server = settings.get_setting(RELAY_SERVER_SETTING.into()).to::<GString>();
+            if let (false, Some(room_code)) = (server.is_empty(), room_code.clone()) {
+                self.use_relay(server, room_code);
+            }
+        }
+        if settings.has_setting(RENDEZVOUS_SERVER_SETTING.into()) {
+            let server = settings.get_setting(RENDEZVOUS_SERVER_SETTING.into()).to::<GString>();
+            if let (false, Some(room_code)) = (server.is_empty(), room_code) {
+                self.use_rendezvous(server, room_code);
             }
         }
+
+        godot_print!("Network Controller Ready");
+    }
+
+    fn physics_process(&mut self, _: f64) {
+        if self.mesh.is_none() {
+            return;
+        }
+        let pos = self.local_position();
+        let mesh = self.mesh_mut();
+        mesh.set_position(pos.x, pos.y);
+        mesh.update(time::monotonic_ms());
+        self.poll_lobby();
+        self.handle_events();
     }
 
     fn process(&mut self, _: f64) {
-        self.start_send_process();
+        if let Some(mesh) = self.mesh.as_mut() {
+            mesh.flush();
+        }
     }
 }
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+use crate::clock_sync::{ClockSample, ClockSync};
+use crate::connection::Connection;
+use crate::desync::DesyncDetector;
+use crate::input_window::InputReceiveWindow;
+use crate::net_stats::{NetStats, PING_TIMEOUT_MS};
+use crate::reliable::ChannelEndpoint;
+use crate::session::Session;
+use crate::transport::PeerId;
+use crate::udp_net::{Ping, Pong};
+
+// 메시 안의 다른 피어 하나. 연결, 세션, 채널, 통계를 피어마다 따로 둔다.
+pub struct Peer {
+    // 전송이 정한 상대 번호. UDP 라면 주소 하나에 번호 하나다.
+    pub addr: PeerId,
+    // 답장을 받기 전까지는 모른다.
+    pub player_id: Option<u8>,
+    pub session: Session,
+    pub connection: Connection,
+    pub channel: ChannelEndpoint,
+    pub stats: NetStats,
+    pub clock: ClockSync,
+    pub desync: DesyncDetector,
+    // 이 피어가 보낸 입력 중 받은 틱. ack 를 만든다.
+    pub input_window: InputReceiveWindow,
+    // Connect 로 받은 시작 위치
+    pub position: (f32, f32),
+    // Ping 으로 계산한, 우리가 이 피어보다 앞선 틱 수
+    pub advantage: Option<i64>,
+    // 호스트: 이 손님이 다른 피어 모두와 연결되고 시계를 맞췄다.
+    pub ready: bool,
+    // 우리 입력을 보고만 가는 관전자. player_id 가 없다.
+    pub spectator: bool,
+    // 관전자에게 다음에 보낼 틱. StartMatch 를 보내기 전에는 None
+    pub spectator_next: Option<u64>,
+    // 다음 process 에 보낼 프레임
+    pub send_buffer: Vec<Vec<u8>>,
+    // Ping id 별 보낸 시각
+    pings: HashMap<u8, u64>,
+    ping_counter: u8,
+    last_ping: Option<u64>,
+}
+
+impl Peer {
+    pub fn new(addr: PeerId, session: Session) -> Self {
+        Self {
+            addr,
+            player_id: None,
+            session,
+            connection: Connection::new(),
+            channel: ChannelEndpoint::new(),
+            stats: NetStats::new(),
+            clock: ClockSync::new(),
+            desync: DesyncDetector::new(),
+            input_window: InputReceiveWindow::new(),
+            position: (0.0, 0.0),
+            advantage: None,
+            ready: false,
+            spectator: false,
+            spectator_next: None,
+            send_buffer: Vec::new(),
+            pings: HashMap::new(),
+            ping_counter: 0,
+            last_ping: None,
+        }
+    }
+
+    // 응답이 없는 Ping 은 잃어버린 것으로 센다.
+    pub fn expire_pings(&mut self, now: u64) {
+        let lost: Vec<u8> = self
+            .pings
+            .iter()
+            .filter(|(_, sent)| now.saturating_sub(**sent) > PING_TIMEOUT_MS)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in lost {
+            self.pings.remove(&id);
+            self.stats.on_ping_lost();
+        }
+    }
+
+    // 보낼 때가 된 Ping. 시계를 맞추는 동안은 샘플을 빨리 모으려고 자주 보낸다.
+    pub fn poll_ping(&mut self, now: u64, tick: u64) -> Option<Ping> {
+        let interval = if self.clock.is_synced() { 1000 } else { 100 };
+        if self.last_ping.is_some_and(|last| now - last <= interval) {
+            return None;
+        }
+        let ping = Ping {
+            id: self.ping_counter,
+            time: now,
+            tick,
+        };
+        self.pings.insert(ping.id, now);
+        self.last_ping = Some(now);
+        self.ping_counter = self.ping_counter.wrapping_add(1);
+        Some(ping)
+    }
+
+    // 보낸 적 없는 Pong 이면 None
+    pub fn on_pong(&mut self, pong: &Pong, received_at: u64) -> Option<ClockSample> {
+        let sent = self.pings.remove(&pong.id)?;
+        self.stats.on_pong(received_at - sent);
+        Some(self.clock.add_sample(pong.ping_time, pong.ping_received, pong.time, received_at))
+    }
+}
@@ -1,25 +1,21 @@
-use std::collections::HashMap;
-
-use godot::engine::utilities::minf;
-use godot::engine::ProjectSettings;
 use godot::prelude::*;
 use godot::engine::Node;
 use godot::engine::Node2D;
 use godot::engine::INode2D;
 use godot::engine::AnimationPlayer;
 
+use crate::fixed::Fixed;
 use crate::input_controller::InputController;
 use crate::gui_player_state::GUIPlayerState;
-use crate::game_manager::GAME_TICK;
+use crate::game_manager::SESSION;
+use crate::simulation::{self, PlayerState};
 
 #[derive(GodotClass)]
 #[class(base=Node2D)]
 pub struct Player {
     pub id: Option<u8>,
-    input_of_tick: HashMap<u64, u8>,
-    input_ok: HashMap<u64, bool>,
-    speed: f64,
-    vel: Vector2,
+    // 세션이 시작되기 전 혼자 움직일 때의 상태
+    local_state: Option<PlayerState>,
     animation_player: Option<Gd<AnimationPlayer>>,
     base: Base<Node2D>
 }
@@ -35,34 +31,26 @@ impl Player {
         }
     }
 
-    #[func]
-    pub fn push_input(&mut self, input: u8, tick: u64) {
-        self.input_of_tick.insert(tick, input);
-        self.input_ok.insert(tick, false);
+    // 리플레이: 기록된 입력은 이미 확정된 것이라 ack 나 재전송 기록을 남기지 않는다.
+    pub fn push_replay_input(&mut self, tick: u64, input: u8) {
+        if let (Some(id), Some(session)) = (self.id, SESSION.lock().unwrap().as_mut()) {
+            session.add_input(id, tick, input);
+        }
     }
 
-    #[func]
-    pub fn push_input_ok(&mut self, tick: u64) {
-        self.input_ok.get_mut(&tick).map(|x| *x = true);
+    // 연결이 끝났을 때. 다시 혼자 움직인다.
+    pub fn reset(&mut self) {
+        self.id = None;
+        self.local_state = None;
     }
 
-    pub fn get_input_5(&mut self, tick: u64) -> [(u64, u8); 5] {
-        let mut index = 0;
-        let mut inputs = [(0u64, 0u8); 5];
-        let mut index_counter = 0;
-        //저장된 인풋들 중 최근 5개의 인풋을 반환
-        while tick - index > 0 {
-            if let Some(x) = self.input_of_tick.get(&(tick - index)) {
-                inputs[index_counter] = (tick - index, *x);
-                index_counter += 1;
+    fn render(&mut self, state: PlayerState) {
+        let mut anim = self.animation_player.clone().unwrap();
+        anim.set_current_animation(if state.running { "anim/run" } else { "anim/idle" }.into());
+        anim.play();
 
-                if index_counter == 5 {
-                    break;
-                }
-            }
-            index += 1;
-        }
-        inputs
+        self.to_gd().set_position(Vector2::new(state.x.to_f32(), state.y.to_f32()));
+        self.to_gd().set_scale(Vector2::new(state.facing as f32, 1.0));
     }
 }
 
@@ -71,10 +59,7 @@ impl INode2D for Player {
     fn init(base: Base<Node2D>) -> Self {
         Self {
             id: None,
-            input_of_tick: HashMap::new(),
-            input_ok: HashMap::new(),
-            speed: 400.0,
-            vel: Vector2::new(0.0, 0.0),
+            local_state: None,
             animation_player: None,
             base,
         }
@@ -89,66 +74,28 @@ impl INode2D for Player {
         self.base_mut().call_deferred("set_gui".into(), &[]);
     }
     
-    fn physics_process(&mut self, delta: f64) {
-        let mut velocity = self.vel;
-
-        let net_stat = GAME_TICK.lock().unwrap();
-        let current_pos = self.to_gd().get_position();
-        let can_jump = current_pos.y == -5.0;
-        let mut jump = false;
-
-        if (*net_stat).tick > 0 {
-            let tick = (*net_stat).tick;
-
-            let mut input = 0u8;
-            if self.input_of_tick.contains_key(&tick) && *self.input_ok.get(&tick).unwrap() {
-                input = *self.input_of_tick.get(&tick).unwrap();
-                self.input_of_tick.remove(&tick);
-            }
-            velocity.x += if input & 0b0001 == 0b0001 { 1.0 } else { 0.0 };
-            velocity.x -= if input & 0b0010 == 0b0010 { 1.0 } else { 0.0 };
-            jump = input & 0b0100 == 0b0100;
-        } else {
-            let input_controller = self.base().get_tree().unwrap().get_root().unwrap().get_node_as::<InputController>("Root/InputController");
-            let input = input_controller.bind().local_input;
-            
-            velocity.x += if input & 0b0001 == 0b0001 { 1.0 } else { 0.0 };
-            velocity.x -= if input & 0b0010 == 0b0010 { 1.0 } else { 0.0 };
-            jump = input & 0b0100 == 0b0100;
+    fn physics_process(&mut self, _delta: f64) {
+        let session_state = match (self.id, SESSION.lock().unwrap().as_ref()) {
+            (Some(id), Some(session)) => session.state().players.get(id as usize).copied(),
+            _ => None,
+        };
+
+        if let Some(state) = session_state {
+            // 세션이 있으면 시작 전에도 시작 위치에 멈춰 있는다.
+            self.local_state = None;
+            self.render(state);
+            return;
         }
 
-        let JUMP_VELOCITY = -30f32; //-725f32;
-        //Maximum speed at which the player can fall.
-        let TERMINAL_VELOCITY = 700f32;
+        let input_controller = self.base().get_tree().unwrap().get_root().unwrap().get_node_as::<InputController>("Root/InputController");
+        let input = input_controller.bind().local_input;
 
-	    if jump && can_jump {
-	    	velocity.y = JUMP_VELOCITY;
-        }
-        
-        let gravity = 100f64;
-	    //Fall.
-	    velocity.y = minf(TERMINAL_VELOCITY as f64, velocity.y as f64 + gravity as f64 * delta) as f32;
-
-        if velocity.x.abs() > 0.0 {
-            velocity.x = velocity.x * delta as f32 * self.speed as f32;
-            let mut anim = self.animation_player.clone().unwrap();
-            anim.set_current_animation("anim/run".into());
-            anim.play();
-        } else {
-            let mut anim = self.animation_player.clone().unwrap();
-            anim.set_current_animation("anim/idle".into());
-            anim.play();
-        }
-
-        let mut new_position = current_pos + velocity;
-        new_position.y = new_position.y.min(-5.0);
-        self.to_gd().set_position(new_position);
-        
-        if velocity.x != 0.0 {
-            self.to_gd().set_scale(Vector2::new(velocity.x.signum(), 1.0));
-        }
-
-        self.vel = velocity;
-        self.vel.x = 0.0;
+        let pos = self.to_gd().get_position();
+        let state = self
+            .local_state
+            .unwrap_or(PlayerState::at(Fixed::from_f32(pos.x), Fixed::from_f32(pos.y)));
+        let state = simulation::step_player(&state, input);
+        self.local_state = Some(state);
+        self.render(state);
     }
-}
\ No newline at end of file
+}
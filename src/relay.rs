@@ -0,0 +1,349 @@
+// 직접 연결할 수 없는 피어끼리 중계 서버를 거쳐 데이터그램을 주고받는다.
+// 서버와는 TCP 로 연결하고, 같은 방에 들어온 클라이언트끼리만 주고받을 수 있다.
+//
+// 스트림은 [길이 u16][종류 u8][필드들] 의 연속이다. 길이는 종류부터 센다.
+
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+
+use crate::time;
+use crate::transport::{Datagram, PeerId, PeerTable, Transport};
+use crate::udp_net::{Reader, UnpackError, Writer, MAX_DATAGRAM_SIZE};
+
+// 서버와 클라이언트가 같아야 한다.
+pub const RELAY_VERSION: u8 = 1;
+pub const MAX_ROOM_LEN: usize = 64;
+// 데이터그램 하나와 헤더가 들어가는 크기
+pub const MAX_RELAY_MESSAGE: usize = MAX_DATAGRAM_SIZE + 64;
+// 보낼 것이 없으면 이 간격으로 KeepAlive 를 보낸다. 서버의 idle 시간보다 짧아야 한다.
+pub const RELAY_KEEPALIVE_MS: u64 = 5000;
+// 주소 앞에 붙여서 UDP 주소와 구별한다. "relay:3" 은 중계 서버의 3 번 클라이언트
+pub const RELAY_SCHEME: &str = "relay:";
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum RelayMessage {
+    // 클라이언트 -> 서버: room 에 들어간다.
+    Join { version: u8, room: String },
+    // 서버 -> 클라이언트: 들어왔다. 이 서버에서 우리 번호
+    Welcome { client: u64 },
+    // 서버 -> 클라이언트: 같은 방의 다른 클라이언트. 들어올 때 이미 있던 클라이언트도 알려 준다.
+    PeerJoined { client: u64 },
+    PeerLeft { client: u64 },
+    // 클라이언트 -> 서버
+    Send { to: u64, data: Vec<u8> },
+    // 서버 -> 클라이언트
+    Deliver { from: u64, data: Vec<u8> },
+    // 서버 -> 클라이언트: 이 뒤로 연결을 끊는다.
+    Error { reason: String },
+    // 클라이언트 -> 서버: 보낼 것이 없어도 방에 남아 있는다.
+    KeepAlive,
+}
+
+impl RelayMessage {
+    // 길이 prefix 를 붙인 바이트
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        match self {
+            RelayMessage::Join { version, room } => {
+                w.put_u8(0);
+                w.put_u8(*version);
+                w.put_str(room);
+            }
+            RelayMessage::Welcome { client } => {
+                w.put_u8(1);
+                w.put_u64(*client);
+            }
+            RelayMessage::PeerJoined { client } => {
+                w.put_u8(2);
+                w.put_u64(*client);
+            }
+            RelayMessage::PeerLeft { client } => {
+                w.put_u8(3);
+                w.put_u64(*client);
+            }
+            RelayMessage::Send { to, data } => {
+                w.put_u8(4);
+                w.put_u64(*to);
+                w.put_bytes(data);
+            }
+            RelayMessage::Deliver { from, data } => {
+                w.put_u8(5);
+                w.put_u64(*from);
+                w.put_bytes(data);
+            }
+            RelayMessage::Error { reason } => {
+                w.put_u8(6);
+                w.put_str(reason);
+            }
+            RelayMessage::KeepAlive => w.put_u8(7),
+        }
+        let body = w.into_bytes();
+        let mut bytes = Vec::with_capacity(body.len() + 2);
+        bytes.extend_from_slice(&(body.len() as u16).to_le_bytes());
+        bytes.extend(body);
+        bytes
+    }
+
+    // 길이 prefix 를 뺀 바이트
+    pub fn decode(body: &[u8]) -> Result<Self, UnpackError> {
+        let mut r = Reader::new(body);
+        let message = match r.get_u8()? {
+            0 => {
+                let version = r.get_u8()?;
+                let room = r.get_str()?;
+                if room.len() > MAX_ROOM_LEN {
+                    return Err(UnpackError::BadLength(room.len()));
+                }
+                RelayMessage::Join { version, room }
+            }
+            1 => RelayMessage::Welcome { client: r.get_u64()? },
+            2 => RelayMessage::PeerJoined { client: r.get_u64()? },
+            3 => RelayMessage::PeerLeft { client: r.get_u64()? },
+            4 => RelayMessage::Send { to: r.get_u64()?, data: r.get_bytes()? },
+            5 => RelayMessage::Deliver { from: r.get_u64()?, data: r.get_bytes()? },
+            6 => RelayMessage::Error { reason: r.get_str()? },
+            7 => RelayMessage::KeepAlive,
+            kind => return Err(UnpackError::UnknownType(kind)),
+        };
+        if r.remaining() > 0 {
+            return Err(UnpackError::TrailingBytes(r.remaining()));
+        }
+        Ok(message)
+    }
+}
+
+// 논블로킹 스트림에서 모은 buffer 앞의 메시지 하나를 꺼낸다. 아직 덜 왔으면 None
+pub fn take_message(buffer: &mut Vec<u8>) -> Result<Option<RelayMessage>, UnpackError> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+    let len = u16::from_le_bytes([buffer[0], buffer[1]]) as usize;
+    if len == 0 || len > MAX_RELAY_MESSAGE {
+        return Err(UnpackError::BadLength(len));
+    }
+    if buffer.len() < 2 + len {
+        return Ok(None);
+    }
+    let message = RelayMessage::decode(&buffer[2..2 + len]);
+    buffer.drain(..2 + len);
+    message.map(Some)
+}
+
+// 블로킹 스트림에서 메시지 하나를 읽는다.
+pub fn read_message(stream: &mut impl Read) -> io::Result<RelayMessage> {
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len)?;
+    let len = u16::from_le_bytes(len) as usize;
+    if len == 0 || len > MAX_RELAY_MESSAGE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("bad relay message length {}", len)));
+    }
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body)?;
+    RelayMessage::decode(&body).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
+}
+
+pub fn relay_endpoint(client: u64) -> String {
+    format!("{}{}", RELAY_SCHEME, client)
+}
+
+pub fn parse_relay_endpoint(endpoint: &str) -> Option<u64> {
+    endpoint.strip_prefix(RELAY_SCHEME)?.parse().ok()
+}
+
+#[derive(Default)]
+struct RelayState {
+    client: Option<u64>,
+    room_peers: Vec<u64>,
+    received: Vec<Datagram>,
+    closed: Option<String>,
+    error: Option<String>,
+}
+
+// 중계 서버의 방 하나를 통한 전송. PeerId 는 서버가 준 클라이언트 번호 그대로다.
+pub struct RelayTransport {
+    stream: TcpStream,
+    state: Arc<Mutex<RelayState>>,
+    last_sent: u64,
+}
+
+impl RelayTransport {
+    pub fn connect(server: &str, room: &str) -> io::Result<Self> {
+        let mut stream = TcpStream::connect(server)?;
+        stream.set_nodelay(true)?;
+        let join = RelayMessage::Join {
+            version: RELAY_VERSION,
+            room: room.to_string(),
+        };
+        stream.write_all(&join.encode())?;
+
+        let state = Arc::new(Mutex::new(RelayState::default()));
+        let state_for_thread = state.clone();
+        let mut reader = stream.try_clone()?;
+        std::thread::spawn(move || loop {
+            let message = match read_message(&mut reader) {
+                Ok(message) => message,
+                Err(err) => {
+                    state_for_thread.lock().unwrap().closed = Some(err.to_string());
+                    return;
+                }
+            };
+            let mut state = state_for_thread.lock().unwrap();
+            match message {
+                RelayMessage::Welcome { client } => state.client = Some(client),
+                RelayMessage::PeerJoined { client } => {
+                    if !state.room_peers.contains(&client) {
+                        state.room_peers.push(client);
+                    }
+                }
+                RelayMessage::PeerLeft { client } => state.room_peers.retain(|peer| *peer != client),
+                RelayMessage::Deliver { from, data } => {
+                    state.received.push((PeerId(from), time::monotonic_ms(), data))
+                }
+                RelayMessage::Error { reason } => {
+                    state.closed = Some(reason);
+                    return;
+                }
+                RelayMessage::Join { .. } | RelayMessage::Send { .. } | RelayMessage::KeepAlive => {}
+            }
+        });
+        Ok(Self {
+            stream,
+            state,
+            last_sent: time::monotonic_ms(),
+        })
+    }
+
+    fn write(&mut self, message: &RelayMessage) {
+        self.last_sent = time::monotonic_ms();
+        if let Err(err) = self.stream.write_all(&message.encode()) {
+            self.state.lock().unwrap().error = Some(format!("Failed to send to relay : {}", err));
+        }
+    }
+
+    // 서버가 아직 받아 주지 않았으면 None
+    pub fn client_id(&self) -> Option<u64> {
+        self.state.lock().unwrap().client
+    }
+
+    // 같은 방에 있는 다른 클라이언트들
+    pub fn room_peers(&self) -> Vec<PeerId> {
+        self.state.lock().unwrap().room_peers.iter().map(|client| PeerId(*client)).collect()
+    }
+
+    // 서버가 연결을 끊었으면 그 이유
+    pub fn closed(&self) -> Option<String> {
+        self.state.lock().unwrap().closed.clone()
+    }
+}
+
+// 읽는 스레드가 스트림을 복제해 들고 있으므로 직접 닫아야 서버가 떠난 것을 안다.
+impl Drop for RelayTransport {
+    fn drop(&mut self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+impl Transport for RelayTransport {
+    fn resolve(&mut self, endpoint: &str) -> Option<PeerId> {
+        parse_relay_endpoint(endpoint).map(PeerId)
+    }
+
+    fn endpoint(&self, peer: PeerId) -> Option<String> {
+        Some(relay_endpoint(peer.0))
+    }
+
+    fn send(&mut self, peer: PeerId, bytes: &[u8]) {
+        self.write(&RelayMessage::Send {
+            to: peer.0,
+            data: bytes.to_vec(),
+        });
+    }
+
+    fn poll(&mut self) -> Vec<Datagram> {
+        if time::monotonic_ms().saturating_sub(self.last_sent) > RELAY_KEEPALIVE_MS {
+            self.write(&RelayMessage::KeepAlive);
+        }
+        std::mem::take(&mut self.state.lock().unwrap().received)
+    }
+
+    fn relay_peers(&mut self) -> Vec<PeerId> {
+        self.room_peers()
+    }
+
+    fn last_error(&mut self) -> Option<String> {
+        self.state.lock().unwrap().error.take()
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Route {
+    Direct(PeerId),
+    Relay(PeerId),
+}
+
+// 직접 전송에 중계 서버의 방 하나를 더한다. "relay:" 주소만 중계 서버로 간다.
+pub struct RelayFallback {
+    direct: Box<dyn Transport>,
+    relay: RelayTransport,
+    routes: PeerTable<Route>,
+}
+
+impl RelayFallback {
+    pub fn new(direct: Box<dyn Transport>, relay: RelayTransport) -> Self {
+        Self {
+            direct,
+            relay,
+            routes: PeerTable::new(),
+        }
+    }
+}
+
+impl Transport for RelayFallback {
+    fn resolve(&mut self, endpoint: &str) -> Option<PeerId> {
+        let route = match parse_relay_endpoint(endpoint) {
+            Some(client) => Route::Relay(PeerId(client)),
+            None => Route::Direct(self.direct.resolve(endpoint)?),
+        };
+        Some(self.routes.id(&route))
+    }
+
+    fn endpoint(&self, peer: PeerId) -> Option<String> {
+        match self.routes.addr(peer)? {
+            Route::Direct(inner) => self.direct.endpoint(*inner),
+            Route::Relay(inner) => self.relay.endpoint(*inner),
+        }
+    }
+
+    fn send(&mut self, peer: PeerId, bytes: &[u8]) {
+        match self.routes.addr(peer).cloned() {
+            Some(Route::Direct(inner)) => self.direct.send(inner, bytes),
+            Some(Route::Relay(inner)) => self.relay.send(inner, bytes),
+            None => {}
+        }
+    }
+
+    fn poll(&mut self) -> Vec<Datagram> {
+        let direct = self.direct.poll().into_iter().map(|(inner, at, bytes)| (Route::Direct(inner), at, bytes));
+        let relay = self.relay.poll().into_iter().map(|(inner, at, bytes)| (Route::Relay(inner), at, bytes));
+        let received: Vec<_> = direct.chain(relay).collect();
+        received
+            .into_iter()
+            .map(|(route, at, bytes)| (self.routes.id(&route), at, bytes))
+            .collect()
+    }
+
+    fn relay_peers(&mut self) -> Vec<PeerId> {
+        self.relay
+            .room_peers()
+            .into_iter()
+            .map(|inner| self.routes.id(&Route::Relay(inner)))
+            .collect()
+    }
+
+    fn last_error(&mut self) -> Option<String> {
+        let direct = self.direct.last_error();
+        self.relay.last_error().or(direct)
+    }
+}
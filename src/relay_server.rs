@@ -0,0 +1,347 @@
+// 중계 서버. 같은 방에 들어온 클라이언트끼리 데이터그램을 옮겨 준다.
+// 엔진 없이 src/bin/relay_server.rs 에서 돌리고, 테스트에서는 poll 에 시각을 넣어 직접 돌린다.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+
+use crate::relay::{take_message, RelayMessage, RELAY_VERSION};
+use crate::server_log::ServerLog;
+use crate::spectator::MAX_SPECTATORS;
+use crate::time;
+use crate::udp_net::MAX_PLAYERS;
+
+// 보내지 못하고 쌓인 바이트가 이보다 많으면 느린 클라이언트로 보고 끊는다.
+const MAX_OUTGOING_BYTES: usize = 256 * 1024;
+
+pub struct RelayConfig {
+    // 이 시간 동안 아무것도 보내지 않은 클라이언트는 끊는다. KeepAlive 도 센다.
+    pub idle_timeout_ms: u64,
+    pub max_room_clients: usize,
+    // 클라이언트마다 초당 옮겨 주는 최대 메시지 수와 바이트. 넘은 것은 UDP 처럼 그냥 버린다.
+    pub messages_per_sec: u64,
+    pub bytes_per_sec: u64,
+    // 접속, 방 입장, 끊김 같은 일을 한 줄씩 받는다.
+    pub log: ServerLog,
+}
+
+impl RelayConfig {
+    pub fn new() -> Self {
+        Self {
+            idle_timeout_ms: 30_000,
+            max_room_clients: MAX_PLAYERS + MAX_SPECTATORS,
+            messages_per_sec: 300,
+            bytes_per_sec: 256 * 1024,
+            log: ServerLog::default(),
+        }
+    }
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 1초 동안 rate 만큼 채워지고 rate 까지만 모이는 버킷
+struct TokenBucket {
+    rate: u64,
+    tokens: u64,
+    updated_at: u64,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, now: u64) -> Self {
+        Self {
+            rate,
+            tokens: rate,
+            updated_at: now,
+        }
+    }
+
+    fn take(&mut self, amount: u64, now: u64) -> bool {
+        let refill = now.saturating_sub(self.updated_at) * self.rate / 1000;
+        if refill > 0 {
+            self.tokens = (self.tokens + refill).min(self.rate);
+            self.updated_at = now;
+        }
+        if self.tokens < amount {
+            return false;
+        }
+        self.tokens -= amount;
+        true
+    }
+}
+
+struct Client {
+    stream: TcpStream,
+    addr: SocketAddr,
+    incoming: Vec<u8>,
+    outgoing: Vec<u8>,
+    room: Option<String>,
+    last_active: u64,
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    // 남은 outgoing 을 보내고 끊는다.
+    closing: bool,
+}
+
+impl Client {
+    fn queue(&mut self, message: &RelayMessage) {
+        self.outgoing.extend(message.encode());
+    }
+}
+
+pub struct RelayServer {
+    listener: TcpListener,
+    config: RelayConfig,
+    clients: BTreeMap<u64, Client>,
+    rooms: HashMap<String, Vec<u64>>,
+    next_client: u64,
+    // 한도를 넘어서 버린 메시지 수
+    dropped: u64,
+}
+
+impl RelayServer {
+    pub fn bind(addr: &str, config: RelayConfig) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            config,
+            clients: BTreeMap::new(),
+            rooms: HashMap::new(),
+            next_client: 1,
+            dropped: 0,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn room_count(&self) -> usize {
+        self.rooms.len()
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.poll(time::monotonic_ms());
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+    }
+
+    // 받을 것을 받고, 옮길 것을 옮기고, 보낼 것을 보낸다.
+    pub fn poll(&mut self, now: u64) {
+        self.accept(now);
+
+        let ids: Vec<u64> = self.clients.keys().copied().collect();
+        for id in ids.iter() {
+            for message in self.read(*id) {
+                self.handle(*id, message, now);
+            }
+        }
+
+        for (id, client) in self.clients.iter_mut() {
+            if !client.closing && now.saturating_sub(client.last_active) > self.config.idle_timeout_ms {
+                self.config.log.write(&format!("client {} ({}) idle", id, client.addr));
+                client.closing = true;
+            }
+        }
+
+        let mut closed = Vec::new();
+        for (id, client) in self.clients.iter_mut() {
+            let broken = !flush(client) || client.outgoing.len() > MAX_OUTGOING_BYTES;
+            if broken || (client.closing && client.outgoing.is_empty()) {
+                closed.push(*id);
+            }
+        }
+        for id in closed {
+            self.remove(id);
+        }
+    }
+
+    fn accept(&mut self, now: u64) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, addr)) => {
+                    if stream.set_nonblocking(true).is_err() {
+                        continue;
+                    }
+                    let _ = stream.set_nodelay(true);
+                    let id = self.next_client;
+                    self.next_client += 1;
+                    self.clients.insert(
+                        id,
+                        Client {
+                            stream,
+                            addr,
+                            incoming: Vec::new(),
+                            outgoing: Vec::new(),
+                            room: None,
+                            last_active: now,
+                            messages: TokenBucket::new(self.config.messages_per_sec, now),
+                            bytes: TokenBucket::new(self.config.bytes_per_sec, now),
+                            closing: false,
+                        },
+                    );
+                }
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => return,
+                Err(err) => {
+                    self.config.log.write(&format!("accept failed: {}", err));
+                    return;
+                }
+            }
+        }
+    }
+
+    // 다 받은 메시지들. 연결이 끊겼거나 형식이 틀리면 닫는다.
+    fn read(&mut self, id: u64) -> Vec<RelayMessage> {
+        let client = self.clients.get_mut(&id).unwrap();
+        let mut buffer = [0u8; 4096];
+        loop {
+            match client.stream.read(&mut buffer) {
+                Ok(0) => {
+                    client.closing = true;
+                    break;
+                }
+                Ok(size) => client.incoming.extend_from_slice(&buffer[..size]),
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => {
+                    client.closing = true;
+                    break;
+                }
+            }
+        }
+        let mut messages = Vec::new();
+        loop {
+            match take_message(&mut client.incoming) {
+                Ok(Some(message)) => messages.push(message),
+                Ok(None) => break,
+                Err(err) => {
+                    self.config.log.write(&format!("client {} ({}) sent bad data: {}", id, client.addr, err));
+                    client.incoming.clear();
+                    client.closing = true;
+                    break;
+                }
+            }
+        }
+        messages
+    }
+
+    fn handle(&mut self, id: u64, message: RelayMessage, now: u64) {
+        let client = self.clients.get_mut(&id).unwrap();
+        if client.closing {
+            return;
+        }
+        client.last_active = now;
+        match message {
+            RelayMessage::Join { version, room } => self.join(id, version, room),
+            RelayMessage::KeepAlive => {}
+            RelayMessage::Send { to, data } => self.forward(id, to, data, now),
+            _ => self.reject(id, "unexpected message"),
+        }
+    }
+
+    fn forward(&mut self, from: u64, to: u64, data: Vec<u8>, now: u64) {
+        let client = self.clients.get_mut(&from).unwrap();
+        let Some(room) = client.room.clone() else {
+            self.reject(from, "send before join");
+            return;
+        };
+        if !client.messages.take(1, now) || !client.bytes.take(data.len() as u64, now) {
+            self.dropped += 1;
+            return;
+        }
+        let same_room = self.rooms.get(&room).is_some_and(|members| members.contains(&to));
+        match self.clients.get_mut(&to) {
+            Some(target) if same_room && to != from => target.queue(&RelayMessage::Deliver { from, data }),
+            _ => self.dropped += 1,
+        }
+    }
+
+    fn join(&mut self, id: u64, version: u8, room: String) {
+        if version != RELAY_VERSION {
+            self.reject(id, &format!("relay version {} (server {})", version, RELAY_VERSION));
+            return;
+        }
+        if self.clients[&id].room.is_some() {
+            self.reject(id, "already joined");
+            return;
+        }
+        let members = self.rooms.get(&room).cloned().unwrap_or_default();
+        if members.len() >= self.config.max_room_clients {
+            self.reject(id, "room is full");
+            return;
+        }
+
+        let client = self.clients.get_mut(&id).unwrap();
+        client.room = Some(room.clone());
+        client.queue(&RelayMessage::Welcome { client: id });
+        for member in members.iter() {
+            client.queue(&RelayMessage::PeerJoined { client: *member });
+        }
+        self.config.log.write(&format!("client {} ({}) joined room {}", id, client.addr, room));
+        for member in members.iter() {
+            if let Some(other) = self.clients.get_mut(member) {
+                other.queue(&RelayMessage::PeerJoined { client: id });
+            }
+        }
+        self.rooms.entry(room).or_default().push(id);
+    }
+
+    fn reject(&mut self, id: u64, reason: &str) {
+        let client = self.clients.get_mut(&id).unwrap();
+        self.config.log.write(&format!("client {} ({}) rejected: {}", id, client.addr, reason));
+        client.queue(&RelayMessage::Error { reason: reason.to_string() });
+        client.closing = true;
+    }
+
+    // 방에서 빼고 남은 클라이언트들에게 알린다. 빈 방은 지운다.
+    fn remove(&mut self, id: u64) {
+        let Some(client) = self.clients.remove(&id) else {
+            return;
+        };
+        self.config.log.write(&format!("client {} ({}) left", id, client.addr));
+        let Some(room) = client.room else {
+            return;
+        };
+        let Some(members) = self.rooms.get_mut(&room) else {
+            return;
+        };
+        members.retain(|member| *member != id);
+        if members.is_empty() {
+            self.rooms.remove(&room);
+            return;
+        }
+        for member in members.clone() {
+            if let Some(other) = self.clients.get_mut(&member) {
+                other.queue(&RelayMessage::PeerLeft { client: id });
+            }
+        }
+    }
+}
+
+// 보낼 수 있는 만큼 보낸다. 연결이 끊겼으면 false
+fn flush(client: &mut Client) -> bool {
+    while !client.outgoing.is_empty() {
+        match client.stream.write(&client.outgoing) {
+            Ok(0) => return false,
+            Ok(size) => {
+                client.outgoing.drain(..size);
+            }
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => return true,
+            Err(_) => return false,
+        }
+    }
+    true
+}
@@ -0,0 +1,281 @@
+use std::collections::BTreeMap;
+
+use crate::udp_net::{self, Channel, ChannelAckPacket, ChannelPacket, Packet};
+
+// 재전송 간격의 상한 (ms). 다시 보낼 때마다 두 배로 늘린다.
+pub const MAX_RTO_MS: u64 = 2000;
+// 받는 쪽이 순서를 기다리며 쌓아 둘 수 있는 메시지 수. 이보다 앞선 것은 버린다.
+pub const RECEIVE_WINDOW: u32 = 256;
+
+struct InFlight {
+    frame: Vec<u8>,
+    sent_at: u64,
+    resends: u32,
+}
+
+// 상대 주소 하나에 대한 채널 상태.
+// 순서 번호는 u32 라 초당 60개를 보내도 두 해가 넘게 걸리므로 wrap 은 신경쓰지 않는다.
+pub struct ChannelEndpoint {
+    sequenced_send: u32,
+    // 마지막으로 넘겨준 순서 번호
+    sequenced_recv: Option<u32>,
+    reliable_send: u32,
+    in_flight: BTreeMap<u32, InFlight>,
+    // 다음에 넘겨줄 순서 번호
+    reliable_recv: u32,
+    // reliable_recv 보다 먼저 도착한 메시지
+    out_of_order: BTreeMap<u32, Vec<u8>>,
+    ack_pending: bool,
+}
+
+impl ChannelEndpoint {
+    pub fn new() -> Self {
+        Self {
+            sequenced_send: 0,
+            sequenced_recv: None,
+            reliable_send: 0,
+            in_flight: BTreeMap::new(),
+            reliable_recv: 0,
+            out_of_order: BTreeMap::new(),
+            ack_pending: false,
+        }
+    }
+
+    // 바로 보낼 프레임을 돌려준다. ReliableOrdered 는 ack 를 받을 때까지 poll 에서 다시 나온다.
+    pub fn send<T: Packet>(&mut self, channel: Channel, packet: &T, now: u64) -> Vec<u8> {
+        let payload = udp_net::pack(packet);
+        match channel {
+            Channel::Unreliable => udp_net::frame(payload),
+            Channel::UnreliableSequenced => {
+                let seq = self.sequenced_send;
+                self.sequenced_send += 1;
+                udp_net::pack_frame(&ChannelPacket { channel, seq, payload })
+            }
+            Channel::ReliableOrdered => {
+                let seq = self.reliable_send;
+                self.reliable_send += 1;
+                let frame = udp_net::pack_frame(&ChannelPacket { channel, seq, payload });
+                self.in_flight.insert(seq, InFlight { frame: frame.clone(), sent_at: now, resends: 0 });
+                frame
+            }
+        }
+    }
+
+    // 받은 채널 패킷에서 지금 넘겨줄 수 있는 패킷들 (순서대로)
+    pub fn receive(&mut self, packet: ChannelPacket) -> Vec<Vec<u8>> {
+        match packet.channel {
+            Channel::Unreliable => vec![packet.payload],
+            Channel::UnreliableSequenced => {
+                if self.sequenced_recv.is_some_and(|last| packet.seq <= last) {
+                    return Vec::new();
+                }
+                self.sequenced_recv = Some(packet.seq);
+                vec![packet.payload]
+            }
+            Channel::ReliableOrdered => {
+                // 중복이어도 ack 가 잃어버렸을 수 있으니 다시 보낸다.
+                self.ack_pending = true;
+                if packet.seq < self.reliable_recv
+                    || packet.seq >= self.reliable_recv.saturating_add(RECEIVE_WINDOW)
+                {
+                    return Vec::new();
+                }
+                self.out_of_order.entry(packet.seq).or_insert(packet.payload);
+
+                let mut delivered = Vec::new();
+                while let Some(payload) = self.out_of_order.remove(&self.reliable_recv) {
+                    delivered.push(payload);
+                    self.reliable_recv += 1;
+                }
+                delivered
+            }
+        }
+    }
+
+    pub fn on_ack(&mut self, ack: &ChannelAckPacket) {
+        self.in_flight.retain(|seq, _| {
+            if *seq < ack.next {
+                return false;
+            }
+            if *seq == ack.next {
+                return true;
+            }
+            let offset = seq - ack.next - 1;
+            !(offset < 32 && ack.bits & (1 << offset) != 0)
+        });
+    }
+
+    // 매 프레임 부른다. 보낼 ack 와 재전송할 프레임들
+    pub fn poll(&mut self, now: u64, rto: u64) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        if self.ack_pending {
+            self.ack_pending = false;
+            frames.push(udp_net::pack_frame(&self.ack()));
+        }
+        for in_flight in self.in_flight.values_mut() {
+            let timeout = rto.checked_shl(in_flight.resends).unwrap_or(MAX_RTO_MS).min(MAX_RTO_MS);
+            if now.saturating_sub(in_flight.sent_at) >= timeout {
+                in_flight.sent_at = now;
+                in_flight.resends += 1;
+                frames.push(in_flight.frame.clone());
+            }
+        }
+        frames
+    }
+
+    fn ack(&self) -> ChannelAckPacket {
+        let mut bits = 0;
+        for seq in self.out_of_order.keys() {
+            let offset = seq - self.reliable_recv - 1;
+            if offset < 32 {
+                bits |= 1 << offset;
+            }
+        }
+        ChannelAckPacket { next: self.reliable_recv, bits }
+    }
+
+    // ack 를 기다리는 메시지 수
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+}
+
+impl Default for ChannelEndpoint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::udp_net::{ChatPacket, Message};
+
+    fn chat(text: &str) -> ChatPacket {
+        ChatPacket { text: text.to_string() }
+    }
+
+    fn decode(frame: &[u8]) -> Message {
+        let frames = udp_net::split_frames(frame).unwrap();
+        assert_eq!(frames.len(), 1);
+        udp_net::decode(&frames[0]).unwrap()
+    }
+
+    fn channel_packet(frame: &[u8]) -> ChannelPacket {
+        match decode(frame) {
+            Message::Channel(packet) => packet,
+            _ => panic!("not a channel packet"),
+        }
+    }
+
+    // (next, bits)
+    fn ack_packet(frame: &[u8]) -> (u32, u32) {
+        match decode(frame) {
+            Message::ChannelAck(ack) => (ack.next, ack.bits),
+            _ => panic!("not an ack"),
+        }
+    }
+
+    fn texts(payloads: Vec<Vec<u8>>) -> Vec<String> {
+        payloads
+            .iter()
+            .map(|payload| match udp_net::decode(payload).unwrap() {
+                Message::Chat(chat) => chat.text,
+                _ => panic!("not a chat"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unreliable_is_sent_as_is() {
+        let mut endpoint = ChannelEndpoint::new();
+        let frame = endpoint.send(Channel::Unreliable, &chat("hi"), 0);
+        assert!(matches!(decode(&frame), Message::Chat(_)));
+        assert_eq!(endpoint.in_flight(), 0);
+        assert!(endpoint.poll(10_000, 100).is_empty());
+    }
+
+    #[test]
+    fn sequenced_drops_stale_packets() {
+        let mut sender = ChannelEndpoint::new();
+        let mut receiver = ChannelEndpoint::new();
+        let first = channel_packet(&sender.send(Channel::UnreliableSequenced, &chat("a"), 0));
+        let second = channel_packet(&sender.send(Channel::UnreliableSequenced, &chat("b"), 0));
+        let third = channel_packet(&sender.send(Channel::UnreliableSequenced, &chat("c"), 0));
+        assert_eq!(texts(receiver.receive(second)), vec!["b"]);
+        assert!(receiver.receive(first).is_empty());
+        assert_eq!(texts(receiver.receive(third)), vec!["c"]);
+        assert_eq!(sender.in_flight(), 0);
+    }
+
+    #[test]
+    fn reliable_delivers_in_order_once() {
+        let mut sender = ChannelEndpoint::new();
+        let mut receiver = ChannelEndpoint::new();
+        let frames: Vec<Vec<u8>> = ["a", "b", "c"]
+            .iter()
+            .map(|text| sender.send(Channel::ReliableOrdered, &chat(text), 0))
+            .collect();
+        assert_eq!(sender.in_flight(), 3);
+
+        assert!(receiver.receive(channel_packet(&frames[2])).is_empty());
+        assert!(receiver.receive(channel_packet(&frames[1])).is_empty());
+        // 기다리던 것이 오면 쌓인 것까지 순서대로 넘겨준다.
+        assert_eq!(texts(receiver.receive(channel_packet(&frames[0]))), vec!["a", "b", "c"]);
+        // 중복은 넘겨주지 않지만 ack 는 다시 보낸다.
+        assert_eq!(receiver.poll(0, 100).len(), 1);
+        assert!(receiver.receive(channel_packet(&frames[1])).is_empty());
+        assert!(receiver.receive(channel_packet(&frames[1])).is_empty());
+        let acks = receiver.poll(0, 100);
+        assert_eq!(acks.len(), 1);
+        assert_eq!(ack_packet(&acks[0]), (3, 0));
+
+        sender.on_ack(&ChannelAckPacket { next: 3, bits: 0 });
+        assert_eq!(sender.in_flight(), 0);
+    }
+
+    #[test]
+    fn selective_ack_stops_resending_received_messages() {
+        let mut sender = ChannelEndpoint::new();
+        let mut receiver = ChannelEndpoint::new();
+        let frames: Vec<Vec<u8>> = (0..4)
+            .map(|i| sender.send(Channel::ReliableOrdered, &chat(&i.to_string()), 0))
+            .collect();
+        receiver.receive(channel_packet(&frames[1]));
+        receiver.receive(channel_packet(&frames[3]));
+        assert_eq!(ack_packet(&receiver.poll(0, 100)[0]), (0, 0b101));
+
+        sender.on_ack(&ChannelAckPacket { next: 0, bits: 0b101 });
+        assert_eq!(sender.in_flight(), 2);
+        let resent: Vec<u32> = sender.poll(100, 100).iter().map(|frame| channel_packet(frame).seq).collect();
+        assert_eq!(resent, vec![0, 2]);
+    }
+
+    #[test]
+    fn resends_with_exponential_backoff() {
+        let mut sender = ChannelEndpoint::new();
+        let frame = sender.send(Channel::ReliableOrdered, &chat("a"), 0);
+        let mut resent_at = Vec::new();
+        for now in 0..=7200 {
+            for resent in sender.poll(now, 100) {
+                assert_eq!(resent, frame);
+                resent_at.push(now);
+            }
+        }
+        // 100, 200, 400, 800, 1600 을 기다리고 그 뒤로는 MAX_RTO_MS 마다
+        assert_eq!(resent_at, vec![100, 300, 700, 1500, 3100, 3100 + MAX_RTO_MS, 3100 + 2 * MAX_RTO_MS]);
+    }
+
+    #[test]
+    fn ignores_messages_beyond_the_window() {
+        let mut receiver = ChannelEndpoint::new();
+        let packet = ChannelPacket {
+            channel: Channel::ReliableOrdered,
+            seq: RECEIVE_WINDOW,
+            payload: udp_net::pack(&chat("far")),
+        };
+        assert!(receiver.receive(packet).is_empty());
+        assert!(receiver.out_of_order.is_empty());
+        assert_eq!(ack_packet(&receiver.poll(0, 100)[0]), (0, 0));
+    }
+}
@@ -0,0 +1,264 @@
+// NAT 구멍 뚫기. 같은 방에 등록한 피어들에게 중개 서버가 서로의 공인 주소를 알려 주면
+// 양쪽이 동시에 Punch 를 보내서 서로의 NAT 에 길을 낸다.
+//
+// 게임과 같은 소켓을 써야 서버가 본 주소가 게임 트래픽의 주소와 같다.
+// 데이터그램은 [RENDEZVOUS_MAGIC][종류 u8][필드들] 이다. 세션 헤더와 겹치지 않게 magic 으로 구별한다.
+
+use crate::transport::{Datagram, PeerId, Transport};
+use crate::udp_net::{Reader, UnpackError, Writer};
+
+// 서버와 클라이언트가 같아야 한다.
+pub const RENDEZVOUS_VERSION: u8 = 1;
+pub const RENDEZVOUS_MAGIC: [u8; 4] = *b"P2RV";
+pub const MAX_ROOM_LEN: usize = 64;
+// 서버에 다시 등록하는 간격. 서버의 idle 시간보다 짧아야 하고, 잃어버린 Introduce 도 이때 다시 받는다. (ms)
+pub const REGISTER_INTERVAL_MS: u64 = 1000;
+// 이 간격으로 이 횟수만큼 Punch 를 보내고도 답이 없으면 실패로 본다.
+pub const PUNCH_INTERVAL_MS: u64 = 100;
+pub const PUNCH_ATTEMPTS: u32 = 30;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum RendezvousMessage {
+    // 클라이언트 -> 서버: room 에 등록한다. 이미 있으면 살아 있다는 뜻이다.
+    Register { version: u8, room: String },
+    // 서버 -> 클라이언트: 서버가 본 우리 주소
+    Registered { endpoint: String },
+    // 서버 -> 클라이언트: 같은 방의 다른 피어. host 는 방에 가장 먼저 들어온 피어
+    Introduce { endpoint: String, host: bool },
+    // 피어 -> 피어
+    Punch,
+    PunchAck,
+    // 서버 -> 클라이언트: 등록을 받지 않는다.
+    Error { reason: String },
+}
+
+impl RendezvousMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.put_array(&RENDEZVOUS_MAGIC);
+        match self {
+            RendezvousMessage::Register { version, room } => {
+                w.put_u8(0);
+                w.put_u8(*version);
+                w.put_str(room);
+            }
+            RendezvousMessage::Registered { endpoint } => {
+                w.put_u8(1);
+                w.put_str(endpoint);
+            }
+            RendezvousMessage::Introduce { endpoint, host } => {
+                w.put_u8(2);
+                w.put_str(endpoint);
+                w.put_u8(*host as u8);
+            }
+            RendezvousMessage::Punch => w.put_u8(3),
+            RendezvousMessage::PunchAck => w.put_u8(4),
+            RendezvousMessage::Error { reason } => {
+                w.put_u8(5);
+                w.put_str(reason);
+            }
+        }
+        w.into_bytes()
+    }
+
+    pub fn decode(datagram: &[u8]) -> Result<Self, UnpackError> {
+        let mut r = Reader::new(datagram);
+        let magic = r.get_array::<4>()?;
+        if magic != RENDEZVOUS_MAGIC {
+            return Err(UnpackError::BadValue(magic[0]));
+        }
+        let message = match r.get_u8()? {
+            0 => {
+                let version = r.get_u8()?;
+                let room = r.get_str()?;
+                if room.len() > MAX_ROOM_LEN {
+                    return Err(UnpackError::BadLength(room.len()));
+                }
+                RendezvousMessage::Register { version, room }
+            }
+            1 => RendezvousMessage::Registered { endpoint: r.get_str()? },
+            2 => {
+                let endpoint = r.get_str()?;
+                let host = match r.get_u8()? {
+                    0 => false,
+                    1 => true,
+                    value => return Err(UnpackError::BadValue(value)),
+                };
+                RendezvousMessage::Introduce { endpoint, host }
+            }
+            3 => RendezvousMessage::Punch,
+            4 => RendezvousMessage::PunchAck,
+            5 => RendezvousMessage::Error { reason: r.get_str()? },
+            kind => return Err(UnpackError::UnknownType(kind)),
+        };
+        if r.remaining() > 0 {
+            return Err(UnpackError::TrailingBytes(r.remaining()));
+        }
+        Ok(message)
+    }
+}
+
+// 중개 서버 것이거나 구멍 뚫기용 데이터그램이면 그 메시지
+pub fn parse_rendezvous(datagram: &[u8]) -> Option<RendezvousMessage> {
+    if !datagram.starts_with(&RENDEZVOUS_MAGIC) {
+        return None;
+    }
+    RendezvousMessage::decode(datagram).ok()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PunchState {
+    Punching,
+    // 상대에게서 Punch 나 PunchAck 를 받았다. 이제 직접 보낼 수 있다.
+    Open,
+    // 재시도를 다 했다. 중계 서버로 넘어가야 한다.
+    Failed,
+}
+
+struct Punch {
+    peer: PeerId,
+    host: bool,
+    state: PunchState,
+    attempts: u32,
+    next_at: u64,
+}
+
+// 중개 서버에 등록해 두고 소개받은 피어마다 구멍을 뚫는다.
+// 받은 데이터그램을 process 에 넘기면 게임 데이터그램만 돌려준다.
+pub struct HolePuncher {
+    server: PeerId,
+    room: String,
+    public_endpoint: Option<String>,
+    next_register_at: u64,
+    // 소개받은 순서대로
+    punches: Vec<Punch>,
+    error: Option<String>,
+    // 아직 가져가지 않은 로그
+    log: Vec<String>,
+}
+
+impl HolePuncher {
+    pub fn new(server: PeerId, room: &str) -> Self {
+        Self {
+            server,
+            room: room.to_string(),
+            public_endpoint: None,
+            next_register_at: 0,
+            punches: Vec::new(),
+            error: None,
+            log: Vec::new(),
+        }
+    }
+
+    // 서버가 본 우리 주소. 다른 사람에게 알려 줄 주소는 이것이다.
+    pub fn public_endpoint(&self) -> Option<&str> {
+        self.public_endpoint.as_deref()
+    }
+
+    pub fn state(&self, peer: PeerId) -> Option<PunchState> {
+        self.punches.iter().find(|punch| punch.peer == peer).map(|punch| punch.state)
+    }
+
+    // 방에 가장 먼저 들어온 피어와 그쪽으로 구멍을 뚫은 상태
+    pub fn host(&self) -> Option<(PeerId, PunchState)> {
+        self.punches.iter().find(|punch| punch.host).map(|punch| (punch.peer, punch.state))
+    }
+
+    // 서버가 등록을 거절했으면 그 이유
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    // 지난 호출 뒤로 생긴 일들. 엔진이 있으면 NetworkController 가 출력한다.
+    pub fn take_log(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.log)
+    }
+
+    pub fn process(&mut self, transport: &mut dyn Transport, received: Vec<Datagram>, now: u64) -> Vec<Datagram> {
+        let mut game = Vec::new();
+        for (peer, received_at, datagram) in received {
+            match parse_rendezvous(&datagram) {
+                Some(message) => self.handle(transport, peer, message, now),
+                None => game.push((peer, received_at, datagram)),
+            }
+        }
+        self.poll(transport, now);
+        game
+    }
+
+    fn handle(&mut self, transport: &mut dyn Transport, from: PeerId, message: RendezvousMessage, now: u64) {
+        match message {
+            RendezvousMessage::Registered { endpoint }
+                if from == self.server && self.public_endpoint.as_ref() != Some(&endpoint) =>
+            {
+                self.log.push(format!("Public endpoint is {}", endpoint));
+                self.public_endpoint = Some(endpoint);
+            }
+            RendezvousMessage::Introduce { endpoint, host } if from == self.server => {
+                let Some(peer) = transport.resolve(&endpoint) else {
+                    self.log.push(format!("Invalid introduced endpoint : {}", endpoint));
+                    return;
+                };
+                match self.punches.iter_mut().find(|punch| punch.peer == peer) {
+                    Some(punch) => punch.host = host,
+                    None => {
+                        self.log.push(format!("Punching {} ({})", endpoint, peer));
+                        self.punches.push(Punch {
+                            peer,
+                            host,
+                            state: PunchState::Punching,
+                            attempts: 0,
+                            next_at: now,
+                        });
+                    }
+                }
+            }
+            RendezvousMessage::Error { reason } if from == self.server => {
+                self.log.push(format!("Rendezvous server refused : {}", reason));
+                self.error = Some(reason);
+            }
+            // 소개보다 상대의 Punch 가 먼저 올 수도 있다.
+            RendezvousMessage::Punch | RendezvousMessage::PunchAck if from != self.server => {
+                if message == RendezvousMessage::Punch {
+                    transport.send(from, &RendezvousMessage::PunchAck.encode());
+                }
+                match self.punches.iter_mut().find(|punch| punch.peer == from) {
+                    Some(punch) => punch.state = PunchState::Open,
+                    None => self.punches.push(Punch {
+                        peer: from,
+                        host: false,
+                        state: PunchState::Open,
+                        attempts: 0,
+                        next_at: now,
+                    }),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // 등록과 Punch 재시도
+    fn poll(&mut self, transport: &mut dyn Transport, now: u64) {
+        if self.error.is_none() && now >= self.next_register_at {
+            let register = RendezvousMessage::Register {
+                version: RENDEZVOUS_VERSION,
+                room: self.room.clone(),
+            };
+            transport.send(self.server, &register.encode());
+            self.next_register_at = now + REGISTER_INTERVAL_MS;
+        }
+        for punch in self.punches.iter_mut() {
+            if punch.state != PunchState::Punching || now < punch.next_at {
+                continue;
+            }
+            if punch.attempts >= PUNCH_ATTEMPTS {
+                self.log.push(format!("Could not punch through to {}", punch.peer));
+                punch.state = PunchState::Failed;
+                continue;
+            }
+            transport.send(punch.peer, &RendezvousMessage::Punch.encode());
+            punch.attempts += 1;
+            punch.next_at = now + PUNCH_INTERVAL_MS;
+        }
+    }
+}
@@ -0,0 +1,182 @@
+// 중개 서버. 같은 방에 등록한 피어들에게 서로의 공인 주소를 알려 준다. 게임 데이터는 거치지 않는다.
+// 소켓과 상관없이 receive/poll 로 돌리므로 테스트에서는 가짜 네트워크 위에서 돌릴 수 있다.
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use crate::rendezvous::{parse_rendezvous, RendezvousMessage, RENDEZVOUS_VERSION};
+use crate::server_log::ServerLog;
+use crate::spectator::MAX_SPECTATORS;
+use crate::time;
+use crate::udp_net::{MAX_DATAGRAM_SIZE, MAX_PLAYERS};
+
+pub struct RendezvousConfig {
+    // 이 시간 동안 다시 등록하지 않은 피어는 방에서 뺀다.
+    pub idle_timeout_ms: u64,
+    pub max_room_clients: usize,
+    // 등록, 방을 떠남, 거절 같은 일을 한 줄씩 받는다.
+    pub log: ServerLog,
+}
+
+impl RendezvousConfig {
+    pub fn new() -> Self {
+        Self {
+            idle_timeout_ms: 10_000,
+            max_room_clients: MAX_PLAYERS + MAX_SPECTATORS,
+            log: ServerLog::default(),
+        }
+    }
+}
+
+impl Default for RendezvousConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Member {
+    addr: SocketAddr,
+    last_seen: u64,
+}
+
+pub struct RendezvousServer {
+    config: RendezvousConfig,
+    // 방 -> 들어온 순서대로의 피어. 첫 피어가 호스트다.
+    rooms: HashMap<String, Vec<Member>>,
+    outgoing: Vec<(SocketAddr, Vec<u8>)>,
+}
+
+impl RendezvousServer {
+    pub fn new(config: RendezvousConfig) -> Self {
+        Self {
+            config,
+            rooms: HashMap::new(),
+            outgoing: Vec::new(),
+        }
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.rooms.values().map(|members| members.len()).sum()
+    }
+
+    pub fn room_count(&self) -> usize {
+        self.rooms.len()
+    }
+
+    // 소켓으로 돌린다. 돌아오지 않는다.
+    pub fn run(&mut self, socket: UdpSocket) -> ! {
+        socket
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .expect("Failed to set read timeout");
+        let mut buffer = [0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            match socket.recv_from(&mut buffer) {
+                Ok((size, addr)) => self.receive(addr, &buffer[..size], time::monotonic_ms()),
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => {}
+                Err(err) => self.config.log.write(&format!("receive failed: {}", err)),
+            }
+            self.poll(time::monotonic_ms());
+            for (addr, datagram) in self.take_outgoing() {
+                if let Err(err) = socket.send_to(&datagram, addr) {
+                    self.config.log.write(&format!("send to {} failed: {}", addr, err));
+                }
+            }
+        }
+    }
+
+    // from 은 서버가 본 보낸 쪽 주소. NAT 뒤라면 NAT 의 공인 주소다.
+    pub fn receive(&mut self, from: SocketAddr, datagram: &[u8], now: u64) {
+        let Some(RendezvousMessage::Register { version, room }) = parse_rendezvous(datagram) else {
+            return;
+        };
+        if version != RENDEZVOUS_VERSION {
+            self.reject(from, &format!("rendezvous version {} (server {})", version, RENDEZVOUS_VERSION));
+            return;
+        }
+        // 다른 방에 있었으면 옮긴다.
+        let previous = self
+            .rooms
+            .iter()
+            .find(|(name, members)| **name != room && members.iter().any(|member| member.addr == from))
+            .map(|(name, _)| name.clone());
+        if let Some(previous) = previous {
+            self.leave(&previous, from);
+        }
+
+        let members = self.rooms.entry(room.clone()).or_default();
+        match members.iter_mut().find(|member| member.addr == from) {
+            Some(member) => member.last_seen = now,
+            None => {
+                if members.len() >= self.config.max_room_clients {
+                    self.reject(from, "room is full");
+                    return;
+                }
+                self.config.log.write(&format!("{} registered in room {}", from, room));
+                members.push(Member { addr: from, last_seen: now });
+            }
+        }
+
+        // 등록할 때마다 다시 알려 준다. 잃어버린 소개도 다음 등록 때 다시 간다.
+        let members = &self.rooms[&room];
+        let from_is_host = members[0].addr == from;
+        let mut outgoing = vec![(from, RendezvousMessage::Registered { endpoint: from.to_string() })];
+        for (index, member) in members.iter().enumerate() {
+            if member.addr == from {
+                continue;
+            }
+            outgoing.push((
+                from,
+                RendezvousMessage::Introduce {
+                    endpoint: member.addr.to_string(),
+                    host: index == 0,
+                },
+            ));
+            outgoing.push((
+                member.addr,
+                RendezvousMessage::Introduce {
+                    endpoint: from.to_string(),
+                    host: from_is_host,
+                },
+            ));
+        }
+        self.outgoing
+            .extend(outgoing.into_iter().map(|(addr, message)| (addr, message.encode())));
+    }
+
+    // 오래 등록하지 않은 피어를 뺀다.
+    pub fn poll(&mut self, now: u64) {
+        let config = &self.config;
+        for (room, members) in self.rooms.iter_mut() {
+            members.retain(|member| {
+                let alive = now.saturating_sub(member.last_seen) <= config.idle_timeout_ms;
+                if !alive {
+                    config.log.write(&format!("{} left room {}", member.addr, room));
+                }
+                alive
+            });
+        }
+        self.rooms.retain(|_, members| !members.is_empty());
+    }
+
+    // 보낼 (주소, 데이터그램)
+    pub fn take_outgoing(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    fn leave(&mut self, room: &str, addr: SocketAddr) {
+        if let Some(members) = self.rooms.get_mut(room) {
+            members.retain(|member| member.addr != addr);
+            if members.is_empty() {
+                self.rooms.remove(room);
+            }
+        }
+    }
+
+    fn reject(&mut self, addr: SocketAddr, reason: &str) {
+        self.config.log.write(&format!("{} rejected: {}", addr, reason));
+        let error = RendezvousMessage::Error { reason: reason.to_string() };
+        self.outgoing.push((addr, error.encode()));
+    }
+}
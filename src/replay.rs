@@ -0,0 +1,373 @@
+use std::collections::BTreeMap;
+
+use crate::rollback::RollbackSession;
+use crate::simulation::State;
+use crate::udp_net::{Reader, UnpackError, Writer, MAX_PLAYERS, PROTOCOL_VERSION};
+
+const REPLAY_MAGIC: [u8; 4] = *b"P2PR";
+// 파일 형식이 바뀌면 올린다.
+pub const REPLAY_VERSION: u8 = 1;
+// 이 간격의 틱마다 상태를 저장해 두고 탐색할 때 가장 가까운 것부터 다시 돌린다.
+pub const SNAPSHOT_INTERVAL: u64 = 300;
+// 잘못된 파일이 메모리를 다 쓰지 않도록 한 시간으로 자른다.
+const MAX_REPLAY_TICKS: usize = 60 * 60 * 60;
+
+// 한 경기의 기록. 시작 위치와 틱마다 모든 플레이어의 입력만 있으면 같은 경기를 다시 만들 수 있다.
+pub struct Replay {
+    pub protocol_version: u8,
+    pub start_tick: u64,
+    // Connect 로 받은 시작 위치 (id, x, y)
+    pub players: Vec<(u8, f32, f32)>,
+    // start_tick 부터 틱마다 세션의 플레이어 수만큼의 입력
+    pub inputs: Vec<Vec<u8>>,
+}
+
+impl Replay {
+    pub fn new(players: Vec<(u8, f32, f32)>) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            start_tick: 1,
+            players,
+            inputs: Vec::new(),
+        }
+    }
+
+    // 다음에 기록할 틱
+    pub fn next_tick(&self) -> u64 {
+        self.start_tick + self.inputs.len() as u64
+    }
+
+    pub fn last_tick(&self) -> u64 {
+        self.next_tick() - 1
+    }
+
+    pub fn push(&mut self, inputs: Vec<u8>) {
+        self.inputs.push(inputs);
+    }
+
+    pub fn inputs_at(&self, tick: u64) -> Option<&Vec<u8>> {
+        if tick < self.start_tick {
+            return None;
+        }
+        self.inputs.get((tick - self.start_tick) as usize)
+    }
+
+    pub fn new_session(&self) -> RollbackSession {
+        RollbackSession::with_players(&self.players)
+    }
+
+    // 엔진 없이 처음부터 끝까지 돌려서 틱마다 (틱, 상태 해시)
+    pub fn state_hashes(&self) -> Vec<(u64, u64)> {
+        let mut session = self.new_session();
+        let mut hashes = Vec::with_capacity(self.inputs.len());
+        for tick in self.start_tick..self.next_tick() {
+            let inputs = &self.inputs[(tick - self.start_tick) as usize];
+            for (id, _, _) in self.players.iter() {
+                session.add_input(*id, tick, inputs[*id as usize]);
+            }
+            session.advance(tick);
+            hashes.push((tick, session.state().checksum()));
+        }
+        hashes
+    }
+
+    // [magic][형식 버전][프로토콜 버전][start tick][플레이어 수] + 플레이어마다 [id][x][y]
+    // + [틱 수][슬롯 수] + 슬롯마다 [run 수] + run 마다 [길이][입력]
+    // 입력은 오래 유지되는 경우가 많아 플레이어별로 run 으로 묶는다.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.put_array(&REPLAY_MAGIC);
+        w.put_u8(REPLAY_VERSION);
+        w.put_u8(self.protocol_version);
+        w.put_u64(self.start_tick);
+        w.put_u8(self.players.len() as u8);
+        for (id, x, y) in self.players.iter() {
+            w.put_u8(*id);
+            w.put_f32(*x);
+            w.put_f32(*y);
+        }
+
+        let slots = self.inputs.first().map_or(0, |inputs| inputs.len());
+        w.put_varint(self.inputs.len() as u64);
+        w.put_u8(slots as u8);
+        for slot in 0..slots {
+            let mut runs: Vec<(u64, u8)> = Vec::new();
+            for inputs in self.inputs.iter() {
+                match runs.last_mut() {
+                    Some(run) if run.1 == inputs[slot] => run.0 += 1,
+                    _ => runs.push((1, inputs[slot])),
+                }
+            }
+            w.put_varint(runs.len() as u64);
+            for (len, input) in runs {
+                w.put_varint(len);
+                w.put_u8(input);
+            }
+        }
+        w.into_bytes()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, UnpackError> {
+        let mut r = Reader::new(data);
+        let magic = r.get_array::<4>()?;
+        if magic != REPLAY_MAGIC {
+            return Err(UnpackError::BadValue(magic[0]));
+        }
+        let version = r.get_u8()?;
+        if version != REPLAY_VERSION {
+            return Err(UnpackError::VersionMismatch(version));
+        }
+        let protocol_version = r.get_u8()?;
+        let start_tick = r.get_u64()?;
+        let count = r.get_u8()? as usize;
+        if count > MAX_PLAYERS {
+            return Err(UnpackError::BadLength(count));
+        }
+        let mut players = Vec::new();
+        for _ in 0..count {
+            let id = r.get_u8()?;
+            if id as usize >= MAX_PLAYERS {
+                return Err(UnpackError::BadValue(id));
+            }
+            players.push((id, r.get_f32()?, r.get_f32()?));
+        }
+
+        let ticks = r.get_varint()? as usize;
+        let slots = r.get_u8()? as usize;
+        if slots > MAX_PLAYERS {
+            return Err(UnpackError::BadLength(slots));
+        }
+        if ticks > MAX_REPLAY_TICKS {
+            return Err(UnpackError::BadLength(ticks));
+        }
+        // 입력이 있으면 플레이어마다 슬롯이 있어야 한다.
+        if let Some(player) = players.iter().find(|player| ticks > 0 && player.0 as usize >= slots) {
+            return Err(UnpackError::BadValue(player.0));
+        }
+        let mut columns = Vec::new();
+        for _ in 0..slots {
+            let mut column = Vec::new();
+            let runs = r.get_varint()?;
+            for _ in 0..runs {
+                let len = r.get_varint()? as usize;
+                let input = r.get_u8()?;
+                if column.len() + len > ticks {
+                    return Err(UnpackError::BadLength(column.len() + len));
+                }
+                column.extend(std::iter::repeat_n(input, len));
+            }
+            if column.len() != ticks {
+                return Err(UnpackError::Truncated);
+            }
+            columns.push(column);
+        }
+        if r.remaining() > 0 {
+            return Err(UnpackError::TrailingBytes(r.remaining()));
+        }
+
+        let inputs = (0..ticks)
+            .map(|tick| columns.iter().map(|column| column[tick]).collect())
+            .collect();
+        Ok(Self {
+            protocol_version,
+            start_tick,
+            players,
+            inputs,
+        })
+    }
+}
+
+// 재생 중인 리플레이와 탐색용 스냅샷
+pub struct ReplayPlayback {
+    replay: Replay,
+    snapshots: BTreeMap<u64, State>,
+}
+
+impl ReplayPlayback {
+    pub fn new(replay: Replay) -> Self {
+        let mut snapshots = BTreeMap::new();
+        let initial = replay.new_session().state().clone();
+        snapshots.insert(initial.tick, initial);
+        Self { replay, snapshots }
+    }
+
+    pub fn replay(&self) -> &Replay {
+        &self.replay
+    }
+
+    // 틱을 하나 돌릴 때마다 부른다.
+    pub fn on_tick(&mut self, state: &State) {
+        if state.tick.is_multiple_of(SNAPSHOT_INTERVAL) {
+            self.snapshots.entry(state.tick).or_insert_with(|| state.clone());
+        }
+    }
+
+    // tick 이전의 가장 가까운 스냅샷
+    pub fn snapshot_before(&self, tick: u64) -> &State {
+        self.snapshots
+            .range(..=tick)
+            .next_back()
+            .map(|(_, state)| state)
+            .unwrap_or_else(|| self.snapshots.values().next().unwrap())
+    }
+}
+
+// 골든 파일: 한 줄에 "틱 해시(16진수)"
+pub fn format_hashes(hashes: &[(u64, u64)]) -> String {
+    hashes.iter().map(|(tick, hash)| format!("{} {:016x}\n", tick, hash)).collect()
+}
+
+pub fn parse_hashes(text: &str) -> Result<Vec<(u64, u64)>, String> {
+    let mut hashes = Vec::new();
+    for (line_no, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parsed = line.split_once(' ').and_then(|(tick, hash)| {
+            Some((tick.parse::<u64>().ok()?, u64::from_str_radix(hash.trim(), 16).ok()?))
+        });
+        match parsed {
+            Some(entry) => hashes.push(entry),
+            None => return Err(format!("line {}: expected \"<tick> <hash>\"", line_no + 1)),
+        }
+    }
+    Ok(hashes)
+}
+
+// 골든과 처음으로 달라진 곳. 길이가 다르면 짧은 쪽이 끝난 다음 틱을 가리킨다.
+#[derive(Debug, PartialEq, Eq)]
+pub struct HashMismatch {
+    pub tick: u64,
+    pub expected: Option<u64>,
+    pub actual: Option<u64>,
+}
+
+pub fn first_mismatch(expected: &[(u64, u64)], actual: &[(u64, u64)]) -> Option<HashMismatch> {
+    for i in 0..expected.len().max(actual.len()) {
+        let (e, a) = (expected.get(i), actual.get(i));
+        if e != a {
+            return Some(HashMismatch {
+                tick: e.or(a).map_or(0, |entry| entry.0),
+                expected: e.map(|entry| entry.1),
+                actual: a.map(|entry| entry.1),
+            });
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::{INPUT_JUMP, INPUT_LEFT, INPUT_RIGHT};
+
+    // 입력이 길게 유지되다가 가끔 바뀌는 경기
+    fn replay(ticks: u64) -> Replay {
+        let mut replay = Replay::new(vec![(0, 0.0, -5.0), (1, 100.0, -5.0)]);
+        for tick in 1..=ticks {
+            let first = match (tick / 40) % 3 {
+                0 => INPUT_RIGHT,
+                1 => INPUT_LEFT | INPUT_JUMP,
+                _ => 0,
+            };
+            let second = if tick % 97 < 5 { INPUT_JUMP } else { INPUT_LEFT };
+            replay.push(vec![first, second]);
+        }
+        replay
+    }
+
+    #[test]
+    fn round_trip() {
+        let replay = replay(1000);
+        let bytes = replay.to_bytes();
+        // run 으로 묶여서 틱마다 쓰는 것보다 작아야 한다.
+        assert!(bytes.len() < replay.inputs.len());
+
+        let decoded = Replay::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.protocol_version, replay.protocol_version);
+        assert_eq!(decoded.start_tick, replay.start_tick);
+        assert_eq!(decoded.players, replay.players);
+        assert_eq!(decoded.inputs, replay.inputs);
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut bytes = replay(10).to_bytes();
+        bytes[0] = b'X';
+        assert_eq!(Replay::from_bytes(&bytes).err(), Some(UnpackError::BadValue(b'X')));
+    }
+
+    #[test]
+    fn rejects_other_version() {
+        let mut bytes = replay(10).to_bytes();
+        bytes[REPLAY_MAGIC.len()] = REPLAY_VERSION + 1;
+        assert_eq!(
+            Replay::from_bytes(&bytes).err(),
+            Some(UnpackError::VersionMismatch(REPLAY_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn rejects_truncated() {
+        let bytes = replay(100).to_bytes();
+        for len in 0..bytes.len() {
+            assert!(Replay::from_bytes(&bytes[..len]).is_err(), "accepted {} of {} bytes", len, bytes.len());
+        }
+    }
+
+    #[test]
+    fn rejects_run_longer_than_ticks() {
+        let mut w = Writer::new();
+        w.put_array(&REPLAY_MAGIC);
+        w.put_u8(REPLAY_VERSION);
+        w.put_u8(PROTOCOL_VERSION);
+        w.put_u64(1);
+        w.put_u8(1);
+        w.put_u8(0);
+        w.put_f32(0.0);
+        w.put_f32(-5.0);
+        w.put_varint(10);
+        w.put_u8(1);
+        w.put_varint(2);
+        w.put_varint(6);
+        w.put_u8(INPUT_RIGHT);
+        w.put_varint(6);
+        w.put_u8(0);
+        assert_eq!(Replay::from_bytes(&w.into_bytes()).err(), Some(UnpackError::BadLength(12)));
+    }
+
+    // 스냅샷에서 다시 돌린 상태가 처음부터 돌린 것과 같아야 한다.
+    #[test]
+    fn seek_matches_forward_play() {
+        let replay = replay(1000);
+        let hashes = replay.state_hashes();
+        let mut playback = ReplayPlayback::new(replay);
+
+        let mut session = playback.replay().new_session();
+        for tick in 1..=playback.replay().last_tick() {
+            let inputs = playback.replay().inputs_at(tick).unwrap().clone();
+            for (id, input) in inputs.iter().enumerate() {
+                session.add_input(id as u8, tick, *input);
+            }
+            session.advance(tick);
+            playback.on_tick(session.state());
+        }
+
+        for target in [1, 299, 300, 450, 901, 1000] {
+            let snapshot = playback.snapshot_before(target).clone();
+            assert!(snapshot.tick <= target && target - snapshot.tick < SNAPSHOT_INTERVAL);
+            let mut session = playback.replay().new_session();
+            session.restore(snapshot.clone());
+            for tick in snapshot.tick + 1..=target {
+                let inputs = playback.replay().inputs_at(tick).unwrap();
+                for (id, input) in inputs.iter().enumerate() {
+                    session.add_input(id as u8, tick, *input);
+                }
+                session.advance(tick);
+            }
+            assert_eq!(session.state().tick, target);
+            assert_eq!(session.state().checksum(), hashes[(target - 1) as usize].1, "tick {}", target);
+        }
+    }
+}
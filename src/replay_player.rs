@@ -0,0 +1,231 @@
+use godot::engine::INode2D;
+use godot::engine::Node2D;
+use godot::engine::ProjectSettings;
+use godot::prelude::*;
+
+use crate::game_manager::{GAME_TICK, SESSION};
+use crate::player::Player;
+use crate::replay::{Replay, ReplayPlayback};
+use crate::udp_net::PROTOCOL_VERSION;
+
+// 기록된 경기를 다시 돌린다. 입력은 InputController 나 네트워크 대신 여기서 Player 에 넣는다.
+#[derive(GodotClass)]
+#[class(base=Node2D)]
+pub struct ReplayPlayer {
+    base: Base<Node2D>,
+    playback: Option<ReplayPlayback>,
+    paused: bool,
+    // 프레임마다 돌리는 틱 수. 1 이 원래 속도
+    speed: u64,
+}
+
+impl ReplayPlayer {
+    fn player_node(&self, id: u8) -> Option<Gd<Player>> {
+        self.base()
+            .get_node_as::<Node2D>("../")
+            .try_get_node_as::<Player>(format!("ReplayPlayer{}", id).as_str())
+    }
+
+    fn current_tick(&self) -> u64 {
+        SESSION.lock().unwrap().as_ref().map_or(0, |session| session.last_tick())
+    }
+
+    // 다음 틱을 돌린다. 리플레이가 끝났으면 false
+    fn run_tick(&mut self) -> bool {
+        let tick = self.current_tick() + 1;
+        let Some(playback) = self.playback.as_ref() else {
+            return false;
+        };
+        let Some(inputs) = playback.replay().inputs_at(tick).cloned() else {
+            return false;
+        };
+        let ids: Vec<u8> = playback.replay().players.iter().map(|player| player.0).collect();
+        for id in ids {
+            if let Some(mut player) = self.player_node(id) {
+                player.bind_mut().push_replay_input(tick, inputs[id as usize]);
+            }
+        }
+
+        if let (Some(session), Some(playback)) = (SESSION.lock().unwrap().as_mut(), self.playback.as_mut()) {
+            session.advance(tick);
+            playback.on_tick(session.state());
+        }
+        GAME_TICK.lock().unwrap().tick = tick;
+        true
+    }
+
+    fn free_players(&mut self) {
+        let ids: Vec<u8> = self
+            .playback
+            .as_ref()
+            .map(|playback| playback.replay().players.iter().map(|player| player.0).collect())
+            .unwrap_or_default();
+        for id in ids {
+            if let Some(mut player) = self.player_node(id) {
+                player.queue_free();
+            }
+        }
+    }
+}
+
+#[godot_api]
+impl ReplayPlayer {
+    #[signal]
+    fn replay_finished();
+
+    // 경기 중이면 불러오지 않는다.
+    #[func]
+    pub fn load_replay(&mut self, path: GString) -> bool {
+        if self.playback.is_none() && SESSION.lock().unwrap().is_some() {
+            godot_print!("Cannot load a replay during a match");
+            return false;
+        }
+        let path = ProjectSettings::singleton().globalize_path(path).to_string();
+        let replay = match std::fs::read(&path).map(|data| Replay::from_bytes(&data)) {
+            Ok(Ok(replay)) => replay,
+            Ok(Err(err)) => {
+                godot_print!("Invalid replay {} : {}", path, err);
+                return false;
+            }
+            Err(err) => {
+                godot_print!("Failed to read replay {} : {}", path, err);
+                return false;
+            }
+        };
+        // 프로토콜이 다르면 시뮬레이션도 달라졌을 수 있다.
+        if replay.protocol_version != PROTOCOL_VERSION {
+            godot_print!(
+                "Replay was recorded with protocol {} (current {})",
+                replay.protocol_version,
+                PROTOCOL_VERSION
+            );
+            return false;
+        }
+        self.stop();
+
+        *SESSION.lock().unwrap() = Some(replay.new_session());
+        let mut root = self.base().get_node_as::<Node2D>("../");
+        if let Ok(scene) = try_load::<PackedScene>("res://Player/player.tscn") {
+            for (id, x, y) in replay.players.iter() {
+                let player = scene.instantiate_as::<Player>();
+                let mut node = player.clone();
+                node.bind_mut().id = Some(*id);
+                root.add_child(player.upcast::<Node>());
+                node.set_position(Vector2::new(*x, *y));
+                node.set_name(format!("ReplayPlayer{}", id).as_str().into());
+            }
+        }
+        if let Some(mut player) = self.base().try_get_node_as::<Player>("../Player") {
+            player.hide();
+        }
+        godot_print!("Loaded replay {} : {} players, {} ticks", path, replay.players.len(), replay.inputs.len());
+
+        self.playback = Some(ReplayPlayback::new(replay));
+        self.paused = false;
+        self.speed = 1;
+        true
+    }
+
+    #[func]
+    pub fn stop(&mut self) {
+        if self.playback.is_none() {
+            return;
+        }
+        self.free_players();
+        self.playback = None;
+        *SESSION.lock().unwrap() = None;
+        GAME_TICK.lock().unwrap().tick = 0;
+        if let Some(mut player) = self.base().try_get_node_as::<Player>("../Player") {
+            player.show();
+        }
+    }
+
+    #[func]
+    pub fn play(&mut self) {
+        self.paused = false;
+    }
+
+    #[func]
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    #[func]
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // 멈춘 상태에서 한 틱만 돌린다.
+    #[func]
+    pub fn step(&mut self) {
+        self.paused = true;
+        self.run_tick();
+    }
+
+    // 빨리 감기. 프레임마다 speed 틱
+    #[func]
+    pub fn set_speed(&mut self, speed: i64) {
+        self.speed = speed.max(1) as u64;
+    }
+
+    // 가장 가까운 이전 스냅샷으로 돌아가서 tick 까지 다시 돌린다.
+    #[func]
+    pub fn seek(&mut self, tick: i64) {
+        let Some(playback) = self.playback.as_ref() else {
+            return;
+        };
+        let target = (tick.max(0) as u64).min(playback.replay().last_tick());
+        let current = self.current_tick();
+        let snapshot = playback.snapshot_before(target);
+        // 앞으로 가는데 현재 틱이 스냅샷보다 가까우면 그대로 이어 간다.
+        if target < current || snapshot.tick > current {
+            let snapshot = snapshot.clone();
+            if let Some(session) = SESSION.lock().unwrap().as_mut() {
+                session.restore(snapshot);
+            }
+        }
+        while self.current_tick() < target {
+            if !self.run_tick() {
+                break;
+            }
+        }
+        GAME_TICK.lock().unwrap().tick = self.current_tick();
+    }
+
+    #[func]
+    pub fn get_tick(&self) -> i64 {
+        self.current_tick() as i64
+    }
+
+    #[func]
+    pub fn get_last_tick(&self) -> i64 {
+        self.playback
+            .as_ref()
+            .map_or(0, |playback| playback.replay().last_tick() as i64)
+    }
+}
+
+#[godot_api]
+impl INode2D for ReplayPlayer {
+    fn init(base: Base<Node2D>) -> Self {
+        Self {
+            base,
+            playback: None,
+            paused: true,
+            speed: 1,
+        }
+    }
+
+    fn physics_process(&mut self, _delta: f64) {
+        if self.playback.is_none() || self.paused {
+            return;
+        }
+        for _ in 0..self.speed {
+            if !self.run_tick() {
+                self.paused = true;
+                self.base_mut().emit_signal("replay_finished".into(), &[]);
+                break;
+            }
+        }
+    }
+}
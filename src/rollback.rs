@@ -0,0 +1,411 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::fixed::Fixed;
+use crate::simulation::{PlayerState, Simulation, State};
+
+// 이보다 오래된 틱으로는 되돌아가지 않는다.
+pub const MAX_ROLLBACK_TICKS: u64 = 120;
+// 이 간격의 틱마다 확정된 상태의 체크섬을 만든다.
+pub const CHECKSUM_INTERVAL: u64 = 30;
+// 확정된 틱보다 이만큼 앞서면 입력이 올 때까지 멈춘다. 늦게 온 입력도 되돌릴 수 있는 범위 안에 있게 한다.
+pub const MAX_PREDICTION_TICKS: u64 = 60;
+
+// 틱마다 상태를 저장해 두었다가, 과거 틱의 실제 입력이 예측과 다르면
+// 그 틱으로 돌아가 현재 틱까지 다시 시뮬레이션한다.
+pub struct RollbackSession {
+    sim: Simulation,
+    // 플레이어 id 별로 받은 입력. 예측할 때 틱 이전의 마지막 입력을 바로 찾도록 틱 순서로 둔다.
+    inputs: Vec<BTreeMap<u64, u8>>,
+    // 틱 t 를 시뮬레이션하기 직전의 상태
+    snapshots: HashMap<u64, State>,
+    // 틱 t 를 시뮬레이션할 때 사용한 입력 (예측값 포함)
+    used_inputs: HashMap<u64, Vec<u8>>,
+    rollback_to: Option<u64>,
+    // 플레이어별로 처음 받은 입력의 틱. 그 이전 틱은 입력이 없는 것으로 확정이다.
+    first_input: Vec<Option<u64>>,
+    // 플레이어별로 빠짐없이 입력을 받은 마지막 틱
+    confirmed: Vec<u64>,
+    // 나간 플레이어는 확정 계산에서 빼고 마지막 입력을 계속 쓴다
+    active: Vec<bool>,
+    next_checksum_tick: u64,
+}
+
+impl RollbackSession {
+    pub fn new(players: Vec<PlayerState>) -> Self {
+        let count = players.len();
+        Self {
+            inputs: vec![BTreeMap::new(); count],
+            sim: Simulation::new(players),
+            snapshots: HashMap::new(),
+            used_inputs: HashMap::new(),
+            rollback_to: None,
+            first_input: vec![None; count],
+            confirmed: vec![0; count],
+            active: vec![true; count],
+            next_checksum_tick: CHECKSUM_INTERVAL,
+        }
+    }
+
+    // 시작 위치 (id, x, y) 로 만든다. 빠진 id 는 처음부터 나간 플레이어로 둔다.
+    pub fn with_players(start: &[(u8, f32, f32)]) -> Self {
+        let count = start.iter().map(|player| player.0 as usize + 1).max().unwrap_or(0);
+        let mut players = vec![PlayerState::at(Fixed::ZERO, Fixed::ZERO); count];
+        let mut present = vec![false; count];
+        for (id, x, y) in start.iter() {
+            players[*id as usize] = PlayerState::at(Fixed::from_f32(*x), Fixed::from_f32(*y));
+            present[*id as usize] = true;
+        }
+        let mut session = Self::new(players);
+        for (id, present) in present.iter().enumerate() {
+            if !present {
+                session.set_inactive(id as u8);
+            }
+        }
+        session
+    }
+
+    // 리플레이 탐색용. 저장해 둔 state 로 돌아가서 그 다음 틱부터 입력을 새로 받는다.
+    pub fn restore(&mut self, state: State) {
+        let tick = state.tick;
+        let count = self.inputs.len();
+        self.sim.set_state(state);
+        self.snapshots.clear();
+        self.used_inputs.clear();
+        self.rollback_to = None;
+        self.inputs = vec![BTreeMap::new(); count];
+        self.first_input = vec![None; count];
+        self.confirmed = vec![tick; count];
+        self.next_checksum_tick = (tick / CHECKSUM_INTERVAL + 1) * CHECKSUM_INTERVAL;
+    }
+
+    pub fn state(&self) -> &State {
+        self.sim.state()
+    }
+
+    // 마지막으로 시뮬레이션한 틱
+    pub fn last_tick(&self) -> u64 {
+        self.sim.state().tick
+    }
+
+    // 이미 시뮬레이션한 틱의 입력이 바뀌었으면 다음 advance 때 그 틱부터 다시 계산한다.
+    pub fn add_input(&mut self, player: u8, tick: u64, input: u8) -> bool {
+        let player = player as usize;
+        if player >= self.inputs.len() || tick == 0 {
+            return false;
+        }
+        if tick + MAX_ROLLBACK_TICKS <= self.last_tick() {
+            return false;
+        }
+        if self.inputs[player].insert(tick, input) == Some(input) {
+            return true;
+        }
+
+        if self.first_input[player].is_none() {
+            self.first_input[player] = Some(tick);
+            self.confirmed[player] = tick - 1;
+        }
+        while self.inputs[player].contains_key(&(self.confirmed[player] + 1)) {
+            self.confirmed[player] += 1;
+        }
+
+        if let Some(used) = self.used_inputs.get(&tick) {
+            if used[player] != input {
+                self.rollback_to = Some(self.rollback_to.map_or(tick, |t| t.min(tick)));
+            }
+        }
+        true
+    }
+
+    // tick 까지 진행시킨다. 되돌렸다면 되돌아간 틱을 돌려준다.
+    pub fn advance(&mut self, tick: u64) -> Option<u64> {
+        let mut rolled_back = None;
+        if let Some(from) = self.rollback_to.take() {
+            if let Some(snapshot) = self.snapshots.get(&from) {
+                self.sim.set_state(snapshot.clone());
+                rolled_back = Some(from);
+            }
+        }
+
+        while self.last_tick() < tick {
+            let t = self.last_tick() + 1;
+            let inputs: Vec<u8> = self.inputs.iter().map(|i| input_at(i, t)).collect();
+            self.snapshots.insert(t, self.sim.state().clone());
+            self.sim.step(&inputs);
+            self.used_inputs.insert(t, inputs);
+        }
+
+        let oldest = tick.saturating_sub(MAX_ROLLBACK_TICKS);
+        self.snapshots.retain(|t, _| *t >= oldest);
+        self.used_inputs.retain(|t, _| *t >= oldest);
+        for inputs in self.inputs.iter_mut() {
+            prune_inputs(inputs, oldest);
+        }
+
+        rolled_back
+    }
+
+    // 모든 플레이어의 입력이 확정된 마지막 틱
+    pub fn confirmed_tick(&self) -> u64 {
+        let confirmed = self.confirmed.iter().zip(self.active.iter()).filter(|(_, active)| **active);
+        confirmed.map(|(tick, _)| *tick).min().unwrap_or_else(|| self.last_tick())
+    }
+
+    // tick 의 플레이어별 입력. 아직 확정되지 않았거나 이미 지운 틱이면 None
+    pub fn confirmed_inputs(&self, tick: u64) -> Option<Vec<u8>> {
+        if tick == 0 || tick > self.confirmed_tick() || tick + MAX_ROLLBACK_TICKS <= self.last_tick() {
+            return None;
+        }
+        Some(self.inputs.iter().map(|inputs| input_at(inputs, tick)).collect())
+    }
+
+    pub fn player_count(&self) -> usize {
+        self.inputs.len()
+    }
+
+    // 모든 플레이어의 입력을 가졌거나 예측할 수 있는 범위 안이면 tick 을 시뮬레이션해도 된다.
+    pub fn can_advance(&self, tick: u64) -> bool {
+        tick <= self.confirmed_tick() + MAX_PREDICTION_TICKS
+    }
+
+    // 피어마다 받은 입력이 다를 수 있어 어긋나면 체크섬이 잡는다.
+    pub fn set_inactive(&mut self, player: u8) {
+        if let Some(active) = self.active.get_mut(player as usize) {
+            *active = false;
+        }
+    }
+
+    // 새로 확정된 체크섬 틱들의 (틱, 체크섬). 틱마다 한 번씩만 나온다.
+    pub fn take_checksums(&mut self) -> Vec<(u64, u64)> {
+        let mut limit = self.confirmed_tick().min(self.last_tick());
+        // 되돌릴 틱 이후의 스냅샷은 아직 예측값이다
+        if let Some(from) = self.rollback_to {
+            limit = limit.min(from - 1);
+        }
+
+        let mut checksums = Vec::new();
+        while self.next_checksum_tick <= limit {
+            let tick = self.next_checksum_tick;
+            if let Some(state) = self.state_after(tick) {
+                checksums.push((tick, state.checksum()));
+            }
+            self.next_checksum_tick += CHECKSUM_INTERVAL;
+        }
+        checksums
+    }
+
+    fn state_after(&self, tick: u64) -> Option<&State> {
+        if tick == self.last_tick() {
+            Some(self.sim.state())
+        } else {
+            self.snapshots.get(&(tick + 1))
+        }
+    }
+
+    // 디싱크가 났을 때 오프라인 비교용으로 남기는 상태와 최근 입력
+    pub fn dump(&self, tick: u64) -> String {
+        let mut out = String::new();
+        out.push_str(&format!("last_tick: {}\n", self.last_tick()));
+        out.push_str(&format!("confirmed_tick: {}\n", self.confirmed_tick()));
+        match self.state_after(tick) {
+            Some(state) => out.push_str(&format!("state after {}: {:?}\n", tick, state)),
+            None => out.push_str(&format!("state after {}: (no snapshot)\n", tick)),
+        }
+        out.push_str(&format!("current state: {:?}\n", self.sim.state()));
+
+        for (id, inputs) in self.inputs.iter().enumerate() {
+            out.push_str(&format!("player {} inputs:", id));
+            for (t, input) in inputs.iter() {
+                out.push_str(&format!(" {}:{}", t, input));
+            }
+            out.push('\n');
+        }
+        let mut used: Vec<_> = self.used_inputs.iter().collect();
+        used.sort();
+        out.push_str("used inputs:");
+        for (t, inputs) in used {
+            out.push_str(&format!(" {}:{:?}", t, inputs));
+        }
+        out.push('\n');
+        out
+    }
+}
+
+// 입력이 없는 틱은 가장 최근에 알려진 입력을 반복한다.
+// 보내는 쪽과 받는 쪽이 같은 규칙을 쓰므로 빠진 입력이 도착하면 롤백으로 맞춰진다.
+pub fn input_at(inputs: &BTreeMap<u64, u8>, tick: u64) -> u8 {
+    inputs.range(..=tick).next_back().map_or(0, |(_, input)| *input)
+}
+
+// oldest 보다 오래된 입력을 지우되, 예측에 쓰일 가장 최근 것 하나는 남긴다.
+pub fn prune_inputs(inputs: &mut BTreeMap<u64, u8>, oldest: u64) {
+    let keep = inputs.range(..oldest).next_back().map(|(t, _)| *t);
+    inputs.retain(|t, _| *t >= oldest || Some(*t) == keep);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::{INPUT_JUMP, INPUT_LEFT, INPUT_RIGHT};
+
+    fn session() -> RollbackSession {
+        RollbackSession::with_players(&[(0, 0.0, -5.0), (1, 100.0, -5.0)])
+    }
+
+    // 플레이어별로 틱마다 다른 입력
+    fn input(player: u8, tick: u64) -> u8 {
+        match (tick + player as u64 * 7) % 11 {
+            0..=3 => INPUT_RIGHT,
+            4..=6 => INPUT_LEFT | INPUT_JUMP,
+            7 => INPUT_JUMP,
+            _ => 0,
+        }
+    }
+
+    // 모든 입력을 제때 받았을 때의 상태
+    fn straight_line(ticks: u64) -> State {
+        let mut session = session();
+        for tick in 1..=ticks {
+            session.add_input(0, tick, input(0, tick));
+            session.add_input(1, tick, input(1, tick));
+            assert_eq!(session.advance(tick), None);
+        }
+        session.state().clone()
+    }
+
+    #[test]
+    fn late_inputs_roll_back_to_the_straight_line_state() {
+        let mut session = session();
+        let mut rollbacks = 0;
+        for tick in 1..=60 {
+            session.add_input(0, tick, input(0, tick));
+            // 플레이어 1 의 입력은 20 틱 늦게 온다.
+            if tick > 20 {
+                session.add_input(1, tick - 20, input(1, tick - 20));
+            }
+            if let Some(from) = session.advance(tick) {
+                assert_eq!(from, tick - 20);
+                rollbacks += 1;
+            }
+        }
+        assert!(rollbacks > 0);
+        for tick in 41..=60 {
+            session.add_input(1, tick, input(1, tick));
+        }
+        assert_eq!(session.advance(60), Some(41));
+        assert_eq!(session.state(), &straight_line(60));
+        assert_eq!(session.advance(60), None);
+    }
+
+    #[test]
+    fn rolls_back_to_the_earliest_changed_tick() {
+        let mut session = session();
+        session.advance(10);
+        session.add_input(1, 8, INPUT_RIGHT);
+        session.add_input(1, 4, INPUT_LEFT);
+        assert_eq!(session.advance(10), Some(4));
+        // 예측과 같은 입력은 되돌리지 않는다.
+        session.add_input(0, 9, 0);
+        assert_eq!(session.advance(10), None);
+    }
+
+    #[test]
+    fn predicts_the_last_known_input() {
+        let mut session = session();
+        session.add_input(0, 3, INPUT_RIGHT);
+        session.advance(6);
+        assert_eq!(session.used_inputs[&2], vec![0, 0]);
+        assert_eq!(session.used_inputs[&6], vec![INPUT_RIGHT, 0]);
+    }
+
+    #[test]
+    fn keeps_snapshots_only_within_the_rollback_window() {
+        let mut session = session();
+        let last = MAX_ROLLBACK_TICKS + 50;
+        for tick in 1..=last {
+            session.add_input(0, tick, input(0, tick));
+            session.add_input(1, tick, input(1, tick));
+            session.advance(tick);
+        }
+        let oldest = last - MAX_ROLLBACK_TICKS;
+        assert_eq!(session.snapshots.len() as u64, MAX_ROLLBACK_TICKS + 1);
+        assert!(session.snapshots.contains_key(&oldest));
+        assert!(!session.snapshots.contains_key(&(oldest - 1)));
+        assert_eq!(session.snapshots[&last].tick, last - 1);
+
+        // 창 밖의 입력은 받지 않고, 창 안쪽은 되돌린다.
+        assert!(!session.add_input(1, oldest, INPUT_JUMP ^ input(1, oldest)));
+        assert_eq!(session.advance(last), None);
+        assert!(session.add_input(1, oldest + 1, INPUT_JUMP ^ input(1, oldest + 1)));
+        assert_eq!(session.advance(last), Some(oldest + 1));
+        assert_eq!(session.confirmed_inputs(oldest), None);
+        assert!(session.confirmed_inputs(oldest + 1).is_some());
+    }
+
+    #[test]
+    fn confirms_contiguous_inputs_of_active_players() {
+        let mut session = session();
+        assert_eq!(session.confirmed_tick(), 0);
+        // 처음 받은 입력의 앞쪽 틱은 입력이 없는 것으로 확정이다.
+        session.add_input(0, 5, INPUT_RIGHT);
+        session.add_input(1, 1, 0);
+        session.add_input(1, 2, 0);
+        assert_eq!(session.confirmed_tick(), 2);
+        // 빈 틱이 채워질 때까지 확정이 멈춘다.
+        session.add_input(1, 4, 0);
+        assert_eq!(session.confirmed_tick(), 2);
+        session.add_input(1, 3, 0);
+        session.add_input(1, 5, 0);
+        session.add_input(1, 6, 0);
+        assert_eq!(session.confirmed_tick(), 5);
+        assert_eq!(session.confirmed_inputs(5), Some(vec![INPUT_RIGHT, 0]));
+        assert_eq!(session.confirmed_inputs(6), None);
+        assert!(session.can_advance(5 + MAX_PREDICTION_TICKS));
+        assert!(!session.can_advance(6 + MAX_PREDICTION_TICKS));
+
+        session.set_inactive(0);
+        assert_eq!(session.confirmed_tick(), 6);
+    }
+
+    #[test]
+    fn missing_ids_start_inactive() {
+        let mut session = RollbackSession::with_players(&[(0, 0.0, 0.0), (2, 0.0, 0.0)]);
+        assert_eq!(session.player_count(), 3);
+        session.add_input(0, 1, 0);
+        session.add_input(2, 1, 0);
+        assert_eq!(session.confirmed_tick(), 1);
+    }
+
+    #[test]
+    fn emits_each_confirmed_checksum_once() {
+        let mut session = session();
+        let last = CHECKSUM_INTERVAL * 2 + 5;
+        for tick in 1..=last {
+            session.add_input(0, tick, input(0, tick));
+            session.advance(tick);
+        }
+        // 플레이어 1 의 입력이 없어서 아직 아무것도 확정되지 않았다.
+        assert!(session.take_checksums().is_empty());
+        for tick in 1..=last {
+            session.add_input(1, tick, input(1, tick));
+        }
+        // 되돌리기 전의 예측 상태로는 만들지 않는다.
+        assert!(session.take_checksums().is_empty());
+        session.advance(last);
+        let checksums = session.take_checksums();
+        let ticks: Vec<u64> = checksums.iter().map(|(tick, _)| *tick).collect();
+        assert_eq!(ticks, vec![CHECKSUM_INTERVAL, CHECKSUM_INTERVAL * 2]);
+        assert_eq!(checksums[0].1, straight_line(CHECKSUM_INTERVAL).checksum());
+        assert!(session.take_checksums().is_empty());
+    }
+
+    #[test]
+    fn prunes_all_but_the_last_old_input() {
+        let mut inputs: BTreeMap<u64, u8> = [(1, 1), (3, 3), (5, 5), (9, 9)].into_iter().collect();
+        assert_eq!(input_at(&inputs, 0), 0);
+        assert_eq!(input_at(&inputs, 4), 3);
+        assert_eq!(input_at(&inputs, 100), 9);
+        prune_inputs(&mut inputs, 6);
+        assert_eq!(inputs.keys().copied().collect::<Vec<_>>(), vec![5, 9]);
+        assert_eq!(input_at(&inputs, 7), 5);
+    }
+}
@@ -0,0 +1,256 @@
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::udp_net::UnpackError;
+
+// 방 코드는 짧아서 추측할 수 있으니 키 교환과 섞어서만 쓴다.
+pub fn psk_from_room_code(room_code: &str) -> [u8; 32] {
+    let code = room_code.trim().to_uppercase();
+    let hkdf = Hkdf::<Sha256>::new(Some(b"p2pactiongame room code"), code.as_bytes());
+    let mut psk = [0u8; 32];
+    hkdf.expand(b"psk", &mut psk).expect("32 bytes is a valid hkdf length");
+    psk
+}
+
+// 중계 서버에 알려 주는 방 이름. 서버가 방 코드를 알면 PSK 도 알게 되므로 코드에서 따로 만든다.
+pub fn relay_room_from_code(room_code: &str) -> String {
+    room_name_from_code(room_code, b"relay room")
+}
+
+// 중개 서버에 알려 주는 방 이름. 중계 서버와 같은 곳에서 돌려도 서로 다른 이름이 된다.
+pub fn rendezvous_room_from_code(room_code: &str) -> String {
+    room_name_from_code(room_code, b"rendezvous room")
+}
+
+fn room_name_from_code(room_code: &str, info: &[u8]) -> String {
+    let code = room_code.trim().to_uppercase();
+    let hkdf = Hkdf::<Sha256>::new(Some(b"p2pactiongame room code"), code.as_bytes());
+    let mut room = [0u8; 16];
+    hkdf.expand(info, &mut room).expect("16 bytes is a valid hkdf length");
+    room.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// X25519 공유 비밀과 PSK 로 방향별 키를 만든다. (먼저 보낸 쪽 -> 답한 쪽, 답한 쪽 -> 먼저 보낸 쪽)
+pub fn derive_keys(
+    shared: &[u8; 32],
+    psk: Option<&[u8; 32]>,
+    initiator_nonce: u64,
+    responder_nonce: u64,
+) -> ([u8; 32], [u8; 32]) {
+    let mut salt = [0u8; 16];
+    salt[..8].copy_from_slice(&initiator_nonce.to_le_bytes());
+    salt[8..].copy_from_slice(&responder_nonce.to_le_bytes());
+    let mut ikm = [0u8; 64];
+    ikm[..32].copy_from_slice(shared);
+    if let Some(psk) = psk {
+        ikm[32..].copy_from_slice(psk);
+    }
+
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), &ikm);
+    let mut initiator_to_responder = [0u8; 32];
+    let mut responder_to_initiator = [0u8; 32];
+    hkdf.expand(b"initiator to responder", &mut initiator_to_responder)
+        .expect("32 bytes is a valid hkdf length");
+    hkdf.expand(b"responder to initiator", &mut responder_to_initiator)
+        .expect("32 bytes is a valid hkdf length");
+    (initiator_to_responder, responder_to_initiator)
+}
+
+// 가장 큰 카운터와 그 아래 64개를 받았는지 기억한다.
+pub struct ReplayWindow {
+    highest: u64,
+    bits: u64,
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self { highest: 0, bits: 0 }
+    }
+
+    pub fn check(&self, counter: u64) -> bool {
+        if counter == 0 {
+            return false;
+        }
+        if counter > self.highest {
+            return true;
+        }
+        let offset = self.highest - counter;
+        offset < 64 && self.bits & (1 << offset) == 0
+    }
+
+    pub fn mark(&mut self, counter: u64) {
+        if counter > self.highest {
+            let shift = counter - self.highest;
+            self.bits = if shift >= 64 { 0 } else { self.bits << shift };
+            self.bits |= 1;
+            self.highest = counter;
+        } else {
+            self.bits |= 1 << (self.highest - counter);
+        }
+    }
+}
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 데이터그램 본문을 ChaCha20-Poly1305 로 감싼다.
+// nonce 는 보내는 카운터이고, 헤더와 카운터를 AAD 로 묶는다.
+pub struct SecureChannel {
+    send: ChaCha20Poly1305,
+    recv: ChaCha20Poly1305,
+    send_counter: u64,
+    replay: ReplayWindow,
+}
+
+impl SecureChannel {
+    pub fn new(send_key: &[u8; 32], recv_key: &[u8; 32]) -> Self {
+        Self {
+            send: ChaCha20Poly1305::new(Key::from_slice(send_key)),
+            recv: ChaCha20Poly1305::new(Key::from_slice(recv_key)),
+            send_counter: 0,
+            replay: ReplayWindow::new(),
+        }
+    }
+
+    // 암호화한 본문: [카운터][암호문 + 태그]
+    pub fn seal(&mut self, header: &[u8], body: &[u8]) -> Vec<u8> {
+        self.send_counter += 1;
+        let counter = self.send_counter.to_le_bytes();
+        let aad = [header, &counter].concat();
+        let ciphertext = self
+            .send
+            .encrypt(&nonce(self.send_counter), Payload { msg: body, aad: &aad })
+            .expect("chacha20poly1305 encryption does not fail");
+        [&counter[..], &ciphertext].concat()
+    }
+
+    pub fn open(&mut self, header: &[u8], sealed: &[u8]) -> Result<Vec<u8>, UnpackError> {
+        if sealed.len() < 8 {
+            return Err(UnpackError::Truncated);
+        }
+        let (counter_bytes, ciphertext) = sealed.split_at(8);
+        let counter = u64::from_le_bytes(counter_bytes.try_into().unwrap());
+        if !self.replay.check(counter) {
+            return Err(UnpackError::Replayed(counter));
+        }
+        let aad = [header, counter_bytes].concat();
+        let body = self
+            .recv
+            .decrypt(&nonce(counter), Payload { msg: ciphertext, aad: &aad })
+            .map_err(|_| UnpackError::Unauthenticated)?;
+        // 인증된 뒤에만 윈도를 움직인다.
+        self.replay.mark(counter);
+        Ok(body)
+    }
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(&counter.to_le_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &[u8] = b"header";
+
+    fn pair() -> (SecureChannel, SecureChannel) {
+        let (to_responder, to_initiator) = derive_keys(&[7; 32], Some(&psk_from_room_code("abcd")), 1, 2);
+        (
+            SecureChannel::new(&to_responder, &to_initiator),
+            SecureChannel::new(&to_initiator, &to_responder),
+        )
+    }
+
+    #[test]
+    fn round_trips_both_ways() {
+        let (mut initiator, mut responder) = pair();
+        let sealed = initiator.seal(HEADER, b"input");
+        assert_eq!(sealed.len(), b"input".len() + crate::udp_net::SEAL_OVERHEAD);
+        assert_eq!(responder.open(HEADER, &sealed).unwrap(), b"input");
+        let sealed = responder.seal(HEADER, b"reply");
+        assert_eq!(initiator.open(HEADER, &sealed).unwrap(), b"reply");
+        // 방향마다 키가 달라서 자기가 보낸 것은 못 연다.
+        let sealed = initiator.seal(HEADER, b"input");
+        assert_eq!(initiator.open(HEADER, &sealed), Err(UnpackError::Unauthenticated));
+    }
+
+    #[test]
+    fn rejects_any_tampered_byte() {
+        let (mut initiator, mut responder) = pair();
+        let sealed = initiator.seal(HEADER, b"tamper me");
+        for i in 8..sealed.len() {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 0x01;
+            assert_eq!(responder.open(HEADER, &tampered), Err(UnpackError::Unauthenticated));
+        }
+        // 카운터를 바꾸면 nonce 와 AAD 가 달라진다.
+        let mut tampered = sealed.clone();
+        tampered[0] ^= 0x02;
+        assert_eq!(responder.open(HEADER, &tampered), Err(UnpackError::Unauthenticated));
+        // 헤더도 인증한다.
+        assert_eq!(responder.open(b"other!", &sealed), Err(UnpackError::Unauthenticated));
+        assert_eq!(responder.open(HEADER, &sealed[..7]), Err(UnpackError::Truncated));
+        // 실패한 것들은 윈도를 움직이지 않는다.
+        assert_eq!(responder.open(HEADER, &sealed).unwrap(), b"tamper me");
+    }
+
+    #[test]
+    fn rejects_replayed_datagrams() {
+        let (mut initiator, mut responder) = pair();
+        let first = initiator.seal(HEADER, b"1");
+        let second = initiator.seal(HEADER, b"2");
+        assert!(responder.open(HEADER, &second).is_ok());
+        // 순서가 바뀐 것은 받지만 두 번은 받지 않는다.
+        assert!(responder.open(HEADER, &first).is_ok());
+        assert_eq!(responder.open(HEADER, &first), Err(UnpackError::Replayed(1)));
+        assert_eq!(responder.open(HEADER, &second), Err(UnpackError::Replayed(2)));
+    }
+
+    #[test]
+    fn different_room_codes_do_not_agree() {
+        let (mut initiator, _) = pair();
+        let (to_responder, to_initiator) = derive_keys(&[7; 32], Some(&psk_from_room_code("abce")), 1, 2);
+        let mut stranger = SecureChannel::new(&to_initiator, &to_responder);
+        let sealed = initiator.seal(HEADER, b"secret");
+        assert_eq!(stranger.open(HEADER, &sealed), Err(UnpackError::Unauthenticated));
+        // 방 코드는 대소문자와 앞뒤 공백을 가리지 않는다.
+        assert_eq!(psk_from_room_code(" abcd "), psk_from_room_code("ABCD"));
+        assert_ne!(relay_room_from_code("abcd"), rendezvous_room_from_code("abcd"));
+    }
+
+    #[test]
+    fn replay_window_edges() {
+        let mut window = ReplayWindow::new();
+        assert!(!window.check(0));
+        assert!(window.check(1));
+        window.mark(100);
+        assert!(!window.check(100));
+        assert!(window.check(101));
+        // 가장 큰 카운터 아래 63 까지만 기억한다.
+        assert!(window.check(37));
+        assert!(!window.check(36));
+        window.mark(37);
+        assert!(!window.check(37));
+        assert!(window.check(38));
+
+        // 한 번에 64 이상 건너뛰면 예전 것은 모두 잊는다.
+        window.mark(164);
+        assert!(window.check(101));
+        assert!(!window.check(100));
+        window.mark(1000);
+        assert!(window.check(999));
+        assert!(!window.check(164));
+        window.mark(u64::MAX);
+        assert!(!window.check(u64::MAX));
+        assert!(window.check(u64::MAX - 63));
+        assert!(!window.check(u64::MAX - 64));
+    }
+}
@@ -0,0 +1,20 @@
+// 서버가 한 줄씩 남기는 로그를 받을 곳. 기본값은 아무것도 남기지 않는다.
+#[derive(Clone, Copy, Default)]
+pub struct ServerLog(Option<fn(&str)>);
+
+impl ServerLog {
+    // 바이너리에서 쓴다.
+    pub fn stdout() -> Self {
+        Self(Some(print_line))
+    }
+
+    pub fn write(&self, text: &str) {
+        if let Some(log) = self.0 {
+            log(text);
+        }
+    }
+}
+
+fn print_line(text: &str) {
+    println!("{}", text);
+}
@@ -0,0 +1,275 @@
+use rand_core::{OsRng, RngCore};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::secure::{derive_keys, SecureChannel};
+use crate::transport::PeerId;
+use crate::udp_net::{self, UnpackError, DATAGRAM_PLAIN, DATAGRAM_SEALED};
+
+// 같은 주소로 challenge 를 다시 보내기까지 기다리는 시간 (ms)
+pub const MIGRATION_RETRY_MS: u64 = 1000;
+
+// 상대가 추측할 수 없어야 하므로 OS 난수를 쓴다. 0 은 "없음" 이라 쓰지 않는다.
+pub fn random_nonce() -> u64 {
+    loop {
+        let nonce = OsRng.next_u64();
+        if nonce != 0 {
+            return nonce;
+        }
+    }
+}
+
+// 양쪽 nonce 로 세션 ID 를 만든다. 0 은 "세션 없음" 이라 쓰지 않는다.
+pub fn session_id(initiator_nonce: u64, responder_nonce: u64) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in initiator_nonce.to_le_bytes().iter().chain(responder_nonce.to_le_bytes().iter()) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash.max(1)
+}
+
+struct Migration {
+    addr: PeerId,
+    challenge: u64,
+    sent_at: u64,
+}
+
+// Connect 의 nonce 교환으로 만든 세션. 데이터그램 헤더의 세션 ID 가 맞아야 받는다.
+// 보안 모드면 Connect 로 공개키도 교환해서 이후 데이터그램을 암호화한다.
+pub struct Session {
+    // 우리가 보낸 Connect 의 nonce
+    local_nonce: u64,
+    // 0 이면 아직 세션이 없다.
+    id: u64,
+    migration: Option<Migration>,
+    // 보안 모드일 때 아직 쓰지 않은 키
+    secret: Option<EphemeralSecret>,
+    public_key: [u8; 32],
+    psk: Option<[u8; 32]>,
+    secure: Option<SecureChannel>,
+    // 우리가 먼저 Connect 를 보냈는지
+    initiator: bool,
+    // 상대가 암호화한 데이터그램을 보낸 적이 있는지
+    peer_sealed: bool,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self {
+            local_nonce: random_nonce(),
+            id: 0,
+            migration: None,
+            secret: None,
+            public_key: [0; 32],
+            psk: None,
+            secure: None,
+            initiator: false,
+            peer_sealed: false,
+        }
+    }
+
+    // psk 는 방 코드에서 만든 키
+    pub fn new_secure(psk: Option<[u8; 32]>) -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public_key = PublicKey::from(&secret).to_bytes();
+        Self {
+            secret: Some(secret),
+            public_key,
+            psk,
+            ..Self::new()
+        }
+    }
+
+    // Connect 에 넣을 공개키. 보안 모드가 아니면 0
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public_key
+    }
+
+    // 키 교환을 끝내고 방향별 키를 정한다. 양쪽 모드가 다르면 실패
+    fn agree(&mut self, remote_public: [u8; 32], initiator_nonce: u64, responder_nonce: u64) -> bool {
+        let remote_secure = remote_public != [0; 32];
+        let secret = match self.secret.take() {
+            Some(secret) if remote_secure => secret,
+            None => return !remote_secure,
+            Some(secret) => {
+                self.secret = Some(secret);
+                return false;
+            }
+        };
+        let shared = secret.diffie_hellman(&PublicKey::from(remote_public));
+        let (to_responder, to_initiator) =
+            derive_keys(shared.as_bytes(), self.psk.as_ref(), initiator_nonce, responder_nonce);
+        self.secure = Some(if self.initiator {
+            SecureChannel::new(&to_responder, &to_initiator)
+        } else {
+            SecureChannel::new(&to_initiator, &to_responder)
+        });
+        true
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn local_nonce(&self) -> u64 {
+        self.local_nonce
+    }
+
+    // 상대가 먼저 Connect 를 보냈다. 답장에 넣을 nonce 를 돌려준다. 보안 모드가 다르면 None
+    pub fn accept(&mut self, remote_nonce: u64, remote_public: [u8; 32]) -> Option<u64> {
+        let local_nonce = random_nonce();
+        self.initiator = false;
+        if !self.agree(remote_public, remote_nonce, local_nonce) {
+            return None;
+        }
+        self.local_nonce = local_nonce;
+        self.id = session_id(remote_nonce, self.local_nonce);
+        Some(self.local_nonce)
+    }
+
+    // 우리 Connect 에 대한 답장. 우리 nonce 를 돌려줬고 헤더의 세션 ID 가 맞아야 한다.
+    pub fn complete(&mut self, echo: u64, remote_nonce: u64, header: u64, remote_public: [u8; 32]) -> bool {
+        let id = session_id(self.local_nonce, remote_nonce);
+        if echo != self.local_nonce || header != id {
+            return false;
+        }
+        self.initiator = true;
+        if !self.agree(remote_public, self.local_nonce, remote_nonce) {
+            return false;
+        }
+        self.id = id;
+        true
+    }
+
+    // 본문을 데이터그램으로 만든다.
+    // 답한 쪽은 상대가 키를 받았는지 모르므로 상대가 암호문을 보내올 때까지 평문으로 보낸다.
+    pub fn wrap(&mut self, body: &[u8]) -> Vec<u8> {
+        match self.secure.as_mut() {
+            Some(secure) if self.initiator || self.peer_sealed => {
+                let header = udp_net::datagram_header(self.id, DATAGRAM_SEALED);
+                [&header[..], &secure.seal(&header, body)].concat()
+            }
+            _ => [&udp_net::datagram_header(self.id, DATAGRAM_PLAIN)[..], body].concat(),
+        }
+    }
+
+    // 데이터그램에서 (세션 ID, 평문 본문)
+    // 보안 세션이 생긴 뒤의 평문은 먼저 보낸 쪽이 상대의 첫 암호문을 받기 전까지만 받는다.
+    pub fn unwrap(&mut self, datagram: &[u8]) -> Result<(u64, Vec<u8>), UnpackError> {
+        let (session, kind, body) = udp_net::split_datagram(datagram)?;
+        let header = &datagram[..udp_net::DATAGRAM_HEADER_SIZE];
+        match (kind, self.secure.as_mut()) {
+            (DATAGRAM_SEALED, Some(secure)) => {
+                let body = secure.open(header, body)?;
+                self.peer_sealed = true;
+                Ok((session, body))
+            }
+            (DATAGRAM_SEALED, None) => Err(UnpackError::Unauthenticated),
+            (_, Some(_)) if !self.initiator || self.peer_sealed => Err(UnpackError::Unauthenticated),
+            _ => Ok((session, body.to_vec())),
+        }
+    }
+
+    // 피어가 아닌 주소에서 우리 세션 ID 로 데이터가 왔다. 그 주소로 보낼 challenge
+    pub fn challenge(&mut self, addr: PeerId, now: u64) -> Option<u64> {
+        if let Some(migration) = self.migration.as_ref() {
+            if migration.addr == addr && now.saturating_sub(migration.sent_at) < MIGRATION_RETRY_MS {
+                return None;
+            }
+        }
+        let challenge = random_nonce();
+        self.migration = Some(Migration { addr, challenge, sent_at: now });
+        Some(challenge)
+    }
+
+    // 새 주소에서 challenge 를 돌려받으면 그 주소를 피어로 바꿔도 된다.
+    pub fn verify_migration(&mut self, addr: PeerId, challenge: u64) -> bool {
+        match self.migration.as_ref() {
+            Some(migration) if migration.addr == addr && migration.challenge == challenge => {
+                self.migration = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 먼저 보낸 쪽과 답한 쪽. 답한 쪽은 이미 세션을 만들었다.
+    fn handshake() -> (Session, Session, u64) {
+        let initiator = Session::new();
+        let mut responder = Session::new();
+        let nonce = responder.accept(initiator.local_nonce(), [0; 32]).unwrap();
+        (initiator, responder, nonce)
+    }
+
+    #[test]
+    fn both_sides_derive_the_same_session_id() {
+        let (mut initiator, responder, nonce) = handshake();
+        assert!(initiator.complete(initiator.local_nonce(), nonce, responder.id(), [0; 32]));
+        assert_eq!(initiator.id(), responder.id());
+        assert_ne!(initiator.id(), 0);
+    }
+
+    #[test]
+    fn rejects_a_reply_that_does_not_echo_our_nonce() {
+        let (mut initiator, responder, nonce) = handshake();
+        let echo = initiator.local_nonce().wrapping_add(1);
+        assert!(!initiator.complete(echo, nonce, responder.id(), [0; 32]));
+        assert_eq!(initiator.id(), 0);
+    }
+
+    #[test]
+    fn rejects_a_reply_with_the_wrong_session_id() {
+        let (mut initiator, responder, nonce) = handshake();
+        let echo = initiator.local_nonce();
+        assert!(!initiator.complete(echo, nonce, responder.id() ^ 1, [0; 32]));
+        assert!(!initiator.complete(echo, nonce, 0, [0; 32]));
+        assert_eq!(initiator.id(), 0);
+    }
+
+    #[test]
+    fn unwraps_the_session_id_from_the_header() {
+        let (mut initiator, mut responder, nonce) = handshake();
+        assert!(initiator.complete(initiator.local_nonce(), nonce, responder.id(), [0; 32]));
+        let datagram = initiator.wrap(b"body");
+        assert_eq!(responder.unwrap(&datagram), Ok((responder.id(), b"body".to_vec())));
+    }
+
+    #[test]
+    fn migrates_only_when_the_new_address_echoes_the_challenge() {
+        let (_, mut responder, _) = handshake();
+        let old = PeerId(1);
+        let new = PeerId(2);
+        let challenge = responder.challenge(new, 1000).unwrap();
+        // 다른 주소나 다른 값으로 돌려준 것은 받지 않는다.
+        assert!(!responder.verify_migration(old, challenge));
+        assert!(!responder.verify_migration(new, challenge ^ 1));
+        assert!(responder.verify_migration(new, challenge));
+        // 한 번 쓴 challenge 는 다시 쓸 수 없다.
+        assert!(!responder.verify_migration(new, challenge));
+    }
+
+    #[test]
+    fn waits_before_challenging_the_same_address_again() {
+        let (_, mut responder, _) = handshake();
+        let addr = PeerId(2);
+        let first = responder.challenge(addr, 1000).unwrap();
+        assert_eq!(responder.challenge(addr, 1000 + MIGRATION_RETRY_MS - 1), None);
+        // 다른 주소는 바로 보내고, 그 뒤로는 새 challenge 만 받는다.
+        let other = responder.challenge(PeerId(3), 1000).unwrap();
+        assert!(!responder.verify_migration(addr, first));
+        assert!(responder.verify_migration(PeerId(3), other));
+        let again = responder.challenge(addr, 1000 + MIGRATION_RETRY_MS).unwrap();
+        assert!(responder.verify_migration(addr, again));
+    }
+}
@@ -0,0 +1,263 @@
+// 엔진과 무관한 게임 규칙. Godot 노드는 여기서 나온 State 를 그리기만 한다.
+// 모든 값은 고정소수점이고, 엔진의 delta 대신 고정된 틱 길이를 쓴다.
+
+use crate::fixed::Fixed;
+
+pub const TICKS_PER_SECOND: i64 = 60;
+
+// 초당 값들. 틱당 값은 아래에서 TICKS_PER_SECOND 로 나눠 만든다.
+pub const SPEED: i64 = 400;
+pub const GRAVITY: i64 = 100;
+
+pub const RUN_PER_TICK: Fixed = Fixed::from_ratio(SPEED, TICKS_PER_SECOND);
+pub const GRAVITY_PER_TICK: Fixed = Fixed::from_ratio(GRAVITY, TICKS_PER_SECOND);
+pub const JUMP_VELOCITY: Fixed = Fixed::from_int(-30); //-725
+//Maximum speed at which the player can fall.
+pub const TERMINAL_VELOCITY: Fixed = Fixed::from_int(700);
+pub const FLOOR_Y: Fixed = Fixed::from_int(-5);
+
+pub const INPUT_RIGHT: u8 = 0b0001;
+pub const INPUT_LEFT: u8 = 0b0010;
+pub const INPUT_JUMP: u8 = 0b0100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PlayerState {
+    pub x: Fixed,
+    pub y: Fixed,
+    pub vel_x: Fixed,
+    pub vel_y: Fixed,
+    pub facing: i8,
+    pub running: bool,
+}
+
+impl PlayerState {
+    pub fn at(x: Fixed, y: Fixed) -> Self {
+        Self {
+            x,
+            y,
+            vel_x: Fixed::ZERO,
+            vel_y: Fixed::ZERO,
+            facing: 1,
+            running: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct State {
+    pub tick: u64,
+    // 플레이어 id 순서
+    pub players: Vec<PlayerState>,
+}
+
+impl State {
+    // FNV-1a. 기계나 러스트 버전에 상관없이 같은 상태면 같은 값이 나와야 한다.
+    pub fn checksum(&self) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        let mut feed = |bytes: &[u8]| {
+            for b in bytes {
+                hash ^= *b as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        };
+        feed(&self.tick.to_le_bytes());
+        for player in self.players.iter() {
+            feed(&player.x.raw().to_le_bytes());
+            feed(&player.y.raw().to_le_bytes());
+            feed(&player.vel_x.raw().to_le_bytes());
+            feed(&player.vel_y.raw().to_le_bytes());
+            feed(&[player.facing as u8, player.running as u8]);
+        }
+        hash
+    }
+}
+
+pub struct Simulation {
+    state: State,
+}
+
+impl Simulation {
+    pub fn new(players: Vec<PlayerState>) -> Self {
+        Self {
+            state: State { tick: 0, players },
+        }
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    pub fn set_state(&mut self, state: State) {
+        self.state = state;
+    }
+
+    // inputs_by_player[id] 가 플레이어 id 의 이번 틱 입력
+    pub fn step(&mut self, inputs_by_player: &[u8]) -> &State {
+        for (id, player) in self.state.players.iter_mut().enumerate() {
+            let input = inputs_by_player.get(id).copied().unwrap_or(0);
+            *player = step_player(player, input);
+        }
+        self.state.tick += 1;
+        &self.state
+    }
+}
+
+pub fn step_player(state: &PlayerState, input: u8) -> PlayerState {
+    let mut next = *state;
+    let mut dir: i64 = 0;
+    let mut vel_y = state.vel_y;
+    let can_jump = state.y == FLOOR_Y;
+
+    dir += if input & INPUT_RIGHT == INPUT_RIGHT { 1 } else { 0 };
+    dir -= if input & INPUT_LEFT == INPUT_LEFT { 1 } else { 0 };
+    let jump = input & INPUT_JUMP == INPUT_JUMP;
+
+    if jump && can_jump {
+        vel_y = JUMP_VELOCITY;
+    }
+
+    //Fall.
+    vel_y = TERMINAL_VELOCITY.min(vel_y + GRAVITY_PER_TICK);
+
+    let vel_x = RUN_PER_TICK * dir;
+    next.running = dir != 0;
+    if next.running {
+        next.facing = dir as i8;
+    }
+
+    next.x = state.x + vel_x;
+    next.y = (state.y + vel_y).min(FLOOR_Y);
+
+    next.vel_x = Fixed::ZERO;
+    next.vel_y = vel_y;
+    next
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn on_floor() -> PlayerState {
+        PlayerState::at(Fixed::from_int(10), FLOOR_Y)
+    }
+
+    fn in_air() -> PlayerState {
+        PlayerState::at(Fixed::from_int(10), Fixed::from_int(-1000))
+    }
+
+    // 가장 작은 양수
+    fn tiny() -> Fixed {
+        Fixed::from_ratio(1, 1 << Fixed::FRAC_BITS)
+    }
+
+    #[test]
+    fn runs_and_faces_the_input_direction() {
+        let right = step_player(&on_floor(), INPUT_RIGHT);
+        assert_eq!(right.x, Fixed::from_int(10) + RUN_PER_TICK);
+        assert_eq!((right.facing, right.running), (1, true));
+        let left = step_player(&right, INPUT_LEFT);
+        assert_eq!(left.x, Fixed::from_int(10));
+        assert_eq!((left.facing, left.running), (-1, true));
+        // 멈춰도 바라보는 방향은 그대로다.
+        let idle = step_player(&left, INPUT_LEFT | INPUT_RIGHT);
+        assert_eq!(idle.x, left.x);
+        assert_eq!((idle.facing, idle.running), (-1, false));
+        assert_eq!(idle.vel_x, Fixed::ZERO);
+    }
+
+    #[test]
+    fn falls_with_gravity() {
+        let first = step_player(&in_air(), 0);
+        assert_eq!(first.vel_y, GRAVITY_PER_TICK);
+        assert_eq!(first.y, Fixed::from_int(-1000) + GRAVITY_PER_TICK);
+        let second = step_player(&first, 0);
+        assert_eq!(second.vel_y, GRAVITY_PER_TICK * 2);
+        assert_eq!(second.y, first.y + GRAVITY_PER_TICK * 2);
+    }
+
+    #[test]
+    fn caps_falling_speed_at_terminal_velocity() {
+        let mut player = PlayerState::at(Fixed::ZERO, Fixed::from_int(-1_000_000));
+        player.vel_y = TERMINAL_VELOCITY - Fixed::from_ratio(1, 2);
+        let player = step_player(&player, 0);
+        assert_eq!(player.vel_y, TERMINAL_VELOCITY);
+        let player = step_player(&player, 0);
+        assert_eq!(player.vel_y, TERMINAL_VELOCITY);
+    }
+
+    #[test]
+    fn jumps_only_from_the_floor() {
+        let jumped = step_player(&on_floor(), INPUT_JUMP);
+        assert_eq!(jumped.vel_y, JUMP_VELOCITY + GRAVITY_PER_TICK);
+        assert_eq!(jumped.y, FLOOR_Y + JUMP_VELOCITY + GRAVITY_PER_TICK);
+        let again = step_player(&jumped, INPUT_JUMP);
+        assert_eq!(again.vel_y, jumped.vel_y + GRAVITY_PER_TICK);
+    }
+
+    #[test]
+    fn lands_on_the_floor() {
+        let mut player = PlayerState::at(Fixed::ZERO, FLOOR_Y - Fixed::ONE);
+        player.vel_y = Fixed::from_int(50);
+        let landed = step_player(&player, 0);
+        assert_eq!(landed.y, FLOOR_Y);
+        // 바닥에 서 있으면 더 내려가지 않는다.
+        let standing = step_player(&on_floor(), 0);
+        assert_eq!(standing.y, FLOOR_Y);
+    }
+
+    #[test]
+    fn steps_every_player_and_the_tick() {
+        let mut sim = Simulation::new(vec![on_floor(), on_floor(), on_floor()]);
+        // 입력이 모자라면 나머지는 입력 없음이다.
+        let state = sim.step(&[INPUT_RIGHT, INPUT_LEFT]).clone();
+        assert_eq!(state.tick, 1);
+        assert_eq!(state.players[0], step_player(&on_floor(), INPUT_RIGHT));
+        assert_eq!(state.players[1], step_player(&on_floor(), INPUT_LEFT));
+        assert_eq!(state.players[2], step_player(&on_floor(), 0));
+    }
+
+    #[test]
+    fn checksum_depends_on_every_field() {
+        let state = State {
+            tick: 5,
+            players: vec![on_floor(), in_air()],
+        };
+        let checksum = state.checksum();
+        assert_eq!(checksum, state.clone().checksum());
+
+        let mut changes = Vec::new();
+        let mut changed = state.clone();
+        changed.tick += 1;
+        changes.push(changed);
+        for field in 0..6 {
+            let mut changed = state.clone();
+            let player = &mut changed.players[1];
+            match field {
+                0 => player.x = player.x + tiny(),
+                1 => player.y = player.y + tiny(),
+                2 => player.vel_x = tiny(),
+                3 => player.vel_y = tiny(),
+                4 => player.facing = -1,
+                _ => player.running = true,
+            }
+            changes.push(changed);
+        }
+        changes.push(State {
+            tick: 5,
+            players: vec![in_air(), on_floor()],
+        });
+        for changed in changes {
+            assert_ne!(changed.checksum(), checksum, "{:?}", changed);
+        }
+    }
+
+    // 기계나 러스트 버전이 달라도 이 값이 나와야 한다.
+    #[test]
+    fn checksum_is_stable() {
+        let state = State {
+            tick: 1,
+            players: vec![PlayerState::at(Fixed::ZERO, FLOOR_Y)],
+        };
+        assert_eq!(state.checksum(), 5063912745066876523);
+    }
+}
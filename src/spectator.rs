@@ -0,0 +1,159 @@
+use std::collections::VecDeque;
+
+// 관전자에게 보내려고 들고 있는 확정 틱 수. 이보다 뒤처진 관전자는 내보낸다.
+pub const SPECTATOR_BUFFER_TICKS: usize = 600;
+// 한 패킷에 담는 틱 수
+pub const SPECTATOR_TICKS_PER_PACKET: usize = 60;
+// 관전자가 ack 하지 않은 패킷이 이만큼 쌓이면 더 보내지 않고 기다린다.
+pub const SPECTATOR_MAX_IN_FLIGHT: usize = 4;
+// 플레이어 하나가 받는 최대 관전자 수
+pub const MAX_SPECTATORS: usize = 4;
+// 관전자는 이만큼 입력을 모아 두고 그 뒤를 따라간다.
+pub const SPECTATOR_DELAY_TICKS: u64 = 6;
+// 한 프레임에 따라잡는 최대 틱 수
+const MAX_CATCH_UP_TICKS: u64 = 4;
+
+// 플레이어 쪽: 확정된 틱의 입력을 차례대로 모아 둔다.
+pub struct SpectatorFeed {
+    // ticks[0] 의 틱
+    first_tick: u64,
+    ticks: VecDeque<Vec<u8>>,
+}
+
+impl SpectatorFeed {
+    pub fn new() -> Self {
+        Self {
+            first_tick: 1,
+            ticks: VecDeque::new(),
+        }
+    }
+
+    // 다음에 넣을 틱
+    pub fn next_tick(&self) -> u64 {
+        self.first_tick + self.ticks.len() as u64
+    }
+
+    pub fn push(&mut self, inputs: Vec<u8>) {
+        self.ticks.push_back(inputs);
+        while self.ticks.len() > SPECTATOR_BUFFER_TICKS {
+            self.ticks.pop_front();
+            self.first_tick += 1;
+        }
+    }
+
+    // from 부터 최대 limit 틱. 이미 버린 틱이면 None
+    pub fn range(&self, from: u64, limit: usize) -> Option<Vec<Vec<u8>>> {
+        if from < self.first_tick {
+            return None;
+        }
+        let start = (from - self.first_tick) as usize;
+        Some(self.ticks.iter().skip(start).take(limit).cloned().collect())
+    }
+}
+
+impl Default for SpectatorFeed {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 관전자 쪽: 받은 입력을 조금 모아 두었다가 한 프레임에 한 틱씩 돌린다.
+// 버퍼가 비면 멈추고, 너무 쌓이면 조금씩 빨리 돌려 따라잡는다.
+pub struct SpectatorPlayout {
+    playing: bool,
+}
+
+impl SpectatorPlayout {
+    pub fn new() -> Self {
+        Self { playing: false }
+    }
+
+    // current 는 마지막으로 돌린 틱, available 은 입력을 받은 마지막 틱
+    pub fn ticks_to_run(&mut self, current: u64, available: u64) -> u64 {
+        let buffered = available.saturating_sub(current);
+        if buffered == 0 {
+            self.playing = false;
+            return 0;
+        }
+        if !self.playing {
+            if buffered < SPECTATOR_DELAY_TICKS {
+                return 0;
+            }
+            self.playing = true;
+        }
+        if buffered > SPECTATOR_DELAY_TICKS * 2 {
+            (buffered - SPECTATOR_DELAY_TICKS).min(MAX_CATCH_UP_TICKS)
+        } else {
+            1
+        }
+    }
+}
+
+impl Default for SpectatorPlayout {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feed_returns_ranges_from_the_first_tick() {
+        let mut feed = SpectatorFeed::new();
+        assert_eq!(feed.next_tick(), 1);
+        assert_eq!(feed.range(1, SPECTATOR_TICKS_PER_PACKET), Some(vec![]));
+        for tick in 1..=5u8 {
+            feed.push(vec![tick, tick * 2]);
+        }
+        assert_eq!(feed.next_tick(), 6);
+        assert_eq!(feed.range(2, 2), Some(vec![vec![2, 4], vec![3, 6]]));
+        assert_eq!(feed.range(4, SPECTATOR_TICKS_PER_PACKET).map(|ticks| ticks.len()), Some(2));
+        // 아직 없는 틱은 비어 있다.
+        assert_eq!(feed.range(6, SPECTATOR_TICKS_PER_PACKET), Some(vec![]));
+    }
+
+    #[test]
+    fn feed_evicts_the_oldest_ticks() {
+        let mut feed = SpectatorFeed::new();
+        for tick in 1..=SPECTATOR_BUFFER_TICKS as u64 + 10 {
+            feed.push(vec![(tick % 256) as u8]);
+        }
+        assert_eq!(feed.next_tick(), SPECTATOR_BUFFER_TICKS as u64 + 11);
+        assert_eq!(feed.range(1, 1), None);
+        assert_eq!(feed.range(10, 1), None);
+        assert_eq!(feed.range(11, 1), Some(vec![vec![11]]));
+    }
+
+    #[test]
+    fn playout_waits_for_the_delay_before_playing() {
+        let mut playout = SpectatorPlayout::new();
+        assert_eq!(playout.ticks_to_run(0, 0), 0);
+        assert_eq!(playout.ticks_to_run(0, SPECTATOR_DELAY_TICKS - 1), 0);
+        assert_eq!(playout.ticks_to_run(0, SPECTATOR_DELAY_TICKS), 1);
+        // 한 번 시작하면 버퍼가 빌 때까지 한 틱씩 돌린다.
+        assert_eq!(playout.ticks_to_run(SPECTATOR_DELAY_TICKS - 1, SPECTATOR_DELAY_TICKS), 1);
+        assert_eq!(playout.ticks_to_run(SPECTATOR_DELAY_TICKS, SPECTATOR_DELAY_TICKS), 0);
+        // 비었으면 다시 모을 때까지 기다린다.
+        assert_eq!(playout.ticks_to_run(SPECTATOR_DELAY_TICKS, SPECTATOR_DELAY_TICKS + 1), 0);
+        assert_eq!(playout.ticks_to_run(SPECTATOR_DELAY_TICKS, SPECTATOR_DELAY_TICKS * 2), 1);
+    }
+
+    #[test]
+    fn playout_catches_up_when_too_far_behind() {
+        let mut playout = SpectatorPlayout::new();
+        let mut current = 0;
+        let available = 100;
+        let mut frames = Vec::new();
+        while available - current > SPECTATOR_DELAY_TICKS * 2 {
+            let ticks = playout.ticks_to_run(current, available);
+            assert!(ticks <= MAX_CATCH_UP_TICKS);
+            frames.push(ticks);
+            current += ticks;
+        }
+        assert!(frames.iter().all(|ticks| *ticks == MAX_CATCH_UP_TICKS));
+        // 지연 두 배 안으로 들어오면 한 틱씩 돌린다.
+        assert_eq!(playout.ticks_to_run(current, available), 1);
+    }
+}
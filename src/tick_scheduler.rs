@@ -0,0 +1,144 @@
+use crate::simulation::TICKS_PER_SECOND;
+
+// 한 프레임에 따라잡을 수 있는 최대 틱 수. 넘는 만큼은 다음 프레임으로 미룬다.
+pub const MAX_TICKS_PER_FRAME: u64 = 4;
+// 상대보다 이만큼 이상 앞서 있으면 속도를 늦춘다.
+pub const ADVANTAGE_THRESHOLD: i64 = 2;
+// 늦출 때는 이 틱 간격마다 한 틱씩만 쉰다.
+const STALL_INTERVAL: u64 = 10;
+
+// 공유 시계에서 지금 있어야 할 틱을 구하고, 프레임마다 몇 틱을 돌릴지 정한다.
+// GGPO 처럼 상대보다 앞서 있으면 몇 틱을 나눠서 쉬어 준다.
+pub struct TickScheduler {
+    // 지금까지 쉬어서 뒤로 민 틱 수
+    skipped_ticks: u64,
+    // 아직 쉬어야 할 틱 수
+    pending_stall: u64,
+    last_stall_tick: u64,
+}
+
+impl TickScheduler {
+    pub fn new() -> Self {
+        Self {
+            skipped_ticks: 0,
+            pending_stall: 0,
+            last_stall_tick: 0,
+        }
+    }
+
+    pub fn target_tick(&self, start_time: u64, now: u64) -> u64 {
+        if now < start_time {
+            return 0;
+        }
+        let ticks = (now - start_time) * TICKS_PER_SECOND as u64 / 1000 + 1;
+        ticks.saturating_sub(self.skipped_ticks)
+    }
+
+    // 상대보다 advantage 틱 앞서 있다는 보고. 양쪽이 절반씩 맞춘다고 보고 절반만 쉰다.
+    pub fn report_advantage(&mut self, advantage: i64) {
+        self.pending_stall = if advantage >= ADVANTAGE_THRESHOLD {
+            (advantage / 2) as u64
+        } else {
+            0
+        };
+    }
+
+    // 이번 프레임에 돌릴 틱 수
+    pub fn ticks_to_run(&mut self, start_time: u64, now: u64, current_tick: u64) -> u64 {
+        if self.pending_stall > 0 && current_tick >= self.last_stall_tick + STALL_INTERVAL {
+            self.pending_stall -= 1;
+            self.skipped_ticks += 1;
+            self.last_stall_tick = current_tick;
+        }
+
+        let target = self.target_tick(start_time, now);
+        target.saturating_sub(current_tick).min(MAX_TICKS_PER_FRAME)
+    }
+}
+
+impl Default for TickScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 틱 n 이 시작되는 시각 (ms)
+    fn time_of(tick: u64) -> u64 {
+        1000 + (tick - 1) * 1000 / TICKS_PER_SECOND as u64 + 1
+    }
+
+    #[test]
+    fn targets_the_shared_clock() {
+        let scheduler = TickScheduler::new();
+        assert_eq!(scheduler.target_tick(1000, 999), 0);
+        assert_eq!(scheduler.target_tick(1000, 1000), 1);
+        assert_eq!(scheduler.target_tick(1000, 2000), TICKS_PER_SECOND as u64 + 1);
+        for tick in [1, 2, 30, 61, 600] {
+            assert_eq!(scheduler.target_tick(1000, time_of(tick)), tick);
+        }
+    }
+
+    #[test]
+    fn catches_up_a_few_ticks_per_frame() {
+        let mut scheduler = TickScheduler::new();
+        let now = time_of(11);
+        let mut tick = 0;
+        let mut frames = Vec::new();
+        while tick < 11 {
+            let ticks = scheduler.ticks_to_run(1000, now, tick);
+            frames.push(ticks);
+            tick += ticks;
+        }
+        assert_eq!(frames, vec![4, 4, 3]);
+        assert_eq!(scheduler.ticks_to_run(1000, now, tick), 0);
+        // 앞서 있으면 돌리지 않는다.
+        assert_eq!(scheduler.ticks_to_run(1000, now, 20), 0);
+    }
+
+    #[test]
+    fn stalls_half_the_advantage_spread_out() {
+        let mut scheduler = TickScheduler::new();
+        scheduler.report_advantage(6);
+        let mut tick = 0;
+        let mut stalls = Vec::new();
+        for frame in 1..=100 {
+            let ticks = scheduler.ticks_to_run(1000, time_of(frame), tick);
+            if ticks == 0 {
+                stalls.push(tick);
+            }
+            tick += ticks;
+        }
+        // 세 틱을 STALL_INTERVAL 간격으로 나눠 쉰다.
+        assert_eq!(stalls.len(), 3);
+        assert!(stalls.windows(2).all(|pair| pair[1] - pair[0] >= STALL_INTERVAL));
+        assert_eq!(tick, 97);
+        assert_eq!(scheduler.target_tick(1000, time_of(100)), 97);
+    }
+
+    #[test]
+    fn ignores_small_advantages() {
+        let mut scheduler = TickScheduler::new();
+        scheduler.report_advantage(ADVANTAGE_THRESHOLD - 1);
+        let mut tick = 0;
+        for frame in 1..=50 {
+            tick += scheduler.ticks_to_run(1000, time_of(frame), tick);
+        }
+        assert_eq!(tick, 50);
+    }
+
+    #[test]
+    fn new_report_replaces_pending_stall() {
+        let mut scheduler = TickScheduler::new();
+        scheduler.report_advantage(20);
+        scheduler.report_advantage(0);
+        let mut tick = 0;
+        for frame in 1..=50 {
+            tick += scheduler.ticks_to_run(1000, time_of(frame), tick);
+        }
+        assert_eq!(tick, 50);
+    }
+}
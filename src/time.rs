@@ -1,7 +1,13 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::Instant;
 
+use lazy_static::lazy_static;
 
-pub fn get_ms_timestamp() -> u64 {
-    let duration = SystemTime::now().duration_since(UNIX_EPOCH).expect("Clock may have gone backwards");
-    duration.as_millis() as u64
-}
\ No newline at end of file
+lazy_static! {
+    static ref CLOCK_EPOCH: Instant = Instant::now();
+}
+
+// 프로세스가 시작된 뒤 흐른 시간 (ms). 벽시계와 달리 뒤로 가거나 건너뛰지 않는다.
+// 피어끼리는 clock_sync 로 구한 차이를 더해서 비교한다.
+pub fn monotonic_ms() -> u64 {
+    CLOCK_EPOCH.elapsed().as_millis() as u64
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::io;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use crate::time;
+use crate::udp_net;
+
+// 전송 하나 안에서 상대를 가리키는 번호. 주소 형식은 전송마다 다르다.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PeerId(pub u64);
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+// 받은 데이터그램: (보낸 피어, 받은 시각 ms, 데이터)
+pub type Datagram = (PeerId, u64, Vec<u8>);
+
+// NetworkController 가 데이터그램을 주고받는 통로.
+// 다른 전송을 쓰려면 이것을 구현해서 NetData 에 넣는다.
+pub trait Transport {
+    // 사람이 입력하거나 PeerList 로 받은 주소. 이 전송에서 쓸 수 없는 주소면 None
+    fn resolve(&mut self, endpoint: &str) -> Option<PeerId>;
+    // 다른 피어에게 알려 줄 수 있는 peer 의 주소
+    fn endpoint(&self, peer: PeerId) -> Option<String>;
+    fn send(&mut self, peer: PeerId, bytes: &[u8]);
+    // 지난 호출 뒤로 받은 것들을 받은 순서대로
+    fn poll(&mut self) -> Vec<Datagram>;
+    // 직접 닿지 않을 때 중계 서버를 거쳐 닿을 수 있는 피어들
+    fn relay_peers(&mut self) -> Vec<PeerId> {
+        Vec::new()
+    }
+    // 지난 호출 뒤로 난 보내기/받기 오류 중 마지막 것
+    fn last_error(&mut self) -> Option<String> {
+        None
+    }
+}
+
+// 주소와 PeerId 를 양쪽으로 찾는다. 처음 보는 주소에는 새 번호를 준다.
+pub struct PeerTable<A> {
+    ids: HashMap<A, PeerId>,
+    addrs: HashMap<PeerId, A>,
+    next: u64,
+}
+
+impl<A: Clone + Eq + Hash> PeerTable<A> {
+    pub fn new() -> Self {
+        Self {
+            ids: HashMap::new(),
+            addrs: HashMap::new(),
+            next: 1,
+        }
+    }
+
+    pub fn id(&mut self, addr: &A) -> PeerId {
+        if let Some(id) = self.ids.get(addr) {
+            return *id;
+        }
+        let id = PeerId(self.next);
+        self.next += 1;
+        self.ids.insert(addr.clone(), id);
+        self.addrs.insert(id, addr.clone());
+        id
+    }
+
+    pub fn addr(&self, id: PeerId) -> Option<&A> {
+        self.addrs.get(&id)
+    }
+}
+
+impl<A: Clone + Eq + Hash> Default for PeerTable<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Default)]
+struct UdpInbox {
+    received: Vec<(SocketAddr, u64, Vec<u8>)>,
+    error: Option<String>,
+}
+
+// 수신은 블로킹 스레드가 받아서 쌓아 두고 poll 에서 한꺼번에 가져간다.
+pub struct UdpTransport {
+    socket: UdpSocket,
+    peers: PeerTable<SocketAddr>,
+    inbox: Arc<Mutex<UdpInbox>>,
+    _thread: std::thread::JoinHandle<()>,
+}
+
+impl UdpTransport {
+    pub fn bind(port: u16) -> io::Result<Self> {
+        let socket = udp_net::start_udp(port)?;
+        let socket_for_thread = socket.try_clone()?;
+        let inbox = Arc::new(Mutex::new(UdpInbox::default()));
+        let inbox_for_thread = inbox.clone();
+        let thread = std::thread::spawn(move || {
+            let mut buffer = [0; udp_net::MAX_DATAGRAM_SIZE];
+            loop {
+                match socket_for_thread.recv_from(&mut buffer) {
+                    Ok((size, addr)) => {
+                        let received_at = time::monotonic_ms();
+                        inbox_for_thread.lock().unwrap().received.push((addr, received_at, buffer[..size].to_vec()));
+                    }
+                    Err(ref err) if err.kind() != ErrorKind::WouldBlock => {
+                        inbox_for_thread.lock().unwrap().error = Some(format!("Failed to receive : {}", err));
+                    }
+                    _ => {}
+                }
+            }
+        });
+        Ok(Self {
+            socket,
+            peers: PeerTable::new(),
+            inbox,
+            _thread: thread,
+        })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn resolve(&mut self, endpoint: &str) -> Option<PeerId> {
+        let addr = endpoint.to_socket_addrs().ok()?.next()?;
+        Some(self.peers.id(&addr))
+    }
+
+    fn endpoint(&self, peer: PeerId) -> Option<String> {
+        self.peers.addr(peer).map(|addr| addr.to_string())
+    }
+
+    fn send(&mut self, peer: PeerId, bytes: &[u8]) {
+        let Some(addr) = self.peers.addr(peer) else {
+            return;
+        };
+        if let Err(err) = self.socket.send_to(bytes, addr) {
+            self.inbox.lock().unwrap().error = Some(format!("Failed to send message : {}", err));
+        }
+    }
+
+    fn poll(&mut self) -> Vec<Datagram> {
+        let received = std::mem::take(&mut self.inbox.lock().unwrap().received);
+        received
+            .into_iter()
+            .map(|(addr, received_at, bytes)| (self.peers.id(&addr), received_at, bytes))
+            .collect()
+    }
+
+    fn last_error(&mut self) -> Option<String> {
+        self.inbox.lock().unwrap().error.take()
+    }
+}
+
+// 한 프로세스 안에서 서로만 아는 두 끝. 테스트용이다.
+pub struct MemoryTransport {
+    // 상대의 이름. resolve 에 이 이름을 주면 상대가 나온다.
+    remote: String,
+    tx: Sender<(u64, Vec<u8>)>,
+    rx: Receiver<(u64, Vec<u8>)>,
+}
+
+impl MemoryTransport {
+    // 상대는 언제나 PeerId(1) 이다.
+    pub const REMOTE: PeerId = PeerId(1);
+
+    pub fn pair(a: &str, b: &str) -> (Self, Self) {
+        let (a_tx, b_rx) = channel();
+        let (b_tx, a_rx) = channel();
+        (
+            Self { remote: b.to_string(), tx: a_tx, rx: a_rx },
+            Self { remote: a.to_string(), tx: b_tx, rx: b_rx },
+        )
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn resolve(&mut self, endpoint: &str) -> Option<PeerId> {
+        (endpoint == self.remote).then_some(Self::REMOTE)
+    }
+
+    fn endpoint(&self, peer: PeerId) -> Option<String> {
+        (peer == Self::REMOTE).then(|| self.remote.clone())
+    }
+
+    fn send(&mut self, peer: PeerId, bytes: &[u8]) {
+        if peer == Self::REMOTE {
+            // 상대가 없어졌으면 잃어버린 것으로 친다.
+            let _ = self.tx.send((time::monotonic_ms(), bytes.to_vec()));
+        }
+    }
+
+    fn poll(&mut self) -> Vec<Datagram> {
+        self.rx
+            .try_iter()
+            .map(|(sent_at, bytes)| (Self::REMOTE, sent_at, bytes))
+            .collect()
+    }
+}
@@ -1,9 +1,11 @@
-use std::mem;
 use std::net::UdpSocket;
 use std::io;
 
-use godot::log::godot_print;
+// 와이어 포맷 버전. 인코딩이 바뀌면 올린다.
+pub const PROTOCOL_VERSION: u8 = 12;
 
+// 입력 패킷 하나에 담는 최대 틱 수
+pub const MAX_INPUTS_PER_PACKET: usize = 256;
 
 #[repr(u8)]
 pub enum PacketType {
@@ -12,88 +14,1026 @@ pub enum PacketType {
     Connect,
     Input,
     InputOK,
+    Checksum,
+    Channel,
+    ChannelAck,
+    Chat,
+    MatchControl,
+    Disconnect,
+    Challenge,
+    ChallengeResponse,
+    PeerList,
+    StartMatch,
+    SpectatorInputs,
 }
 
-impl From<u8> for PacketType {
-    fn from(v: u8) -> Self {
+impl TryFrom<u8> for PacketType {
+    type Error = UnpackError;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
         match v {
-            0 => PacketType::Ping,
-            1 => PacketType::Pong,
-            2 => PacketType::Connect,
-            3 => PacketType::Input,
-            4 => PacketType::InputOK,
-            _ => panic!("Unknown packet type")
+            0 => Ok(PacketType::Ping),
+            1 => Ok(PacketType::Pong),
+            2 => Ok(PacketType::Connect),
+            3 => Ok(PacketType::Input),
+            4 => Ok(PacketType::InputOK),
+            5 => Ok(PacketType::Checksum),
+            6 => Ok(PacketType::Channel),
+            7 => Ok(PacketType::ChannelAck),
+            8 => Ok(PacketType::Chat),
+            9 => Ok(PacketType::MatchControl),
+            10 => Ok(PacketType::Disconnect),
+            11 => Ok(PacketType::Challenge),
+            12 => Ok(PacketType::ChallengeResponse),
+            13 => Ok(PacketType::PeerList),
+            14 => Ok(PacketType::StartMatch),
+            15 => Ok(PacketType::SpectatorInputs),
+            _ => Err(UnpackError::UnknownType(v)),
         }
     }
 }
 
 
+// 시간은 모두 보낸 쪽의 로컬 시계 (ms)
 pub struct Ping {
-    pub id: u8
+    pub id: u8,
+    pub time: u64,
+    // 보낸 쪽의 현재 틱. 프레임 어드밴티지 계산용
+    pub tick: u64
 }
 
 pub struct Pong {
-    pub id: u8
+    pub id: u8,
+    pub ping_time: u64,
+    pub ping_received: u64,
+    pub time: u64
 }
 
+// Connect 의 id 필드에서 아직 정해지지 않았다는 뜻
+pub const UNASSIGNED_ID: u8 = u8::MAX;
+// 관전자가 Connect 의 player_id 로 보내고, 받아 준 쪽이 assigned_id 로 돌려준다.
+pub const SPECTATOR_ID: u8 = u8::MAX - 1;
+
+// 먼저 보내는 쪽은 echo = 0, 답장은 받은 nonce 를 echo 에 돌려준다.
+// 세션 ID 는 두 nonce 로 만든다. public_key 는 보안 모드의 X25519 공개키이고 아니면 0 이다.
+// player_id 는 보낸 쪽의 id, assigned_id 는 호스트가 새로 들어온 쪽에 정해 준 id
 pub struct Connect {
     pub x: f32,
     pub y: f32,
-    pub game_start_time: u64
+    pub player_id: u8,
+    pub assigned_id: u8,
+    pub nonce: u64,
+    pub echo: u64,
+    pub public_key: [u8; 32]
 }
 
+// 상대가 아직 받았다고 하지 않은 player 의 입력 전부. (tick, input) 틱 오름차순
 pub struct InputPacket {
-    pub input: [u8; 5],
-    pub tick: [u64; 5]
+    pub player: u8,
+    pub inputs: Vec<(u64, u8)>
 }
 
+// 누적 ack. through 까지 전부 받았고, bits 의 i 번째 비트는 through + 1 + i 틱을 받았다는 뜻
 pub struct InputOKPacket {
-    pub tick: [u64; 5]
+    pub through: u64,
+    pub bits: u32
+}
+
+// tick 을 시뮬레이션한 직후 상태의 체크섬
+pub struct ChecksumPacket {
+    pub tick: u64,
+    pub hash: u64
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Channel {
+    // 그냥 보낸다. 이 채널로 보낸 패킷은 ChannelPacket 으로 감싸지 않는다.
+    Unreliable,
+    // 순서 번호보다 오래된 패킷은 버린다.
+    UnreliableSequenced,
+    // ack 를 받을 때까지 다시 보내고, 보낸 순서대로 넘겨준다.
+    ReliableOrdered,
+}
+
+impl TryFrom<u8> for Channel {
+    type Error = UnpackError;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v {
+            0 => Ok(Channel::Unreliable),
+            1 => Ok(Channel::UnreliableSequenced),
+            2 => Ok(Channel::ReliableOrdered),
+            _ => Err(UnpackError::BadValue(v)),
+        }
+    }
+}
+
+// 채널에 실린 패킷. payload 는 pack() 한 패킷 ([type][version][payload])
+pub struct ChannelPacket {
+    pub channel: Channel,
+    pub seq: u32,
+    pub payload: Vec<u8>
+}
+
+// ReliableOrdered 채널의 ack. next 앞은 전부 받았고, bits 의 i 번째 비트는 next + 1 + i 를 받았다는 뜻
+pub struct ChannelAckPacket {
+    pub next: u32,
+    pub bits: u32
+}
+
+// 채팅 한 줄의 최대 바이트 수
+pub const MAX_CHAT_LEN: usize = 256;
+
+pub struct ChatPacket {
+    pub text: String
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MatchAction {
+    Ready,
+    Unready,
+    Rematch,
+    Leave,
+}
+
+impl TryFrom<u8> for MatchAction {
+    type Error = UnpackError;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v {
+            0 => Ok(MatchAction::Ready),
+            1 => Ok(MatchAction::Unready),
+            2 => Ok(MatchAction::Rematch),
+            3 => Ok(MatchAction::Leave),
+            _ => Err(UnpackError::BadValue(v)),
+        }
+    }
+}
+
+pub struct MatchControlPacket {
+    pub action: MatchAction
+}
+
+// 연결을 끊는다. reliable 로 보낸다.
+pub struct Disconnect;
+
+// 피어가 새 주소에서 보낸 것 같을 때 그 주소로 보낸다. 같은 nonce 가 새 주소에서 돌아오면 주소를 바꾼다.
+pub struct Challenge {
+    pub nonce: u64
+}
+
+pub struct ChallengeResponse {
+    pub nonce: u64
+}
+
+// 호스트가 알려 주는 다른 피어들 (id, 주소). 새로 들어온 쪽은 자기보다 id 가 작은 피어에 Connect 를 보낸다.
+pub struct PeerList {
+    pub peers: Vec<(u8, String)>
+}
+
+// 호스트가 정한 시작 시각 (공유 시계)과 플레이어별 시작 위치 (id, x, y)
+#[derive(Clone)]
+pub struct StartMatch {
+    pub game_start_time: u64,
+    pub players: Vec<(u8, f32, f32)>
+}
+
+// 한 방의 최대 인원
+pub const MAX_PLAYERS: usize = 8;
+
+// 관전자에게 보내는 확정 입력. start_tick 부터 틱마다 플레이어 수만큼의 입력
+pub struct SpectatorInputs {
+    pub start_tick: u64,
+    pub inputs: Vec<Vec<u8>>
+}
+
+pub enum Message {
+    Ping(Ping),
+    Pong(Pong),
+    Connect(Connect),
+    Input(InputPacket),
+    InputOK(InputOKPacket),
+    Checksum(ChecksumPacket),
+    Channel(ChannelPacket),
+    ChannelAck(ChannelAckPacket),
+    Chat(ChatPacket),
+    MatchControl(MatchControlPacket),
+    Disconnect(Disconnect),
+    Challenge(Challenge),
+    ChallengeResponse(ChallengeResponse),
+    PeerList(PeerList),
+    StartMatch(StartMatch),
+    SpectatorInputs(SpectatorInputs),
 }
 
 //Error Type for unpacking
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum UnpackError {
-    InvalidSize
+    // 알 수 없는 패킷 타입 바이트
+    UnknownType(u8),
+    // 필드를 읽다가 데이터가 끝남
+    Truncated,
+    // 길이 prefix 가 프레임 최소 크기보다 작거나 데이터그램 밖을 가리킴
+    BadLength(usize),
+    // 패킷을 다 읽고도 남은 바이트 수
+    TrailingBytes(usize),
+    VersionMismatch(u8),
+    // enum 필드에 정의되지 않은 값
+    BadValue(u8),
+    // UTF-8 이 아닌 문자열
+    BadString,
+    // 복호화/인증 실패, 또는 보안 세션에서 암호화되지 않은 데이터그램
+    Unauthenticated,
+    // 이미 받은 카운터
+    Replayed(u64),
+}
+
+impl std::fmt::Display for UnpackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnpackError::UnknownType(t) => write!(f, "unknown packet type {}", t),
+            UnpackError::Truncated => write!(f, "truncated packet"),
+            UnpackError::BadLength(len) => write!(f, "bad frame length {}", len),
+            UnpackError::TrailingBytes(n) => write!(f, "{} trailing bytes", n),
+            UnpackError::VersionMismatch(v) => write!(f, "protocol version mismatch ({})", v),
+            UnpackError::BadValue(v) => write!(f, "bad enum value {}", v),
+            UnpackError::BadString => write!(f, "invalid utf-8 string"),
+            UnpackError::Unauthenticated => write!(f, "unauthenticated datagram"),
+            UnpackError::Replayed(counter) => write!(f, "replayed counter {}", counter),
+        }
+    }
+}
+
+// 모든 필드는 little-endian 으로 순서대로 기록한다.
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self { buf: Vec::new() }
+    }
+
+    pub fn put_u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub fn put_u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn put_u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn put_f32(&mut self, v: f32) {
+        self.buf.extend_from_slice(&v.to_bits().to_le_bytes());
+    }
+
+    // LEB128
+    pub fn put_varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.buf.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        self.buf.push(v as u8);
+    }
+
+    // [길이(varint)][바이트]
+    pub fn put_bytes(&mut self, v: &[u8]) {
+        self.put_varint(v.len() as u64);
+        self.buf.extend_from_slice(v);
+    }
+
+    pub fn put_str(&mut self, v: &str) {
+        self.put_bytes(v.as_bytes());
+    }
+
+    // 길이 없이 그대로
+    pub fn put_array(&mut self, v: &[u8]) {
+        self.buf.extend_from_slice(v);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+impl Default for Writer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    // 아직 읽지 않은 바이트 수
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], UnpackError> {
+        let end = self.pos + N;
+        if end > self.data.len() {
+            return Err(UnpackError::Truncated);
+        }
+        let mut bytes = [0u8; N];
+        bytes.copy_from_slice(&self.data[self.pos..end]);
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    pub fn get_array<const N: usize>(&mut self) -> Result<[u8; N], UnpackError> {
+        self.take::<N>()
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8, UnpackError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    pub fn get_u32(&mut self) -> Result<u32, UnpackError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    pub fn get_u64(&mut self) -> Result<u64, UnpackError> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    pub fn get_f32(&mut self) -> Result<f32, UnpackError> {
+        Ok(f32::from_bits(u32::from_le_bytes(self.take()?)))
+    }
+
+    pub fn get_varint(&mut self) -> Result<u64, UnpackError> {
+        let mut v: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.get_u8()?;
+            v |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(UnpackError::BadLength(10))
+    }
+
+    pub fn get_bytes(&mut self) -> Result<Vec<u8>, UnpackError> {
+        let len = self.get_varint()?;
+        if len > (self.data.len() - self.pos) as u64 {
+            return Err(UnpackError::Truncated);
+        }
+        let end = self.pos + len as usize;
+        let bytes = self.data[self.pos..end].to_vec();
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    pub fn get_str(&mut self) -> Result<String, UnpackError> {
+        String::from_utf8(self.get_bytes()?).map_err(|_| UnpackError::BadString)
+    }
+
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+}
+
+pub trait Packet: Sized {
+    const TYPE: PacketType;
+
+    fn write(&self, w: &mut Writer);
+    fn read(r: &mut Reader) -> Result<Self, UnpackError>;
+}
+
+impl Packet for Ping {
+    const TYPE: PacketType = PacketType::Ping;
+
+    fn write(&self, w: &mut Writer) {
+        w.put_u8(self.id);
+        w.put_u64(self.time);
+        w.put_u64(self.tick);
+    }
+
+    fn read(r: &mut Reader) -> Result<Self, UnpackError> {
+        Ok(Ping {
+            id: r.get_u8()?,
+            time: r.get_u64()?,
+            tick: r.get_u64()?,
+        })
+    }
+}
+
+impl Packet for Pong {
+    const TYPE: PacketType = PacketType::Pong;
+
+    fn write(&self, w: &mut Writer) {
+        w.put_u8(self.id);
+        w.put_u64(self.ping_time);
+        w.put_u64(self.ping_received);
+        w.put_u64(self.time);
+    }
+
+    fn read(r: &mut Reader) -> Result<Self, UnpackError> {
+        Ok(Pong {
+            id: r.get_u8()?,
+            ping_time: r.get_u64()?,
+            ping_received: r.get_u64()?,
+            time: r.get_u64()?,
+        })
+    }
+}
+
+impl Packet for Connect {
+    const TYPE: PacketType = PacketType::Connect;
+
+    fn write(&self, w: &mut Writer) {
+        w.put_f32(self.x);
+        w.put_f32(self.y);
+        w.put_u8(self.player_id);
+        w.put_u8(self.assigned_id);
+        w.put_u64(self.nonce);
+        w.put_u64(self.echo);
+        w.put_array(&self.public_key);
+    }
+
+    fn read(r: &mut Reader) -> Result<Self, UnpackError> {
+        Ok(Connect {
+            x: r.get_f32()?,
+            y: r.get_f32()?,
+            player_id: r.get_u8()?,
+            assigned_id: r.get_u8()?,
+            nonce: r.get_u64()?,
+            echo: r.get_u64()?,
+            public_key: r.get_array()?,
+        })
+    }
+}
+
+// [player][base tick][run 수] + run 마다 [앞 run 과의 틈][길이][입력]
+// 틱이 연속이고 같은 입력이 이어지는 경우가 대부분이라 대개 몇 바이트로 끝난다.
+impl Packet for InputPacket {
+    const TYPE: PacketType = PacketType::Input;
+
+    fn write(&self, w: &mut Writer) {
+        let mut runs: Vec<(u64, u64, u8)> = Vec::new();
+        for (tick, input) in self.inputs.iter() {
+            match runs.last_mut() {
+                Some((start, len, run_input)) if *start + *len == *tick && *run_input == *input => {
+                    *len += 1;
+                }
+                _ => runs.push((*tick, 1, *input)),
+            }
+        }
+
+        let base = runs.first().map_or(0, |run| run.0);
+        w.put_u8(self.player);
+        w.put_u64(base);
+        w.put_varint(runs.len() as u64);
+        let mut next = base;
+        for (start, len, input) in runs {
+            w.put_varint(start - next);
+            w.put_varint(len);
+            w.put_u8(input);
+            next = start + len;
+        }
+    }
+
+    fn read(r: &mut Reader) -> Result<Self, UnpackError> {
+        let player = r.get_u8()?;
+        let mut next = r.get_u64()?;
+        let run_count = r.get_varint()?;
+        let mut inputs = Vec::new();
+        for _ in 0..run_count {
+            let start = next.checked_add(r.get_varint()?).ok_or(UnpackError::Truncated)?;
+            let len = r.get_varint()?;
+            let input = r.get_u8()?;
+            if inputs.len() as u64 + len > MAX_INPUTS_PER_PACKET as u64 {
+                return Err(UnpackError::BadLength(len as usize));
+            }
+            let end = start.checked_add(len).ok_or(UnpackError::Truncated)?;
+            inputs.extend((start..end).map(|tick| (tick, input)));
+            next = end;
+        }
+        Ok(InputPacket { player, inputs })
+    }
+}
+
+impl Packet for InputOKPacket {
+    const TYPE: PacketType = PacketType::InputOK;
+
+    fn write(&self, w: &mut Writer) {
+        w.put_u64(self.through);
+        w.put_u32(self.bits);
+    }
+
+    fn read(r: &mut Reader) -> Result<Self, UnpackError> {
+        Ok(InputOKPacket {
+            through: r.get_u64()?,
+            bits: r.get_u32()?,
+        })
+    }
+}
+
+impl Packet for ChecksumPacket {
+    const TYPE: PacketType = PacketType::Checksum;
+
+    fn write(&self, w: &mut Writer) {
+        w.put_u64(self.tick);
+        w.put_u64(self.hash);
+    }
+
+    fn read(r: &mut Reader) -> Result<Self, UnpackError> {
+        Ok(ChecksumPacket {
+            tick: r.get_u64()?,
+            hash: r.get_u64()?,
+        })
+    }
+}
+
+impl Packet for ChannelPacket {
+    const TYPE: PacketType = PacketType::Channel;
+
+    fn write(&self, w: &mut Writer) {
+        w.put_u8(self.channel as u8);
+        w.put_u32(self.seq);
+        w.put_bytes(&self.payload);
+    }
+
+    fn read(r: &mut Reader) -> Result<Self, UnpackError> {
+        Ok(ChannelPacket {
+            channel: Channel::try_from(r.get_u8()?)?,
+            seq: r.get_u32()?,
+            payload: r.get_bytes()?,
+        })
+    }
+}
+
+impl Packet for ChannelAckPacket {
+    const TYPE: PacketType = PacketType::ChannelAck;
+
+    fn write(&self, w: &mut Writer) {
+        w.put_u32(self.next);
+        w.put_u32(self.bits);
+    }
+
+    fn read(r: &mut Reader) -> Result<Self, UnpackError> {
+        Ok(ChannelAckPacket {
+            next: r.get_u32()?,
+            bits: r.get_u32()?,
+        })
+    }
+}
+
+impl Packet for ChatPacket {
+    const TYPE: PacketType = PacketType::Chat;
+
+    fn write(&self, w: &mut Writer) {
+        w.put_str(&self.text);
+    }
+
+    fn read(r: &mut Reader) -> Result<Self, UnpackError> {
+        let text = r.get_str()?;
+        if text.len() > MAX_CHAT_LEN {
+            return Err(UnpackError::BadLength(text.len()));
+        }
+        Ok(ChatPacket { text })
+    }
 }
 
-pub fn unpack<T>(data: &[u8]) -> Result<(T, u32), UnpackError>
+impl Packet for MatchControlPacket {
+    const TYPE: PacketType = PacketType::MatchControl;
+
+    fn write(&self, w: &mut Writer) {
+        w.put_u8(self.action as u8);
+    }
+
+    fn read(r: &mut Reader) -> Result<Self, UnpackError> {
+        Ok(MatchControlPacket {
+            action: MatchAction::try_from(r.get_u8()?)?,
+        })
+    }
+}
+
+impl Packet for Disconnect {
+    const TYPE: PacketType = PacketType::Disconnect;
+
+    fn write(&self, _: &mut Writer) {}
+
+    fn read(_: &mut Reader) -> Result<Self, UnpackError> {
+        Ok(Disconnect)
+    }
+}
+
+impl Packet for Challenge {
+    const TYPE: PacketType = PacketType::Challenge;
+
+    fn write(&self, w: &mut Writer) {
+        w.put_u64(self.nonce);
+    }
+
+    fn read(r: &mut Reader) -> Result<Self, UnpackError> {
+        Ok(Challenge { nonce: r.get_u64()? })
+    }
+}
+
+impl Packet for ChallengeResponse {
+    const TYPE: PacketType = PacketType::ChallengeResponse;
+
+    fn write(&self, w: &mut Writer) {
+        w.put_u64(self.nonce);
+    }
+
+    fn read(r: &mut Reader) -> Result<Self, UnpackError> {
+        Ok(ChallengeResponse { nonce: r.get_u64()? })
+    }
+}
+
+impl Packet for PeerList {
+    const TYPE: PacketType = PacketType::PeerList;
+
+    fn write(&self, w: &mut Writer) {
+        w.put_varint(self.peers.len() as u64);
+        for (id, addr) in self.peers.iter() {
+            w.put_u8(*id);
+            w.put_str(addr);
+        }
+    }
+
+    fn read(r: &mut Reader) -> Result<Self, UnpackError> {
+        let count = r.get_varint()?;
+        if count > MAX_PLAYERS as u64 {
+            return Err(UnpackError::BadLength(count as usize));
+        }
+        let mut peers = Vec::new();
+        for _ in 0..count {
+            peers.push((r.get_u8()?, r.get_str()?));
+        }
+        Ok(PeerList { peers })
+    }
+}
+
+impl Packet for StartMatch {
+    const TYPE: PacketType = PacketType::StartMatch;
+
+    fn write(&self, w: &mut Writer) {
+        w.put_u64(self.game_start_time);
+        w.put_varint(self.players.len() as u64);
+        for (id, x, y) in self.players.iter() {
+            w.put_u8(*id);
+            w.put_f32(*x);
+            w.put_f32(*y);
+        }
+    }
+
+    fn read(r: &mut Reader) -> Result<Self, UnpackError> {
+        let game_start_time = r.get_u64()?;
+        let count = r.get_varint()?;
+        if count > MAX_PLAYERS as u64 {
+            return Err(UnpackError::BadLength(count as usize));
+        }
+        let mut players = Vec::new();
+        for _ in 0..count {
+            players.push((r.get_u8()?, r.get_f32()?, r.get_f32()?));
+        }
+        Ok(StartMatch { game_start_time, players })
+    }
+}
+
+// [start tick][플레이어 수][틱 수] + 틱마다 플레이어 수만큼의 입력
+impl Packet for SpectatorInputs {
+    const TYPE: PacketType = PacketType::SpectatorInputs;
+
+    fn write(&self, w: &mut Writer) {
+        let players = self.inputs.first().map_or(0, |inputs| inputs.len());
+        w.put_u64(self.start_tick);
+        w.put_u8(players as u8);
+        w.put_varint(self.inputs.len() as u64);
+        for inputs in self.inputs.iter() {
+            for input in inputs.iter() {
+                w.put_u8(*input);
+            }
+        }
+    }
+
+    fn read(r: &mut Reader) -> Result<Self, UnpackError> {
+        let start_tick = r.get_u64()?;
+        let players = r.get_u8()? as usize;
+        if players > MAX_PLAYERS {
+            return Err(UnpackError::BadLength(players));
+        }
+        let count = r.get_varint()?;
+        if count > MAX_INPUTS_PER_PACKET as u64 {
+            return Err(UnpackError::BadLength(count as usize));
+        }
+        let mut inputs = Vec::new();
+        for _ in 0..count {
+            let mut tick_inputs = Vec::new();
+            for _ in 0..players {
+                tick_inputs.push(r.get_u8()?);
+            }
+            inputs.push(tick_inputs);
+        }
+        Ok(SpectatorInputs { start_tick, inputs })
+    }
+}
+
+// data 는 패킷 타입 바이트 다음부터 시작한다: [version][payload]
+pub fn unpack<T: Packet>(data: &[u8]) -> Result<(T, u32), UnpackError>
 {
-    if data.len() < mem::size_of::<T>() {
-        return Err(UnpackError::InvalidSize);
+    let mut reader = Reader::new(data);
+    let version = reader.get_u8()?;
+    if version != PROTOCOL_VERSION {
+        return Err(UnpackError::VersionMismatch(version));
     }
+    let my_struct = T::read(&mut reader)?;
+    Ok((my_struct, reader.position() as u32))
+}
+
+pub fn pack<T: Packet>(data: &T) -> Vec<u8> {
+    let mut writer = Writer::new();
+    writer.put_u8(T::TYPE as u8);
+    writer.put_u8(PROTOCOL_VERSION);
+    data.write(&mut writer);
+    writer.into_bytes()
+}
+
+// 데이터그램 = [세션 ID(u64 LE)][종류] + 본문. 받는 쪽 버퍼 크기이기도 하다.
+// 세션 ID 0 은 아직 세션이 없는 Connect 용이다.
+// 본문은 평문이면 프레임 여러 개, 암호화되어 있으면 [카운터(u64 LE)][암호문 + 태그]
+pub const MAX_DATAGRAM_SIZE: usize = 1024;
+pub const DATAGRAM_HEADER_SIZE: usize = 9;
+pub const DATAGRAM_PLAIN: u8 = 0;
+pub const DATAGRAM_SEALED: u8 = 1;
+// 암호화할 때 붙는 카운터와 태그
+pub const SEAL_OVERHEAD: usize = 8 + 16;
+// 암호화 여부와 상관없이 데이터그램 하나에 들어가는 본문 크기
+pub const MAX_BODY_SIZE: usize = MAX_DATAGRAM_SIZE - DATAGRAM_HEADER_SIZE - SEAL_OVERHEAD;
+
+// 프레임 = [전체 길이(u16 LE, prefix 포함)][type][version][payload]
+const FRAME_PREFIX_SIZE: usize = 2;
+pub const MAX_FRAME_SIZE: usize = MAX_BODY_SIZE;
+
+pub fn frame(packet: Vec<u8>) -> Vec<u8> {
+    let size = packet.len() + FRAME_PREFIX_SIZE;
+    assert!(size <= MAX_FRAME_SIZE, "packet too large to frame: {}", packet.len());
+    let mut framed = Vec::with_capacity(size);
+    framed.extend_from_slice(&(size as u16).to_le_bytes());
+    framed.extend(packet);
+    framed
+}
+
+pub fn pack_frame<T: Packet>(data: &T) -> Vec<u8> {
+    frame(pack(data))
+}
+
+pub fn datagram_header(session: u64, kind: u8) -> [u8; DATAGRAM_HEADER_SIZE] {
+    let mut header = [0u8; DATAGRAM_HEADER_SIZE];
+    header[..8].copy_from_slice(&session.to_le_bytes());
+    header[8] = kind;
+    header
+}
 
-    let my_struct: T;
-    let raw: &[u8] = &data[..mem::size_of::<T>()];
-    unsafe {
-        let ptr = raw.as_ptr() as *const T;
-        my_struct = ptr.read();
+// (세션 ID, 종류, 본문)
+pub fn split_datagram(datagram: &[u8]) -> Result<(u64, u8, &[u8]), UnpackError> {
+    let mut header = Reader::new(datagram);
+    let session = header.get_u64()?;
+    let kind = header.get_u8()?;
+    if kind != DATAGRAM_PLAIN && kind != DATAGRAM_SEALED {
+        return Err(UnpackError::BadValue(kind));
     }
-    Ok((my_struct, mem::size_of::<T>() as u32))
+    Ok((session, kind, &datagram[DATAGRAM_HEADER_SIZE..]))
 }
 
-pub fn pack<T>(data: &T, packet_type: PacketType) -> Vec<u8> {
-    let mut packet = Vec::new();
-    packet.push(packet_type as u8);
-    let data_ptr: *const u8 = data as *const T as *const u8;
-    let data_size = mem::size_of::<T>();
-    unsafe {
-        let data_slice = std::slice::from_raw_parts(data_ptr, data_size);
-        packet.extend_from_slice(data_slice);
+// 평문 본문 하나를 프레임들로 나눈다. 하나라도 잘못되면 전체를 버린다.
+pub fn split_frames(body: &[u8]) -> Result<Vec<Vec<u8>>, UnpackError> {
+    let mut frames = Vec::new();
+    let mut i = 0;
+    while i < body.len() {
+        if i + FRAME_PREFIX_SIZE > body.len() {
+            return Err(UnpackError::Truncated);
+        }
+        let pkt_size = u16::from_le_bytes([body[i], body[i + 1]]) as usize;
+        // prefix 와 타입 바이트는 최소한 있어야 한다
+        if pkt_size < FRAME_PREFIX_SIZE + 1 {
+            return Err(UnpackError::BadLength(pkt_size));
+        }
+        if i + pkt_size > body.len() {
+            return Err(UnpackError::BadLength(pkt_size));
+        }
+        frames.push(body[(i + FRAME_PREFIX_SIZE)..(i + pkt_size)].to_vec());
+        i += pkt_size;
+    }
+    Ok(frames)
+}
+
+// 프레임들을 MAX_BODY_SIZE 를 넘지 않게 본문으로 묶는다.
+pub fn batch_frames(frames: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let mut bodies: Vec<Vec<u8>> = Vec::new();
+    for frame in frames {
+        match bodies.last_mut() {
+            Some(body) if body.len() + frame.len() <= MAX_BODY_SIZE => {
+                body.extend_from_slice(frame);
+            }
+            _ => bodies.push(frame.clone()),
+        }
+    }
+    bodies
+}
+
+fn decode_as<T: Packet>(data: &[u8]) -> Result<T, UnpackError> {
+    let (packet, size) = unpack::<T>(data)?;
+    let trailing = data.len() - size as usize;
+    if trailing > 0 {
+        return Err(UnpackError::TrailingBytes(trailing));
+    }
+    Ok(packet)
+}
+
+// 프레임 하나 ([type][version][payload]) 를 해석한다.
+pub fn decode(frame: &[u8]) -> Result<Message, UnpackError> {
+    let (&type_byte, data) = frame.split_first().ok_or(UnpackError::Truncated)?;
+    match PacketType::try_from(type_byte)? {
+        PacketType::Ping => decode_as::<Ping>(data).map(Message::Ping),
+        PacketType::Pong => decode_as::<Pong>(data).map(Message::Pong),
+        PacketType::Connect => decode_as::<Connect>(data).map(Message::Connect),
+        PacketType::Input => decode_as::<InputPacket>(data).map(Message::Input),
+        PacketType::InputOK => decode_as::<InputOKPacket>(data).map(Message::InputOK),
+        PacketType::Checksum => decode_as::<ChecksumPacket>(data).map(Message::Checksum),
+        PacketType::Channel => decode_as::<ChannelPacket>(data).map(Message::Channel),
+        PacketType::ChannelAck => decode_as::<ChannelAckPacket>(data).map(Message::ChannelAck),
+        PacketType::Chat => decode_as::<ChatPacket>(data).map(Message::Chat),
+        PacketType::MatchControl => decode_as::<MatchControlPacket>(data).map(Message::MatchControl),
+        PacketType::Disconnect => decode_as::<Disconnect>(data).map(Message::Disconnect),
+        PacketType::Challenge => decode_as::<Challenge>(data).map(Message::Challenge),
+        PacketType::ChallengeResponse => {
+            decode_as::<ChallengeResponse>(data).map(Message::ChallengeResponse)
+        }
+        PacketType::PeerList => decode_as::<PeerList>(data).map(Message::PeerList),
+        PacketType::StartMatch => decode_as::<StartMatch>(data).map(Message::StartMatch),
+        PacketType::SpectatorInputs => {
+            decode_as::<SpectatorInputs>(data).map(Message::SpectatorInputs)
+        }
     }
-    packet
 }
 
 pub fn start_udp(port: u16) -> io::Result<UdpSocket> {
     let socket = UdpSocket::bind(format!("0.0.0.0:{}", port))?;
-    godot_print!("UDP socket started on port {}", port);
     //socket.set_nonblocking(true)?;
     Ok(socket)
 }
 
-pub fn send_bytes(socket: Option<&UdpSocket>, packet: &[u8], addr: &str) {
-    let r = socket.unwrap().send_to(&packet, addr);
-    if r.is_err() {
-        godot_print!("Failed to send message : {}", r.err().unwrap());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 테스트용 SplitMix64. 시드가 같으면 같은 바이트가 나온다.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            let mut z = self.0;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            z ^ (z >> 31)
+        }
+
+        fn bytes(&mut self, max: usize) -> Vec<u8> {
+            let len = (self.next() % (max as u64 + 1)) as usize;
+            (0..len).map(|_| self.next() as u8).collect()
+        }
+    }
+
+    fn ping() -> Ping {
+        Ping { id: 3, time: 1234, tick: 56 }
+    }
+
+    #[test]
+    fn random_bytes_never_panic() {
+        let mut rng = Rng(1);
+        for _ in 0..20_000 {
+            let bytes = rng.bytes(MAX_DATAGRAM_SIZE);
+            if let Ok((_, _, body)) = split_datagram(&bytes) {
+                assert_eq!(body.len(), bytes.len() - DATAGRAM_HEADER_SIZE);
+                if let Ok(frames) = split_frames(body) {
+                    for frame in frames {
+                        let _ = decode(&frame);
+                    }
+                }
+            }
+            let _ = split_frames(&bytes);
+            let _ = decode(&bytes);
+        }
     }
-}
\ No newline at end of file
+
+    // 타입 바이트가 맞는 무작위 본문도 디코더 깊숙이까지 들어가 본다.
+    #[test]
+    fn random_payloads_never_panic() {
+        let mut rng = Rng(2);
+        for _ in 0..20_000 {
+            let mut bytes = rng.bytes(64);
+            bytes.insert(0, PROTOCOL_VERSION);
+            bytes.insert(0, (rng.next() % 16) as u8);
+            let _ = decode(&bytes);
+        }
+    }
+
+    #[test]
+    fn round_trips_frames_in_a_datagram() {
+        let mut datagram = datagram_header(42, DATAGRAM_PLAIN).to_vec();
+        datagram.extend(pack_frame(&ping()));
+        datagram.extend(pack_frame(&ChecksumPacket { tick: 7, hash: 9 }));
+        let (session, kind, body) = split_datagram(&datagram).unwrap();
+        assert_eq!((session, kind), (42, DATAGRAM_PLAIN));
+        let frames = split_frames(body).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert!(matches!(decode(&frames[0]), Ok(Message::Ping(Ping { id: 3, time: 1234, tick: 56 }))));
+        assert!(matches!(decode(&frames[1]), Ok(Message::Checksum(ChecksumPacket { tick: 7, hash: 9 }))));
+    }
+
+    #[test]
+    fn rejects_short_and_unknown_datagram_headers() {
+        for len in 0..DATAGRAM_HEADER_SIZE {
+            assert_eq!(split_datagram(&vec![0; len]).err(), Some(UnpackError::Truncated));
+        }
+        let datagram = datagram_header(1, 2);
+        assert_eq!(split_datagram(&datagram).err(), Some(UnpackError::BadValue(2)));
+    }
+
+    #[test]
+    fn rejects_truncated_frames() {
+        let frame = pack_frame(&ping());
+        assert_eq!(split_frames(&frame[..1]).err(), Some(UnpackError::Truncated));
+        for len in 2..frame.len() {
+            assert_eq!(split_frames(&frame[..len]).err(), Some(UnpackError::BadLength(frame.len())));
+        }
+        // 뒤에 붙은 프레임이 잘려도 전체를 버린다.
+        let mut body = frame.clone();
+        body.extend_from_slice(&frame[..frame.len() - 1]);
+        assert_eq!(split_frames(&body).err(), Some(UnpackError::BadLength(frame.len())));
+
+        let packet = pack(&ping());
+        assert_eq!(decode(&[]).err(), Some(UnpackError::Truncated));
+        for len in 1..packet.len() {
+            assert_eq!(decode(&packet[..len]).err(), Some(UnpackError::Truncated));
+        }
+    }
+
+    #[test]
+    fn rejects_bad_length_prefixes() {
+        for size in 0..=FRAME_PREFIX_SIZE {
+            let mut body = (size as u16).to_le_bytes().to_vec();
+            body.extend_from_slice(&[0; 8]);
+            assert_eq!(split_frames(&body).err(), Some(UnpackError::BadLength(size)));
+        }
+        let mut body = u16::MAX.to_le_bytes().to_vec();
+        body.extend(pack(&ping()));
+        assert_eq!(split_frames(&body).err(), Some(UnpackError::BadLength(u16::MAX as usize)));
+        let mut body = pack_frame(&ping());
+        body[0] += 1;
+        assert_eq!(split_frames(&body).err(), Some(UnpackError::BadLength(body.len() + 1)));
+    }
+
+    #[test]
+    fn rejects_unknown_types_and_versions() {
+        for type_byte in 16..=u8::MAX {
+            let mut packet = pack(&ping());
+            packet[0] = type_byte;
+            assert_eq!(decode(&packet).err(), Some(UnpackError::UnknownType(type_byte)));
+        }
+        let mut packet = pack(&ping());
+        packet[1] = PROTOCOL_VERSION + 1;
+        assert_eq!(decode(&packet).err(), Some(UnpackError::VersionMismatch(PROTOCOL_VERSION + 1)));
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let mut packet = pack(&ping());
+        packet.extend_from_slice(&[0; 3]);
+        assert_eq!(decode(&packet).err(), Some(UnpackError::TrailingBytes(3)));
+        let mut packet = pack(&Disconnect);
+        packet.push(0);
+        assert_eq!(decode(&packet).err(), Some(UnpackError::TrailingBytes(1)));
+    }
+
+    #[test]
+    fn frames_up_to_the_body_size() {
+        let framed = frame(vec![0; MAX_FRAME_SIZE - FRAME_PREFIX_SIZE]);
+        assert_eq!(framed.len(), MAX_BODY_SIZE);
+        assert_eq!(split_frames(&framed).unwrap()[0].len(), MAX_FRAME_SIZE - FRAME_PREFIX_SIZE);
+    }
+
+    #[test]
+    #[should_panic(expected = "packet too large to frame")]
+    fn refuses_to_frame_oversized_packets() {
+        frame(vec![0; MAX_FRAME_SIZE - FRAME_PREFIX_SIZE + 1]);
+    }
+}
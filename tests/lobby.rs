@@ -0,0 +1,144 @@
+// 로비 서버를 localhost 에 띄우고 LobbyClient 로 방을 만들고 들어가 본다.
+// 서버는 테스트 스레드에서 poll 에 가짜 시각을 넣어 돌린다.
+
+use std::net::TcpStream;
+use std::time::Duration;
+
+use p2pactiongame::lobby::{read_message, LobbyClient, LobbyMessage, LOBBY_CODE_LEN};
+use p2pactiongame::lobby_server::{LobbyConfig, LobbyServer};
+
+fn server(config: LobbyConfig) -> (LobbyServer, String) {
+    let server = LobbyServer::bind("127.0.0.1:0", config).unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    (server, addr)
+}
+
+// 조건이 맞을 때까지 서버를 돌린다. 시각은 한 번에 1ms 씩 흐른다.
+fn run_until(server: &mut LobbyServer, now: &mut u64, mut done: impl FnMut(&mut LobbyServer) -> bool) {
+    for _ in 0..2000 {
+        server.poll(*now);
+        if done(server) {
+            return;
+        }
+        *now += 1;
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    panic!("timed out");
+}
+
+fn settings() -> Vec<(String, String)> {
+    vec![("map".to_string(), "arena".to_string())]
+}
+
+// 호스트가 방을 만들고 손님이 코드로 들어온 상태
+fn room(server: &mut LobbyServer, addr: &str, now: &mut u64) -> (LobbyClient, LobbyClient) {
+    let host = LobbyClient::create(addr, "host", ":5555", settings()).unwrap();
+    run_until(server, now, |_| host.code().is_some());
+    let code = host.code().unwrap();
+    let guest = LobbyClient::join(addr, &format!(" {} ", code.to_lowercase()), "guest", "10.0.0.2:6000").unwrap();
+    run_until(server, now, |_| host.members().len() == 2 && guest.members().len() == 2);
+    (host, guest)
+}
+
+#[test]
+fn host_and_guest_see_readiness_and_settings_then_start() {
+    let (mut server, addr) = server(LobbyConfig::new());
+    let mut now = 0;
+    let (mut host, mut guest) = room(&mut server, &addr, &mut now);
+
+    let code = host.code().unwrap();
+    assert_eq!(code.len(), LOBBY_CODE_LEN);
+    assert_eq!(guest.code(), Some(code));
+    assert!(host.is_host());
+    assert_eq!(guest.member_id(), Some(1));
+    let members = guest.members();
+    assert_eq!(members[0].name, "host");
+    // ":포트" 는 서버가 본 IP 로 채운다.
+    assert_eq!(members[0].endpoint, "127.0.0.1:5555");
+    assert_eq!(members[1].endpoint, "10.0.0.2:6000");
+    assert_eq!(guest.setting("map").as_deref(), Some("arena"));
+
+    guest.set_ready(true).unwrap();
+    run_until(&mut server, &mut now, |_| host.members()[1].ready);
+
+    // 설정이 바뀌면 다시 준비해야 한다.
+    host.set_setting("map", "forest").unwrap();
+    run_until(&mut server, &mut now, |_| guest.setting("map").as_deref() == Some("forest"));
+    assert!(guest.members().iter().all(|member| !member.ready));
+
+    // 모두 준비되지 않으면 시작하지 않는다.
+    host.set_ready(true).unwrap();
+    let revision = guest.revision();
+    host.start().unwrap();
+    run_until(&mut server, &mut now, |_| guest.revision() > revision + 1);
+    assert_eq!(guest.started(), None);
+
+    guest.set_ready(true).unwrap();
+    run_until(&mut server, &mut now, |_| host.members().iter().all(|member| member.ready));
+    host.start().unwrap();
+    run_until(&mut server, &mut now, |_| host.started().is_some() && guest.started().is_some());
+    assert_eq!(guest.started().as_deref(), Some("127.0.0.1:5555"));
+
+    // 시작한 방에는 들어올 수 없다.
+    let late = LobbyClient::join(&addr, &host.code().unwrap(), "late", ":7000").unwrap();
+    run_until(&mut server, &mut now, |_| late.closed().is_some());
+    assert_eq!(late.closed().as_deref(), Some("game already started"));
+}
+
+#[test]
+fn only_the_host_changes_settings() {
+    let (mut server, addr) = server(LobbyConfig::new());
+    let mut now = 0;
+    let (host, mut guest) = room(&mut server, &addr, &mut now);
+    guest.set_setting("map", "mine").unwrap();
+    run_until(&mut server, &mut now, |_| guest.closed().is_some());
+    assert_eq!(guest.closed().as_deref(), Some("only the host can change settings"));
+    run_until(&mut server, &mut now, |_| host.members().len() == 1);
+    assert_eq!(host.setting("map").as_deref(), Some("arena"));
+}
+
+#[test]
+fn rejects_unknown_codes_and_full_rooms() {
+    let (mut server, addr) = server(LobbyConfig {
+        max_room_members: 2,
+        ..LobbyConfig::new()
+    });
+    let mut now = 0;
+    let (host, _guest) = room(&mut server, &addr, &mut now);
+
+    let lost = LobbyClient::join(&addr, "ZZZZZZ", "lost", ":7000").unwrap();
+    let third = LobbyClient::join(&addr, &host.code().unwrap(), "third", ":7000").unwrap();
+    run_until(&mut server, &mut now, |_| lost.closed().is_some() && third.closed().is_some());
+    assert_eq!(lost.closed().as_deref(), Some("no such room"));
+    assert_eq!(third.closed().as_deref(), Some("room is full"));
+}
+
+#[test]
+fn closes_the_room_when_the_host_leaves() {
+    let (mut server, addr) = server(LobbyConfig::new());
+    let mut now = 0;
+    let (host, guest) = room(&mut server, &addr, &mut now);
+    assert_eq!(server.room_count(), 1);
+    drop(host);
+    run_until(&mut server, &mut now, |_| guest.closed().is_some());
+    assert_eq!(guest.closed().as_deref(), Some("host left"));
+    run_until(&mut server, &mut now, |server| server.client_count() == 0);
+    assert_eq!(server.room_count(), 0);
+}
+
+#[test]
+fn rejects_other_versions() {
+    let (mut server, addr) = server(LobbyConfig::new());
+    let mut now = 0;
+    let mut stream = TcpStream::connect(&addr).unwrap();
+    let create = LobbyMessage::Create {
+        version: 0,
+        name: "old".to_string(),
+        endpoint: ":5000".to_string(),
+        settings: Vec::new(),
+    };
+    std::io::Write::write_all(&mut stream, &create.encode()).unwrap();
+    run_until(&mut server, &mut now, |server| server.client_count() == 0);
+    assert!(matches!(read_message(&mut stream), Ok(LobbyMessage::Error { .. })));
+    assert_eq!(server.room_count(), 0);
+}
@@ -0,0 +1,173 @@
+// 시뮬레이션 링크 위에서 reliable 채널 두 개와 네트워크 스택 (Mesh) 두 개를 돌린다.
+// 손실, 연속 손실, 순서 바뀜, 중복이 있어도 모두 순서대로 도착해야 하고, 같은 시드면 결과가 똑같아야 한다.
+
+use p2pactiongame::mesh::{Mesh, MeshEvent};
+use p2pactiongame::netsim::{BurstLoss, Latency, Link, LinkConfig, SimNetwork};
+use p2pactiongame::reliable::ChannelEndpoint;
+use p2pactiongame::simulation::{INPUT_JUMP, INPUT_LEFT, INPUT_RIGHT};
+use p2pactiongame::transport::{PeerId, Transport};
+use p2pactiongame::udp_net::{self, Channel, ChatPacket, Message};
+
+const TICK_MS: u64 = 16;
+const MESSAGES: usize = 200;
+
+fn bad_link() -> LinkConfig {
+    LinkConfig {
+        latency: Latency::Normal { mean: 80, std_dev: 20 },
+        loss: 0.1,
+        burst: Some(BurstLoss {
+            enter: 0.02,
+            exit: 0.3,
+            loss: 0.8,
+        }),
+        reorder: 0.1,
+        reorder_delay_ms: 60,
+        duplicate: 0.05,
+    }
+}
+
+fn send(transport: &mut dyn Transport, frames: &[Vec<u8>], to: PeerId) {
+    for body in udp_net::batch_frames(frames) {
+        transport.send(to, &body);
+    }
+}
+
+// 받은 데이터그램의 프레임들을 (받은 시각, 메시지) 로
+fn receive(transport: &mut dyn Transport) -> Vec<(u64, Message)> {
+    let mut messages = Vec::new();
+    for (_, received_at, datagram) in transport.poll() {
+        for frame in udp_net::split_frames(&datagram).unwrap() {
+            messages.push((received_at, udp_net::decode(&frame).unwrap()));
+        }
+    }
+    messages
+}
+
+// 받은 쪽에서 (받은 시각, 내용)
+fn run(seed: u64) -> Vec<(u64, String)> {
+    let network = SimNetwork::new(seed, bad_link());
+    let mut a = network.endpoint("10.0.0.1:5000".parse().unwrap());
+    let mut b = network.endpoint("10.0.0.2:5000".parse().unwrap());
+    let b_addr = a.resolve("10.0.0.2:5000").unwrap();
+    let a_addr = b.resolve("10.0.0.1:5000").unwrap();
+    let mut a_channel = ChannelEndpoint::new();
+    let mut b_channel = ChannelEndpoint::new();
+    let mut received = Vec::new();
+
+    for tick in 0..2000 {
+        let now = network.now();
+        let mut frames = a_channel.poll(now, 200);
+        if tick < MESSAGES {
+            frames.push(a_channel.send(Channel::ReliableOrdered, &ChatPacket { text: tick.to_string() }, now));
+        }
+        send(&mut a, &frames, b_addr);
+
+        for (received_at, message) in receive(&mut b) {
+            if let Message::Channel(packet) = message {
+                for payload in b_channel.receive(packet) {
+                    if let Ok(Message::Chat(chat)) = udp_net::decode(&payload) {
+                        received.push((received_at, chat.text));
+                    }
+                }
+            }
+        }
+        send(&mut b, &b_channel.poll(now, 200), a_addr);
+
+        for (_, message) in receive(&mut a) {
+            if let Message::ChannelAck(ack) = message {
+                a_channel.on_ack(&ack);
+            }
+        }
+        network.advance(TICK_MS);
+    }
+    received
+}
+
+#[test]
+fn reliable_channel_survives_bad_link() {
+    let received = run(7);
+    let texts: Vec<String> = received.iter().map(|(_, text)| text.clone()).collect();
+    let expected: Vec<String> = (0..MESSAGES).map(|i| i.to_string()).collect();
+    assert_eq!(texts, expected);
+}
+
+#[test]
+fn same_seed_reproduces_run() {
+    assert_eq!(run(42), run(42));
+    assert_ne!(run(42), run(43));
+}
+
+#[test]
+fn link_matches_configured_rates() {
+    let mut link = Link::new(
+        LinkConfig {
+            loss: 0.2,
+            duplicate: 0.1,
+            ..LinkConfig::ideal()
+        },
+        1,
+    );
+    let samples: Vec<usize> = (0..10000).map(|_| link.delays().len()).collect();
+    let lost = samples.iter().filter(|n| **n == 0).count();
+    let duplicated = samples.iter().filter(|n| **n == 2).count();
+    assert!((1800..2200).contains(&lost), "lost {}", lost);
+    assert!((600..1000).contains(&duplicated), "duplicated {}", duplicated);
+}
+
+fn script(tick: u64, player: u8) -> u8 {
+    match (tick / 11 + player as u64 * 2) % 5 {
+        0 => INPUT_RIGHT,
+        1 => INPUT_RIGHT | INPUT_JUMP,
+        2 => 0,
+        3 => INPUT_LEFT | INPUT_JUMP,
+        _ => INPUT_LEFT,
+    }
+}
+
+// 두 피어가 프레임마다 본 (틱, 상태 체크섬)
+fn run_match(seed: u64) -> Vec<Vec<(u64, u64)>> {
+    let network = SimNetwork::new(seed, bad_link());
+    let mut peers: Vec<Mesh> = (1..=2)
+        .map(|i| Mesh::new(Box::new(network.endpoint(format!("10.0.0.{}:5000", i).parse().unwrap()))))
+        .collect();
+    peers[1].send_connect("10.0.0.1:5000", (100.0, 0.0), network.now());
+    let mut traces = vec![Vec::new(), Vec::new()];
+    let mut scheduled = [0u64; 2];
+
+    for _ in 0..1500 {
+        let now = network.now();
+        for ((mesh, trace), scheduled) in peers.iter_mut().zip(traces.iter_mut()).zip(scheduled.iter_mut()) {
+            mesh.update(now);
+            mesh.advance(now);
+            let tick = mesh.tick();
+            if let (true, Some(id)) = (tick > 0, mesh.local_player_id()) {
+                // 입력 지연 3 틱. 한 프레임에 여러 틱을 돌렸으면 건너뛴 틱도 채운다.
+                let first = if *scheduled == 0 { tick + 3 } else { *scheduled + 1 };
+                for tick in first..=tick + 3 {
+                    mesh.add_local_input(tick, script(tick, id));
+                }
+                *scheduled = tick + 3;
+                mesh.send_inputs(now);
+            }
+            mesh.flush();
+            for event in mesh.take_events() {
+                assert!(!matches!(event, MeshEvent::Desync { .. }));
+            }
+            let checksum = mesh.session().as_ref().map_or(0, |session| session.state().checksum());
+            trace.push((tick, checksum));
+        }
+        network.advance(TICK_MS);
+    }
+    traces
+}
+
+// 두 스택이 나쁜 링크 위에서 경기를 하고, 같은 시드로 다시 하면 프레임마다 같은 것을 본다.
+#[test]
+fn same_seed_reproduces_match() {
+    let first = run_match(11);
+    for trace in first.iter() {
+        assert!(trace.last().unwrap().0 > 1000, "{:?}", trace.last());
+    }
+    assert_eq!(first, run_match(11));
+    assert_ne!(first, run_match(12));
+}
@@ -0,0 +1,133 @@
+// 중계 서버를 localhost 에 띄우고 RelayTransport 로 붙어 본다.
+// 서버는 테스트 스레드에서 poll 에 가짜 시각을 넣어 돌린다.
+
+use std::time::Duration;
+
+use p2pactiongame::relay::{relay_endpoint, RelayFallback, RelayTransport};
+use p2pactiongame::relay_server::{RelayConfig, RelayServer};
+use p2pactiongame::transport::{MemoryTransport, Transport};
+
+fn server(config: RelayConfig) -> (RelayServer, String) {
+    let server = RelayServer::bind("127.0.0.1:0", config).unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    (server, addr)
+}
+
+// 조건이 맞을 때까지 서버를 돌린다. 시각은 한 번에 1ms 씩 흐른다.
+fn run_until(server: &mut RelayServer, now: &mut u64, mut done: impl FnMut(&mut RelayServer) -> bool) {
+    for _ in 0..2000 {
+        server.poll(*now);
+        if done(server) {
+            return;
+        }
+        *now += 1;
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    panic!("timed out");
+}
+
+#[test]
+fn relays_between_clients_in_the_same_room() {
+    let (mut server, addr) = server(RelayConfig::new());
+    let mut now = 0;
+    let mut a = RelayTransport::connect(&addr, "room").unwrap();
+    let mut b = RelayTransport::connect(&addr, "room").unwrap();
+    let mut other = RelayTransport::connect(&addr, "other").unwrap();
+    run_until(&mut server, &mut now, |_| {
+        a.relay_peers().len() == 1 && b.relay_peers().len() == 1 && other.client_id().is_some()
+    });
+    assert_eq!(server.room_count(), 2);
+
+    let to_b = a.relay_peers()[0];
+    assert_eq!(Some(to_b.0), b.client_id());
+    a.send(to_b, b"hello");
+    // 다른 방의 클라이언트에게는 보낼 수 없다.
+    let to_other = a.resolve(&relay_endpoint(other.client_id().unwrap())).unwrap();
+    a.send(to_other, b"nope");
+    let mut received = Vec::new();
+    run_until(&mut server, &mut now, |server| {
+        received.extend(b.poll());
+        !received.is_empty() && server.dropped() == 1
+    });
+    assert_eq!(received[0].0 .0, a.client_id().unwrap());
+    assert_eq!(received[0].2, b"hello");
+    assert!(other.poll().is_empty());
+
+    drop(a);
+    run_until(&mut server, &mut now, |_| b.relay_peers().is_empty());
+    assert_eq!(server.client_count(), 2);
+}
+
+#[test]
+fn drops_messages_over_the_rate_limit() {
+    let (mut server, addr) = server(RelayConfig {
+        messages_per_sec: 10,
+        ..RelayConfig::new()
+    });
+    let mut now = 0;
+    let mut a = RelayTransport::connect(&addr, "room").unwrap();
+    let mut b = RelayTransport::connect(&addr, "room").unwrap();
+    run_until(&mut server, &mut now, |_| a.relay_peers().len() == 1);
+
+    let to_b = a.relay_peers()[0];
+    for i in 0..50u8 {
+        a.send(to_b, &[i]);
+    }
+    run_until(&mut server, &mut now, |server| server.dropped() >= 40);
+    let mut received = Vec::new();
+    run_until(&mut server, &mut now, |_| {
+        received.extend(b.poll());
+        received.len() >= 10
+    });
+    assert_eq!(received.len(), 10);
+}
+
+#[test]
+fn cleans_up_idle_clients_and_empty_rooms() {
+    let (mut server, addr) = server(RelayConfig {
+        idle_timeout_ms: 100,
+        ..RelayConfig::new()
+    });
+    let mut now = 0;
+    let a = RelayTransport::connect(&addr, "room").unwrap();
+    run_until(&mut server, &mut now, |_| a.client_id().is_some());
+    assert_eq!(server.room_count(), 1);
+
+    now += 200;
+    run_until(&mut server, &mut now, |server| server.client_count() == 0);
+    assert_eq!(server.room_count(), 0);
+    run_until(&mut server, &mut now, |_| a.closed().is_some());
+}
+
+#[test]
+fn fallback_routes_relay_endpoints_through_the_relay() {
+    let (mut server, addr) = server(RelayConfig::new());
+    let mut now = 0;
+    let (direct, _remote) = MemoryTransport::pair("a", "b");
+    let mut a = RelayFallback::new(Box::new(direct), RelayTransport::connect(&addr, "room").unwrap());
+    let mut b = RelayTransport::connect(&addr, "room").unwrap();
+    run_until(&mut server, &mut now, |_| a.relay_peers().len() == 1 && b.client_id().is_some());
+
+    let direct_b = a.resolve("b").unwrap();
+    let relay_b = a.relay_peers()[0];
+    assert_ne!(direct_b, relay_b);
+    assert_eq!(a.endpoint(direct_b).as_deref(), Some("b"));
+    assert_eq!(a.endpoint(relay_b), Some(relay_endpoint(b.client_id().unwrap())));
+    assert_eq!(a.resolve(&a.endpoint(relay_b).unwrap()), Some(relay_b));
+
+    a.send(relay_b, b"via relay");
+    let mut received = Vec::new();
+    run_until(&mut server, &mut now, |_| {
+        received.extend(b.poll());
+        !received.is_empty()
+    });
+    let to_a = received[0].0;
+    b.send(to_a, b"back");
+    let mut replies = Vec::new();
+    run_until(&mut server, &mut now, |_| {
+        replies.extend(a.poll());
+        !replies.is_empty()
+    });
+    assert_eq!(replies[0].0, relay_b);
+    assert_eq!(replies[0].2, b"back");
+}
@@ -0,0 +1,177 @@
+// 두 피어를 가짜 NAT 뒤에 두고 중개 서버로 구멍을 뚫는다.
+// 서버도 같은 가짜 네트워크 위의 한 주소에서 돈다.
+
+use std::net::SocketAddr;
+
+use p2pactiongame::netsim::{Latency, LinkConfig, NatKind, SimNetwork, SimTransport};
+use p2pactiongame::rendezvous::{
+    HolePuncher, PunchState, RendezvousMessage, PUNCH_ATTEMPTS, PUNCH_INTERVAL_MS,
+};
+use p2pactiongame::rendezvous_server::{RendezvousConfig, RendezvousServer};
+use p2pactiongame::transport::{PeerId, Transport};
+
+const STEP_MS: u64 = 10;
+const SERVER: &str = "198.51.100.1:7500";
+const A_PRIVATE: &str = "192.168.1.2:5000";
+const A_PUBLIC: &str = "203.0.113.10:40000";
+const B_PRIVATE: &str = "192.168.2.3:5000";
+const B_PUBLIC: &str = "203.0.113.20:50000";
+
+fn addr(text: &str) -> SocketAddr {
+    text.parse().unwrap()
+}
+
+fn network(b_nat: NatKind) -> SimNetwork {
+    let mut link = LinkConfig::ideal();
+    link.latency = Latency::Fixed(30);
+    let network = SimNetwork::new(7, link);
+    network.add_nat(addr(A_PRIVATE), addr(A_PUBLIC), NatKind::PortRestricted);
+    network.add_nat(addr(B_PRIVATE), addr(B_PUBLIC), b_nat);
+    network
+}
+
+struct Client {
+    transport: SimTransport,
+    punch: HolePuncher,
+    // 구멍 뚫기 것을 뺀 게임 데이터그램
+    received: Vec<Vec<u8>>,
+}
+
+impl Client {
+    fn new(network: &SimNetwork, private: &str) -> Self {
+        let mut transport = network.endpoint(addr(private));
+        let server = transport.resolve(SERVER).unwrap();
+        Self {
+            transport,
+            punch: HolePuncher::new(server, "room"),
+            received: Vec::new(),
+        }
+    }
+
+    fn step(&mut self, now: u64) {
+        let received = self.transport.poll();
+        let game = self.punch.process(&mut self.transport, received, now);
+        self.received.extend(game.into_iter().map(|(_, _, bytes)| bytes));
+    }
+
+    fn peer(&mut self, endpoint: &str) -> PeerId {
+        self.transport.resolve(endpoint).unwrap()
+    }
+}
+
+fn serve(server: &mut RendezvousServer, transport: &mut SimTransport, now: u64) {
+    for (peer, _, datagram) in transport.poll() {
+        let from = transport.endpoint(peer).unwrap().parse().unwrap();
+        server.receive(from, &datagram, now);
+    }
+    server.poll(now);
+    for (to, datagram) in server.take_outgoing() {
+        let peer = transport.resolve(&to.to_string()).unwrap();
+        transport.send(peer, &datagram);
+    }
+}
+
+fn run(network: &SimNetwork, server: &mut RendezvousServer, server_transport: &mut SimTransport, clients: &mut [&mut Client], ms: u64) {
+    for _ in 0..ms / STEP_MS {
+        let now = network.now();
+        serve(server, server_transport, now);
+        for client in clients.iter_mut() {
+            client.step(now);
+        }
+        network.advance(STEP_MS);
+    }
+}
+
+#[test]
+fn nat_blocks_unsolicited_datagrams() {
+    let network = network(NatKind::PortRestricted);
+    let mut a = network.endpoint(addr(A_PRIVATE));
+    let mut b = network.endpoint(addr(B_PRIVATE));
+    let to_b = a.resolve(B_PUBLIC).unwrap();
+    a.send(to_b, b"hello");
+    network.advance(100);
+    assert!(b.poll().is_empty());
+    assert_eq!(network.nat_dropped(), 1);
+
+    // B 가 먼저 A 쪽으로 보냈으면 들어온다.
+    let to_a = b.resolve(A_PUBLIC).unwrap();
+    b.send(to_a, b"open");
+    a.send(to_b, b"hello");
+    network.advance(100);
+    let received = b.poll();
+    assert_eq!(received.len(), 1);
+    assert_eq!(b.endpoint(received[0].0).as_deref(), Some(A_PUBLIC));
+}
+
+#[test]
+fn punches_through_port_restricted_nats() {
+    let network = network(NatKind::PortRestricted);
+    let mut server = RendezvousServer::new(RendezvousConfig::new());
+    let mut server_transport = network.endpoint(addr(SERVER));
+    let mut a = Client::new(&network, A_PRIVATE);
+    run(&network, &mut server, &mut server_transport, &mut [&mut a], 200);
+    let mut b = Client::new(&network, B_PRIVATE);
+    run(&network, &mut server, &mut server_transport, &mut [&mut a, &mut b], 1000);
+
+    assert_eq!(a.punch.public_endpoint(), Some(A_PUBLIC));
+    assert_eq!(b.punch.public_endpoint(), Some(B_PUBLIC));
+    assert_eq!(server.room_count(), 1);
+    assert_eq!(server.client_count(), 2);
+
+    let a_to_b = a.peer(B_PUBLIC);
+    let b_to_a = b.peer(A_PUBLIC);
+    assert_eq!(a.punch.state(a_to_b), Some(PunchState::Open));
+    assert_eq!(b.punch.state(b_to_a), Some(PunchState::Open));
+    // A 가 먼저 등록했으니 호스트다.
+    assert_eq!(b.punch.host(), Some((b_to_a, PunchState::Open)));
+    assert_eq!(a.punch.host(), None);
+
+    // 구멍이 뚫렸으니 게임 데이터그램이 양쪽으로 지나가고 구멍 뚫기 것은 걸러진다.
+    a.transport.send(a_to_b, b"from a");
+    b.transport.send(b_to_a, b"from b");
+    run(&network, &mut server, &mut server_transport, &mut [&mut a, &mut b], 100);
+    assert_eq!(a.received, vec![b"from b".to_vec()]);
+    assert_eq!(b.received, vec![b"from a".to_vec()]);
+
+    // 등록이 끊긴 피어는 방에서 빠진다.
+    run(&network, &mut server, &mut server_transport, &mut [&mut a], 11_000);
+    assert_eq!(server.client_count(), 1);
+}
+
+#[test]
+fn gives_up_behind_symmetric_nat() {
+    let network = network(NatKind::Symmetric);
+    let mut server = RendezvousServer::new(RendezvousConfig::new());
+    let mut server_transport = network.endpoint(addr(SERVER));
+    let mut a = Client::new(&network, A_PRIVATE);
+    let mut b = Client::new(&network, B_PRIVATE);
+    run(&network, &mut server, &mut server_transport, &mut [&mut a, &mut b], 300);
+
+    let a_to_b = a.peer(B_PUBLIC);
+    assert_eq!(a.punch.state(a_to_b), Some(PunchState::Punching));
+    let give_up = PUNCH_ATTEMPTS as u64 * PUNCH_INTERVAL_MS + 500;
+    run(&network, &mut server, &mut server_transport, &mut [&mut a, &mut b], give_up);
+    assert_eq!(a.punch.state(a_to_b), Some(PunchState::Failed));
+    assert!(a.punch.take_log().iter().any(|line| line.starts_with("Could not punch through")));
+    let (host, state) = b.punch.host().unwrap();
+    assert_eq!(b.transport.endpoint(host).as_deref(), Some(A_PUBLIC));
+    assert_eq!(state, PunchState::Failed);
+    assert!(network.nat_dropped() > 0);
+}
+
+#[test]
+fn rejects_other_versions() {
+    let mut server = RendezvousServer::new(RendezvousConfig::new());
+    let register = RendezvousMessage::Register {
+        version: 0,
+        room: "room".to_string(),
+    };
+    server.receive(addr(A_PUBLIC), &register.encode(), 0);
+    let outgoing = server.take_outgoing();
+    assert_eq!(outgoing.len(), 1);
+    assert!(matches!(
+        RendezvousMessage::decode(&outgoing[0].1),
+        Ok(RendezvousMessage::Error { .. })
+    ));
+    assert_eq!(server.client_count(), 0);
+}
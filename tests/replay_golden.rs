@@ -0,0 +1,26 @@
+// tests/replays 의 리플레이마다 옆의 .golden 과 틱별 상태 해시를 비교한다.
+// 시뮬레이션을 일부러 바꿨다면 replay_check --bless 로 골든을 다시 만든다.
+
+use std::path::Path;
+
+use p2pactiongame::replay::{first_mismatch, parse_hashes, Replay};
+
+#[test]
+fn replays_match_golden_hashes() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/replays");
+    let mut checked = 0;
+    for entry in std::fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_none_or(|ext| ext != "p2pr") {
+            continue;
+        }
+        let replay = Replay::from_bytes(&std::fs::read(&path).unwrap()).unwrap();
+        let golden_path = path.with_extension("golden");
+        let golden = parse_hashes(&std::fs::read_to_string(&golden_path).unwrap()).unwrap();
+
+        let mismatch = first_mismatch(&golden, &replay.state_hashes());
+        assert!(mismatch.is_none(), "{}: {:?}", path.display(), mismatch);
+        checked += 1;
+    }
+    assert!(checked > 0, "no replays in {}", dir.display());
+}
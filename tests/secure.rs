@@ -0,0 +1,122 @@
+// 보안 모드의 핸드셰이크와 암호화를 localhost 의 실제 UDP 소켓 두 개로 돌려 본다.
+// 가운데에서 바꾸거나 다시 보낸 데이터그램은 받는 쪽 Session 이 버린다.
+
+use std::net::UdpSocket;
+use std::time::Duration;
+
+use p2pactiongame::secure::psk_from_room_code;
+use p2pactiongame::session::Session;
+use p2pactiongame::udp_net::{
+    self, ChatPacket, Connect, Message, UnpackError, DATAGRAM_PLAIN, MAX_DATAGRAM_SIZE, UNASSIGNED_ID,
+};
+
+fn socket() -> UdpSocket {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    socket
+}
+
+fn receive(socket: &UdpSocket) -> Vec<u8> {
+    let mut buffer = [0; MAX_DATAGRAM_SIZE];
+    let (size, _) = socket.recv_from(&mut buffer).unwrap();
+    buffer[..size].to_vec()
+}
+
+fn connect(session: &Session, nonce: u64, echo: u64) -> Vec<u8> {
+    udp_net::pack_frame(&Connect {
+        x: 0.0,
+        y: 0.0,
+        player_id: UNASSIGNED_ID,
+        assigned_id: UNASSIGNED_ID,
+        nonce,
+        echo,
+        public_key: session.public_key(),
+    })
+}
+
+fn message(body: &[u8]) -> Message {
+    let frames = udp_net::split_frames(body).unwrap();
+    udp_net::decode(&frames[0]).unwrap()
+}
+
+fn chat_text(body: &[u8]) -> String {
+    match message(body) {
+        Message::Chat(chat) => chat.text,
+        _ => panic!("not a chat"),
+    }
+}
+
+// 양쪽 Session 이 Connect 를 주고받아 키를 정한 상태
+fn handshake(a_socket: &UdpSocket, b_socket: &UdpSocket, b_room: &str) -> (Session, Session) {
+    let mut a = Session::new_secure(Some(psk_from_room_code("ROOM42")));
+    let mut b = Session::new_secure(Some(psk_from_room_code(b_room)));
+
+    let mut hello = udp_net::datagram_header(0, DATAGRAM_PLAIN).to_vec();
+    hello.extend(connect(&a, a.local_nonce(), 0));
+    a_socket.send_to(&hello, b_socket.local_addr().unwrap()).unwrap();
+
+    let (_, body) = b.unwrap(&receive(b_socket)).unwrap();
+    let Message::Connect(hello) = message(&body) else {
+        panic!("expected Connect");
+    };
+    let nonce = b.accept(hello.nonce, hello.public_key).unwrap();
+    let reply = b.wrap(&connect(&b, nonce, hello.nonce));
+    b_socket.send_to(&reply, a_socket.local_addr().unwrap()).unwrap();
+
+    let reply = receive(a_socket);
+    let (header, body) = a.unwrap(&reply).unwrap();
+    let Message::Connect(reply) = message(&body) else {
+        panic!("expected Connect");
+    };
+    assert!(a.complete(reply.echo, reply.nonce, header, reply.public_key));
+    assert_eq!(a.id(), b.id());
+    (a, b)
+}
+
+#[test]
+fn secure_session_over_localhost() {
+    let a_socket = socket();
+    let b_socket = socket();
+    let (mut a, mut b) = handshake(&a_socket, &b_socket, "room42");
+    let b_addr = b_socket.local_addr().unwrap();
+
+    let sealed = a.wrap(&udp_net::pack_frame(&ChatPacket { text: "gg".to_string() }));
+    // 평문이 그대로 드러나지 않는다.
+    assert!(!sealed.windows(2).any(|bytes| bytes == b"gg"));
+    a_socket.send_to(&sealed, b_addr).unwrap();
+    let (_, body) = b.unwrap(&receive(&b_socket)).unwrap();
+    assert_eq!(chat_text(&body), "gg");
+
+    // 가운데에서 한 바이트를 바꾸면 버린다.
+    let mut tampered = a.wrap(&udp_net::pack_frame(&ChatPacket { text: "ok".to_string() }));
+    let last = tampered.len() - 1;
+    tampered[last] ^= 0x80;
+    a_socket.send_to(&tampered, b_addr).unwrap();
+    assert_eq!(b.unwrap(&receive(&b_socket)).err(), Some(UnpackError::Unauthenticated));
+
+    // 같은 데이터그램을 다시 보내도 버린다.
+    a_socket.send_to(&sealed, b_addr).unwrap();
+    assert!(matches!(b.unwrap(&receive(&b_socket)), Err(UnpackError::Replayed(_))));
+
+    // 상대가 암호문을 보냈으니 이제 평문은 받지 않는다.
+    let mut plain = udp_net::datagram_header(a.id(), DATAGRAM_PLAIN).to_vec();
+    plain.extend(udp_net::pack_frame(&ChatPacket { text: "spoof".to_string() }));
+    a_socket.send_to(&plain, b_addr).unwrap();
+    assert_eq!(b.unwrap(&receive(&b_socket)).err(), Some(UnpackError::Unauthenticated));
+
+    // 답장도 암호화된다.
+    let reply = b.wrap(&udp_net::pack_frame(&ChatPacket { text: "wp".to_string() }));
+    b_socket.send_to(&reply, a_socket.local_addr().unwrap()).unwrap();
+    let (_, body) = a.unwrap(&receive(&a_socket)).unwrap();
+    assert_eq!(chat_text(&body), "wp");
+}
+
+#[test]
+fn wrong_room_code_cannot_read_traffic() {
+    let a_socket = socket();
+    let b_socket = socket();
+    let (mut a, mut b) = handshake(&a_socket, &b_socket, "ROOM43");
+    let sealed = a.wrap(&udp_net::pack_frame(&ChatPacket { text: "gg".to_string() }));
+    a_socket.send_to(&sealed, b_socket.local_addr().unwrap()).unwrap();
+    assert_eq!(b.unwrap(&receive(&b_socket)).err(), Some(UnpackError::Unauthenticated));
+}
@@ -0,0 +1,54 @@
+use p2pactiongame::relay::{parse_relay_endpoint, relay_endpoint, take_message, RelayMessage};
+use p2pactiongame::transport::{MemoryTransport, Transport};
+
+#[test]
+fn memory_pair_delivers_in_order() {
+    let (mut a, mut b) = MemoryTransport::pair("a", "b");
+    let to_b = a.resolve("b").unwrap();
+    assert!(a.resolve("c").is_none());
+    assert_eq!(a.endpoint(to_b).as_deref(), Some("b"));
+
+    for i in 0..10u8 {
+        a.send(to_b, &[i]);
+    }
+    let received: Vec<Vec<u8>> = b.poll().into_iter().map(|(_, _, bytes)| bytes).collect();
+    assert_eq!(received, (0..10u8).map(|i| vec![i]).collect::<Vec<_>>());
+    assert!(b.poll().is_empty());
+
+    let to_a = b.resolve("a").unwrap();
+    b.send(to_a, b"pong");
+    let (from, _, bytes) = a.poll().pop().unwrap();
+    assert_eq!(from, to_b);
+    assert_eq!(bytes, b"pong");
+}
+
+#[test]
+fn relay_messages_survive_split_stream() {
+    let messages = vec![
+        RelayMessage::Join { version: 1, room: "room".to_string() },
+        RelayMessage::Welcome { client: 7 },
+        RelayMessage::PeerJoined { client: 8 },
+        RelayMessage::Send { to: 8, data: vec![1, 2, 3] },
+        RelayMessage::Deliver { from: 7, data: vec![] },
+        RelayMessage::PeerLeft { client: 8 },
+        RelayMessage::Error { reason: "bye".to_string() },
+    ];
+    let stream: Vec<u8> = messages.iter().flat_map(|message| message.encode()).collect();
+
+    // 한 바이트씩 들어와도 메시지 경계를 지킨다.
+    let mut buffer = Vec::new();
+    let mut decoded = Vec::new();
+    for byte in stream {
+        buffer.push(byte);
+        while let Some(message) = take_message(&mut buffer).unwrap() {
+            decoded.push(message);
+        }
+    }
+    assert_eq!(decoded, messages);
+    assert!(buffer.is_empty());
+
+    let mut bad = vec![1, 0, 99];
+    assert!(take_message(&mut bad).is_err());
+    assert_eq!(parse_relay_endpoint(&relay_endpoint(42)), Some(42));
+    assert_eq!(parse_relay_endpoint("127.0.0.1:5000"), None);
+}